@@ -13,11 +13,42 @@ const STYLES: clap::builder::styling::Styles = clap::builder::styling::Styles::s
 #[command(styles=STYLES)]
 struct CliArgs {
     #[command(subcommand)]
-    command: Commands,
+    command: CliCommand,
     #[arg(short, long)]
     verbose: bool,
+    /// List every file and directory a sync or restore deleted at the end, instead of counting them
+    #[arg(long, global = true)]
+    list_deleted: bool,
+    /// Template of the progress bars, e.g. `{bar:40} {bytes}/{total_bytes} {bytes_per_sec} {eta} {file}`.
+    /// Besides the keys of indicatif like `{bar}`, `{pos}`, `{len}`, `{bytes}`, `{total_bytes}`,
+    /// `{bytes_per_sec}`, `{eta}`, `{elapsed}`, `{prefix}` and `{wide_msg}`, `{file}` shows the
+    /// file which is being processed.
+    #[arg(
+        long,
+        global = true,
+        value_name = "TEMPLATE",
+        env = "SAFEALL_PROGRESS_TEMPLATE",
+        value_parser = parse_progress_template
+    )]
+    progress_template: Option<String>,
 }
 
+#[derive(clap::Subcommand)]
+enum CliCommand {
+    #[command(flatten)]
+    Run(Commands),
+    /// Retry only the files and folders which a backup or sync could not process, as listed
+    /// in the error report it wrote, without scanning the whole source again.
+    RetryFailed {
+        /// Error report of the backup or sync, `safeall-errors-<timestamp>.json`
+        #[arg(long, value_name = "FILE")]
+        from: String,
+        #[command(flatten)]
+        options: BackupOptions,
+    },
+}
+
+/// The commands which run a [`safeall::Command`].
 #[derive(clap::Subcommand)]
 enum Commands {
     /// Backup files from source directory to destination directory.
@@ -27,6 +58,12 @@ enum Commands {
         source_root: String,
         /// Folder which will be your backup
         destination_root: String,
+        /// Write a tar archive to the destination instead of a folder, compressed with gzip or
+        /// zstd if its name ends with .gz or .zst. A name ending with .zip writes a zip archive
+        #[arg(long)]
+        archive: bool,
+        #[command(flatten)]
+        options: BackupOptions,
     },
     /// Sync destination directory from source directory.
     /// This delets files in the destination directory when they do not exist in the source directory.
@@ -35,6 +72,8 @@ enum Commands {
         source_root: String,
         /// Folder which will be your backup
         destination_root: String,
+        #[command(flatten)]
+        options: BackupOptions,
     },
     /// Restore the source directory from the destination directory.
     Restore {
@@ -45,34 +84,799 @@ enum Commands {
         /// If you want to delete the files that are not in your backup
         #[arg(short, long)]
         delete_files: bool,
+        /// Fail if the folder to restore into does not exist instead of creating it
+        #[arg(long)]
+        no_create: bool,
+        /// Fail if the folder to restore into is not empty instead of merging the backup into it
+        #[arg(long)]
+        require_empty: bool,
+        /// Whether the folder to restore into may be on the drive of the backup
+        #[arg(long, value_enum, default_value_t = TargetFilesystem::Any)]
+        target_filesystem: TargetFilesystem,
+        /// Rename files which differ from the backup to `<name>.pre-restore` instead of
+        /// overwriting them
+        #[arg(long)]
+        keep_overwritten: bool,
+        #[command(flatten)]
+        options: BackupOptions,
+    },
+    /// Verify that the destination directory is an intact backup of the source directory.
+    /// Nothing is modified.
+    Verify {
+        /// Folder which you have backed up
+        source_root: String,
+        /// Folder where you have your backup
+        destination_root: String,
+        #[command(flatten)]
+        options: BackupOptions,
     },
+    /// Check that the files in the destination directory still have the hashes recorded when
+    /// they were backed up. The source is not needed and nothing is modified.
+    Scrub {
+        /// Folder where you have your backup
+        destination_root: String,
+        /// Folder which you have backed up, to repair corrupted files from with --repair
+        #[arg(long, value_name = "SOURCE_ROOT")]
+        source: Option<String>,
+        #[command(flatten)]
+        options: BackupOptions,
+    },
+    /// Remove old snapshots of the files which syncs have moved to the quarantine, or old
+    /// snapshots of a repository. A snapshot is kept if any of the rules keeps it.
+    ///
+    /// The chunks of pruned repository snapshots are only removed by gc.
+    Prune {
+        /// Folder where you have your backup
+        destination_root: String,
+        /// Keep the most recent snapshots
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        keep_last: usize,
+        /// Keep the most recent snapshot of each of the last days with snapshots
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        keep_daily: usize,
+        /// Keep the most recent snapshot of each of the last weeks with snapshots
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        keep_weekly: usize,
+        /// Keep the most recent snapshot of each of the last months with snapshots
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        keep_monthly: usize,
+        #[command(flatten)]
+        options: BackupOptions,
+    },
+    /// Store a snapshot of the source directory in a repository, where the content of files
+    /// is deduplicated across all snapshots.
+    Snapshot {
+        /// Folder which you want to backup
+        source_root: String,
+        /// Folder of the repository, which is created if it does not exist
+        destination_root: String,
+        #[command(flatten)]
+        options: BackupOptions,
+    },
+    /// Restore a snapshot of a repository into the source directory.
+    RestoreSnapshot {
+        /// Folder which you want to restore from the repository
+        source_root: String,
+        /// Folder of the repository
+        destination_root: String,
+        /// ID of the snapshot, the most recent one if not given
+        #[arg(long, value_name = "ID")]
+        snapshot: Option<String>,
+        #[command(flatten)]
+        options: BackupOptions,
+    },
+    /// List the snapshots of a repository.
+    Snapshots {
+        /// Folder of the repository
+        destination_root: String,
+        #[command(flatten)]
+        options: BackupOptions,
+    },
+    /// Remove the chunks of a repository which no snapshot uses anymore, e.g. after prune.
+    Gc {
+        /// Folder of the repository
+        destination_root: String,
+        #[command(flatten)]
+        options: BackupOptions,
+    },
+    /// Check that the snapshots of a repository only use chunks which it has and that its
+    /// packs are intact, without reading the chunks.
+    Check {
+        /// Folder of the repository
+        destination_root: String,
+        #[command(flatten)]
+        options: BackupOptions,
+    },
+    /// Check the source and the destination for common problems before the first backup, e.g.
+    /// names or paths which the destination cannot store or too little free space.
+    Doctor {
+        /// Folder which you want to backup
+        source_root: String,
+        /// Folder which will be your backup
+        destination_root: String,
+        #[command(flatten)]
+        options: BackupOptions,
+    },
+    /// List, add or remove the passphrases and key files which unlock an encrypted repository.
+    /// The repository is unlocked with --passphrase, --passphrase-file or --key-file.
+    Keys {
+        /// Folder of the repository
+        destination_root: String,
+        #[command(subcommand)]
+        action: KeyAction,
+        #[command(flatten)]
+        options: BackupOptions,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum KeyAction {
+    /// List the keys of the repository.
+    List,
+    /// Register another passphrase or key file. A key file which does not exist is created.
+    Add {
+        #[command(flatten)]
+        new: NewSecret,
+    },
+    /// Remove a key, which must not be the last one.
+    Remove {
+        /// ID of the key, as listed by `keys list`
+        id: String,
+    },
+    /// Replace the key which unlocks the repository with another passphrase or key file.
+    Rotate {
+        #[command(flatten)]
+        new: NewSecret,
+    },
+}
+
+#[derive(clap::Args)]
+#[group(required = true, multiple = false)]
+struct NewSecret {
+    /// The new passphrase
+    #[arg(
+        long = "new-passphrase",
+        env = "SAFEALL_NEW_PASSPHRASE",
+        hide_env_values = true
+    )]
+    passphrase: Option<String>,
+    /// Read the new passphrase from the first line of this file
+    #[arg(long = "new-passphrase-file", value_name = "FILE", value_parser = read_passphrase_file)]
+    passphrase_file: Option<String>,
+    /// The new key file, which is created with random content if it does not exist
+    #[arg(long = "new-key-file", value_name = "FILE")]
+    key_file: Option<std::path::PathBuf>,
+}
+
+impl From<NewSecret> for safeall::Secret {
+    fn from(new: NewSecret) -> Self {
+        new.key_file.map_or_else(
+            || {
+                safeall::Secret::Passphrase(
+                    new.passphrase_file.or(new.passphrase).unwrap_or_default(),
+                )
+            },
+            safeall::Secret::KeyFile,
+        )
+    }
+}
+
+impl From<KeyAction> for safeall::KeyAction {
+    fn from(action: KeyAction) -> Self {
+        match action {
+            KeyAction::List => safeall::KeyAction::List,
+            KeyAction::Add { new } => safeall::KeyAction::Add(new.into()),
+            KeyAction::Remove { id } => safeall::KeyAction::Remove(id),
+            KeyAction::Rotate { new } => safeall::KeyAction::Rotate(new.into()),
+        }
+    }
+}
+
+#[derive(clap::Args)]
+#[allow(clippy::struct_excessive_bools)]
+struct BackupOptions {
+    /// Copy `SQLite` databases together with their write-ahead log such that the backup is consistent
+    #[arg(long)]
+    sqlite_consistent_copy: bool,
+    /// Copy files in chunks of this many bytes
+    #[arg(long, value_name = "BYTES")]
+    copy_buffer_size: Option<usize>,
+    /// Bypass the page cache of the operating system while copying
+    #[arg(long)]
+    bypass_page_cache: bool,
+    /// Slow down the backup while the machine runs on battery or is under thermal pressure
+    #[arg(long)]
+    power_aware_throttling: bool,
+    /// What sync does with files in the destination which have never been backed up by safeall
+    #[arg(long, value_enum, default_value_t = SuspiciousDeletionPolicy::Quarantine)]
+    suspicious_deletions: SuspiciousDeletionPolicy,
+    /// Abort a sync which would modify or delete more than this percentage of the destination
+    #[arg(long, value_name = "PERCENT", default_value_t = safeall::DEFAULT_MASS_CHANGE_THRESHOLD, value_parser = clap::value_parser!(u8).range(0..=100))]
+    max_change_percentage: u8,
+    /// Sync even if it modifies or deletes most of the destination
+    #[arg(long)]
+    allow_mass_change: bool,
+    /// Copy files which failed or changed during the copy again this many times at the end
+    #[arg(long, value_name = "PASSES", default_value_t = safeall::DEFAULT_RETRY_PASSES)]
+    retry_passes: usize,
+    /// Run even if the destination is not the one used last time, e.g. after replacing the drive
+    #[arg(long)]
+    accept_new_destination: bool,
+    /// Only back up files matching this glob pattern (can be given multiple times)
+    #[arg(long, value_name = "PATTERN")]
+    include: Vec<String>,
+    /// Neither back up nor delete files and directories matching this glob pattern (can be given multiple times)
+    ///
+    /// The metadata of destinations (`.safeall/`), partial copies, the safeall executable and
+    /// its data directory are always excluded.
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+    /// Do not honor `.safeallignore` files
+    #[arg(long)]
+    no_ignore_files: bool,
+    /// Exclude directories marked with a `CACHEDIR.TAG` and the usual cache and build
+    /// directories, e.g. `.cache`, `node_modules`, `target` and `__pycache__`
+    #[arg(long)]
+    exclude_caches: bool,
+    /// Refuse to run unless the destination, and the source of a sync, is on a mounted drive
+    #[arg(long)]
+    require_mounted: bool,
+    /// Only show what would be copied and deleted without changing anything
+    #[arg(long)]
+    dry_run: bool,
+    /// How symbolic links to files and directories are backed up
+    #[arg(long, value_enum, default_value_t = SymlinkPolicy::Follow)]
+    symlinks: SymlinkPolicy,
+    /// Copy files which are hard links of each other separately instead of linking them
+    #[arg(long)]
+    no_hard_links: bool,
+    /// Do not copy extended attributes like tags and security labels
+    #[arg(long)]
+    no_xattrs: bool,
+    /// Write out the holes of sparse files as zeros
+    #[arg(long)]
+    no_sparse: bool,
+    /// What happens when a directory in the source cannot be read
+    #[arg(long, value_enum, default_value_t = ErrorPolicy::Collect)]
+    read_errors: ErrorPolicy,
+    /// Order in which the directories are visited
+    #[arg(long, value_enum, default_value_t = WalkOrder::BreadthFirst)]
+    walk_order: WalkOrder,
+    /// Level of the zstd compression of new chunks of a repository, from 1 (fastest) to 22 (smallest)
+    #[arg(long, value_name = "LEVEL", default_value_t = safeall::DEFAULT_COMPRESSION_LEVEL, value_parser = clap::value_parser!(i32).range(1..=22))]
+    compression_level: i32,
+    /// Store the new chunks of a repository uncompressed, e.g. when the files are compressed already
+    #[arg(long)]
+    no_compression: bool,
+    /// Passphrase of an encrypted repository. A snapshot into a new repository encrypts it with it.
+    /// Better set through the environment than on the command line, where other users can see it
+    #[arg(long, env = "SAFEALL_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+    /// Read the passphrase of an encrypted repository from the first line of this file instead
+    #[arg(long, value_name = "FILE", value_parser = read_passphrase_file)]
+    passphrase_file: Option<String>,
+    /// Unlock an encrypted repository with this key file instead of a passphrase. A snapshot
+    /// into a new repository creates the key file with random content if it does not exist
+    #[arg(long, value_name = "FILE")]
+    key_file: Option<std::path::PathBuf>,
+    /// Always copy the data, even if the filesystem could share it with the source
+    #[arg(long)]
+    no_reflink: bool,
+    /// How FIFOs, sockets and device nodes are backed up
+    #[arg(long, value_enum, default_value_t = SpecialFilePolicy::Skip)]
+    special_files: SpecialFilePolicy,
+    /// The largest file the destination can store, e.g. 4GiB for a FAT formatted drive which is not detected as such
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    destination_max_file_size: Option<u64>,
+    /// How files larger than the destination can store are backed up
+    #[arg(long, value_enum, default_value_t = OversizedFilePolicy::Error)]
+    oversized_files: OversizedFilePolicy,
+    /// Write partial copies to this directory on the destination drive instead of next to the file they replace
+    #[arg(long, value_name = "DIR")]
+    staging_dir: Option<std::path::PathBuf>,
+    /// How files which already exist in the destination are compared to decide whether to copy them again
+    #[arg(long, value_enum, default_value_t = CompareStrategy::MetadataAndHash)]
+    compare: CompareStrategy,
+    /// Do not descend into directories on other filesystems than the source, e.g. `/proc` or network drives
+    #[arg(long)]
+    same_filesystem: bool,
+    /// Abort once more than this many files or directories failed, e.g. because the drive was disconnected
+    #[arg(long, value_name = "COUNT")]
+    max_errors: Option<usize>,
+    /// Only back up this many levels of the source, 1 backs up only what is directly in it
+    #[arg(long, value_name = "LEVELS")]
+    max_depth: Option<usize>,
+    /// Skip files smaller than this size, e.g. 10KB or 1MiB
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_size: Option<u64>,
+    /// Skip files larger than this size, e.g. 10GiB for disk images
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+    /// Compare larger files by size, permissions and modification time only instead of reading them
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    hash_max_size: Option<u64>,
+    /// Remember the hashes of the files in the destination instead of reading unchanged files on every run
+    #[arg(long)]
+    hash_cache: bool,
+    /// Remember the listings of the directories instead of reading unchanged directories on every run
+    #[arg(long)]
+    scan_cache: bool,
+    /// Read every copied file back and compare it with the source
+    #[arg(long)]
+    verify_writes: bool,
+    /// Copy the files which verify or scrub found damaged from the source again
+    #[arg(long)]
+    repair: bool,
+    /// Ask before deleting anything once all files have been copied
+    #[arg(long)]
+    interactive_delete: bool,
+    /// Write parity data of this many percent of every file, such that scrub --repair can reconstruct damaged files
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+    parity: Option<u8>,
+    /// Give everything written to the destination this owner, e.g. 1000:1000 when running as root
+    #[arg(long, value_name = "UID:GID", value_parser = parse_owner)]
+    owner: Option<safeall::Owner>,
+    /// Warn about files which take longer than this many seconds to copy, e.g. on a dying disk
+    #[arg(long, value_name = "SECONDS")]
+    stall_timeout: Option<u64>,
+    /// Cancel stalled copies and try them again at the end
+    #[arg(long, requires = "stall_timeout")]
+    cancel_stalled: bool,
+    /// Only back up files modified at or after this time, e.g. 2024-05-01 or 2024-05-01T12:00:00+02:00
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    modified_after: Option<std::time::SystemTime>,
+    /// Only back up files modified before this time
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    modified_before: Option<std::time::SystemTime>,
+}
+
+/// Parses an RFC 3339 timestamp or a date with an optional time in the local time zone.
+fn parse_time(time: &str) -> Result<std::time::SystemTime, String> {
+    use chrono::TimeZone;
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(time) {
+        return Ok(time.into());
+    }
+    let local = chrono::NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M"))
+        .or_else(|_| {
+            chrono::NaiveDate::parse_from_str(time, "%Y-%m-%d")
+                .map(|date| date.and_time(chrono::NaiveTime::MIN))
+        })
+        .map_err(|_| format!("\"{time}\" is neither a date like 2024-05-01 nor RFC 3339"))?;
+    chrono::Local
+        .from_local_datetime(&local)
+        .earliest()
+        .map(Into::into)
+        .ok_or_else(|| format!("\"{time}\" does not exist in the local time zone"))
+}
+
+/// Parses numeric user and group ids like `1000:100`.
+fn parse_progress_template(template: &str) -> Result<String, String> {
+    indicatif::ProgressStyle::with_template(template)
+        .map(|_| template.to_owned())
+        .map_err(|e| e.to_string())
+}
+
+fn parse_owner(owner: &str) -> Result<safeall::Owner, String> {
+    let (uid, gid) = owner
+        .split_once(':')
+        .ok_or_else(|| format!("expected UID:GID, got \"{owner}\""))?;
+    let parse = |id: &str| {
+        id.trim()
+            .parse()
+            .map_err(|_| format!("invalid id \"{id}\""))
+    };
+    Ok(safeall::Owner {
+        uid: parse(uid)?,
+        gid: parse(gid)?,
+    })
+}
+
+fn read_passphrase_file(path: &str) -> Result<String, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read \"{path}\": {e}"))?;
+    Ok(text.lines().next().unwrap_or_default().to_owned())
+}
+
+/// Parses a number of bytes with an optional unit like `KB` (1000 bytes) or `KiB` (1024 bytes).
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("\"{size}\" does not start with a number"))?;
+    let factor: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1000,
+        "KIB" => 1 << 10,
+        "M" | "MB" => 1000_u64.pow(2),
+        "MIB" => 1 << 20,
+        "G" | "GB" => 1000_u64.pow(3),
+        "GIB" => 1 << 30,
+        "T" | "TB" => 1000_u64.pow(4),
+        "TIB" => 1 << 40,
+        unit => return Err(format!("Unknown unit \"{unit}\"")),
+    };
+    number
+        .checked_mul(factor)
+        .ok_or_else(|| format!("\"{size}\" is too large"))
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum SuspiciousDeletionPolicy {
+    /// Move them into the quarantine of the destination
+    Quarantine,
+    /// Leave them in the destination
+    Keep,
+    /// Delete them like any other file
+    Delete,
+}
+
+impl From<SuspiciousDeletionPolicy> for safeall::SuspiciousDeletionPolicy {
+    fn from(policy: SuspiciousDeletionPolicy) -> Self {
+        match policy {
+            SuspiciousDeletionPolicy::Quarantine => safeall::SuspiciousDeletionPolicy::Quarantine,
+            SuspiciousDeletionPolicy::Keep => safeall::SuspiciousDeletionPolicy::Keep,
+            SuspiciousDeletionPolicy::Delete => safeall::SuspiciousDeletionPolicy::Delete,
+        }
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum SymlinkPolicy {
+    /// Copy the file the link points to
+    Follow,
+    /// Recreate the link in the destination
+    Preserve,
+    /// Do not back up links
+    Skip,
+}
+
+impl From<SymlinkPolicy> for safeall::SymlinkPolicy {
+    fn from(policy: SymlinkPolicy) -> Self {
+        match policy {
+            SymlinkPolicy::Follow => safeall::SymlinkPolicy::Follow,
+            SymlinkPolicy::Preserve => safeall::SymlinkPolicy::Preserve,
+            SymlinkPolicy::Skip => safeall::SymlinkPolicy::Skip,
+        }
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum SpecialFilePolicy {
+    /// Leave them out of the backup
+    Skip,
+    /// Create them again in the destination
+    Recreate,
+    /// Report them as errors
+    Error,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum OversizedFilePolicy {
+    /// Report them as errors
+    Error,
+    /// Leave them out of the backup
+    Skip,
+    /// Store them in parts which are joined again when restoring
+    Split,
+}
+
+impl From<OversizedFilePolicy> for safeall::OversizedFilePolicy {
+    fn from(policy: OversizedFilePolicy) -> Self {
+        match policy {
+            OversizedFilePolicy::Error => safeall::OversizedFilePolicy::Error,
+            OversizedFilePolicy::Skip => safeall::OversizedFilePolicy::Skip,
+            OversizedFilePolicy::Split => safeall::OversizedFilePolicy::Split,
+        }
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum TargetFilesystem {
+    /// Restore to any drive
+    Any,
+    /// Only restore to the drive of the backup
+    SameAsBackup,
+    /// Never restore to the drive of the backup
+    OtherThanBackup,
+}
+
+impl From<TargetFilesystem> for safeall::TargetFilesystem {
+    fn from(filesystem: TargetFilesystem) -> Self {
+        match filesystem {
+            TargetFilesystem::Any => safeall::TargetFilesystem::Any,
+            TargetFilesystem::SameAsBackup => safeall::TargetFilesystem::SameAsBackup,
+            TargetFilesystem::OtherThanBackup => safeall::TargetFilesystem::OtherThanBackup,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+enum CompareStrategy {
+    /// Compare type, size, permissions and modification time, then the content
+    MetadataAndHash,
+    /// Compare type, size and modification time without reading the files
+    SizeAndMtime,
+    /// Compare type and size without reading the files
+    SizeOnly,
+    /// Compare type, size and content, whatever the modification time
+    AlwaysHash,
+    /// Compare type, size, permissions and modification time without reading the files
+    NeverHash,
+}
+
+impl CompareStrategy {
+    fn comparator(self) -> std::sync::Arc<dyn safeall::Comparator> {
+        let strategy = match self {
+            CompareStrategy::MetadataAndHash => {
+                return std::sync::Arc::new(safeall::MetadataAndHash);
+            }
+            CompareStrategy::SizeAndMtime => safeall::CompareStrategy::SizeAndMtime,
+            CompareStrategy::SizeOnly => safeall::CompareStrategy::SizeOnly,
+            CompareStrategy::AlwaysHash => safeall::CompareStrategy::AlwaysHash,
+            CompareStrategy::NeverHash => safeall::CompareStrategy::NeverHash,
+        };
+        std::sync::Arc::new(strategy)
+    }
+}
+
+impl From<SpecialFilePolicy> for safeall::SpecialFilePolicy {
+    fn from(policy: SpecialFilePolicy) -> Self {
+        match policy {
+            SpecialFilePolicy::Skip => safeall::SpecialFilePolicy::Skip,
+            SpecialFilePolicy::Recreate => safeall::SpecialFilePolicy::Recreate,
+            SpecialFilePolicy::Error => safeall::SpecialFilePolicy::Error,
+        }
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum ErrorPolicy {
+    /// Abort the run at the first error
+    FailFast,
+    /// Report all errors at the end
+    Collect,
+    /// Skip what cannot be read silently
+    Ignore,
+}
+
+impl From<ErrorPolicy> for safeall::ErrorPolicy {
+    fn from(policy: ErrorPolicy) -> Self {
+        match policy {
+            ErrorPolicy::FailFast => safeall::ErrorPolicy::FailFast,
+            ErrorPolicy::Collect => safeall::ErrorPolicy::Collect,
+            ErrorPolicy::Ignore => safeall::ErrorPolicy::Ignore,
+        }
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum WalkOrder {
+    /// All folders of a level before the next level
+    BreadthFirst,
+    /// All of a folder before its next sibling, which needs less memory for very wide trees
+    DepthFirst,
+}
+
+impl From<WalkOrder> for safeall::WalkOrder {
+    fn from(walk_order: WalkOrder) -> Self {
+        match walk_order {
+            WalkOrder::BreadthFirst => safeall::WalkOrder::BreadthFirst,
+            WalkOrder::DepthFirst => safeall::WalkOrder::DepthFirst,
+        }
+    }
+}
+
+impl From<BackupOptions> for safeall::BackupOptions {
+    fn from(options: BackupOptions) -> Self {
+        safeall::BackupOptions {
+            sqlite_consistent_copy: options.sqlite_consistent_copy,
+            copy_buffer_size: options.copy_buffer_size,
+            bypass_page_cache: options.bypass_page_cache,
+            power_aware_throttling: options.power_aware_throttling,
+            suspicious_deletions: options.suspicious_deletions.into(),
+            mass_change_threshold: (!options.allow_mass_change)
+                .then_some(options.max_change_percentage),
+            retry_passes: options.retry_passes,
+            accept_new_destination: options.accept_new_destination,
+            include: options.include,
+            exclude: options.exclude,
+            ignore_files: !options.no_ignore_files,
+            exclude_caches: options.exclude_caches,
+            require_mounted: options.require_mounted,
+            dry_run: options.dry_run,
+            symlinks: options.symlinks.into(),
+            preserve_hard_links: !options.no_hard_links,
+            preserve_xattrs: !options.no_xattrs,
+            preserve_sparse_files: !options.no_sparse,
+            read_errors: options.read_errors.into(),
+            reflink: !options.no_reflink,
+            special_files: options.special_files.into(),
+            destination_max_file_size: options.destination_max_file_size,
+            oversized_files: options.oversized_files.into(),
+            comparator: options.compare.comparator(),
+            same_filesystem: options.same_filesystem,
+            max_errors: options.max_errors,
+            max_depth: options.max_depth,
+            min_size: options.min_size,
+            max_size: options.max_size,
+            stall_timeout: options.stall_timeout.map(std::time::Duration::from_secs),
+            cancel_stalled: options.cancel_stalled,
+            modified_after: options.modified_after,
+            modified_before: options.modified_before,
+            staging_directory: options.staging_dir,
+            hash_max_size: options.hash_max_size,
+            hash_cache: options.hash_cache,
+            destination_owner: options.owner,
+            scan_cache: options.scan_cache,
+            verify_writes: options.verify_writes,
+            repair: options.repair,
+            interactive_delete: options.interactive_delete,
+            parity: options.parity,
+            walk_order: options.walk_order.into(),
+            compression_level: (!options.no_compression).then_some(options.compression_level),
+            secret: options.key_file.map(safeall::Secret::KeyFile).or_else(|| {
+                options
+                    .passphrase_file
+                    .or(options.passphrase)
+                    .map(safeall::Secret::Passphrase)
+            }),
+            known_destinations: safeall::known_destinations_path(),
+            ..safeall::BackupOptions::default()
+        }
+    }
 }
 
 impl From<Commands> for safeall::Command {
+    #[allow(clippy::too_many_lines)]
     fn from(commands: Commands) -> Self {
         match commands {
             Commands::Backup {
                 source_root,
                 destination_root,
+                archive,
+                options,
             } => safeall::Command::Backup {
                 source_root: source_root.into(),
-                destination_root: destination_root.into(),
+                destination: if archive {
+                    safeall::Destination::Archive(destination_root.into())
+                } else {
+                    safeall::Destination::Directory(destination_root.into())
+                },
+                options: options.into(),
             },
             Commands::Sync {
                 source_root,
                 destination_root,
+                options,
             } => safeall::Command::Sync {
                 source_root: source_root.into(),
                 destination_root: destination_root.into(),
+                options: options.into(),
             },
             Commands::Restore {
                 source_root,
                 destination_root,
                 delete_files,
+                no_create,
+                require_empty,
+                target_filesystem,
+                keep_overwritten,
+                options,
             } => safeall::Command::Restore {
                 source_root: source_root.into(),
                 destination_root: destination_root.into(),
                 delete_files,
+                target: safeall::RestoreTarget {
+                    create: !no_create,
+                    non_empty: if require_empty {
+                        safeall::NonEmptyTarget::RequireEmpty
+                    } else {
+                        safeall::NonEmptyTarget::Merge
+                    },
+                    filesystem: target_filesystem.into(),
+                },
+                options: safeall::BackupOptions {
+                    keep_overwritten,
+                    ..options.into()
+                },
+            },
+            Commands::Verify {
+                source_root,
+                destination_root,
+                options,
+            } => safeall::Command::Verify {
+                source_root: source_root.into(),
+                destination_root: destination_root.into(),
+                options: options.into(),
+            },
+            Commands::Scrub {
+                destination_root,
+                source,
+                options,
+            } => safeall::Command::Scrub {
+                source_root: source.map(Into::into),
+                destination_root: destination_root.into(),
+                options: options.into(),
+            },
+            Commands::Prune {
+                destination_root,
+                keep_last,
+                keep_daily,
+                keep_weekly,
+                keep_monthly,
+                options,
+            } => safeall::Command::Prune {
+                destination_root: destination_root.into(),
+                policy: safeall::RetentionPolicy {
+                    keep_last,
+                    keep_daily,
+                    keep_weekly,
+                    keep_monthly,
+                },
+                options: options.into(),
+            },
+            Commands::Snapshot {
+                source_root,
+                destination_root,
+                options,
+            } => safeall::Command::Snapshot {
+                source_root: source_root.into(),
+                destination_root: destination_root.into(),
+                options: options.into(),
+            },
+            Commands::RestoreSnapshot {
+                source_root,
+                destination_root,
+                snapshot,
+                options,
+            } => safeall::Command::RestoreSnapshot {
+                source_root: source_root.into(),
+                destination_root: destination_root.into(),
+                snapshot,
+                options: options.into(),
+            },
+            Commands::Snapshots {
+                destination_root,
+                options,
+            } => safeall::Command::ListSnapshots {
+                destination_root: destination_root.into(),
+                options: options.into(),
+            },
+            Commands::Gc {
+                destination_root,
+                options,
+            } => safeall::Command::CollectGarbage {
+                destination_root: destination_root.into(),
+                options: options.into(),
+            },
+            Commands::Check {
+                destination_root,
+                options,
+            } => safeall::Command::CheckRepository {
+                destination_root: destination_root.into(),
+                options: options.into(),
+            },
+            Commands::Doctor {
+                source_root,
+                destination_root,
+                options,
+            } => safeall::Command::Doctor {
+                source_root: source_root.into(),
+                destination_root: destination_root.into(),
+                options: options.into(),
+            },
+            Commands::Keys {
+                destination_root,
+                action,
+                options,
+            } => safeall::Command::ManageKeys {
+                destination_root: destination_root.into(),
+                action: action.into(),
+                options: options.into(),
             },
         }
     }
@@ -80,7 +884,16 @@ impl From<Commands> for safeall::Command {
 
 struct CliOutput {
     progress_bar: Option<indicatif::ProgressBar>,
+    /// Processed and total files once the progress bar counts bytes.
+    files: Option<(usize, usize)>,
+    /// Whether the progress bar is a spinner, as the number of entries is being counted.
+    scanning: bool,
     verbosity: Verbosity,
+    /// Template of the progress bars given by the user, instead of the styles of [`style`].
+    progress_template: Option<String>,
+    /// The file which is being processed, for the key `{file}` of the template.
+    current_file: std::sync::Arc<std::sync::Mutex<String>>,
+    list_deleted: bool,
 }
 
 enum Verbosity {
@@ -103,6 +916,34 @@ mod style {
         .unwrap()
     }
 
+    pub fn spinner_style(dottet_style: &str) -> indicatif::ProgressStyle {
+        indicatif::ProgressStyle::with_template(&format!(
+            "{{spinner}} {{wide_msg:.{dottet_style}}} [{{pos:>}} entries]"
+        ))
+        .unwrap()
+    }
+
+    pub fn spinner_style_finished(dottet_style: &str) -> indicatif::ProgressStyle {
+        indicatif::ProgressStyle::with_template(&format!(
+            "[{{pos:>}} entries] {{wide_msg:.{dottet_style}}}"
+        ))
+        .unwrap()
+    }
+
+    pub fn bytes_progress_bar_style(dottet_style: &str) -> indicatif::ProgressStyle {
+        indicatif::ProgressStyle::with_template(&format!(
+            "{{bar}} {{wide_msg:.{dottet_style}}} [{{bytes:>}}/{{total_bytes:}} ({{eta}})] {{prefix}}"
+        ))
+        .unwrap()
+    }
+
+    pub fn bytes_progress_bar_style_finished(dottet_style: &str) -> indicatif::ProgressStyle {
+        indicatif::ProgressStyle::with_template(&format!(
+            "[{{total_bytes:}}, {{prefix}}] {{wide_msg:.{dottet_style}}}"
+        ))
+        .unwrap()
+    }
+
     pub fn warning() -> console::Style {
         console::Style::from_dotted_str(warning_dotted())
     }
@@ -147,21 +988,90 @@ mod style {
 }
 
 impl CliOutput {
-    fn new(verbosity: Verbosity) -> Self {
+    fn new(verbosity: Verbosity, progress_template: Option<String>, list_deleted: bool) -> Self {
         let progress_bar = None;
         Self {
             progress_bar,
+            files: None,
+            scanning: false,
             verbosity,
+            progress_template,
+            current_file: std::sync::Arc::default(),
+            list_deleted,
+        }
+    }
+
+    fn progress_bar_style(&self, dottet_style: &str) -> indicatif::ProgressStyle {
+        if self.scanning {
+            style::spinner_style(dottet_style)
+        } else if let Some(template) = &self.progress_template {
+            let current_file = self.current_file.clone();
+            indicatif::ProgressStyle::with_template(template)
+                .expect("The template has been validated when parsing the arguments")
+                .with_key(
+                    "file",
+                    move |_: &indicatif::ProgressState, w: &mut dyn std::fmt::Write| {
+                        let _ = w.write_str(&current_file.lock().expect("Lock is never poisoned"));
+                    },
+                )
+        } else if self.files.is_some() {
+            style::bytes_progress_bar_style(dottet_style)
+        } else {
+            style::progress_bar_style(dottet_style)
+        }
+    }
+
+    fn progress_bar_style_finished(&self, dottet_style: &str) -> indicatif::ProgressStyle {
+        if self.scanning {
+            style::spinner_style_finished(dottet_style)
+        } else if self.files.is_some() {
+            style::bytes_progress_bar_style_finished(dottet_style)
+        } else {
+            style::progress_bar_style_finished(dottet_style)
         }
     }
+    /// Remembers the file which a message is about, for the key `{file}` of the template.
+    fn set_current_file(&self, message: &safeall::Message) {
+        use safeall::Message as M;
+        let (M::Info(
+            safeall::Info::StartCopingFile { source: path, .. }
+            | safeall::Info::StartDeletingFile(path),
+        )
+        | M::Progress(safeall::Progress::IncrementSuccess(safeall::Increment::Scanned {
+            path,
+            ..
+        }))) = message
+        else {
+            return;
+        };
+        *self.current_file.lock().expect("Lock is never poisoned") = path.display().to_string();
+    }
+
+    #[allow(clippy::too_many_lines)]
     fn process_message(&mut self, message: safeall::Message) {
         use safeall::Message as M;
         use safeall::Progress as P;
+        self.set_current_file(&message);
+        let deleted = match &message {
+            M::Info(safeall::Info::Report(report)) if self.list_deleted => Some((
+                report.deleted_directories.clone(),
+                report.deleted_files.clone(),
+            )),
+            _ => None,
+        };
+        if let M::Info(info @ safeall::Info::PurgeCheckpoint { checkpoint, .. }) = &message {
+            if confirm(&format!("{info}")) {
+                checkpoint.approve();
+            } else {
+                checkpoint.cancel();
+            }
+            return;
+        }
         match self.verbosity {
             Verbosity::Normal => match message {
                 M::Warning(warning) => {
                     if let Some(progress_bar) = &self.progress_bar {
-                        progress_bar.set_style(style::progress_bar_style(style::warning_dotted()));
+                        progress_bar.set_style(self.progress_bar_style(style::warning_dotted()));
                         progress_bar.set_message(format!("{warning}"));
                         eprintln!(); // Such that the does not get overwritten
                     } else {
@@ -174,48 +1084,88 @@ impl CliOutput {
                 M::Info(info) => {
                     if let Some(progress_bar) = &self.progress_bar {
                         progress_bar
-                            .set_style(style::progress_bar_style(style::increment_info_dotted()));
+                            .set_style(self.progress_bar_style(style::increment_info_dotted()));
                         progress_bar.set_message(format!("{info}"));
                     } else {
                         println!("{}", style::info().apply_to(format!("INFO: {info}")));
                     }
                 }
+                M::Heartbeat(activity) => {
+                    if let Some(progress_bar) = &self.progress_bar {
+                        progress_bar.set_message(format!("{activity}"));
+                    }
+                }
                 M::Progress(ref progress) => match progress {
+                    P::Start(_, safeall::ProgressType::Scanning) => {
+                        self.create_spinner(format!("{progress}"));
+                    }
                     P::Start(total, _) => {
                         self.create_progress_bar(*total, format!("{progress}"));
                     }
-                    P::IncrementSuccess(_) => {
+                    P::TotalBytes(bytes, _) => {
+                        if let Some(ref progress_bar) = self.progress_bar {
+                            // NOTE: Large files take longer, so the bytes give a better estimate
+                            let total = usize::try_from(progress_bar.length().unwrap_or(0))
+                                .unwrap_or(usize::MAX);
+                            self.files = Some((0, total));
+                            progress_bar.set_length(*bytes);
+                            progress_bar.set_prefix(format!("0/{total} files"));
+                            progress_bar.set_style(self.progress_bar_style(style::info_dotted()));
+                        }
+                    }
+                    P::IncrementSuccess(increment) => {
+                        if let Some((done, total)) = &mut self.files {
+                            *done += 1;
+                            if let Some(ref progress_bar) = self.progress_bar {
+                                progress_bar.set_prefix(format!("{done}/{total} files"));
+                                progress_bar.inc(increment.bytes());
+                            }
+                        } else if let Some(ref progress_bar) = self.progress_bar {
+                            if let safeall::Increment::Scanned { entries, .. } = increment {
+                                progress_bar.set_position(*entries as u64);
+                            } else {
+                                progress_bar.inc(1);
+                            }
+                        }
                         if let Some(ref progress_bar) = self.progress_bar {
-                            progress_bar.set_style(style::progress_bar_style(
-                                style::increment_success_dotted(),
-                            ));
+                            progress_bar.set_style(
+                                self.progress_bar_style(style::increment_success_dotted()),
+                            );
                             progress_bar.set_message(format!("{progress}"));
-                            progress_bar.inc(1);
                         }
                     }
                     P::EndFail(_, _) => {
                         if let Some(ref progress_bar) = self.progress_bar {
-                            progress_bar.set_style(style::progress_bar_style_finished(
-                                style::increment_fail_dotted(),
-                            ));
+                            progress_bar.set_style(
+                                self.progress_bar_style_finished(style::increment_fail_dotted()),
+                            );
                             progress_bar.abandon_with_message(format!("{progress}"));
                         }
                         self.progress_bar = None;
+                        self.files = None;
+                        self.scanning = false;
                     }
                     P::EndSuccess(_) => {
                         if let Some(ref progress_bar) = self.progress_bar {
-                            progress_bar.set_style(style::progress_bar_style_finished(
-                                style::success_dotted(),
-                            ));
+                            progress_bar.set_style(
+                                self.progress_bar_style_finished(style::success_dotted()),
+                            );
                             progress_bar.abandon_with_message(format!("{progress}"));
                         }
                         self.progress_bar = None;
+                        self.files = None;
+                        self.scanning = false;
                     }
                     P::IncrementFail(_) => {
+                        if let Some((done, total)) = &mut self.files {
+                            *done += 1;
+                            if let Some(ref progress_bar) = self.progress_bar {
+                                progress_bar.set_prefix(format!("{done}/{total} files"));
+                            }
+                        }
                         if let Some(ref progress_bar) = self.progress_bar {
-                            progress_bar.set_style(style::progress_bar_style(
-                                style::increment_fail_dotted(),
-                            ));
+                            progress_bar
+                                .set_style(self.progress_bar_style(style::increment_fail_dotted()));
                             progress_bar.set_message(format!("{progress}"));
                             eprintln!(); // Such that the does not get overwritten
                         }
@@ -232,6 +1182,9 @@ impl CliOutput {
                 M::Info(info) => {
                     println!("{}", style::info().apply_to(format!("INFO: {info}")));
                 }
+                M::Heartbeat(activity) => {
+                    println!("{}", style::info().apply_to(format!("INFO: {activity}")));
+                }
                 M::Progress(progress) => {
                     let style = match progress {
                         P::IncrementSuccess(_) => {
@@ -242,20 +1195,49 @@ impl CliOutput {
                         }
                         P::EndSuccess(_) => style::success().apply_to(format!("INFO: {progress}")),
                         P::EndFail(_, _) => style::error().apply_to(format!("ERROR: {progress}")),
-                        P::Start(_, _) => style::info().apply_to(format!("INFO: {progress}")),
+                        P::Start(_, _) | P::TotalBytes(_, _) => {
+                            style::info().apply_to(format!("INFO: {progress}"))
+                        }
                     };
                     println!("{style}");
                 }
             },
         }
+        if let Some((directories, files)) = deleted {
+            for directory in directories {
+                println!("  Deleted directory \"{}\"", directory.display());
+            }
+            for file in files {
+                println!("  Deleted file \"{}\"", file.display());
+            }
+        }
     }
 
     fn create_progress_bar(&mut self, length: usize, message: String) {
+        self.files = None;
+        self.scanning = false;
         let progress_bar = indicatif::ProgressBar::new(length as u64);
-        progress_bar.set_style(style::progress_bar_style(style::info_dotted()));
+        progress_bar.set_style(self.progress_bar_style(style::info_dotted()));
         progress_bar.set_message(message);
         self.progress_bar = Some(progress_bar);
     }
+
+    fn create_spinner(&mut self, message: String) {
+        self.files = None;
+        self.scanning = true;
+        let spinner = indicatif::ProgressBar::new_spinner();
+        spinner.set_style(self.progress_bar_style(style::info_dotted()));
+        spinner.set_message(message);
+        self.progress_bar = Some(spinner);
+    }
+}
+
+/// Asks a yes or no question on the terminal, where anything but yes means no.
+fn confirm(question: &str) -> bool {
+    eprint!("{} [y/N] ", style::warning().apply_to(question));
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
 }
 
 async fn cli() -> bool {
@@ -265,21 +1247,59 @@ async fn cli() -> bool {
     } else {
         Verbosity::Normal
     };
+    let (command, mut error_report) = match cli_args.command {
+        CliCommand::RetryFailed { from, options } => {
+            match safeall::ErrorReport::read(std::path::Path::new(&from)) {
+                Ok(report) => {
+                    for path in report.not_retried() {
+                        eprintln!(
+                            "{}",
+                            style::warning().apply_to(format!(
+                                "WARNING: \"{}\" is not retried as it is not in the source \"{}\".",
+                                path.display(),
+                                report.source_root.display()
+                            ))
+                        );
+                    }
+                    (
+                        report.retry_command(&options.into()),
+                        Some(safeall::ErrorReport::new(
+                            &report.source_root,
+                            &report.destination_root,
+                        )),
+                    )
+                }
+                Err(error) => {
+                    eprintln!("{}", style::error().apply_to(format!("ERROR: {error}")));
+                    return false;
+                }
+            }
+        }
+        CliCommand::Run(command) => {
+            let command = command.into();
+            let error_report = safeall::ErrorReport::for_command(&command);
+            (command, error_report)
+        }
+    };
     let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
-    let run =
-        tokio::spawn(async move { safeall::run(cli_args.command.into(), message_sender).await });
+    let run = tokio::spawn(async move { safeall::run(command, message_sender).await });
 
-    let mut cli_output = CliOutput::new(verbosity);
+    let mut cli_output =
+        CliOutput::new(verbosity, cli_args.progress_template, cli_args.list_deleted);
 
     while let Some(message) = message_receiver.recv().await {
+        if let Some(error_report) = &mut error_report {
+            error_report.record(&message);
+        }
         cli_output.process_message(message);
     }
 
+    let mut success = true;
     match run.await {
         Ok(result) => {
             if let Err(error) = result {
                 eprintln!("{}", style::error().apply_to(format!("ERROR: {error}")));
-                return false;
+                success = false;
             }
         }
         Err(error) => {
@@ -290,7 +1310,24 @@ async fn cli() -> bool {
             return false;
         }
     }
-    true
+    if let Some(error_report) = error_report.filter(|report| !report.failed.is_empty()) {
+        match error_report.write(std::path::Path::new(".")) {
+            Ok(path) => eprintln!(
+                "{}",
+                style::warning().apply_to(format!(
+                    "WARNING: {} files or folders could not be processed. Retry them with \
+                    `safeall retry-failed --from {}`",
+                    error_report.failed.len(),
+                    path.display()
+                ))
+            ),
+            Err(error) => eprintln!(
+                "{}",
+                style::error().apply_to(format!("ERROR: Cannot write the error report: {error}"))
+            ),
+        }
+    }
+    success
 }
 
 #[tokio::main(flavor = "multi_thread")]
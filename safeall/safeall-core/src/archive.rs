@@ -0,0 +1,501 @@
+//! Backups into a single tar archive instead of a directory. Every backup writes the archive
+//! anew from start to end, which suits tapes and object storage that cannot update files in
+//! place. The archive is compressed with gzip or zstd if its name ends with `.gz`/`.tgz` or
+//! `.zst`/`.tzst`. If it ends with `.zip`, a zip archive is written instead, which Windows can
+//! open without further tools.
+
+use crate::{
+    CopyReason, Error, Increment, Info, Message, MessageSender, ProcessPathError,
+    ProcessPathErrorKind, Progress, ProgressType, ReadDirType, RecursiveReadDir, SymlinkPolicy,
+    Warning,
+};
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Tar(Compression),
+    Zip,
+}
+
+impl Format {
+    fn of(archive: &std::path::Path) -> Self {
+        let extension = archive
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("gz" | "tgz") => Format::Tar(Compression::Gzip),
+            Some("zst" | "tzst") => Format::Tar(Compression::Zstd),
+            Some("zip") => Format::Zip,
+            _ => Format::Tar(Compression::None),
+        }
+    }
+}
+
+enum Encoder {
+    Plain(std::io::BufWriter<std::fs::File>),
+    Gzip(flate2::write::GzEncoder<std::io::BufWriter<std::fs::File>>),
+    Zstd(zstd::stream::write::Encoder<'static, std::io::BufWriter<std::fs::File>>),
+}
+
+impl Encoder {
+    fn new(
+        file: std::fs::File,
+        compression: Compression,
+        level: Option<i32>,
+    ) -> std::io::Result<Self> {
+        let writer = std::io::BufWriter::new(file);
+        Ok(match compression {
+            Compression::None => Encoder::Plain(writer),
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                writer,
+                flate2::Compression::default(),
+            )),
+            // NOTE: Level 0 is the default level of zstd
+            Compression::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(
+                writer,
+                level.unwrap_or(0),
+            )?),
+        })
+    }
+
+    /// Writes the end of the compressed stream and returns the file.
+    fn finish(self) -> std::io::Result<std::fs::File> {
+        let writer = match self {
+            Encoder::Plain(writer) => writer,
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+        };
+        writer
+            .into_inner()
+            .map_err(std::io::IntoInnerError::into_error)
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::Plain(writer) => writer.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::Plain(writer) => writer.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// What happened to an entry of the archive, sent from the thread which writes it.
+enum Event {
+    /// A file or link with its size.
+    Added(std::path::PathBuf, u64),
+    /// The entry, a directory if the flag is set, could not be read and is not in the archive.
+    Failed(std::path::PathBuf, bool, std::io::Error),
+}
+
+/// Writes the archive via a partial file. Entries which cannot be read are reported and left
+/// out, but an error while writing the archive stops it as it would be truncated.
+fn write(
+    archive: &std::path::Path,
+    source_root: &std::path::Path,
+    entries: Vec<(std::path::PathBuf, bool)>,
+    symlinks: SymlinkPolicy,
+    level: Option<i32>,
+    events: &tokio::sync::mpsc::UnboundedSender<Event>,
+) -> std::io::Result<()> {
+    let partial = crate::staging_path(archive, None);
+    let written = (|| {
+        let file = std::fs::File::create(&partial)?;
+        let file = match Format::of(archive) {
+            Format::Tar(compression) => write_tar(
+                Encoder::new(file, compression, level)?,
+                source_root,
+                entries,
+                symlinks,
+                events,
+            )?,
+            Format::Zip => write_zip(file, source_root, entries, symlinks, events)?,
+        };
+        file.sync_all()?;
+        std::fs::rename(&partial, archive)
+    })();
+    if written.is_err() {
+        std::fs::remove_file(&partial).ok();
+    }
+    written
+}
+
+fn write_tar(
+    encoder: Encoder,
+    source_root: &std::path::Path,
+    entries: Vec<(std::path::PathBuf, bool)>,
+    symlinks: SymlinkPolicy,
+    events: &tokio::sync::mpsc::UnboundedSender<Event>,
+) -> std::io::Result<std::fs::File> {
+    let mut builder = tar::Builder::new(encoder);
+    builder.follow_symlinks(symlinks == SymlinkPolicy::Follow);
+    for (path, is_dir) in entries {
+        let relative = path.strip_prefix(source_root).unwrap_or(&path);
+        let is_symlink = path
+            .symlink_metadata()
+            .is_ok_and(|m| m.file_type().is_symlink());
+        let event = if is_dir {
+            match builder.append_dir(relative, &path) {
+                Ok(()) => continue,
+                Err(e) => Event::Failed(path, true, e),
+            }
+        } else if is_symlink && symlinks == SymlinkPolicy::Preserve {
+            match builder.append_path_with_name(&path, relative) {
+                Ok(()) => Event::Added(path, 0),
+                Err(e) => Event::Failed(path, false, e),
+            }
+        } else {
+            // NOTE: Once the header of a file is written, a failure would leave the archive
+            // broken, so only opening the file may fail without stopping
+            match std::fs::File::open(&path) {
+                Ok(mut file) => {
+                    builder.append_file(relative, &mut file)?;
+                    let size = file.metadata().map_or(0, |metadata| metadata.len());
+                    Event::Added(path, size)
+                }
+                Err(e) => Event::Failed(path, false, e),
+            }
+        };
+        events.send(event).ok();
+    }
+    builder.into_inner()?.finish()
+}
+
+/// ID of the zip extra field with the modification time as Unix timestamp.
+const EXTENDED_TIMESTAMP: u16 = 0x5455;
+
+/// Options of a zip entry with the modification time and permissions of `metadata`. The
+/// time of the zip header is local and only exact to two seconds, so the exact time is
+/// added as extended timestamp which most tools prefer.
+fn zip_options(metadata: &std::fs::Metadata) -> zip::write::FullFileOptions<'static> {
+    let mut options = zip::write::FullFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .large_file(metadata.len() > u64::from(u32::MAX));
+    if let Ok(modified) = metadata.modified() {
+        use chrono::{Datelike, Timelike};
+        let local = chrono::DateTime::<chrono::Local>::from(modified);
+        // NOTE: Zip cannot store times before 1980, such files keep the default time
+        let time = u16::try_from(local.year()).ok().and_then(|year| {
+            zip::DateTime::from_date_and_time(
+                year,
+                u8::try_from(local.month()).ok()?,
+                u8::try_from(local.day()).ok()?,
+                u8::try_from(local.hour()).ok()?,
+                u8::try_from(local.minute()).ok()?,
+                u8::try_from(local.second()).ok()?,
+            )
+            .ok()
+        });
+        if let Some(time) = time {
+            options = options.last_modified_time(time);
+        }
+        let seconds = modified
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()
+            .and_then(|duration| u32::try_from(duration.as_secs()).ok());
+        if let Some(seconds) = seconds {
+            let data = std::iter::once(1).chain(seconds.to_le_bytes()).collect();
+            options
+                .add_extra_data(EXTENDED_TIMESTAMP, data, false)
+                .expect("The extended timestamp is a valid extra field");
+        }
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        options = options.unix_permissions(metadata.permissions().mode());
+    }
+    options
+}
+
+/// Name of an entry in a zip archive, which always separates directories with `/`.
+fn zip_name(relative: &std::path::Path) -> String {
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn write_zip(
+    file: std::fs::File,
+    source_root: &std::path::Path,
+    entries: Vec<(std::path::PathBuf, bool)>,
+    symlinks: SymlinkPolicy,
+    events: &tokio::sync::mpsc::UnboundedSender<Event>,
+) -> std::io::Result<std::fs::File> {
+    let mut writer = zip::ZipWriter::new(std::io::BufWriter::new(file));
+    for (path, is_dir) in entries {
+        let name = zip_name(path.strip_prefix(source_root).unwrap_or(&path));
+        let is_symlink = path
+            .symlink_metadata()
+            .is_ok_and(|m| m.file_type().is_symlink());
+        let event = if is_dir {
+            match path.metadata() {
+                Ok(metadata) => {
+                    writer.add_directory(name, zip_options(&metadata))?;
+                    continue;
+                }
+                Err(e) => Event::Failed(path, true, e),
+            }
+        } else if is_symlink && symlinks == SymlinkPolicy::Preserve {
+            match path
+                .symlink_metadata()
+                .and_then(|metadata| Ok((std::fs::read_link(&path)?, metadata)))
+            {
+                Ok((target, metadata)) => {
+                    let target = target.to_string_lossy().into_owned();
+                    writer.add_symlink(name, target, zip_options(&metadata))?;
+                    Event::Added(path, 0)
+                }
+                Err(e) => Event::Failed(path, false, e),
+            }
+        } else {
+            // NOTE: As with tar, only opening the file may fail without stopping
+            match std::fs::File::open(&path).and_then(|file| Ok((file.metadata()?, file))) {
+                Ok((metadata, mut file)) => {
+                    writer.start_file(name, zip_options(&metadata))?;
+                    let size = std::io::copy(&mut file, &mut writer)?;
+                    Event::Added(path, size)
+                }
+                Err(e) => Event::Failed(path, false, e),
+            }
+        };
+        events.send(event).ok();
+    }
+    writer
+        .finish()?
+        .into_inner()
+        .map_err(std::io::IntoInnerError::into_error)
+}
+
+/// Backs up the files below `source_root` into a tar or zip archive, replacing the archive if it
+/// already exists.
+#[allow(clippy::too_many_lines)]
+pub async fn create(
+    source_root: &std::path::Path,
+    archive: &std::path::Path,
+    filter: &crate::filter::Filter,
+    options: &crate::BackupOptions,
+    message_sender: &impl MessageSender,
+) -> Result<(), Error> {
+    if !source_root.exists() {
+        return Err(Error::SourceRootPathDoesNotExist(source_root.to_owned()));
+    }
+    if !source_root.is_dir() {
+        return Err(Error::SourceRootIsNotADirectory(source_root.to_owned()));
+    }
+    if archive.is_dir() {
+        return Err(Error::CannotWriteArchive(
+            archive.to_owned(),
+            "it is a directory".to_owned(),
+        ));
+    }
+    if let Some(parent) = archive
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        crate::validate_or_create_destination_root(parent, message_sender)?;
+    }
+    let read_dir = |readdir_type| {
+        RecursiveReadDir::try_new(source_root, readdir_type)
+            .map(|r| r.with_filter(filter.clone()).skipping_special_files(true))
+            .map_err(|e| Error::CannotReadDirectoryContent(source_root.to_owned(), e.to_string()))
+    };
+
+    let mut entries = vec![];
+    let mut directory_errors = vec![];
+    let mut scan_progress = crate::scan::ScanProgress::start(message_sender);
+    for (readdir_type, is_dir) in [
+        (ReadDirType::DirectoriesOnly, true),
+        (ReadDirType::FilesOnly, false),
+    ] {
+        let mut walk = read_dir(readdir_type)?;
+        for entry in walk.by_ref() {
+            scan_progress.entry(&entry);
+            match entry {
+                Ok(path) => entries.push((path, is_dir)),
+                Err(e) => directory_errors.push(e),
+            }
+        }
+        if is_dir {
+            for path in walk.cycles() {
+                message_sender.send(Message::Warning(Warning::CycleDetected(path.clone())));
+            }
+        }
+    }
+    scan_progress.end();
+    if options.symlinks == SymlinkPolicy::Skip {
+        entries.retain(|(path, is_dir)| {
+            let is_symlink = path
+                .symlink_metadata()
+                .is_ok_and(|metadata| metadata.file_type().is_symlink());
+            if is_symlink && !is_dir {
+                message_sender.send(Message::Warning(Warning::SymlinkSkipped(path.clone())));
+            }
+            !is_symlink
+        });
+    }
+
+    let file_count = entries.iter().filter(|(_, is_dir)| !is_dir).count();
+    message_sender.send(Message::Progress(Progress::Start(
+        file_count,
+        ProgressType::CopingFiles,
+    )));
+    let (event_sender, mut event_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let (owned_archive, owned_source) = (archive.to_owned(), source_root.to_owned());
+    let (symlinks, level) = (options.symlinks, options.compression_level);
+    let writer = tokio::task::spawn_blocking(move || {
+        write(
+            &owned_archive,
+            &owned_source,
+            entries,
+            symlinks,
+            level,
+            &event_sender,
+        )
+    });
+    let mut file_errors = vec![];
+    let (mut files, mut bytes) = (0, 0);
+    while let Some(event) = event_receiver.recv().await {
+        match event {
+            Event::Added(path, size) => {
+                files += 1;
+                bytes += size;
+                message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                    Increment::FileCopied {
+                        source: path,
+                        destination: archive.to_owned(),
+                        bytes: size,
+                        reason: CopyReason::NewFile,
+                    },
+                )));
+            }
+            Event::Failed(path, _, e) if e.kind() == std::io::ErrorKind::NotFound => {
+                message_sender.send(Message::Warning(Warning::SourceVanished(path)));
+            }
+            Event::Failed(path, is_dir, e) => {
+                let error = ProcessPathError {
+                    not_processed: Some(path),
+                    kind: ProcessPathErrorKind::CannotCopyFile {
+                        to: archive.to_owned(),
+                        io_error: e.to_string(),
+                    },
+                };
+                if is_dir {
+                    directory_errors.push(error);
+                } else {
+                    message_sender.send(Message::Progress(Progress::IncrementFail(error.clone())));
+                    file_errors.push(error);
+                }
+            }
+        }
+    }
+    let written = writer
+        .await
+        .map_err(std::io::Error::other)
+        .flatten()
+        .map_err(|e| Error::CannotWriteArchive(archive.to_owned(), e.to_string()));
+    if written.is_err() || !file_errors.is_empty() {
+        message_sender.send(Message::Progress(Progress::EndFail(
+            file_errors.len(),
+            ProgressType::CopingFiles,
+        )));
+    } else {
+        message_sender.send(Message::Progress(Progress::EndSuccess(
+            ProgressType::CopingFiles,
+        )));
+    }
+    written?;
+    message_sender.send(Message::Info(Info::ArchiveCreated {
+        archive: archive.to_owned(),
+        files,
+        bytes,
+    }));
+    Error::from_processing_results(directory_errors, file_errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let format = |name| Format::of(std::path::Path::new(name));
+        assert_eq!(format("backup.tar"), Format::Tar(Compression::None));
+        assert_eq!(format("backup.tar.gz"), Format::Tar(Compression::Gzip));
+        assert_eq!(format("backup.TGZ"), Format::Tar(Compression::Gzip));
+        assert_eq!(format("backup.tar.zst"), Format::Tar(Compression::Zstd));
+        assert_eq!(format("backup.tzst"), Format::Tar(Compression::Zstd));
+        assert_eq!(format("backup.zip"), Format::Zip);
+        assert_eq!(format("backup.ZIP"), Format::Zip);
+    }
+
+    #[tokio::test]
+    async fn test_zip() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(source.path().join("docs/empty")).unwrap();
+        std::fs::write(source.path().join("docs/notes.txt"), b"notes").unwrap();
+        std::fs::write(source.path().join("photo.jpg"), vec![7; 100_000]).unwrap();
+        let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_001);
+        std::fs::File::options()
+            .write(true)
+            .open(source.path().join("docs/notes.txt"))
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let archive = destination.path().join("backup.zip");
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        crate::run(
+            crate::Command::Backup {
+                source_root: source.path().to_owned(),
+                destination: crate::Destination::Archive(archive.clone()),
+                options: crate::BackupOptions::default(),
+            },
+            message_sender,
+        )
+        .await
+        .unwrap();
+
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(&archive).unwrap()).unwrap();
+        let mut names = zip.file_names().collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            ["docs/", "docs/empty/", "docs/notes.txt", "photo.jpg"]
+        );
+        let mut photo = vec![];
+        std::io::Read::read_to_end(&mut zip.by_name("photo.jpg").unwrap(), &mut photo).unwrap();
+        assert_eq!(photo, vec![7; 100_000]);
+        assert!(zip.by_name("photo.jpg").unwrap().compressed_size() < 10_000);
+        let mut notes = zip.by_name("docs/notes.txt").unwrap();
+        let mut content = vec![];
+        std::io::Read::read_to_end(&mut notes, &mut content).unwrap();
+        assert_eq!(content, b"notes");
+        let timestamp = notes.extra_data_fields().find_map(|field| {
+            if let zip::extra_fields::ExtraField::ExtendedTimestamp(timestamp) = field {
+                timestamp.mod_time()
+            } else {
+                None
+            }
+        });
+        assert_eq!(timestamp, Some(1_700_000_001));
+    }
+}
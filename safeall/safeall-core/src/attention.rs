@@ -0,0 +1,136 @@
+//! Files which a run has backed up with a caveat, or skipped without an error, listed at its
+//! end so they do not get lost between the other messages.
+
+use crate::{Increment, Message, Progress, ProgressType, Warning};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AttentionReason {
+    /// Deleted from the source after the scan, so it is not in the backup.
+    Vanished,
+    /// Could only be copied in a retry pass, usually because another program had it open.
+    Locked,
+    /// Changed during every copy, so the backup might be a mix of old and new content.
+    ChangedDuringCopy,
+    /// The backup has the time of the copy, so the next run copies it again.
+    ModifiedTimeNotCopied,
+}
+
+impl std::fmt::Display for AttentionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttentionReason::Vanished => write!(f, "deleted before it could be copied"),
+            AttentionReason::Locked => write!(f, "in use, copied only after retrying"),
+            AttentionReason::ChangedDuringCopy => write!(f, "changed while it was copied"),
+            AttentionReason::ModifiedTimeNotCopied => {
+                write!(f, "modification time not copied, copied again next run")
+            }
+        }
+    }
+}
+
+/// A source file which needs to be checked after a run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attention {
+    pub path: std::path::PathBuf,
+    pub reason: AttentionReason,
+}
+
+/// Collects the files which need attention from the messages of a run.
+#[derive(Debug, Default)]
+pub struct Collector {
+    retrying: bool,
+    files: std::collections::BTreeSet<(AttentionReason, std::path::PathBuf)>,
+}
+
+impl Collector {
+    pub fn record(&mut self, message: &Message) {
+        match message {
+            Message::Progress(Progress::Start(_, ProgressType::RetryingFiles)) => {
+                self.retrying = true;
+            }
+            Message::Progress(
+                Progress::EndSuccess(ProgressType::RetryingFiles)
+                | Progress::EndFail(_, ProgressType::RetryingFiles),
+            ) => self.retrying = false,
+            Message::Progress(Progress::IncrementSuccess(Increment::FileCopied {
+                source, ..
+            })) if self.retrying => {
+                // NOTE: Changed files are retried as well, which does not mean they were locked
+                let changed = (AttentionReason::ChangedDuringCopy, source.clone());
+                if !self.files.remove(&changed) {
+                    self.files.insert((AttentionReason::Locked, source.clone()));
+                }
+            }
+            Message::Warning(Warning::SourceVanished(source)) => {
+                self.files
+                    .insert((AttentionReason::Vanished, source.clone()));
+            }
+            Message::Warning(Warning::SourceChangedDuringCopy(source)) => {
+                self.files
+                    .insert((AttentionReason::ChangedDuringCopy, source.clone()));
+            }
+            Message::Warning(Warning::CannotCopyModifiedTime { source, .. }) => {
+                self.files
+                    .insert((AttentionReason::ModifiedTimeNotCopied, source.clone()));
+            }
+            _ => {}
+        }
+    }
+
+    /// The files by their reason.
+    pub fn finish(self) -> Vec<Attention> {
+        self.files
+            .into_iter()
+            .map(|(reason, path)| Attention { path, reason })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attention_collector() {
+        let copied = |source: &str| {
+            Message::Progress(Progress::IncrementSuccess(Increment::FileCopied {
+                source: source.into(),
+                destination: "backup".into(),
+                bytes: 1,
+                reason: crate::CopyReason::NewFile,
+            }))
+        };
+        let mut collector = Collector::default();
+        for message in [
+            Message::Warning(Warning::SourceVanished("gone.txt".into())),
+            Message::Warning(Warning::SourceChangedDuringCopy("log.txt".into())),
+            Message::Warning(Warning::SourceChangedDuringCopy("db.txt".into())),
+            Message::Warning(Warning::CannotCopyModifiedTime {
+                source: "old.txt".into(),
+                destination: "backup".into(),
+            }),
+            copied("fine.txt"),
+            Message::Progress(Progress::Start(3, ProgressType::RetryingFiles)),
+            copied("log.txt"),
+            copied("open.xlsx"),
+            Message::Warning(Warning::SourceChangedDuringCopy("db.txt".into())),
+            Message::Progress(Progress::EndSuccess(ProgressType::RetryingFiles)),
+        ] {
+            collector.record(&message);
+        }
+
+        let attention = |path: &str, reason| Attention {
+            path: path.into(),
+            reason,
+        };
+        assert_eq!(
+            collector.finish(),
+            vec![
+                attention("gone.txt", AttentionReason::Vanished),
+                attention("open.xlsx", AttentionReason::Locked),
+                attention("db.txt", AttentionReason::ChangedDuringCopy),
+                attention("old.txt", AttentionReason::ModifiedTimeNotCopied),
+            ]
+        );
+    }
+}
@@ -0,0 +1,45 @@
+//! Points of a run which wait for the frontend to approve the next phase, e.g. the deletions
+//! of a sync once all files have been copied.
+
+/// Sent to the frontend, which answers it once with [`Checkpoint::approve`] or
+/// [`Checkpoint::cancel`]. Dropping all clones without an answer cancels.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    reply: std::sync::Arc<std::sync::Mutex<Option<tokio::sync::oneshot::Sender<bool>>>>,
+}
+
+impl Checkpoint {
+    fn new() -> (Self, tokio::sync::oneshot::Receiver<bool>) {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let checkpoint = Self {
+            reply: std::sync::Arc::new(std::sync::Mutex::new(Some(sender))),
+        };
+        (checkpoint, receiver)
+    }
+
+    /// Continues the run. Only the first answer counts.
+    pub fn approve(&self) {
+        self.answer(true);
+    }
+
+    /// Skips the next phase. Only the first answer counts.
+    pub fn cancel(&self) {
+        self.answer(false);
+    }
+
+    fn answer(&self, approved: bool) {
+        if let Some(reply) = self.reply.lock().expect("Lock is never poisoned").take() {
+            reply.send(approved).ok();
+        }
+    }
+}
+
+/// Sends a checkpoint and waits for its answer. `false` if it has been dropped unanswered.
+pub async fn wait(
+    message_sender: &impl crate::MessageSender,
+    info: impl FnOnce(Checkpoint) -> crate::Info,
+) -> bool {
+    let (checkpoint, receiver) = Checkpoint::new();
+    message_sender.send(crate::Message::Info(info(checkpoint)));
+    receiver.await.unwrap_or(false)
+}
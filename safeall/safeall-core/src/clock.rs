@@ -0,0 +1,69 @@
+//! Difference between the clock of this machine and the clock of the destination, which
+//! sets the modification times on network shares like NFS or SMB.
+
+const PROBE_FILE: &str = "clock";
+
+/// Differences below this are expected from the resolution of modification times, e.g. two
+/// seconds on FAT.
+pub const MAX_CLOCK_SKEW: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How far the clock of the destination is off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSkew {
+    Ahead(std::time::Duration),
+    Behind(std::time::Duration),
+}
+
+impl std::fmt::Display for ClockSkew {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClockSkew::Ahead(skew) => write!(f, "{} ahead", crate::format_duration(*skew)),
+            ClockSkew::Behind(skew) => write!(f, "{} behind", crate::format_duration(*skew)),
+        }
+    }
+}
+
+/// Writes a file to the metadata directory of the destination and compares the modification
+/// time the destination gave it with the local time. `None` if the clocks agree.
+pub fn measure(destination_root: &std::path::Path) -> std::io::Result<Option<ClockSkew>> {
+    let directory = destination_root.join(crate::METADATA_DIRECTORY);
+    std::fs::create_dir_all(&directory)?;
+    let probe = directory.join(PROBE_FILE);
+    let before = std::time::SystemTime::now();
+    std::fs::write(&probe, b"")?;
+    let after = std::time::SystemTime::now();
+    let modified = std::fs::metadata(&probe)?.modified();
+    std::fs::remove_file(&probe)?;
+    let modified = modified?;
+    let skew = if let Ok(skew) = before.duration_since(modified) {
+        ClockSkew::Behind(skew)
+    } else if let Ok(skew) = modified.duration_since(after) {
+        ClockSkew::Ahead(skew)
+    } else {
+        return Ok(None);
+    };
+    Ok(match skew {
+        ClockSkew::Ahead(duration) | ClockSkew::Behind(duration) if duration > MAX_CLOCK_SKEW => {
+            Some(skew)
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_measure_local_clock() {
+        let destination = tempfile::tempdir().unwrap();
+        assert_eq!(measure(destination.path()).unwrap(), None);
+        assert!(
+            !destination
+                .path()
+                .join(crate::METADATA_DIRECTORY)
+                .join(PROBE_FILE)
+                .exists()
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! Decision whether a file in the destination is already a backup of the file in the source.
+
+/// What happens to a file whose destination already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Skip(SkipReason),
+    Copy(CopyReason),
+}
+
+/// Why a file is copied, the first difference which has been found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CopyReason {
+    /// There is no backup of the file yet.
+    NewFile,
+    /// E.g. a file whose backup is a link.
+    TypeChanged,
+    SizeChanged,
+    PermissionsChanged,
+    ModifiedTimeChanged,
+    /// Same metadata but a different hash.
+    ContentChanged,
+    /// The files could not be compared, so the file is copied to be safe.
+    Forced,
+}
+
+impl std::fmt::Display for CopyReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CopyReason::NewFile => write!(f, "new file"),
+            CopyReason::TypeChanged => write!(f, "type changed"),
+            CopyReason::SizeChanged => write!(f, "size changed"),
+            CopyReason::PermissionsChanged => write!(f, "permissions changed"),
+            CopyReason::ModifiedTimeChanged => write!(f, "modification time changed"),
+            CopyReason::ContentChanged => write!(f, "content changed"),
+            CopyReason::Forced => write!(f, "forced"),
+        }
+    }
+}
+
+/// Why a file is not copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SkipReason {
+    /// Same size and modification time, the content has not been read.
+    SameMetadata,
+    /// Same size, the modification time and the content have not been checked.
+    SameSize,
+    /// Same content, which has been hashed.
+    SameContent,
+    /// A link which already points to the same target.
+    AlreadyLinked,
+    /// All content of the file is already stored in the repository.
+    AlreadyStored,
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::SameMetadata => write!(f, "same size and modification time"),
+            SkipReason::SameSize => write!(f, "same size"),
+            SkipReason::SameContent => write!(f, "same content"),
+            SkipReason::AlreadyLinked => write!(f, "already linked"),
+            SkipReason::AlreadyStored => write!(f, "already stored"),
+        }
+    }
+}
+
+/// The first difference of the metadata, comparing the permissions and the modification
+/// time only if asked to.
+fn metadata_difference(
+    source_metadata: &std::fs::Metadata,
+    destination_metadata: &std::fs::Metadata,
+    permissions: bool,
+    modified: bool,
+) -> Option<CopyReason> {
+    if source_metadata.file_type() != destination_metadata.file_type() {
+        Some(CopyReason::TypeChanged)
+    } else if source_metadata.len() != destination_metadata.len() {
+        Some(CopyReason::SizeChanged)
+    } else if permissions && source_metadata.permissions() != destination_metadata.permissions() {
+        Some(CopyReason::PermissionsChanged)
+    } else if modified && source_metadata.modified().ok() != destination_metadata.modified().ok() {
+        Some(CopyReason::ModifiedTimeChanged)
+    } else {
+        None
+    }
+}
+
+/// Compares a source file with the existing file in the destination.
+///
+/// It is called from a blocking thread, so implementations may read the content of both
+/// files or query a database. When it fails, a warning is sent and the file is copied.
+pub trait Comparator: std::fmt::Debug + Send + Sync {
+    fn compare(
+        &self,
+        source: &std::path::Path,
+        source_metadata: &std::fs::Metadata,
+        destination: &std::path::Path,
+        destination_metadata: &std::fs::Metadata,
+    ) -> std::io::Result<Decision>;
+
+    /// Whether comparisons read the content of the files, in which case all files which
+    /// already exist in the destination are compared in a phase of their own.
+    fn reads_content(&self) -> bool {
+        true
+    }
+}
+
+/// Skips files with the same type, length, permissions, modification time and content.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetadataAndHash;
+
+impl Comparator for MetadataAndHash {
+    fn compare(
+        &self,
+        source: &std::path::Path,
+        source_metadata: &std::fs::Metadata,
+        destination: &std::path::Path,
+        destination_metadata: &std::fs::Metadata,
+    ) -> std::io::Result<Decision> {
+        if let Some(reason) = metadata_difference(source_metadata, destination_metadata, true, true)
+        {
+            return Ok(Decision::Copy(reason));
+        }
+        if hash(source)? != hash(destination)? {
+            return Ok(Decision::Copy(CopyReason::ContentChanged));
+        }
+        Ok(Decision::Skip(SkipReason::SameContent))
+    }
+}
+
+/// Cheaper or stricter alternatives to [`MetadataAndHash`], e.g. for slow disks or network
+/// shares where reading both files again costs more than an occasional extra copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareStrategy {
+    /// Skips files with the same type, length and modification time without reading them.
+    SizeAndMtime,
+    /// Skips files with the same type and length without reading them.
+    SizeOnly,
+    /// Skips files with the same type, length and content, whatever their modification time.
+    AlwaysHash,
+    /// Skips files with the same type, length, permissions and modification time without
+    /// reading them.
+    NeverHash,
+}
+
+impl Comparator for CompareStrategy {
+    fn compare(
+        &self,
+        source: &std::path::Path,
+        source_metadata: &std::fs::Metadata,
+        destination: &std::path::Path,
+        destination_metadata: &std::fs::Metadata,
+    ) -> std::io::Result<Decision> {
+        let (permissions, modified, skip) = match self {
+            CompareStrategy::SizeAndMtime => (false, true, SkipReason::SameMetadata),
+            CompareStrategy::SizeOnly => (false, false, SkipReason::SameSize),
+            CompareStrategy::AlwaysHash => (false, false, SkipReason::SameContent),
+            CompareStrategy::NeverHash => (true, true, SkipReason::SameMetadata),
+        };
+        if let Some(reason) =
+            metadata_difference(source_metadata, destination_metadata, permissions, modified)
+        {
+            return Ok(Decision::Copy(reason));
+        }
+        if *self == CompareStrategy::AlwaysHash && hash(source)? != hash(destination)? {
+            return Ok(Decision::Copy(CopyReason::ContentChanged));
+        }
+        Ok(Decision::Skip(skip))
+    }
+
+    fn reads_content(&self) -> bool {
+        *self == CompareStrategy::AlwaysHash
+    }
+}
+
+/// Files of at least this size are hashed on all cores. Smaller files are already hashed in
+/// parallel with each other.
+#[cfg(feature = "parallel-hashing")]
+const PARALLEL_HASHING_MIN_SIZE: u64 = 16 * 1024 * 1024;
+
+fn hash(path: &std::path::Path) -> std::io::Result<blake3::Hash> {
+    crate::hash_cache::cached(path, hash_file)
+}
+
+pub fn hash_file(path: &std::path::Path) -> std::io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    #[cfg(feature = "parallel-hashing")]
+    if std::fs::metadata(path)?.len() >= PARALLEL_HASHING_MIN_SIZE {
+        hasher.update_mmap_rayon(path)?;
+        return Ok(hasher.finalize());
+    }
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_and_hash() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let destination = directory.path().join("destination");
+        std::fs::write(&source, b"content").unwrap();
+        std::fs::write(&destination, b"CONTENT").unwrap();
+        let modified = std::fs::metadata(&source).unwrap().modified().unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&destination)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let compare = || {
+            MetadataAndHash.compare(
+                &source,
+                &std::fs::metadata(&source).unwrap(),
+                &destination,
+                &std::fs::metadata(&destination).unwrap(),
+            )
+        };
+        assert_eq!(
+            compare().unwrap(),
+            Decision::Copy(CopyReason::ContentChanged)
+        );
+        std::fs::write(&destination, b"content").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&destination)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert_eq!(compare().unwrap(), Decision::Skip(SkipReason::SameContent));
+    }
+
+    #[test]
+    fn test_hash_large_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("large");
+        let content: Vec<u8> = (0..20 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+        assert_eq!(hash_file(&path).unwrap(), blake3::hash(&content));
+    }
+
+    #[test]
+    fn test_compare_strategies() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let destination = directory.path().join("destination");
+        std::fs::write(&source, b"content").unwrap();
+        std::fs::write(&destination, b"CONTENT").unwrap();
+        let modified = std::fs::metadata(&source).unwrap().modified().unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&destination)
+            .unwrap()
+            .set_modified(modified - std::time::Duration::from_mins(1))
+            .unwrap();
+
+        let compare = |strategy: CompareStrategy| {
+            strategy
+                .compare(
+                    &source,
+                    &std::fs::metadata(&source).unwrap(),
+                    &destination,
+                    &std::fs::metadata(&destination).unwrap(),
+                )
+                .unwrap()
+        };
+        assert_eq!(
+            compare(CompareStrategy::SizeOnly),
+            Decision::Skip(SkipReason::SameSize)
+        );
+        assert_eq!(
+            compare(CompareStrategy::SizeAndMtime),
+            Decision::Copy(CopyReason::ModifiedTimeChanged)
+        );
+        assert_eq!(
+            compare(CompareStrategy::NeverHash),
+            Decision::Copy(CopyReason::ModifiedTimeChanged)
+        );
+        assert_eq!(
+            compare(CompareStrategy::AlwaysHash),
+            Decision::Copy(CopyReason::ContentChanged)
+        );
+        std::fs::write(&destination, b"content").unwrap();
+        assert_eq!(
+            compare(CompareStrategy::AlwaysHash),
+            Decision::Skip(SkipReason::SameContent)
+        );
+        std::fs::write(&destination, b"longer content").unwrap();
+        assert_eq!(
+            compare(CompareStrategy::SizeOnly),
+            Decision::Copy(CopyReason::SizeChanged)
+        );
+    }
+}
@@ -0,0 +1,616 @@
+//! Chunked file copier with a configurable buffer size and optional page cache bypass.
+
+use std::io::{Read, Write};
+
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Alignment required for buffers, offsets and lengths when the page cache is bypassed.
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CopyTuning {
+    pub buffer_size: usize,
+    pub bypass_page_cache: bool,
+}
+
+impl CopyTuning {
+    fn aligned_buffer_size(&self) -> usize {
+        if self.bypass_page_cache {
+            self.buffer_size
+                .max(DIRECT_IO_ALIGNMENT)
+                .next_multiple_of(DIRECT_IO_ALIGNMENT)
+        } else {
+            self.buffer_size.max(1)
+        }
+    }
+}
+
+/// Copies `source` to `destination` including the permissions and returns the number of bytes copied.
+/// The data is fed into `hasher` while it is read, such that hashing needs no second read.
+/// Fails with [`std::io::ErrorKind::Interrupted`] once `cancelled` is set between two chunks.
+pub fn copy_file(
+    source: &std::path::Path,
+    destination: &std::path::Path,
+    tuning: CopyTuning,
+    mut hasher: Option<&mut blake3::Hasher>,
+    cancelled: &std::sync::atomic::AtomicBool,
+) -> std::io::Result<u64> {
+    let (mut reader, source_direct) = open(source, tuning, |o| o.read(true))?;
+    let metadata = reader.metadata()?;
+    let (mut writer, destination_direct) = open(destination, tuning, |o| {
+        o.write(true).create(true).truncate(true)
+    })?;
+
+    let buffer_size = tuning.aligned_buffer_size();
+    let mut storage = vec![0u8; buffer_size + DIRECT_IO_ALIGNMENT];
+    let offset = storage.as_ptr().align_offset(DIRECT_IO_ALIGNMENT);
+    let buffer = &mut storage[offset..offset + buffer_size];
+
+    let mut total = 0u64;
+    loop {
+        check_cancelled(cancelled)?;
+        let filled = fill(&mut reader, buffer, source_direct.then_some(metadata.len()))?;
+        if filled == 0 {
+            break;
+        }
+        total += filled as u64;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&buffer[..filled]);
+        }
+        if destination_direct && filled % DIRECT_IO_ALIGNMENT != 0 {
+            // NOTE: Direct writes must be aligned, the padding is truncated afterwards
+            let padded = filled.next_multiple_of(DIRECT_IO_ALIGNMENT);
+            buffer[filled..padded].fill(0);
+            writer.write_all(&buffer[..padded])?;
+            writer.set_len(total)?;
+            break;
+        }
+        writer.write_all(&buffer[..filled])?;
+    }
+    writer.set_permissions(metadata.permissions())?;
+    Ok(total)
+}
+
+/// Writes `path` to the disk and evicts it from the page cache, such that it is read from the
+/// disk again instead of from memory.
+#[cfg(target_os = "linux")]
+pub fn drop_from_page_cache(path: &std::path::Path) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let file = std::fs::File::open(path)?;
+    file.sync_all()?;
+    // SAFETY: The file descriptor is valid as long as `file` is alive
+    let result = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    if result != 0 {
+        return Err(std::io::Error::from_raw_os_error(result));
+    }
+    Ok(())
+}
+
+/// Does nothing, reading the file back may be served from memory.
+#[cfg(not(target_os = "linux"))]
+pub fn drop_from_page_cache(_path: &std::path::Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Lets `destination` share the data blocks of `source` on copy-on-write filesystems like
+/// btrfs, XFS or APFS, which takes no time and no space. Returns `false` if the filesystem
+/// cannot do this, e.g. because the files are on different filesystems, in which case an
+/// existing `destination` is left as it is.
+#[cfg(target_os = "linux")]
+pub fn clone_file(
+    source: &std::path::Path,
+    destination: &std::path::Path,
+) -> std::io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    let Some(temporary) = clone_path(destination) else {
+        return Ok(false);
+    };
+    let reader = std::fs::File::open(source)?;
+    // NOTE: The clone is renamed afterwards, such that a failed clone keeps the destination
+    let writer = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temporary)?;
+    // SAFETY: Both file descriptors are valid as long as `reader` and `writer` are alive
+    let result = unsafe { libc::ioctl(writer.as_raw_fd(), libc::FICLONE, reader.as_raw_fd()) };
+    if result == -1 {
+        std::fs::remove_file(&temporary).ok();
+        return Ok(false);
+    }
+    writer
+        .set_permissions(reader.metadata()?.permissions())
+        .and_then(|()| std::fs::rename(&temporary, destination))
+        .inspect_err(|_| {
+            std::fs::remove_file(&temporary).ok();
+        })?;
+    Ok(true)
+}
+
+#[cfg(target_os = "macos")]
+pub fn clone_file(
+    source: &std::path::Path,
+    destination: &std::path::Path,
+) -> std::io::Result<bool> {
+    use std::os::unix::ffi::OsStrExt;
+
+    // NOTE: `clonefile` cannot replace an existing file, so the clone is renamed afterwards
+    let Some(temporary) = clone_path(destination) else {
+        return Ok(false);
+    };
+    let to_c_string = |path: &std::path::Path| {
+        std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(std::io::Error::other)
+    };
+    let (source_c, temporary_c) = (to_c_string(source)?, to_c_string(&temporary)?);
+    // SAFETY: Both paths are valid nul terminated strings
+    let result = unsafe { libc::clonefile(source_c.as_ptr(), temporary_c.as_ptr(), 0) };
+    if result == -1 {
+        return Ok(false);
+    }
+    std::fs::rename(&temporary, destination).inspect_err(|_| {
+        std::fs::remove_file(&temporary).ok();
+    })?;
+    Ok(true)
+}
+
+/// Where the clone of `destination` is created before it is renamed into place.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn clone_path(destination: &std::path::Path) -> Option<std::path::PathBuf> {
+    let mut temporary_name = std::ffi::OsString::from(".");
+    temporary_name.push(destination.file_name()?);
+    temporary_name.push(".safeall-clone");
+    Some(destination.with_file_name(temporary_name))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+#[allow(clippy::unnecessary_wraps)]
+pub fn clone_file(
+    _source: &std::path::Path,
+    _destination: &std::path::Path,
+) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+/// Whether `metadata` belongs to a file which occupies less space on disk than its length.
+#[cfg(unix)]
+pub fn is_sparse(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    // NOTE: `blocks` is always in units of 512 bytes
+    metadata.blocks() * 512 < metadata.len()
+}
+
+#[cfg(not(unix))]
+pub fn is_sparse(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Copies only the data regions of `source` and leaves holes in `destination` where `source`
+/// has them, including the permissions. Returns the length of the file or `None` if the
+/// filesystem cannot report holes, in which case nothing has been written. Fails once
+/// `cancelled` is set between two data regions.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn copy_sparse_file(
+    source: &std::path::Path,
+    destination: &std::path::Path,
+    cancelled: &std::sync::atomic::AtomicBool,
+) -> std::io::Result<Option<u64>> {
+    use std::io::Seek;
+    use std::os::fd::AsRawFd;
+
+    let mut reader = std::fs::File::open(source)?;
+    let metadata = reader.metadata()?;
+    let length = metadata.len();
+    let fd = reader.as_raw_fd();
+    let seek = |offset: u64, whence: libc::c_int| -> std::io::Result<Option<u64>> {
+        let offset = libc::off_t::try_from(offset).map_err(std::io::Error::other)?;
+        // SAFETY: The file descriptor is valid as long as `reader` is alive
+        let result = unsafe { libc::lseek(fd, offset, whence) };
+        if result >= 0 {
+            return Ok(Some(result.cast_unsigned()));
+        }
+        let error = std::io::Error::last_os_error();
+        match error.raw_os_error() {
+            // NOTE: There is no data after the offset
+            Some(libc::ENXIO) => Ok(None),
+            _ => Err(error),
+        }
+    };
+    match seek(0, libc::SEEK_DATA) {
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(None),
+        result => result?,
+    };
+
+    let mut writer = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(destination)?;
+    let mut offset = 0;
+    while let Some(data) = seek(offset, libc::SEEK_DATA)? {
+        check_cancelled(cancelled)?;
+        let hole = seek(data, libc::SEEK_HOLE)?.unwrap_or(length);
+        reader.seek(std::io::SeekFrom::Start(data))?;
+        writer.seek(std::io::SeekFrom::Start(data))?;
+        std::io::copy(&mut (&reader).take(hole - data), &mut writer)?;
+        offset = hole;
+    }
+    writer.set_len(length)?;
+    writer.set_permissions(metadata.permissions())?;
+    Ok(Some(length))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+#[allow(clippy::unnecessary_wraps)]
+pub fn copy_sparse_file(
+    _source: &std::path::Path,
+    _destination: &std::path::Path,
+    _cancelled: &std::sync::atomic::AtomicBool,
+) -> std::io::Result<Option<u64>> {
+    Ok(None)
+}
+
+fn check_cancelled(cancelled: &std::sync::atomic::AtomicBool) -> std::io::Result<()> {
+    if cancelled.load(std::sync::atomic::Ordering::Relaxed) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Interrupted,
+            "the copy has been cancelled",
+        ));
+    }
+    Ok(())
+}
+
+/// Moves `from` to `to` like a rename. Between filesystems, where a rename fails, files and
+/// directories are copied with their permissions and modification times and then deleted.
+pub fn move_path(from: &std::path::Path, to: &std::path::Path) -> std::io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            copy_tree(from, to)?;
+            if std::fs::symlink_metadata(from)?.is_dir() {
+                std::fs::remove_dir_all(from)
+            } else {
+                std::fs::remove_file(from)
+            }
+        }
+        result => result,
+    }
+}
+
+/// Copies `from` and everything below it without following symbolic links.
+fn copy_tree(from: &std::path::Path, to: &std::path::Path) -> std::io::Result<()> {
+    let metadata = std::fs::symlink_metadata(from)?;
+    if metadata.is_dir() {
+        std::fs::create_dir_all(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        // NOTE: Only now as the directory might not be writable
+        return std::fs::set_permissions(to, metadata.permissions());
+    }
+    if metadata.is_symlink() {
+        return copy_symlink(from, to);
+    }
+    std::fs::copy(from, to)?;
+    std::fs::File::options()
+        .write(true)
+        .open(to)?
+        .set_modified(metadata.modified()?)
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &std::path::Path, to: &std::path::Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(std::fs::read_link(from)?, to)
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(from: &std::path::Path, to: &std::path::Path) -> std::io::Result<()> {
+    std::fs::copy(from, to).map(|_| ())
+}
+
+/// Reads until `buffer` is full or the end of the file is reached. With direct I/O,
+/// `direct_length` is the length of the file.
+fn fill(
+    reader: &mut (impl Read + std::io::Seek),
+    buffer: &mut [u8],
+    direct_length: Option<u64>,
+) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => {
+                filled += n;
+                // NOTE: A further direct read would not be aligned anymore, which is only
+                // fine at the end of the file
+                if let Some(length) = direct_length
+                    && filled % DIRECT_IO_ALIGNMENT != 0
+                {
+                    if reader.stream_position()? < length {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof,
+                            "a direct read ended in the middle of the file",
+                        ));
+                    }
+                    break;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Opens `path` and bypasses the page cache if requested and supported by the filesystem.
+/// Returns whether the file has been opened for direct I/O.
+fn open(
+    path: &std::path::Path,
+    tuning: CopyTuning,
+    configure: impl Fn(&mut std::fs::OpenOptions) -> &mut std::fs::OpenOptions,
+) -> std::io::Result<(std::fs::File, bool)> {
+    if tuning.bypass_page_cache
+        && let Some(file) = open_direct(path, &configure)
+    {
+        return Ok(file);
+    }
+    let file = configure(&mut std::fs::OpenOptions::new()).open(path)?;
+    Ok((file, false))
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(
+    path: &std::path::Path,
+    configure: impl Fn(&mut std::fs::OpenOptions) -> &mut std::fs::OpenOptions,
+) -> Option<(std::fs::File, bool)> {
+    use std::os::unix::fs::OpenOptionsExt;
+    // NOTE: Filesystems like tmpfs do not support `O_DIRECT`, we then fall back to buffered I/O
+    let file = configure(std::fs::OpenOptions::new().custom_flags(libc::O_DIRECT))
+        .open(path)
+        .ok()?;
+    Some((file, true))
+}
+
+#[cfg(target_os = "macos")]
+fn open_direct(
+    path: &std::path::Path,
+    configure: impl Fn(&mut std::fs::OpenOptions) -> &mut std::fs::OpenOptions,
+) -> Option<(std::fs::File, bool)> {
+    use std::os::fd::AsRawFd;
+    let file = configure(&mut std::fs::OpenOptions::new())
+        .open(path)
+        .ok()?;
+    // SAFETY: The file descriptor is valid as long as `file` is alive
+    let result = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) };
+    // NOTE: `F_NOCACHE` has no alignment requirements
+    (result != -1).then_some((file, false))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn open_direct(
+    _path: &std::path::Path,
+    _configure: impl Fn(&mut std::fs::OpenOptions) -> &mut std::fs::OpenOptions,
+) -> Option<(std::fs::File, bool)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_file_chunked() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let content: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &content).unwrap();
+
+        for (buffer_size, bypass_page_cache) in [(7, false), (4096, false), (1, true)] {
+            let destination = directory
+                .path()
+                .join(format!("{buffer_size}-{bypass_page_cache}"));
+            let mut hasher = blake3::Hasher::new();
+            let copied = copy_file(
+                &source,
+                &destination,
+                CopyTuning {
+                    buffer_size,
+                    bypass_page_cache,
+                },
+                Some(&mut hasher),
+                &std::sync::atomic::AtomicBool::new(false),
+            )
+            .unwrap();
+            assert_eq!(copied, content.len() as u64);
+            assert_eq!(hasher.finalize(), blake3::hash(&content));
+            assert_eq!(std::fs::read(&destination).unwrap(), content);
+        }
+    }
+
+    /// Returns at most `max` bytes per read, like a read interrupted by a signal or from a
+    /// network filesystem.
+    struct ShortReads {
+        inner: std::io::Cursor<Vec<u8>>,
+        max: usize,
+    }
+
+    impl Read for ShortReads {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            let max = buffer.len().min(self.max);
+            self.inner.read(&mut buffer[..max])
+        }
+    }
+
+    impl std::io::Seek for ShortReads {
+        fn seek(&mut self, position: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(position)
+        }
+    }
+
+    #[test]
+    fn test_fill_short_reads() {
+        let content: Vec<u8> = (0..=250)
+            .cycle()
+            .take(3 * DIRECT_IO_ALIGNMENT + 100)
+            .collect();
+        let length = content.len() as u64;
+        let short_reads = |max| ShortReads {
+            inner: std::io::Cursor::new(content.clone()),
+            max,
+        };
+        let mut buffer = vec![0; 2 * DIRECT_IO_ALIGNMENT];
+
+        let mut reader = short_reads(7);
+        assert_eq!(fill(&mut reader, &mut buffer, None).unwrap(), buffer.len());
+        assert_eq!(buffer, content[..buffer.len()]);
+        assert_eq!(
+            fill(&mut reader, &mut buffer, None).unwrap(),
+            DIRECT_IO_ALIGNMENT + 100
+        );
+        assert_eq!(fill(&mut reader, &mut buffer, None).unwrap(), 0);
+
+        let mut reader = short_reads(DIRECT_IO_ALIGNMENT);
+        assert_eq!(
+            fill(&mut reader, &mut buffer, Some(length)).unwrap(),
+            buffer.len()
+        );
+        assert_eq!(
+            fill(&mut reader, &mut buffer, Some(length)).unwrap(),
+            DIRECT_IO_ALIGNMENT + 100
+        );
+
+        let mut reader = short_reads(100);
+        assert_eq!(
+            fill(&mut reader, &mut buffer, Some(length))
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_copy_file_cancelled() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        std::fs::write(&source, b"content").unwrap();
+        let tuning = CopyTuning {
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            bypass_page_cache: false,
+        };
+
+        let error = copy_file(
+            &source,
+            &directory.path().join("destination"),
+            tuning,
+            None,
+            &std::sync::atomic::AtomicBool::new(true),
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn test_copy_tree() {
+        let directory = tempfile::tempdir().unwrap();
+        let from = directory.path().join("from");
+        let to = directory.path().join("to");
+        std::fs::create_dir_all(from.join("nested")).unwrap();
+        std::fs::write(from.join("nested/file"), b"content").unwrap();
+        let modified = std::fs::metadata(from.join("nested/file"))
+            .unwrap()
+            .modified()
+            .unwrap()
+            - std::time::Duration::from_hours(1);
+        std::fs::File::options()
+            .write(true)
+            .open(from.join("nested/file"))
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        copy_tree(&from, &to).unwrap();
+        assert_eq!(std::fs::read(to.join("nested/file")).unwrap(), b"content");
+        assert_eq!(
+            std::fs::metadata(to.join("nested/file"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            modified
+        );
+
+        let moved = directory.path().join("moved");
+        move_path(&from, &moved).unwrap();
+        assert!(!from.exists());
+        assert_eq!(
+            std::fs::read(moved.join("nested/file")).unwrap(),
+            b"content"
+        );
+    }
+
+    #[test]
+    fn test_clone_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let destination = directory.path().join("destination");
+        std::fs::write(&source, b"shared blocks").unwrap();
+        std::fs::write(&destination, b"old content which is longer").unwrap();
+
+        // NOTE: Most filesystems of temporary directories cannot share blocks
+        if clone_file(&source, &destination).unwrap() {
+            assert_eq!(std::fs::read(&destination).unwrap(), b"shared blocks");
+        } else {
+            assert_eq!(
+                std::fs::read(&destination).unwrap(),
+                b"old content which is longer"
+            );
+        }
+        assert_eq!(std::fs::read(&source).unwrap(), b"shared blocks");
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_failed_clone_keeps_destination() {
+        let directory = tempfile::tempdir().unwrap();
+        let destination = directory.path().join("destination");
+        std::fs::write(&destination, b"previous backup").unwrap();
+
+        // NOTE: A directory cannot be cloned on any filesystem
+        assert!(!clone_file(directory.path(), &destination).unwrap());
+        assert_eq!(std::fs::read(&destination).unwrap(), b"previous backup");
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_copy_sparse_file() {
+        use std::io::Seek;
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let destination = directory.path().join("destination");
+        let mut file = std::fs::File::create(&source).unwrap();
+        file.set_len(8 * 1024 * 1024).unwrap();
+        file.seek(std::io::SeekFrom::Start(4 * 1024 * 1024))
+            .unwrap();
+        file.write_all(b"data in the middle").unwrap();
+        drop(file);
+        if !is_sparse(&std::fs::metadata(&source).unwrap()) {
+            // NOTE: The filesystem of the temporary directory does not support sparse files
+            return;
+        }
+
+        let Some(copied) = copy_sparse_file(
+            &source,
+            &destination,
+            &std::sync::atomic::AtomicBool::new(false),
+        )
+        .unwrap() else {
+            return;
+        };
+        assert_eq!(copied, 8 * 1024 * 1024);
+        assert_eq!(
+            std::fs::read(&destination).unwrap(),
+            std::fs::read(&source).unwrap()
+        );
+        assert!(is_sparse(&std::fs::metadata(&destination).unwrap()));
+    }
+}
@@ -0,0 +1,242 @@
+//! Identifies destinations such that a run does not write to the wrong drive or into the empty
+//! mount point of a drive which is not mounted.
+
+const ID_FILE: &str = "id";
+const KNOWN_DESTINATIONS_FILE: &str = "destinations";
+const KNOWN_DESTINATIONS_LOCK_SUFFIX: &str = ".lock";
+
+fn id_path(destination_root: &std::path::Path) -> std::path::PathBuf {
+    destination_root
+        .join(crate::METADATA_DIRECTORY)
+        .join(ID_FILE)
+}
+
+/// Directory in which safeall keeps its data on this machine.
+#[must_use]
+pub fn data_directory() -> Option<std::path::PathBuf> {
+    dirs::data_local_dir().map(|d| d.join("safeall"))
+}
+
+/// File in which the ID of every destination used on this machine is remembered.
+#[must_use]
+pub fn known_destinations_path() -> Option<std::path::PathBuf> {
+    data_directory().map(|d| d.join(KNOWN_DESTINATIONS_FILE))
+}
+
+fn read_id(destination_root: &std::path::Path) -> Option<String> {
+    std::fs::read_to_string(id_path(destination_root))
+        .ok()
+        .map(|id| id.trim().to_owned())
+        .filter(|id| !id.is_empty())
+}
+
+/// Known destinations, one `<id>\t<escaped path>` per line.
+fn load_known_destinations(
+    path: &std::path::Path,
+) -> std::collections::HashMap<std::path::PathBuf, String> {
+    std::fs::read_to_string(path)
+        .map(|known| {
+            known
+                .lines()
+                .filter_map(|line| line.split_once('\t'))
+                .map(|(id, path)| (crate::manifest::unescape(path), id.to_owned()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Waits until no other process of safeall, e.g. the GUI and the CLI, updates the known
+/// destinations. The lock is released when the file is dropped.
+fn lock_known_destinations(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(KNOWN_DESTINATIONS_LOCK_SUFFIX);
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path)?;
+    file.lock()?;
+    Ok(file)
+}
+
+/// Replaces the known destinations at once, such that a concurrent [`verify`] never reads a
+/// file which is only partly written.
+fn save_known_destinations(
+    path: &std::path::Path,
+    known: &std::collections::HashMap<std::path::PathBuf, String>,
+) -> std::io::Result<()> {
+    let mut lines: Vec<_> = known
+        .iter()
+        .map(|(path, id)| format!("{id}\t{}", crate::manifest::escape(path)))
+        .collect();
+    lines.sort();
+    let mut content = lines.join("\n");
+    content.push('\n');
+    let partial = crate::staging_path(path, None);
+    std::fs::write(&partial, content)?;
+    std::fs::rename(&partial, path)
+}
+
+fn remember(
+    known_destinations: &std::path::Path,
+    destination_root: &std::path::Path,
+    id: String,
+    message_sender: &impl crate::MessageSender,
+) {
+    let update = || {
+        let _lock = lock_known_destinations(known_destinations)?;
+        let mut known = load_known_destinations(known_destinations);
+        known.insert(key(destination_root), id);
+        save_known_destinations(known_destinations, &known)
+    };
+    if let Err(e) = update() {
+        message_sender.send(crate::Message::Warning(
+            crate::Warning::CannotRememberDestination {
+                path: known_destinations.to_owned(),
+                io_error: e.to_string(),
+            },
+        ));
+    }
+}
+
+fn key(destination_root: &std::path::Path) -> std::path::PathBuf {
+    destination_root
+        .canonicalize()
+        .unwrap_or_else(|_| destination_root.to_owned())
+}
+
+/// Checks that the destination carries the ID it had on the last run, without writing to it.
+///
+/// The IDs of the last runs are remembered in `known_destinations`, nothing is checked if it
+/// is `None`. With `accept_new` a different or missing ID is accepted instead of failing.
+pub fn verify(
+    destination_root: &std::path::Path,
+    known_destinations: Option<&std::path::Path>,
+    accept_new: bool,
+    message_sender: &impl crate::MessageSender,
+) -> Result<(), crate::Error> {
+    let Some(known_destinations) = known_destinations else {
+        return Ok(());
+    };
+    let known = load_known_destinations(known_destinations);
+    match (read_id(destination_root), known.get(&key(destination_root))) {
+        (Some(id), Some(expected)) if id == *expected => Ok(()),
+        (Some(found), Some(expected)) if !accept_new => Err(crate::Error::DestinationIdMismatch {
+            destination_root: destination_root.to_owned(),
+            expected: expected.clone(),
+            found,
+        }),
+        (None, Some(_)) if !accept_new => Err(crate::Error::DestinationIdMissing(
+            destination_root.to_owned(),
+        )),
+        (Some(id), _) => {
+            remember(known_destinations, destination_root, id, message_sender);
+            Ok(())
+        }
+        (None, _) => Ok(()),
+    }
+}
+
+/// Gives a destination without an ID a new one and remembers it in `known_destinations`.
+/// Meant for destinations which passed [`verify`] and exist.
+pub fn assign(
+    destination_root: &std::path::Path,
+    known_destinations: Option<&std::path::Path>,
+    message_sender: &impl crate::MessageSender,
+) -> Result<(), crate::Error> {
+    if read_id(destination_root).is_some() {
+        return Ok(());
+    }
+    let id = uuid::Uuid::new_v4().to_string();
+    let path = id_path(destination_root);
+    std::fs::create_dir_all(destination_root.join(crate::METADATA_DIRECTORY))
+        .and_then(|()| std::fs::write(&path, format!("{id}\n")))
+        .map_err(|e| crate::Error::CannotWriteDestinationId(path, e.to_string()))?;
+    if let Some(known_destinations) = known_destinations {
+        remember(known_destinations, destination_root, id, message_sender);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_and_assign() {
+        let data = tempfile::tempdir().unwrap();
+        let known = data.path().join(KNOWN_DESTINATIONS_FILE);
+        let destination = tempfile::tempdir().unwrap();
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        verify(destination.path(), Some(&known), false, &message_sender).unwrap();
+        assert!(read_id(destination.path()).is_none());
+        assign(destination.path(), Some(&known), &message_sender).unwrap();
+        let id = read_id(destination.path()).unwrap();
+        assign(destination.path(), Some(&known), &message_sender).unwrap();
+        assert_eq!(read_id(destination.path()).unwrap(), id);
+        verify(destination.path(), Some(&known), false, &message_sender).unwrap();
+
+        std::fs::write(id_path(destination.path()), "other\n").unwrap();
+        assert!(matches!(
+            verify(destination.path(), Some(&known), false, &message_sender),
+            Err(crate::Error::DestinationIdMismatch { .. })
+        ));
+        verify(destination.path(), None, false, &message_sender).unwrap();
+        verify(destination.path(), Some(&known), true, &message_sender).unwrap();
+        verify(destination.path(), Some(&known), false, &message_sender).unwrap();
+    }
+
+    #[test]
+    fn test_verify_does_not_write_to_empty_mount_point() {
+        let data = tempfile::tempdir().unwrap();
+        let known = data.path().join(KNOWN_DESTINATIONS_FILE);
+        let parent = tempfile::tempdir().unwrap();
+        let destination = parent.path().join("mount-point");
+        std::fs::create_dir(&destination).unwrap();
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        assign(&destination, Some(&known), &message_sender).unwrap();
+
+        // NOTE: Looks like the empty mount point of a drive which is not mounted
+        std::fs::remove_dir_all(&destination).unwrap();
+        std::fs::create_dir(&destination).unwrap();
+        assert!(matches!(
+            verify(&destination, Some(&known), false, &message_sender),
+            Err(crate::Error::DestinationIdMissing(_))
+        ));
+        assert_eq!(std::fs::read_dir(&destination).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_remember_concurrently() {
+        let data = tempfile::tempdir().unwrap();
+        let known = data.path().join(KNOWN_DESTINATIONS_FILE);
+
+        std::thread::scope(|scope| {
+            for number in 0..8 {
+                let known = &known;
+                scope.spawn(move || {
+                    let (message_sender, _message_receiver) =
+                        tokio::sync::mpsc::unbounded_channel();
+                    let destination = std::path::PathBuf::from(format!("/backup-{number}"));
+                    remember(known, &destination, number.to_string(), &message_sender);
+                });
+            }
+        });
+
+        let known = load_known_destinations(&known);
+        assert_eq!(known.len(), 8);
+        assert_eq!(known[std::path::Path::new("/backup-3")], "3");
+    }
+
+    #[test]
+    fn test_known_destinations_in_data_directory() {
+        assert_eq!(
+            known_destinations_path(),
+            dirs::data_local_dir().map(|d| d.join("safeall").join(KNOWN_DESTINATIONS_FILE))
+        );
+    }
+}
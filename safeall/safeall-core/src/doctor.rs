@@ -0,0 +1,567 @@
+//! Checks a source and a destination for common problems before a run, e.g. a destination
+//! which cannot store the names or modification times of the files in the source.
+//!
+//! Nothing is written to the source. The destination only gets a probe file, which is removed
+//! again, in its nearest directory which exists.
+
+use crate::{BackupOptions, Error, ReadDirType, RecursiveReadDir, Severity};
+
+/// Paths shown per finding, the others are only counted.
+const MAX_EXAMPLES: usize = 3;
+
+/// Granularities of modification times, from the finest to the coarsest: NTFS, most network
+/// shares, some FUSE filesystems, exFAT, ext3 and HFS+, and FAT.
+const GRANULARITIES: [std::time::Duration; 6] = [
+    std::time::Duration::from_nanos(100),
+    std::time::Duration::from_micros(1),
+    std::time::Duration::from_millis(1),
+    std::time::Duration::from_millis(10),
+    std::time::Duration::from_secs(1),
+    std::time::Duration::from_secs(2),
+];
+
+/// Paths with the same problem.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Paths {
+    pub count: usize,
+    /// The first few of them.
+    pub examples: Vec<std::path::PathBuf>,
+}
+
+impl Paths {
+    fn push(&mut self, path: &std::path::Path) {
+        self.count += 1;
+        if self.examples.len() < MAX_EXAMPLES {
+            self.examples.push(path.to_owned());
+        }
+    }
+}
+
+impl std::fmt::Display for Paths {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} paths, e.g. ", self.count)?;
+        for (i, path) in self.examples.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "\"{}\"", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// A problem which the doctor found, with what to do about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    /// The destination is left out of the backup, but it is lost together with the source.
+    DestinationInsideSource,
+    SourceInsideDestination,
+    DestinationNotWritable(String),
+    /// Paths of the source which only differ in case, while the destination does not tell
+    /// them apart.
+    CaseCollisions(Paths),
+    /// Files of the source whose modification time the destination cannot store exactly.
+    CoarseModificationTimes {
+        granularity: std::time::Duration,
+        files: usize,
+    },
+    /// Paths which would be longer in the destination than its filesystem allows.
+    PathsTooLong {
+        max_name: usize,
+        max_path: usize,
+        paths: Paths,
+    },
+    NotEnoughSpace {
+        needed: u64,
+        available: u64,
+    },
+    /// Files larger than the filesystem of the destination can store, which are left out of
+    /// the backup if `skipped`.
+    FilesTooLarge {
+        max_size: u64,
+        paths: Paths,
+        skipped: bool,
+    },
+    Unreadable(Paths),
+}
+
+impl Finding {
+    /// Errors make runs fail, warnings make them slower or less safe than expected.
+    #[must_use]
+    pub fn severity(&self) -> Severity {
+        match self {
+            Finding::DestinationInsideSource
+            | Finding::CoarseModificationTimes { .. }
+            | Finding::FilesTooLarge { skipped: true, .. } => Severity::Warning,
+            Finding::SourceInsideDestination
+            | Finding::DestinationNotWritable(_)
+            | Finding::CaseCollisions(_)
+            | Finding::PathsTooLong { .. }
+            | Finding::NotEnoughSpace { .. }
+            | Finding::FilesTooLarge { skipped: false, .. }
+            | Finding::Unreadable(_) => Severity::Error,
+        }
+    }
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Finding::DestinationInsideSource => write!(
+                f,
+                "The destination is inside the source. It is left out of the backup, but it is lost together with the source if the drive fails. Choose a destination on another drive."
+            ),
+            Finding::SourceInsideDestination => write!(
+                f,
+                "The source is inside the destination, every backup would contain the previous ones. Choose a destination outside of the source."
+            ),
+            Finding::DestinationNotWritable(io_error) => write!(
+                f,
+                "Cannot write to the destination: {io_error}. Check its permissions and that it is not mounted read-only."
+            ),
+            Finding::CaseCollisions(paths) => write!(
+                f,
+                "The destination does not tell apart names which only differ in case, but the source has {paths} which do. They would overwrite each other, rename them or choose a destination with a case-sensitive filesystem."
+            ),
+            Finding::CoarseModificationTimes { granularity, files } => write!(
+                f,
+                "The destination stores modification times to {} only, so {files} files would look modified in every run. Compare the files without their modification time, e.g. by size only.",
+                format_granularity(*granularity)
+            ),
+            Finding::PathsTooLong {
+                max_name,
+                max_path,
+                paths,
+            } => write!(
+                f,
+                "The destination allows names of {max_name} bytes and paths of {max_path} bytes, {paths} in the source would be longer. Shorten them or choose a destination closer to the root of its drive."
+            ),
+            Finding::NotEnoughSpace { needed, available } => write!(
+                f,
+                "The backup needs ~{} more, but the destination only has ~{} free. Free some space or choose a larger drive.",
+                crate::format_bytes(*needed),
+                crate::format_bytes(*available)
+            ),
+            Finding::FilesTooLarge {
+                max_size,
+                paths,
+                skipped,
+            } => write!(
+                f,
+                "The destination stores files up to {} only, e.g. because it is formatted with FAT, but the source has {paths} which are larger. {}",
+                crate::format_bytes(*max_size),
+                if *skipped {
+                    "They are left out of the backup."
+                } else {
+                    "Format the destination with exFAT or NTFS or skip or split these files."
+                }
+            ),
+            Finding::Unreadable(paths) => write!(
+                f,
+                "Cannot read {paths} in the source. Check their permissions or exclude them."
+            ),
+        }
+    }
+}
+
+fn format_granularity(granularity: std::time::Duration) -> String {
+    if granularity >= std::time::Duration::from_secs(1) {
+        format!("{} s", granularity.as_secs())
+    } else if granularity >= std::time::Duration::from_millis(1) {
+        format!("{} ms", granularity.as_millis())
+    } else if granularity >= std::time::Duration::from_micros(1) {
+        format!("{} µs", granularity.as_micros())
+    } else {
+        format!("{} ns", granularity.as_nanos())
+    }
+}
+
+/// `path` with symbolic links resolved as far as it exists.
+fn resolve(path: &std::path::Path) -> std::path::PathBuf {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_owned());
+    for existing in path.ancestors() {
+        if let Ok(resolved) = existing.canonicalize() {
+            let rest = path
+                .strip_prefix(existing)
+                .unwrap_or(std::path::Path::new(""));
+            return resolved.join(rest);
+        }
+    }
+    path
+}
+
+/// What the probe file found out about the filesystem of the destination.
+struct Probe {
+    case_insensitive: bool,
+    /// `None` if modification times are stored to the nanosecond.
+    granularity: Option<std::time::Duration>,
+}
+
+/// Writes a probe file to `directory`, looks it up with another case and gives it an odd
+/// modification time.
+fn probe(directory: &std::path::Path) -> std::io::Result<Probe> {
+    let name = format!(".safeall-doctor-{}.probe", uuid::Uuid::new_v4().simple());
+    let path = directory.join(&name);
+    let file = std::fs::File::create(&path)?;
+    let requested = std::time::UNIX_EPOCH + std::time::Duration::new(1_000_000_001, 123_456_789);
+    let stored = file
+        .set_modified(requested)
+        .and_then(|()| file.metadata()?.modified());
+    drop(file);
+    let case_insensitive = directory.join(name.to_uppercase()).exists();
+    std::fs::remove_file(&path)?;
+    let stored = stored?;
+    let granularity = (stored != requested).then(|| {
+        let error = stored
+            .duration_since(requested)
+            .or_else(|_| requested.duration_since(stored))
+            .unwrap_or_default();
+        let since_epoch = stored
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        GRANULARITIES
+            .into_iter()
+            .find(|granularity| {
+                error < *granularity && since_epoch.as_nanos() % granularity.as_nanos() == 0
+            })
+            .unwrap_or(error)
+    });
+    Ok(Probe {
+        case_insensitive,
+        granularity,
+    })
+}
+
+/// The longest name and path in bytes which the filesystem of `directory` allows.
+#[cfg(unix)]
+fn limits(directory: &std::path::Path) -> (usize, usize) {
+    use std::os::unix::ffi::OsStrExt;
+
+    let limit = |name, default| {
+        let Ok(path) = std::ffi::CString::new(directory.as_os_str().as_bytes()) else {
+            return default;
+        };
+        // SAFETY: The path is a valid nul terminated string
+        let limit = unsafe { libc::pathconf(path.as_ptr(), name) };
+        usize::try_from(limit)
+            .ok()
+            .filter(|limit| *limit > 0)
+            .unwrap_or(default)
+    };
+    (
+        limit(libc::_PC_NAME_MAX, 255),
+        limit(libc::_PC_PATH_MAX, 4096),
+    )
+}
+
+#[cfg(not(unix))]
+fn limits(_directory: &std::path::Path) -> (usize, usize) {
+    // NOTE: Longer paths need the long path support of Windows, which is off by default
+    (255, 260)
+}
+
+/// Bytes which can still be written to the filesystem of `directory`.
+#[cfg(unix)]
+fn available_space(directory: &std::path::Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(directory.as_os_str().as_bytes()).ok()?;
+    // SAFETY: `statvfs` is plain data which is valid when zeroed
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: The path is a valid nul terminated string and `stat` is a valid pointer
+    if unsafe { libc::statvfs(path.as_ptr(), &raw mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::useless_conversion)]
+    Some(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+}
+
+#[cfg(windows)]
+fn available_space(directory: &std::path::Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    let path: Vec<u16> = directory
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+    let mut available = 0;
+    // SAFETY: The path is a valid nul terminated wide string and the pointer is valid
+    let result = unsafe {
+        windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &raw mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    (result != 0).then_some(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn available_space(_directory: &std::path::Path) -> Option<u64> {
+    None
+}
+
+/// Walks the source like a backup and collects the findings about its entries.
+#[allow(clippy::too_many_lines)]
+fn examine(
+    source_root: &std::path::Path,
+    destination_root: &std::path::Path,
+    existing: &std::path::Path,
+    options: &BackupOptions,
+) -> Result<Vec<Finding>, Error> {
+    let mut findings = vec![];
+    let resolved_source = resolve(source_root);
+    let resolved_destination = resolve(destination_root);
+    if resolved_destination.starts_with(&resolved_source) {
+        findings.push(Finding::DestinationInsideSource);
+    } else if resolved_source.starts_with(&resolved_destination) {
+        findings.push(Finding::SourceInsideDestination);
+    }
+    let probe = match probe(existing) {
+        Ok(probe) => Some(probe),
+        Err(e) => {
+            findings.push(Finding::DestinationNotWritable(e.to_string()));
+            None
+        }
+    };
+    let case_insensitive = probe.as_ref().is_some_and(|probe| probe.case_insensitive);
+    let granularity = probe.and_then(|probe| probe.granularity);
+    let (max_name, max_path) = limits(existing);
+    let max_file_size = options
+        .destination_max_file_size
+        .or_else(|| crate::size_limit::max_file_size(existing));
+    let destination_length = resolved_destination.as_os_str().len();
+
+    let filter = options.filter(&[source_root, destination_root])?;
+    let read_dir = |readdir_type| {
+        RecursiveReadDir::try_new(source_root, readdir_type)
+            .map(|r| r.with_filter(filter.clone()).skipping_special_files(true))
+            .map_err(|e| Error::CannotReadDirectoryContent(source_root.to_owned(), e.to_string()))
+    };
+    let mut names = std::collections::HashMap::new();
+    let mut collisions = Paths::default();
+    let mut too_long = Paths::default();
+    let mut unreadable = Paths::default();
+    let mut too_large = Paths::default();
+    let mut coarse_files = 0;
+    let mut needed: u64 = 0;
+    for (readdir_type, is_dir) in [
+        (ReadDirType::DirectoriesOnly, true),
+        (ReadDirType::FilesOnly, false),
+    ] {
+        for entry in read_dir(readdir_type)? {
+            let path = match entry {
+                Ok(path) => path,
+                Err(e) => {
+                    unreadable.push(e.not_processed.as_deref().unwrap_or(source_root));
+                    continue;
+                }
+            };
+            let relative = path.strip_prefix(source_root).unwrap_or(&path);
+            if case_insensitive {
+                let folded = relative.to_string_lossy().to_lowercase();
+                if let Some(other) = names.insert(folded, relative.to_owned())
+                    && other != relative
+                {
+                    collisions.push(&path);
+                }
+            }
+            let name_length = relative.file_name().map_or(0, std::ffi::OsStr::len);
+            // NOTE: Plus one for the separator between the destination and the relative path
+            if name_length > max_name
+                || destination_length + 1 + relative.as_os_str().len() > max_path
+            {
+                too_long.push(&path);
+            }
+            if is_dir {
+                continue;
+            }
+            let Ok(metadata) = std::fs::File::open(&path).and_then(|file| file.metadata()) else {
+                unreadable.push(&path);
+                continue;
+            };
+            if let Some(granularity) = granularity
+                && let Ok(modified) = metadata.modified()
+                && modified
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .is_ok_and(|since| since.as_nanos() % granularity.as_nanos() != 0)
+            {
+                coarse_files += 1;
+            }
+            if max_file_size.is_some_and(|max_size| metadata.len() > max_size)
+                && options.oversized_files != crate::OversizedFilePolicy::Split
+            {
+                too_large.push(&path);
+                continue;
+            }
+            // NOTE: A file which already has a backup of the same size is not expected to
+            // take more space
+            let backed_up = std::fs::metadata(destination_root.join(relative))
+                .map_or(0, |metadata| metadata.len());
+            needed += metadata.len().saturating_sub(backed_up);
+        }
+    }
+    if collisions.count > 0 {
+        findings.push(Finding::CaseCollisions(collisions));
+    }
+    if let Some(granularity) = granularity
+        && coarse_files > 0
+    {
+        findings.push(Finding::CoarseModificationTimes {
+            granularity,
+            files: coarse_files,
+        });
+    }
+    if too_long.count > 0 {
+        findings.push(Finding::PathsTooLong {
+            max_name,
+            max_path,
+            paths: too_long,
+        });
+    }
+    if let Some(available) = available_space(existing)
+        && needed > available
+    {
+        findings.push(Finding::NotEnoughSpace { needed, available });
+    }
+    if let Some(max_size) = max_file_size
+        && too_large.count > 0
+    {
+        findings.push(Finding::FilesTooLarge {
+            max_size,
+            paths: too_large,
+            skipped: options.oversized_files == crate::OversizedFilePolicy::Skip,
+        });
+    }
+    if unreadable.count > 0 {
+        findings.push(Finding::Unreadable(unreadable));
+    }
+    Ok(findings)
+}
+
+/// Checks the source and the destination of a backup or sync for common problems. An
+/// empty list means that none have been found.
+pub async fn diagnose(
+    source_root: &std::path::Path,
+    destination_root: &std::path::Path,
+    options: &BackupOptions,
+) -> Result<Vec<Finding>, Error> {
+    if !source_root.exists() {
+        return Err(Error::SourceRootPathDoesNotExist(source_root.to_owned()));
+    }
+    if !source_root.is_dir() {
+        return Err(Error::SourceRootIsNotADirectory(source_root.to_owned()));
+    }
+    if destination_root.exists() && !destination_root.is_dir() {
+        return Err(Error::RootDestinatinIsNotADirectory(
+            destination_root.to_owned(),
+        ));
+    }
+    let Some(existing) = destination_root
+        .ancestors()
+        .find(|ancestor| ancestor.is_dir())
+        .map(ToOwned::to_owned)
+    else {
+        return Err(Error::CannotCreateRootDestinationDir(
+            destination_root.to_owned(),
+            "none of its parent directories exists".to_owned(),
+        ));
+    };
+    let (owned_source, owned_destination, owned_options) = (
+        source_root.to_owned(),
+        destination_root.to_owned(),
+        options.clone(),
+    );
+    tokio::task::spawn_blocking(move || {
+        examine(&owned_source, &owned_destination, &existing, &owned_options)
+    })
+    .await
+    .map_err(|e| Error::CannotReadDirectoryContent(source_root.to_owned(), e.to_string()))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_diagnose() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::create_dir(source.path().join("docs")).unwrap();
+        std::fs::write(source.path().join("docs/notes.txt"), b"notes").unwrap();
+        let options = BackupOptions::default();
+        assert_eq!(
+            diagnose(source.path(), destination.path(), &options)
+                .await
+                .unwrap(),
+            vec![]
+        );
+        assert!(
+            std::fs::read_dir(destination.path())
+                .unwrap()
+                .next()
+                .is_none()
+        );
+
+        let nested = source.path().join("backup");
+        assert_eq!(
+            diagnose(source.path(), &nested, &options).await.unwrap(),
+            vec![Finding::DestinationInsideSource]
+        );
+        assert!(!nested.exists());
+        assert_eq!(
+            diagnose(&source.path().join("docs"), source.path(), &options)
+                .await
+                .unwrap(),
+            vec![Finding::SourceInsideDestination]
+        );
+
+        let deep = destination.path().join("deep/".repeat(1000));
+        let findings = diagnose(source.path(), &deep, &options).await.unwrap();
+        assert!(matches!(
+            findings.as_slice(),
+            [Finding::PathsTooLong { paths, .. }] if paths.count == 2
+        ));
+        assert_eq!(findings[0].severity(), Severity::Error);
+
+        let limited = BackupOptions {
+            destination_max_file_size: Some(3),
+            oversized_files: crate::OversizedFilePolicy::Skip,
+            ..BackupOptions::default()
+        };
+        let findings = diagnose(source.path(), destination.path(), &limited)
+            .await
+            .unwrap();
+        assert!(matches!(
+            findings.as_slice(),
+            [Finding::FilesTooLarge { max_size: 3, paths, skipped: true }] if paths.count == 1
+        ));
+        assert_eq!(findings[0].severity(), Severity::Warning);
+
+        assert!(matches!(
+            diagnose(&source.path().join("missing"), destination.path(), &options).await,
+            Err(Error::SourceRootPathDoesNotExist(_))
+        ));
+    }
+
+    #[test]
+    fn test_probe() {
+        let directory = tempfile::tempdir().unwrap();
+        let probe = probe(directory.path()).unwrap();
+        #[cfg(target_os = "linux")]
+        assert!(!probe.case_insensitive);
+        assert!(
+            std::fs::read_dir(directory.path())
+                .unwrap()
+                .next()
+                .is_none()
+        );
+        assert_eq!(format_granularity(std::time::Duration::from_secs(2)), "2 s");
+        assert_eq!(
+            format_granularity(std::time::Duration::from_nanos(100)),
+            "100 ns"
+        );
+    }
+}
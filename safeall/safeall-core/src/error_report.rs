@@ -0,0 +1,319 @@
+//! The files and directories which a backup or sync could not process, written to
+//! `safeall-errors-<timestamp>.json` such that a later run can retry only them instead of
+//! scanning the whole source again.
+
+use crate::{BackupOptions, Command, Destination, Message, Progress, RunId};
+
+const FILE_PREFIX: &str = "safeall-errors-";
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FailedPath {
+    #[serde(with = "encoded_path")]
+    pub path: std::path::PathBuf,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ErrorReport {
+    pub run_id: Option<RunId>,
+    #[serde(with = "encoded_path")]
+    pub source_root: std::path::PathBuf,
+    #[serde(with = "encoded_path")]
+    pub destination_root: std::path::PathBuf,
+    pub failed: Vec<FailedPath>,
+}
+
+/// Paths are written as strings if they are valid Unicode, and otherwise as their raw bytes
+/// (UTF-16 units on Windows) in hex, such that a retry finds names in other encodings.
+mod encoded_path {
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[serde(untagged)]
+    enum EncodedPath {
+        Unicode(String),
+        Raw { raw: String },
+    }
+
+    #[cfg(unix)]
+    fn raw(path: &std::path::Path) -> String {
+        use std::fmt::Write;
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str()
+            .as_bytes()
+            .iter()
+            .fold(String::new(), |mut raw, byte| {
+                let _ = write!(raw, "{byte:02x}");
+                raw
+            })
+    }
+
+    #[cfg(unix)]
+    fn from_raw(raw: &str) -> Option<std::path::PathBuf> {
+        use std::os::unix::ffi::OsStringExt;
+        let bytes = (0..raw.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(raw.get(i..i + 2)?, 16).ok())
+            .collect::<Option<_>>()?;
+        Some(std::ffi::OsString::from_vec(bytes).into())
+    }
+
+    #[cfg(windows)]
+    fn raw(path: &std::path::Path) -> String {
+        use std::fmt::Write;
+        use std::os::windows::ffi::OsStrExt;
+        path.as_os_str()
+            .encode_wide()
+            .fold(String::new(), |mut raw, unit| {
+                let _ = write!(raw, "{unit:04x}");
+                raw
+            })
+    }
+
+    #[cfg(windows)]
+    fn from_raw(raw: &str) -> Option<std::path::PathBuf> {
+        use std::os::windows::ffi::OsStringExt;
+        let units: Vec<_> = (0..raw.len())
+            .step_by(4)
+            .map(|i| u16::from_str_radix(raw.get(i..i + 4)?, 16).ok())
+            .collect::<Option<_>>()?;
+        Some(std::ffi::OsString::from_wide(&units).into())
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn raw(path: &std::path::Path) -> String {
+        path.to_string_lossy().into_owned()
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn from_raw(raw: &str) -> Option<std::path::PathBuf> {
+        Some(raw.into())
+    }
+
+    pub fn serialize<S: serde::Serializer>(
+        path: &std::path::Path,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        use serde::Serialize;
+        match path.to_str() {
+            Some(unicode) => EncodedPath::Unicode(unicode.to_owned()),
+            None => EncodedPath::Raw { raw: raw(path) },
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<std::path::PathBuf, D::Error> {
+        use serde::Deserialize;
+        match EncodedPath::deserialize(deserializer)? {
+            EncodedPath::Unicode(unicode) => Ok(unicode.into()),
+            EncodedPath::Raw { raw } => from_raw(&raw)
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid raw path \"{raw}\""))),
+        }
+    }
+}
+
+impl ErrorReport {
+    #[must_use]
+    pub fn new(source_root: &std::path::Path, destination_root: &std::path::Path) -> Self {
+        Self {
+            run_id: None,
+            source_root: source_root.to_owned(),
+            destination_root: destination_root.to_owned(),
+            failed: vec![],
+        }
+    }
+
+    /// The report of a backup into a directory, of paths or a sync, `None` for other commands.
+    #[must_use]
+    pub fn for_command(command: &Command) -> Option<Self> {
+        match command {
+            Command::Backup {
+                source_root,
+                destination: Destination::Directory(destination_root),
+                ..
+            }
+            | Command::BackupPaths {
+                source_root,
+                destination_root,
+                ..
+            }
+            | Command::Sync {
+                source_root,
+                destination_root,
+                ..
+            } => Some(Self::new(source_root, destination_root)),
+            _ => None,
+        }
+    }
+
+    /// Records the ID of the run and the paths which it failed to process.
+    pub fn record(&mut self, message: &Message) {
+        match message {
+            Message::Info(crate::Info::RunStarted(run_id)) => self.run_id = Some(*run_id),
+            Message::Progress(Progress::IncrementFail(error)) => {
+                if let Some(path) = &error.not_processed {
+                    self.failed.push(FailedPath {
+                        path: path.clone(),
+                        error: error.to_string(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Name of the report of a run which finished at `time`.
+    #[must_use]
+    pub fn file_name(time: std::time::SystemTime) -> String {
+        let local = chrono::DateTime::<chrono::Local>::from(time);
+        format!("{FILE_PREFIX}{}.json", local.format("%Y%m%d-%H%M%S"))
+    }
+
+    /// Writes the report into `directory`, returning its path.
+    pub fn write(&self, directory: &std::path::Path) -> std::io::Result<std::path::PathBuf> {
+        let path = directory.join(Self::file_name(std::time::SystemTime::now()));
+        std::fs::write(&path, self.to_json()?)?;
+        Ok(path)
+    }
+
+    pub fn read(path: &std::path::Path) -> std::io::Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?).map_err(|reason| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} is not an error report: {reason}", path.display()),
+            )
+        })
+    }
+
+    /// The failed paths which [`Self::retry_command`] leaves out as they are not in the source
+    /// root, e.g. files which could not be deleted from the destination.
+    pub fn not_retried(&self) -> impl Iterator<Item = &std::path::Path> {
+        self.failed
+            .iter()
+            .map(|failed| failed.path.as_path())
+            .filter(|path| !path.starts_with(&self.source_root))
+    }
+
+    /// The backup of only the failed paths which are in the source root, see
+    /// [`Self::not_retried`] for the others.
+    #[must_use]
+    pub fn retry_command(&self, options: &BackupOptions) -> Command {
+        let mut paths: Vec<_> = self
+            .failed
+            .iter()
+            .filter(|failed| failed.path.starts_with(&self.source_root))
+            .map(|failed| failed.path.clone())
+            .collect();
+        paths.sort();
+        paths.dedup();
+        Command::BackupPaths {
+            paths,
+            source_root: self.source_root.clone(),
+            destination_root: self.destination_root.clone(),
+            options: options.clone(),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_round_trip() {
+        let report = ErrorReport {
+            run_id: Some(RunId::new()),
+            source_root: "/home/user".into(),
+            destination_root: "/mnt/backup".into(),
+            failed: vec![
+                FailedPath {
+                    path: "/home/user/\"quoted\" \\ name.txt".into(),
+                    error: "Permission denied\n(os error 13)\u{1}".to_owned(),
+                },
+                FailedPath {
+                    path: "/home/user/Ünïcode".into(),
+                    error: String::new(),
+                },
+            ],
+        };
+        let json = report.to_json().unwrap();
+        assert_eq!(ErrorReport::from_json(&json).unwrap(), report);
+
+        let empty = ErrorReport::new("/a".as_ref(), "/b".as_ref());
+        let json = empty.to_json().unwrap();
+        assert_eq!(ErrorReport::from_json(&json).unwrap(), empty);
+
+        assert!(ErrorReport::from_json("{}").is_err());
+        assert!(ErrorReport::from_json(r#"{"failed": [}"#).is_err());
+        assert!(ErrorReport::from_json(&format!("{json} x")).is_err());
+        assert!(
+            ErrorReport::file_name(std::time::SystemTime::now()).starts_with("safeall-errors-")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_json_keeps_non_unicode_paths() {
+        use std::os::unix::ffi::OsStrExt;
+        let mut report = ErrorReport::new("/home/user".as_ref(), "/backup".as_ref());
+        let latin1 = std::path::Path::new(std::ffi::OsStr::from_bytes(b"/home/user/caf\xe9.txt"));
+        report.failed.push(FailedPath {
+            path: latin1.to_owned(),
+            error: String::new(),
+        });
+        let json = report.to_json().unwrap();
+        assert!(json.contains("2f686f6d652f757365722f636166e92e747874"));
+        assert_eq!(
+            ErrorReport::from_json(&json).unwrap().failed[0].path,
+            latin1
+        );
+        assert!(ErrorReport::from_json(&json.replace("2f686f6d65", "2f686f6d6")).is_err());
+    }
+
+    #[test]
+    fn test_retry_command() {
+        let mut report = ErrorReport::new("/home/user".as_ref(), "/backup".as_ref());
+        for path in [
+            "/home/user/notes.txt",
+            "/home/user/docs",
+            "/elsewhere.txt",
+            "/home/user/notes.txt",
+        ] {
+            report.failed.push(FailedPath {
+                path: path.into(),
+                error: String::new(),
+            });
+        }
+
+        let Command::BackupPaths {
+            paths,
+            source_root,
+            destination_root,
+            ..
+        } = report.retry_command(&BackupOptions::default())
+        else {
+            panic!("The failed paths are backed up");
+        };
+        assert_eq!(
+            paths,
+            [
+                std::path::Path::new("/home/user/docs"),
+                std::path::Path::new("/home/user/notes.txt")
+            ]
+        );
+        assert_eq!(source_root, report.source_root);
+        assert_eq!(destination_root, report.destination_root);
+        assert_eq!(
+            report.not_retried().collect::<Vec<_>>(),
+            [std::path::Path::new("/elsewhere.txt")]
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! Windows file attributes (readonly, hidden, system and archive), which have no equivalent in
+//! the permissions copied on other platforms.
+
+#[cfg(windows)]
+use windows_sys::Win32::Storage::FileSystem::{
+    FILE_ATTRIBUTE_ARCHIVE, FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_NORMAL, FILE_ATTRIBUTE_READONLY,
+    FILE_ATTRIBUTE_SYSTEM,
+};
+
+#[cfg(windows)]
+const COPIED_ATTRIBUTES: u32 = FILE_ATTRIBUTE_READONLY
+    | FILE_ATTRIBUTE_HIDDEN
+    | FILE_ATTRIBUTE_SYSTEM
+    | FILE_ATTRIBUTE_ARCHIVE;
+
+/// Sets the attributes of `source` on `destination`. Must happen after everything else which
+/// writes to `destination`, since it may become readonly.
+#[cfg(windows)]
+pub fn copy(source: &std::path::Path, destination: &std::path::Path) -> std::io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::fs::MetadataExt;
+
+    let source_attributes = std::fs::metadata(source)?.file_attributes() & COPIED_ATTRIBUTES;
+    let destination_attributes = std::fs::metadata(destination)?.file_attributes();
+    let mut attributes =
+        destination_attributes & !(COPIED_ATTRIBUTES | FILE_ATTRIBUTE_NORMAL) | source_attributes;
+    // NOTE: `FILE_ATTRIBUTE_NORMAL` is only valid on its own
+    if attributes == 0 {
+        attributes = FILE_ATTRIBUTE_NORMAL;
+    }
+    if attributes == destination_attributes {
+        return Ok(());
+    }
+    let path: Vec<u16> = destination
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+    // SAFETY: The path is a valid nul terminated wide string
+    let result = unsafe {
+        windows_sys::Win32::Storage::FileSystem::SetFileAttributesW(path.as_ptr(), attributes)
+    };
+    if result == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+#[allow(clippy::unnecessary_wraps)]
+pub fn copy(_source: &std::path::Path, _destination: &std::path::Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Clears the readonly attribute of an existing `destination` such that it can be overwritten.
+#[cfg(windows)]
+pub fn make_writable(destination: &std::path::Path) -> std::io::Result<()> {
+    let Ok(metadata) = std::fs::metadata(destination) else {
+        return Ok(());
+    };
+    let mut permissions = metadata.permissions();
+    if permissions.readonly() {
+        // NOTE: On Windows this only clears the readonly attribute
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        std::fs::set_permissions(destination, permissions)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+#[allow(clippy::unnecessary_wraps)]
+pub fn make_writable(_destination: &std::path::Path) -> std::io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,88 @@
+//! Categories of files by their extension, to show what a run has copied and skipped.
+
+const DOCUMENTS: [&str; 16] = [
+    "pdf", "doc", "docx", "odt", "rtf", "txt", "md", "xls", "xlsx", "ods", "csv", "ppt", "pptx",
+    "odp", "epub", "tex",
+];
+const PHOTOS: [&str; 14] = [
+    "jpg", "jpeg", "png", "gif", "bmp", "tif", "tiff", "webp", "heic", "heif", "raw", "cr2", "nef",
+    "dng",
+];
+const VIDEOS: [&str; 10] = [
+    "mp4", "mkv", "mov", "avi", "wmv", "webm", "m4v", "mpg", "mpeg", "3gp",
+];
+const CODE: [&str; 26] = [
+    "rs", "c", "h", "cpp", "hpp", "cc", "py", "js", "ts", "jsx", "tsx", "java", "kt", "go", "rb",
+    "php", "cs", "swift", "sh", "html", "css", "json", "toml", "yaml", "yml", "sql",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FileCategory {
+    Documents,
+    Photos,
+    Videos,
+    Code,
+    Other,
+}
+
+impl FileCategory {
+    /// The category of a file by its extension, ignoring case.
+    #[must_use]
+    pub fn of(path: &std::path::Path) -> Self {
+        let Some(extension) = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+        else {
+            return FileCategory::Other;
+        };
+        let extension = extension.as_str();
+        if DOCUMENTS.contains(&extension) {
+            FileCategory::Documents
+        } else if PHOTOS.contains(&extension) {
+            FileCategory::Photos
+        } else if VIDEOS.contains(&extension) {
+            FileCategory::Videos
+        } else if CODE.contains(&extension) {
+            FileCategory::Code
+        } else {
+            FileCategory::Other
+        }
+    }
+}
+
+impl std::fmt::Display for FileCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileCategory::Documents => write!(f, "documents"),
+            FileCategory::Photos => write!(f, "photos"),
+            FileCategory::Videos => write!(f, "videos"),
+            FileCategory::Code => write!(f, "code"),
+            FileCategory::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// Files and bytes of one category which have been copied and skipped as unchanged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CategoryStats {
+    pub files_copied: u64,
+    pub bytes_copied: u64,
+    pub files_skipped: u64,
+    pub bytes_skipped: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_category() {
+        let category = |path: &str| FileCategory::of(std::path::Path::new(path));
+        assert_eq!(category("report.PDF"), FileCategory::Documents);
+        assert_eq!(category("holiday/IMG_0001.jpeg"), FileCategory::Photos);
+        assert_eq!(category("clip.mkv"), FileCategory::Videos);
+        assert_eq!(category("src/main.rs"), FileCategory::Code);
+        assert_eq!(category("archive.tar.gz"), FileCategory::Other);
+        assert_eq!(category("Makefile"), FileCategory::Other);
+    }
+}
@@ -0,0 +1,567 @@
+//! Include and exclude glob patterns which decide what the traversal yields.
+//!
+//! Patterns without a `/` match the name of a file or directory at any depth, all other
+//! patterns match the path relative to the root of the traversal. Additionally
+//! `.safeallignore` files with gitignore syntax exclude paths in their directory.
+//!
+//! Caches can be excluded as well: directories marked with a `CACHEDIR.TAG` (see
+//! <https://bford.info/cachedir/>) and the usual cache and build directories of
+//! [`CACHE_DIRECTORIES`].
+
+pub const IGNORE_FILE: &str = ".safeallignore";
+
+/// Files of safeall itself which are never backed up, whatever the include patterns say:
+/// the metadata of other destinations (lock, history, quarantine) and partial copies.
+pub const ALWAYS_EXCLUDED: [&str; 3] = [".safeall/", "*.safeall-partial", ".*.safeall-clone"];
+
+/// Directories of package managers, compilers and tools which can be recreated, excluded
+/// together with tagged caches.
+pub const CACHE_DIRECTORIES: [&str; 13] = [
+    ".cache/",
+    "node_modules/",
+    "target/",
+    "__pycache__/",
+    ".pytest_cache/",
+    ".mypy_cache/",
+    ".ruff_cache/",
+    ".tox/",
+    ".gradle/",
+    ".next/",
+    ".parcel-cache/",
+    ".sass-cache/",
+    ".terraform/",
+];
+
+const CACHE_TAG: &str = "CACHEDIR.TAG";
+/// A `CACHEDIR.TAG` only marks a cache if it starts with this.
+const CACHE_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Rule of a library consumer which decides whether an entry is backed up, e.g. to skip
+/// files owned by another tenant.
+///
+/// It is called during the traversal of the source and of the destination, with the
+/// absolute path of every file and directory which passed the patterns. Rejected directories
+/// are not descended into. As with the patterns, files rejected in the destination are not
+/// deleted by a sync.
+pub trait PathFilter: std::fmt::Debug + Send + Sync {
+    fn accept(&self, path: &std::path::Path, metadata: &std::fs::Metadata) -> bool;
+
+    /// Absolute paths which the traversal starts from instead of the root, such that the rest
+    /// of the root is never read. The directories leading to them are yielded as well. `None`,
+    /// the default, traverses the whole root.
+    fn only_paths(&self) -> Option<&[std::path::PathBuf]> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    include: Option<globset::GlobSet>,
+    exclude: Option<globset::GlobSet>,
+    /// The patterns of [`CACHE_DIRECTORIES`] if caches are excluded.
+    caches: Option<globset::GlobSet>,
+    ignore_files: bool,
+    excluded_paths: Vec<std::path::PathBuf>,
+    same_filesystem: bool,
+    max_depth: Option<usize>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_after: Option<std::time::SystemTime>,
+    modified_before: Option<std::time::SystemTime>,
+    custom: Option<std::sync::Arc<dyn PathFilter>>,
+    keep_directory_symlinks: bool,
+    walk_order: crate::WalkOrder,
+}
+
+/// Exclude pattern which matches exactly the file or directory at `relative_path` from the
+/// root, e.g. to leave out a single file which keeps failing.
+#[must_use]
+pub fn exclude_pattern(relative_path: &std::path::Path) -> String {
+    let components: Vec<_> = relative_path
+        .components()
+        .filter_map(|component| match component {
+            std::path::Component::Normal(name) => Some(globset::escape(&name.to_string_lossy())),
+            _ => None,
+        })
+        .collect();
+    // NOTE: The leading separator anchors the pattern at the root
+    format!("/{}", components.join("/"))
+}
+
+/// Starts the traversal from the given paths instead of the root. Everything in them is
+/// accepted unless the rule of the application rejects it.
+#[derive(Debug)]
+pub struct ExplicitPaths {
+    paths: Vec<std::path::PathBuf>,
+    inner: Option<std::sync::Arc<dyn PathFilter>>,
+}
+
+impl ExplicitPaths {
+    /// The `paths` relative to `root` or absolute. Paths inside another one are dropped, such
+    /// that nothing is visited twice.
+    pub fn new(
+        root: &std::path::Path,
+        paths: &[std::path::PathBuf],
+        inner: Option<std::sync::Arc<dyn PathFilter>>,
+    ) -> Self {
+        let mut paths: Vec<_> = paths.iter().map(|path| root.join(path)).collect();
+        // NOTE: Sorted, a path comes right after the paths it is in
+        paths.sort();
+        let mut outermost: Vec<std::path::PathBuf> = vec![];
+        for path in paths {
+            if outermost.last().is_none_or(|last| !path.starts_with(last)) {
+                outermost.push(path);
+            }
+        }
+        Self {
+            paths: outermost,
+            inner,
+        }
+    }
+}
+
+impl PathFilter for ExplicitPaths {
+    fn accept(&self, path: &std::path::Path, metadata: &std::fs::Metadata) -> bool {
+        self.inner
+            .as_ref()
+            .is_none_or(|inner| inner.accept(path, metadata))
+    }
+
+    fn only_paths(&self) -> Option<&[std::path::PathBuf]> {
+        Some(&self.paths)
+    }
+}
+
+fn build(patterns: &[String]) -> Result<Option<globset::GlobSet>, globset::Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = globset::GlobSetBuilder::new();
+    for pattern in patterns {
+        let trimmed = pattern.trim_end_matches('/');
+        let glob = if trimmed.contains('/') {
+            trimmed.trim_start_matches('/').to_owned()
+        } else {
+            format!("**/{trimmed}")
+        };
+        builder.add(
+            globset::GlobBuilder::new(&glob)
+                .literal_separator(true)
+                .build()?,
+        );
+    }
+    builder.build().map(Some)
+}
+
+impl Filter {
+    pub fn try_new(
+        include: &[String],
+        exclude: &[String],
+        ignore_files: bool,
+    ) -> Result<Self, globset::Error> {
+        let exclude: Vec<_> = exclude
+            .iter()
+            .cloned()
+            .chain(ALWAYS_EXCLUDED.map(ToOwned::to_owned))
+            .collect();
+        Ok(Self {
+            include: build(include)?,
+            exclude: build(&exclude)?,
+            caches: None,
+            ignore_files,
+            excluded_paths: vec![],
+            same_filesystem: false,
+            max_depth: None,
+            min_size: None,
+            max_size: None,
+            modified_after: None,
+            modified_before: None,
+            custom: None,
+            keep_directory_symlinks: false,
+            walk_order: crate::WalkOrder::default(),
+        })
+    }
+
+    /// Never yields `path` or anything below it, e.g. the destination when it is inside the
+    /// source. Paths which do not exist are ignored.
+    #[must_use]
+    pub fn excluding(mut self, path: &std::path::Path) -> Self {
+        if let Ok(path) = path.canonicalize() {
+            self.excluded_paths.push(path);
+        }
+        self
+    }
+
+    /// Only paths with the same name as an excluded path are canonicalized.
+    pub fn excludes_path(&self, path: &std::path::Path) -> bool {
+        self.excluded_paths.iter().any(|excluded| {
+            excluded.file_name() == path.file_name()
+                && path.canonicalize().is_ok_and(|path| &path == excluded)
+        })
+    }
+
+    /// Does not descend into directories with a `CACHEDIR.TAG` and into the directories of
+    /// [`CACHE_DIRECTORIES`].
+    #[must_use]
+    pub fn excluding_caches(mut self, exclude_caches: bool) -> Self {
+        self.caches = exclude_caches.then(|| {
+            build(&CACHE_DIRECTORIES.map(ToOwned::to_owned))
+                .expect("The cache patterns are valid")
+                .expect("There are cache patterns")
+        });
+        self
+    }
+
+    /// Whether caches are excluded and `directory` has a valid `CACHEDIR.TAG`. The tag itself
+    /// is not excluded when the directory is backed up anyway, e.g. as the root.
+    pub fn is_tagged_cache(&self, directory: &std::path::Path) -> bool {
+        if self.caches.is_none() {
+            return false;
+        }
+        let Ok(mut tag) = std::fs::File::open(directory.join(CACHE_TAG)) else {
+            return false;
+        };
+        let mut signature = [0; CACHE_TAG_SIGNATURE.len()];
+        std::io::Read::read_exact(&mut tag, &mut signature).is_ok()
+            && signature == CACHE_TAG_SIGNATURE
+    }
+
+    /// Does not descend into directories on another filesystem than the root of the
+    /// traversal, like `/proc` or network drives mounted into the source. Only on Unix.
+    #[must_use]
+    pub fn on_same_filesystem(mut self, same_filesystem: bool) -> Self {
+        self.same_filesystem = same_filesystem;
+        self
+    }
+
+    pub fn stays_on_same_filesystem(&self) -> bool {
+        self.same_filesystem
+    }
+
+    /// Only descends this many levels below the root of the traversal, where 1 yields only
+    /// the entries of the root itself.
+    #[must_use]
+    pub fn with_max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    /// Only accepts files with at least `min_size` and at most `max_size` bytes.
+    #[must_use]
+    pub fn with_size_limits(mut self, min_size: Option<u64>, max_size: Option<u64>) -> Self {
+        self.min_size = min_size;
+        self.max_size = max_size;
+        self
+    }
+
+    /// Only accepts files last modified at or after `after` and before `before`.
+    #[must_use]
+    pub fn with_modified_range(
+        mut self,
+        after: Option<std::time::SystemTime>,
+        before: Option<std::time::SystemTime>,
+    ) -> Self {
+        self.modified_after = after;
+        self.modified_before = before;
+        self
+    }
+
+    /// Yields symbolic links to directories like files instead of descending into them, such
+    /// that they can be backed up as links.
+    #[must_use]
+    pub fn keeping_directory_symlinks(mut self, keep: bool) -> Self {
+        self.keep_directory_symlinks = keep;
+        self
+    }
+
+    pub fn keeps_directory_symlinks(&self) -> bool {
+        self.keep_directory_symlinks
+    }
+
+    /// Visits the directories in this order.
+    #[must_use]
+    pub fn with_walk_order(mut self, walk_order: crate::WalkOrder) -> Self {
+        self.walk_order = walk_order;
+        self
+    }
+
+    pub fn walk_order(&self) -> crate::WalkOrder {
+        self.walk_order
+    }
+
+    /// Additionally asks `path_filter` about every entry.
+    #[must_use]
+    pub fn with_path_filter(mut self, path_filter: Option<std::sync::Arc<dyn PathFilter>>) -> Self {
+        self.custom = path_filter;
+        self
+    }
+
+    /// The paths the traversal starts from instead of the root, see [`PathFilter::only_paths`].
+    pub fn only_paths(&self) -> Option<&[std::path::PathBuf]> {
+        self.custom.as_ref()?.only_paths()
+    }
+
+    /// Checks the size and modification time of a file and asks the path filter. Entries
+    /// whose metadata cannot be read are accepted such that the error is reported when they
+    /// are copied.
+    pub fn accepts_metadata(&self, path: &std::path::Path, is_dir: bool) -> bool {
+        let has_limits = !is_dir
+            && (self.min_size.is_some()
+                || self.max_size.is_some()
+                || self.modified_after.is_some()
+                || self.modified_before.is_some());
+        if !has_limits && self.custom.is_none() {
+            return true;
+        }
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return true;
+        };
+        (!has_limits || self.within_limits(&metadata))
+            && self
+                .custom
+                .as_ref()
+                .is_none_or(|path_filter| path_filter.accept(path, &metadata))
+    }
+
+    fn within_limits(&self, metadata: &std::fs::Metadata) -> bool {
+        let modified = metadata.modified().ok();
+        self.min_size.is_none_or(|min| metadata.len() >= min)
+            && self.max_size.is_none_or(|max| metadata.len() <= max)
+            && self
+                .modified_after
+                .is_none_or(|after| modified.is_none_or(|modified| modified >= after))
+            && self
+                .modified_before
+                .is_none_or(|before| modified.is_none_or(|modified| modified < before))
+    }
+
+    pub fn uses_ignore_files(&self) -> bool {
+        self.ignore_files
+    }
+
+    fn is_excluded(&self, relative_path: &std::path::Path) -> bool {
+        self.exclude
+            .as_ref()
+            .is_some_and(|exclude| exclude.is_match(relative_path))
+    }
+
+    /// Excluded directories are not descended into.
+    pub fn accepts_directory(&self, relative_path: &std::path::Path) -> bool {
+        !self.is_excluded(relative_path)
+            && self
+                .caches
+                .as_ref()
+                .is_none_or(|caches| !caches.is_match(relative_path))
+    }
+
+    /// If there are include patterns only files matching one of them are accepted. Exclude
+    /// patterns take precedence.
+    pub fn accepts_file(&self, relative_path: &std::path::Path) -> bool {
+        !self.is_excluded(relative_path)
+            && self
+                .include
+                .as_ref()
+                .is_none_or(|include| include.is_match(relative_path))
+    }
+}
+
+/// The `.safeallignore` files found so far during a traversal.
+#[derive(Debug, Default)]
+pub struct IgnoreFiles {
+    matchers: Vec<ignore::gitignore::Gitignore>,
+}
+
+impl IgnoreFiles {
+    /// Loads the ignore file of `directory` if there is one. Invalid lines are skipped.
+    pub fn load(&mut self, directory: &std::path::Path) {
+        let path = directory.join(IGNORE_FILE);
+        if !path.is_file() {
+            return;
+        }
+        let (matcher, _invalid_lines) = ignore::gitignore::Gitignore::new(path);
+        if !matcher.is_empty() {
+            self.matchers.push(matcher);
+        }
+    }
+
+    /// Rules of deeper directories take precedence, like in git.
+    pub fn is_ignored(&self, path: &std::path::Path, is_dir: bool) -> bool {
+        let mut matchers: Vec<_> = self
+            .matchers
+            .iter()
+            .filter(|matcher| path.starts_with(matcher.path()))
+            .collect();
+        matchers.sort_by_key(|matcher| std::cmp::Reverse(matcher.path().components().count()));
+        matchers
+            .into_iter()
+            .map(|matcher| matcher.matched(path, is_dir))
+            .find(|matched| !matched.is_none())
+            .is_some_and(|matched| matched.is_ignore())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_size_limits() {
+        let directory = tempfile::tempdir().unwrap();
+        let small = directory.path().join("small");
+        let large = directory.path().join("large");
+        std::fs::write(&small, [0; 10]).unwrap();
+        std::fs::write(&large, [0; 1000]).unwrap();
+
+        let filter = Filter::default().with_size_limits(Some(100), None);
+        assert!(!filter.accepts_metadata(&small, false));
+        assert!(filter.accepts_metadata(&large, false));
+        let filter = Filter::default().with_size_limits(None, Some(100));
+        assert!(filter.accepts_metadata(&small, false));
+        assert!(!filter.accepts_metadata(&large, false));
+        assert!(filter.accepts_metadata(&directory.path().join("missing"), false));
+    }
+
+    #[test]
+    fn test_filter_modified_range() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("file");
+        std::fs::write(&path, b"content").unwrap();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let hour = std::time::Duration::from_hours(1);
+
+        let accepts = |after, before| {
+            Filter::default()
+                .with_modified_range(after, before)
+                .accepts_metadata(&path, false)
+        };
+        assert!(accepts(Some(modified - hour), None));
+        assert!(!accepts(Some(modified + hour), None));
+        assert!(accepts(None, Some(modified + hour)));
+        assert!(!accepts(None, Some(modified)));
+        assert!(accepts(Some(modified), Some(modified + hour)));
+    }
+
+    #[test]
+    fn test_exclude_pattern() {
+        let path = std::path::Path::new;
+        let filter = |relative_path| {
+            Filter::try_new(&[], &[exclude_pattern(path(relative_path))], false).unwrap()
+        };
+        assert!(!filter("notes [old].txt").accepts_file(path("notes [old].txt")));
+        assert!(filter("notes [old].txt").accepts_file(path("docs/notes [old].txt")));
+        assert!(filter("notes [old].txt").accepts_file(path("notes o.txt")));
+        assert!(!filter("docs/*.txt").accepts_file(path("docs/*.txt")));
+        assert!(filter("docs/*.txt").accepts_file(path("docs/a.txt")));
+        assert!(!filter("docs").accepts_directory(path("docs")));
+    }
+
+    #[derive(Debug)]
+    struct NoEmptyFiles;
+
+    impl PathFilter for NoEmptyFiles {
+        fn accept(&self, _path: &std::path::Path, metadata: &std::fs::Metadata) -> bool {
+            metadata.is_dir() || metadata.len() > 0
+        }
+    }
+
+    #[test]
+    fn test_path_filter() {
+        let directory = tempfile::tempdir().unwrap();
+        let empty = directory.path().join("empty");
+        let full = directory.path().join("full");
+        std::fs::write(&empty, b"").unwrap();
+        std::fs::write(&full, b"content").unwrap();
+
+        let filter = Filter::default().with_path_filter(Some(std::sync::Arc::new(NoEmptyFiles)));
+        assert!(!filter.accepts_metadata(&empty, false));
+        assert!(filter.accepts_metadata(&full, false));
+        assert!(filter.accepts_metadata(directory.path(), true));
+        assert!(Filter::default().accepts_metadata(&empty, false));
+    }
+
+    #[test]
+    fn test_include_and_exclude_patterns() {
+        let patterns = |p: &[&str]| p.iter().map(ToString::to_string).collect::<Vec<_>>();
+        let filter = Filter::try_new(
+            &patterns(&["*.txt", "docs/**"]),
+            &patterns(&["node_modules/", "secret*", "/build"]),
+            false,
+        )
+        .unwrap();
+        let path = std::path::Path::new;
+
+        assert!(filter.accepts_file(path("a.txt")));
+        assert!(filter.accepts_file(path("deep/down/a.txt")));
+        assert!(filter.accepts_file(path("docs/manual.pdf")));
+        assert!(!filter.accepts_file(path("a.rs")));
+        assert!(!filter.accepts_file(path("secret.txt")));
+        assert!(!filter.accepts_file(path("deep/secret.txt")));
+
+        assert!(filter.accepts_directory(path("src")));
+        assert!(!filter.accepts_directory(path("node_modules")));
+        assert!(!filter.accepts_directory(path("web/node_modules")));
+        assert!(!filter.accepts_directory(path("build")));
+        assert!(filter.accepts_directory(path("web/build")));
+
+        assert!(!filter.accepts_directory(path("backup/.safeall")));
+        assert!(!filter.accepts_file(path("docs/a.txt.safeall-partial")));
+
+        let everything = Filter::default();
+        assert!(everything.accepts_file(path("a.rs")));
+        assert!(Filter::try_new(&patterns(&["a[b"]), &[], false).is_err());
+    }
+
+    #[test]
+    fn test_exclude_caches() {
+        let root = tempfile::tempdir().unwrap();
+        let tagged = root.path().join("thumbnails");
+        let untagged = root.path().join("photos");
+        std::fs::create_dir(&tagged).unwrap();
+        std::fs::create_dir(&untagged).unwrap();
+        std::fs::write(
+            tagged.join(CACHE_TAG),
+            [CACHE_TAG_SIGNATURE, b"\n# Created by a viewer\n"].concat(),
+        )
+        .unwrap();
+        std::fs::write(untagged.join(CACHE_TAG), b"Not a signature").unwrap();
+        let path = std::path::Path::new;
+
+        let filter = Filter::default();
+        assert!(!filter.is_tagged_cache(&tagged));
+        assert!(filter.accepts_directory(path("web/node_modules")));
+
+        let filter = filter.excluding_caches(true);
+        assert!(filter.is_tagged_cache(&tagged));
+        assert!(!filter.is_tagged_cache(&untagged));
+        assert!(!filter.is_tagged_cache(root.path()));
+        assert!(!filter.accepts_directory(path("web/node_modules")));
+        assert!(!filter.accepts_directory(path("project/target")));
+        assert!(!filter.accepts_directory(path("__pycache__")));
+        assert!(filter.accepts_directory(path("photos")));
+        assert!(filter.accepts_file(path("src/target.rs")));
+    }
+
+    #[test]
+    fn test_nested_ignore_files() {
+        let root = tempfile::tempdir().unwrap();
+        let nested = root.path().join("project");
+        std::fs::create_dir(&nested).unwrap();
+        std::fs::write(root.path().join(IGNORE_FILE), "*.log\nbuild/\n").unwrap();
+        std::fs::write(nested.join(IGNORE_FILE), "!important.log\n").unwrap();
+
+        let mut ignore_files = IgnoreFiles::default();
+        ignore_files.load(root.path());
+        ignore_files.load(&nested);
+
+        assert!(ignore_files.is_ignored(&root.path().join("debug.log"), false));
+        assert!(ignore_files.is_ignored(&nested.join("debug.log"), false));
+        assert!(!ignore_files.is_ignored(&nested.join("important.log"), false));
+        assert!(ignore_files.is_ignored(&nested.join("build"), true));
+        assert!(!ignore_files.is_ignored(&nested.join("build"), false));
+        assert!(!ignore_files.is_ignored(&nested.join("main.rs"), false));
+    }
+}
@@ -0,0 +1,335 @@
+//! Reduces parallelism and bandwidth while the machine runs on battery or is under thermal pressure.
+
+/// Maximum bytes per second copied while throttled.
+const THROTTLED_BANDWIDTH: u64 = 16 * 1024 * 1024;
+const PROBE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PowerState {
+    pub on_battery: bool,
+    pub thermal_pressure: bool,
+}
+
+impl PowerState {
+    fn throttle(self) -> bool {
+        self.on_battery || self.thermal_pressure
+    }
+}
+
+#[derive(Debug)]
+pub struct Governor {
+    enabled: bool,
+    parallelism: u32,
+    permits: tokio::sync::Semaphore,
+    last_probe: std::sync::Mutex<Option<(std::time::Instant, PowerState)>>,
+    probe: fn() -> PowerState,
+}
+
+/// Held while a file is processed, blocks other files when the backup is throttled.
+pub struct Permit<'a> {
+    _semaphore: tokio::sync::SemaphorePermit<'a>,
+    throttled: bool,
+    written: std::sync::atomic::AtomicU64,
+}
+
+/// Forwards every message and counts the bytes of the files which have been copied.
+pub struct Metered<'a, S> {
+    written: &'a std::sync::atomic::AtomicU64,
+    message_sender: &'a S,
+}
+
+impl Governor {
+    pub fn new(enabled: bool, parallelism: usize) -> Self {
+        Self::with_probe(enabled, parallelism, probe)
+    }
+
+    fn with_probe(enabled: bool, parallelism: usize, probe: fn() -> PowerState) -> Self {
+        let parallelism = u32::try_from(parallelism.max(1)).unwrap_or(u32::MAX);
+        Self {
+            enabled,
+            parallelism,
+            permits: tokio::sync::Semaphore::new(parallelism as usize),
+            last_probe: std::sync::Mutex::new(None),
+            probe,
+        }
+    }
+
+    /// Waits until the file may be processed. Sends a message whenever throttling starts or stops.
+    pub async fn acquire(&self, message_sender: &impl crate::MessageSender) -> Permit<'_> {
+        let throttled = self.enabled && self.power_state(message_sender).await.throttle();
+        // NOTE: Taking all permits while throttled means only one file is processed at a time
+        let weight = if throttled { self.parallelism } else { 1 };
+        let permit = self
+            .permits
+            .acquire_many(weight)
+            .await
+            .expect("The semaphore is never closed");
+        Permit {
+            _semaphore: permit,
+            throttled,
+            written: std::sync::atomic::AtomicU64::new(0),
+        }
+    }
+
+    async fn power_state(&self, message_sender: &impl crate::MessageSender) -> PowerState {
+        let previous = *self.last_probe.lock().expect("Lock is never poisoned");
+        if let Some((probed_at, state)) = previous
+            && probed_at.elapsed() < PROBE_INTERVAL
+        {
+            return state;
+        }
+        // NOTE: Store the old state first such that concurrent callers do not probe too
+        let old_state = previous.map(|(_, state)| state).unwrap_or_default();
+        *self.last_probe.lock().expect("Lock is never poisoned") =
+            Some((std::time::Instant::now(), old_state));
+
+        let state = tokio::task::spawn_blocking(self.probe)
+            .await
+            .unwrap_or_default();
+        *self.last_probe.lock().expect("Lock is never poisoned") =
+            Some((std::time::Instant::now(), state));
+        if state.throttle() && state != old_state {
+            message_sender.send(crate::Message::Info(crate::Info::ThrottlingStarted(state)));
+        } else if !state.throttle() && old_state.throttle() {
+            message_sender.send(crate::Message::Info(crate::Info::ThrottlingStopped));
+        }
+        state
+    }
+}
+
+impl Permit<'_> {
+    /// Sender through which the file is processed, such that only copied bytes are throttled.
+    pub fn meter<'a, S: crate::MessageSender>(&'a self, message_sender: &'a S) -> Metered<'a, S> {
+        Metered {
+            written: &self.written,
+            message_sender,
+        }
+    }
+
+    /// Keeps the permit long enough to stay below the throttled bandwidth for the bytes written.
+    pub async fn limit_bandwidth(&self) {
+        if !self.throttled {
+            return;
+        }
+        let written = self.written.load(std::sync::atomic::Ordering::Relaxed);
+        tokio::time::sleep(throttled_duration(written)).await;
+    }
+}
+
+impl<S: crate::MessageSender> crate::MessageSender for Metered<'_, S> {
+    fn send(&self, message: crate::Message) {
+        if let crate::Message::Progress(crate::Progress::IncrementSuccess(
+            crate::Increment::FileCopied { bytes, .. },
+        )) = &message
+        {
+            self.written
+                .fetch_add(*bytes, std::sync::atomic::Ordering::Relaxed);
+        }
+        self.message_sender.send(message);
+    }
+
+    fn compared(&self, duration: std::time::Duration) {
+        self.message_sender.compared(duration);
+    }
+}
+
+/// Time it takes to write the bytes at the throttled bandwidth.
+fn throttled_duration(bytes: u64) -> std::time::Duration {
+    #[allow(clippy::cast_precision_loss)]
+    let seconds = bytes as f64 / THROTTLED_BANDWIDTH as f64;
+    std::time::Duration::from_secs_f64(seconds)
+}
+
+#[cfg(target_os = "linux")]
+fn probe() -> PowerState {
+    fn read(path: &std::path::Path) -> Option<String> {
+        std::fs::read_to_string(path)
+            .ok()
+            .map(|s| s.trim().to_owned())
+    }
+
+    let on_battery = std::fs::read_dir("/sys/class/power_supply")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .any(|supply| {
+            let path = supply.path();
+            read(&path.join("type")).as_deref() == Some("Battery")
+                && read(&path.join("status")).as_deref() == Some("Discharging")
+        });
+
+    let thermal_pressure = std::fs::read_dir("/sys/class/thermal")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|zone| {
+            zone.file_name()
+                .to_string_lossy()
+                .starts_with("thermal_zone")
+        })
+        .any(|zone| {
+            let path = zone.path();
+            let Some(temperature) = read(&path.join("temp")).and_then(|t| t.parse::<i64>().ok())
+            else {
+                return false;
+            };
+            (0..16)
+                .filter(|i| {
+                    matches!(
+                        read(&path.join(format!("trip_point_{i}_type"))).as_deref(),
+                        Some("passive" | "hot")
+                    )
+                })
+                .filter_map(|i| read(&path.join(format!("trip_point_{i}_temp"))))
+                .filter_map(|t| t.parse::<i64>().ok())
+                .any(|trip| trip > 0 && temperature >= trip)
+        });
+
+    PowerState {
+        on_battery,
+        thermal_pressure,
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn probe() -> PowerState {
+    fn pmset(argument: &str) -> String {
+        std::process::Command::new("pmset")
+            .args(["-g", argument])
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+            .unwrap_or_default()
+    }
+
+    let on_battery = pmset("batt").contains("'Battery Power'");
+    let thermal_pressure = pmset("therm").lines().any(|line| {
+        line.split_once('=').is_some_and(|(key, value)| {
+            key.trim() == "CPU_Speed_Limit" && value.trim().parse::<u32>().is_ok_and(|l| l < 100)
+        })
+    });
+
+    PowerState {
+        on_battery,
+        thermal_pressure,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn probe() -> PowerState {
+    PowerState::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn on_battery() -> PowerState {
+        PowerState {
+            on_battery: true,
+            thermal_pressure: false,
+        }
+    }
+
+    fn hot() -> PowerState {
+        PowerState {
+            on_battery: false,
+            thermal_pressure: true,
+        }
+    }
+
+    fn copied(bytes: u64) -> crate::Message {
+        crate::Message::Progress(crate::Progress::IncrementSuccess(
+            crate::Increment::FileCopied {
+                source: "source".into(),
+                destination: "destination".into(),
+                bytes,
+                reason: crate::comparator::CopyReason::NewFile,
+            },
+        ))
+    }
+
+    #[test]
+    fn test_throttle() {
+        assert!(!PowerState::default().throttle());
+        assert!(on_battery().throttle());
+        assert!(hot().throttle());
+    }
+
+    #[test]
+    fn test_throttled_duration() {
+        assert_eq!(throttled_duration(0), std::time::Duration::ZERO);
+        assert_eq!(
+            throttled_duration(THROTTLED_BANDWIDTH),
+            std::time::Duration::from_secs(1)
+        );
+        assert_eq!(
+            throttled_duration(THROTTLED_BANDWIDTH / 4),
+            std::time::Duration::from_millis(250)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_throttled_takes_all_permits() {
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let governor = Governor::with_probe(true, 4, hot);
+
+        let permit = governor.acquire(&message_sender).await;
+        assert!(permit.throttled);
+        assert_eq!(governor.permits.available_permits(), 0);
+        assert!(matches!(
+            message_receiver.try_recv(),
+            Ok(crate::Message::Info(crate::Info::ThrottlingStarted(state))) if state == hot()
+        ));
+        drop(permit);
+        assert_eq!(governor.permits.available_permits(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_does_not_probe() {
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let governor = Governor::with_probe(false, 4, || panic!("Must not probe"));
+
+        let permit = governor.acquire(&message_sender).await;
+        assert!(!permit.throttled);
+        assert_eq!(governor.permits.available_permits(), 3);
+        assert!(message_receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_limit_bandwidth_only_for_written_bytes() {
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let governor = Governor::with_probe(true, 1, on_battery);
+
+        // NOTE: A skipped file is not written and must not be throttled
+        let permit = governor.acquire(&message_sender).await;
+        let started = std::time::Instant::now();
+        permit.limit_bandwidth().await;
+        assert!(started.elapsed() < std::time::Duration::from_millis(50));
+        drop(permit);
+
+        let permit = governor.acquire(&message_sender).await;
+        crate::MessageSender::send(
+            &permit.meter(&message_sender),
+            copied(THROTTLED_BANDWIDTH / 10),
+        );
+        let started = std::time::Instant::now();
+        permit.limit_bandwidth().await;
+        assert!(started.elapsed() >= std::time::Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_meter_forwards_messages() {
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let governor = Governor::with_probe(true, 1, PowerState::default);
+
+        let permit = governor.acquire(&message_sender).await;
+        crate::MessageSender::send(&permit.meter(&message_sender), copied(7));
+        assert_eq!(permit.written.load(std::sync::atomic::Ordering::Relaxed), 7);
+        assert!(matches!(
+            message_receiver.try_recv(),
+            Ok(crate::Message::Progress(crate::Progress::IncrementSuccess(
+                crate::Increment::FileCopied { bytes: 7, .. }
+            )))
+        ));
+    }
+}
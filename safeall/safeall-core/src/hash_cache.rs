@@ -0,0 +1,287 @@
+//! Hashes of the files in a destination from previous runs, such that comparisons do not read
+//! unchanged files again.
+//!
+//! An entry is only used while the file has the size and modification time it had when it
+//! was hashed. Files which safeall writes are forgotten, unless their hash has been computed
+//! while they were copied.
+
+const HASH_CACHE_FILE: &str = "hashes";
+
+tokio::task_local! {
+    static CACHE: std::sync::Arc<HashCache>;
+}
+
+thread_local! {
+    static ENTERED: std::cell::RefCell<Option<std::sync::Arc<HashCache>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    length: u64,
+    modified: u128,
+    hash: blake3::Hash,
+}
+
+#[derive(Debug)]
+pub struct HashCache {
+    root: std::path::PathBuf,
+    entries: std::sync::Mutex<std::collections::HashMap<std::path::PathBuf, Entry>>,
+    /// Hashes of files written by this run, whose metadata is only final once the run is done.
+    written: std::sync::Mutex<std::collections::HashMap<std::path::PathBuf, blake3::Hash>>,
+}
+
+fn cache_path(root: &std::path::Path) -> std::path::PathBuf {
+    root.join(crate::METADATA_DIRECTORY).join(HASH_CACHE_FILE)
+}
+
+/// Size and modification time in nanoseconds, `None` if the modification time is unknown.
+fn key(metadata: &std::fs::Metadata) -> Option<(u64, u128)> {
+    let modified = metadata
+        .modified()
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?
+        .as_nanos();
+    Some((metadata.len(), modified))
+}
+
+fn current_key(path: &std::path::Path) -> Option<(u64, u128)> {
+    std::fs::metadata(path).ok().as_ref().and_then(key)
+}
+
+fn parse_line(line: &str) -> Option<(std::path::PathBuf, Entry)> {
+    let mut parts = line.splitn(4, ' ');
+    let hash = blake3::Hash::from_hex(parts.next()?).ok()?;
+    let length = parts.next()?.parse().ok()?;
+    let modified = parts.next()?.parse().ok()?;
+    let path = crate::manifest::unescape(parts.next()?);
+    Some((
+        path,
+        Entry {
+            length,
+            modified,
+            hash,
+        },
+    ))
+}
+
+impl HashCache {
+    /// Loads the hashes of the destination at `root`. Invalid lines are skipped.
+    pub fn load(root: &std::path::Path) -> Self {
+        let entries = std::fs::read_to_string(cache_path(root))
+            .map(|content| content.lines().filter_map(parse_line).collect())
+            .unwrap_or_default();
+        Self {
+            root: root.to_owned(),
+            entries: std::sync::Mutex::new(entries),
+            written: std::sync::Mutex::default(),
+        }
+    }
+
+    pub fn root(&self) -> &std::path::Path {
+        &self.root
+    }
+
+    fn entries(
+        &self,
+    ) -> std::sync::MutexGuard<'_, std::collections::HashMap<std::path::PathBuf, Entry>> {
+        self.entries.lock().expect("Lock is never poisoned")
+    }
+
+    /// The hash of `path`, computed with `compute` unless an entry matches its current size
+    /// and modification time. Paths outside of the destination are never cached.
+    pub fn hash(
+        &self,
+        path: &std::path::Path,
+        compute: impl FnOnce(&std::path::Path) -> std::io::Result<blake3::Hash>,
+    ) -> std::io::Result<blake3::Hash> {
+        let (Ok(relative_path), Some((length, modified))) =
+            (path.strip_prefix(&self.root), current_key(path))
+        else {
+            return compute(path);
+        };
+        if let Some(entry) = self.entries().get(relative_path)
+            && (entry.length, entry.modified) == (length, modified)
+        {
+            return Ok(entry.hash);
+        }
+        let hash = compute(path)?;
+        self.entries().insert(
+            relative_path.to_owned(),
+            Entry {
+                length,
+                modified,
+                hash,
+            },
+        );
+        Ok(hash)
+    }
+
+    fn written(
+        &self,
+    ) -> std::sync::MutexGuard<'_, std::collections::HashMap<std::path::PathBuf, blake3::Hash>>
+    {
+        self.written.lock().expect("Lock is never poisoned")
+    }
+
+    fn forget(&self, path: &std::path::Path) {
+        if let Ok(relative_path) = path.strip_prefix(&self.root) {
+            self.entries().remove(relative_path);
+            self.written().remove(relative_path);
+        }
+    }
+
+    /// Remembers the hash of a file which has been computed while writing it. The entry gets
+    /// the size and modification time the file has when the cache is saved.
+    pub fn record(&self, path: &std::path::Path, hash: blake3::Hash) {
+        if let Ok(relative_path) = path.strip_prefix(&self.root) {
+            self.entries().remove(relative_path);
+            self.written().insert(relative_path.to_owned(), hash);
+        }
+    }
+
+    /// Writes the entries of all files which still have the size and modification time they
+    /// were hashed with.
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        for (path, hash) in self.written().drain() {
+            if let Some((length, modified)) = current_key(&self.root.join(&path)) {
+                self.entries().insert(
+                    path,
+                    Entry {
+                        length,
+                        modified,
+                        hash,
+                    },
+                );
+            }
+        }
+        let mut lines: Vec<_> = self
+            .entries()
+            .iter()
+            .filter(|(path, entry)| {
+                current_key(&self.root.join(path)) == Some((entry.length, entry.modified))
+            })
+            .map(|(path, entry)| {
+                format!(
+                    "{} {} {} {}",
+                    entry.hash.to_hex(),
+                    entry.length,
+                    entry.modified,
+                    crate::manifest::escape(path)
+                )
+            })
+            .collect();
+        lines.sort();
+        let path = cache_path(&self.root);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut content = lines.join("\n");
+        content.push('\n');
+        std::fs::write(path, content)
+    }
+}
+
+/// Runs `future` such that comparisons use `cache`.
+pub async fn scope<F: Future>(cache: Option<std::sync::Arc<HashCache>>, future: F) -> F::Output {
+    match cache {
+        Some(cache) => CACHE.scope(cache, future).await,
+        None => future.await,
+    }
+}
+
+/// The cache of the running command, which has to be [`enter`]ed on blocking threads.
+pub fn current() -> Option<std::sync::Arc<HashCache>> {
+    CACHE.try_with(std::sync::Arc::clone).ok()
+}
+
+/// Runs `f` such that [`cached`] uses `cache` on this thread.
+pub fn enter<T>(cache: Option<std::sync::Arc<HashCache>>, f: impl FnOnce() -> T) -> T {
+    let previous = ENTERED.replace(cache);
+    let result = f();
+    ENTERED.set(previous);
+    result
+}
+
+/// The hash of `path` from the entered cache if there is one, otherwise from `compute`.
+pub fn cached(
+    path: &std::path::Path,
+    compute: impl FnOnce(&std::path::Path) -> std::io::Result<blake3::Hash>,
+) -> std::io::Result<blake3::Hash> {
+    match ENTERED.with_borrow(Clone::clone) {
+        Some(cache) => cache.hash(path, compute),
+        None => compute(path),
+    }
+}
+
+/// Drops the entry of a file which is about to be written.
+pub fn forget(path: &std::path::Path) {
+    if let Some(cache) = current() {
+        cache.forget(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_cache() {
+        let root = tempfile::tempdir().unwrap();
+        let file = root.path().join("file");
+        std::fs::write(&file, b"content").unwrap();
+        let modified = std::fs::metadata(&file).unwrap().modified().unwrap();
+        let set_modified = |modified| {
+            std::fs::File::options()
+                .write(true)
+                .open(&file)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+        let hash_file = |path: &std::path::Path| Ok(blake3::hash(&std::fs::read(path)?));
+
+        let cache = HashCache::load(root.path());
+        let hash = cache.hash(&file, hash_file).unwrap();
+        cache.save().unwrap();
+
+        // NOTE: Same size and modification time, so the stale hash is still used
+        std::fs::write(&file, b"CONTENT").unwrap();
+        set_modified(modified);
+        let cache = HashCache::load(root.path());
+        assert_eq!(cache.hash(&file, hash_file).unwrap(), hash);
+
+        set_modified(modified + std::time::Duration::from_secs(1));
+        assert_eq!(
+            cache.hash(&file, hash_file).unwrap(),
+            blake3::hash(b"CONTENT")
+        );
+        cache.forget(&file);
+        assert!(cache.entries().is_empty());
+    }
+
+    #[test]
+    fn test_hash_cache_record() {
+        let root = tempfile::tempdir().unwrap();
+        let file = root.path().join("file");
+        std::fs::write(&file, b"content").unwrap();
+
+        let cache = HashCache::load(root.path());
+        cache.record(&file, blake3::hash(b"content"));
+        // NOTE: The modification time is set after the copy and must not invalidate the hash
+        std::fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_hours(1))
+            .unwrap();
+        cache.save().unwrap();
+
+        let cache = HashCache::load(root.path());
+        let hash = cache
+            .hash(&file, |_| panic!("Hash must come from the cache"))
+            .unwrap();
+        assert_eq!(hash, blake3::hash(b"content"));
+    }
+}
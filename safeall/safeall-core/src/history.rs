@@ -0,0 +1,546 @@
+//! History of past runs stored in the destination, used to estimate the next run.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const HISTORY_FILE: &str = "history";
+/// Number of runs kept in the history file.
+const MAX_HISTORY: usize = 50;
+/// Number of most recent runs used for an estimate.
+const RUNS_FOR_ESTIMATE: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunKind {
+    Backup,
+    Sync,
+}
+
+impl RunKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            RunKind::Backup => "backup",
+            RunKind::Sync => "sync",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "backup" => Some(RunKind::Backup),
+            "sync" => Some(RunKind::Sync),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RunRecord {
+    kind: RunKind,
+    started: u64,
+    duration: std::time::Duration,
+    files_copied: u64,
+    bytes_copied: u64,
+    /// `None` for runs of older versions.
+    run_id: Option<crate::RunId>,
+}
+
+impl RunRecord {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split('\t');
+        Some(Self {
+            kind: RunKind::parse(fields.next()?)?,
+            started: fields.next()?.parse().ok()?,
+            duration: std::time::Duration::from_millis(fields.next()?.parse().ok()?),
+            files_copied: fields.next()?.parse().ok()?,
+            bytes_copied: fields.next()?.parse().ok()?,
+            run_id: fields.next().and_then(|run_id| run_id.parse().ok()),
+        })
+    }
+}
+
+impl std::fmt::Display for RunRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            self.kind.as_str(),
+            self.started,
+            self.duration.as_millis(),
+            self.files_copied,
+            self.bytes_copied
+        )?;
+        if let Some(run_id) = self.run_id {
+            write!(f, "\t{run_id}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Expected duration and amount of copied data of a run, based on previous runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Estimate {
+    pub runs: usize,
+    pub duration: std::time::Duration,
+    pub files_copied: u64,
+    pub bytes_copied: u64,
+}
+
+fn history_path(destination_root: &std::path::Path) -> std::path::PathBuf {
+    destination_root
+        .join(crate::METADATA_DIRECTORY)
+        .join(HISTORY_FILE)
+}
+
+fn load(destination_root: &std::path::Path) -> Vec<RunRecord> {
+    std::fs::read_to_string(history_path(destination_root))
+        .map(|history| history.lines().filter_map(RunRecord::parse).collect())
+        .unwrap_or_default()
+}
+
+fn median<T: Ord + Copy>(mut values: Vec<T>) -> Option<T> {
+    values.sort_unstable();
+    values.get(values.len() / 2).copied()
+}
+
+fn estimate(records: &[RunRecord], kind: RunKind) -> Option<Estimate> {
+    let recent: Vec<_> = records
+        .iter()
+        .rev()
+        .filter(|r| r.kind == kind)
+        .take(RUNS_FOR_ESTIMATE)
+        .collect();
+    Some(Estimate {
+        runs: recent.len(),
+        duration: median(recent.iter().map(|r| r.duration).collect())?,
+        files_copied: median(recent.iter().map(|r| r.files_copied).collect())?,
+        bytes_copied: median(recent.iter().map(|r| r.bytes_copied).collect())?,
+    })
+}
+
+/// Where the time of a finished run went, to tell whether the source, hashing or the
+/// destination is the bottleneck.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunReport {
+    pub run_id: crate::RunId,
+    pub duration: std::time::Duration,
+    /// Walking the source and destination before each phase.
+    pub scan: std::time::Duration,
+    /// Comparing existing files with their backup, summed over all files which are
+    /// compared concurrently.
+    pub hash: std::time::Duration,
+    /// Creating directories and copying files, including the retries.
+    pub copy: std::time::Duration,
+    /// Deleting what is not in the source anymore.
+    pub purge: std::time::Duration,
+    pub files_copied: u64,
+    pub bytes_copied: u64,
+    /// Copied and skipped files by their category, only with the categories which occurred.
+    pub categories: std::collections::BTreeMap<crate::FileCategory, crate::CategoryStats>,
+    /// Number of copied files by why they have been copied.
+    pub copy_reasons: std::collections::BTreeMap<crate::CopyReason, u64>,
+    /// Files deleted from the destination because they are not in the source anymore,
+    /// including the ones moved into the quarantine.
+    pub deleted_files: Vec<std::path::PathBuf>,
+    /// Directories deleted from the destination together with everything in them.
+    pub deleted_directories: Vec<std::path::PathBuf>,
+}
+
+impl RunReport {
+    /// Copied bytes per second during the copy phase.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn throughput(&self) -> Option<f64> {
+        let seconds = self.copy.as_secs_f64();
+        (seconds > 0.0).then(|| self.bytes_copied as f64 / seconds)
+    }
+}
+
+#[derive(Debug)]
+struct Timings {
+    /// End of the last phase or start of the current one.
+    phase_started: std::time::Instant,
+    scan: std::time::Duration,
+    hash: std::time::Duration,
+    copy: std::time::Duration,
+    purge: std::time::Duration,
+}
+
+impl Timings {
+    fn record(&mut self, progress: &crate::Progress) {
+        use crate::ProgressType as T;
+        let elapsed = self.phase_started.elapsed();
+        match progress {
+            // NOTE: Each phase counts its entries before it starts
+            crate::Progress::Start(_, _) => self.scan += elapsed,
+            crate::Progress::EndSuccess(phase) | crate::Progress::EndFail(_, phase) => {
+                match phase {
+                    T::CreatingDirectories | T::CopingFiles | T::RetryingFiles => {
+                        self.copy += elapsed;
+                    }
+                    T::DeletingDirs | T::DeletingFiles => self.purge += elapsed,
+                    T::Scanning => self.scan += elapsed,
+                    // NOTE: Each comparison is already recorded on its own and verifying
+                    // and scrubbing are not recorded as runs
+                    T::Hashing | T::Verifying | T::Scrubbing => {}
+                }
+            }
+            crate::Progress::IncrementSuccess(_)
+            | crate::Progress::IncrementFail(_)
+            | crate::Progress::TotalBytes(_, _) => return,
+        }
+        self.phase_started = std::time::Instant::now();
+    }
+}
+
+/// Forwards all messages and records what has been copied for the run history.
+pub struct Recorder<'a, S> {
+    message_sender: &'a S,
+    kind: RunKind,
+    run_id: crate::RunId,
+    started: std::time::SystemTime,
+    timer: std::time::Instant,
+    files_copied: AtomicU64,
+    bytes_copied: AtomicU64,
+    timings: std::sync::Mutex<Timings>,
+    suggestions: std::sync::Mutex<crate::suggest::Collector>,
+    attention: std::sync::Mutex<crate::attention::Collector>,
+    categories:
+        std::sync::Mutex<std::collections::BTreeMap<crate::FileCategory, crate::CategoryStats>>,
+    copy_reasons: std::sync::Mutex<std::collections::BTreeMap<crate::CopyReason, u64>>,
+    /// Deleted files and directories.
+    deleted: std::sync::Mutex<(Vec<std::path::PathBuf>, Vec<std::path::PathBuf>)>,
+}
+
+impl<S> Recorder<'_, S> {
+    fn categories(
+        &self,
+    ) -> std::sync::MutexGuard<
+        '_,
+        std::collections::BTreeMap<crate::FileCategory, crate::CategoryStats>,
+    > {
+        self.categories.lock().expect("Lock is never poisoned")
+    }
+}
+
+impl<S: crate::MessageSender> crate::MessageSender for Recorder<'_, S> {
+    fn send(&self, message: crate::Message) {
+        if let crate::Message::Progress(crate::Progress::IncrementSuccess(
+            crate::Increment::FileCopied {
+                source,
+                bytes,
+                reason,
+                ..
+            },
+        )) = &message
+        {
+            self.files_copied.fetch_add(1, Ordering::Relaxed);
+            *self
+                .copy_reasons
+                .lock()
+                .expect("Lock is never poisoned")
+                .entry(*reason)
+                .or_default() += 1;
+            self.bytes_copied.fetch_add(*bytes, Ordering::Relaxed);
+            self.suggestions
+                .lock()
+                .expect("Lock is never poisoned")
+                .record(source, *bytes);
+            let mut categories = self.categories();
+            let stats = categories
+                .entry(crate::FileCategory::of(source))
+                .or_default();
+            stats.files_copied += 1;
+            stats.bytes_copied += *bytes;
+        }
+        if let crate::Message::Progress(crate::Progress::IncrementSuccess(
+            crate::Increment::SkippingFileNoModification { source, bytes, .. },
+        )) = &message
+        {
+            let mut categories = self.categories();
+            let stats = categories
+                .entry(crate::FileCategory::of(source))
+                .or_default();
+            stats.files_skipped += 1;
+            stats.bytes_skipped += *bytes;
+        }
+        if let crate::Message::Progress(crate::Progress::IncrementSuccess(increment)) = &message {
+            let mut deleted = self.deleted.lock().expect("Lock is never poisoned");
+            match increment {
+                crate::Increment::DeletedFile(path) => deleted.0.push(path.clone()),
+                crate::Increment::DeletedDir(path) => deleted.1.push(path.clone()),
+                crate::Increment::Quarantined { path, quarantine } => {
+                    if quarantine.is_dir() {
+                        deleted.1.push(path.clone());
+                    } else {
+                        deleted.0.push(path.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.attention
+            .lock()
+            .expect("Lock is never poisoned")
+            .record(&message);
+        if let crate::Message::Progress(progress) = &message {
+            self.timings
+                .lock()
+                .expect("Lock is never poisoned")
+                .record(progress);
+        }
+        self.message_sender.send(message);
+    }
+
+    fn compared(&self, duration: std::time::Duration) {
+        self.timings.lock().expect("Lock is never poisoned").hash += duration;
+        self.message_sender.compared(duration);
+    }
+}
+
+impl<'a, S: crate::MessageSender> Recorder<'a, S> {
+    /// Starts recording a run and announces an estimate if there is a history for it.
+    pub fn start(
+        source_root: &std::path::Path,
+        destination_root: &std::path::Path,
+        kind: RunKind,
+        run_id: crate::RunId,
+        message_sender: &'a S,
+    ) -> Self {
+        if let Some(estimate) = estimate(&load(destination_root), kind) {
+            message_sender.send(crate::Message::Info(crate::Info::Estimate(estimate)));
+        }
+        Self {
+            message_sender,
+            kind,
+            run_id,
+            started: std::time::SystemTime::now(),
+            timer: std::time::Instant::now(),
+            files_copied: AtomicU64::new(0),
+            bytes_copied: AtomicU64::new(0),
+            timings: std::sync::Mutex::new(Timings {
+                phase_started: std::time::Instant::now(),
+                scan: std::time::Duration::ZERO,
+                hash: std::time::Duration::ZERO,
+                copy: std::time::Duration::ZERO,
+                purge: std::time::Duration::ZERO,
+            }),
+            suggestions: std::sync::Mutex::new(crate::suggest::Collector::new(source_root)),
+            attention: std::sync::Mutex::new(crate::attention::Collector::default()),
+            categories: std::sync::Mutex::new(std::collections::BTreeMap::new()),
+            copy_reasons: std::sync::Mutex::new(std::collections::BTreeMap::new()),
+            deleted: std::sync::Mutex::default(),
+        }
+    }
+
+    /// Reports the run and appends it to the history if it ran to completion.
+    pub fn finish(self, destination_root: &std::path::Path, result: &Result<(), crate::Error>) {
+        if !matches!(
+            result,
+            Ok(())
+                | Err(crate::Error::ProcessPathErrors { .. } | crate::Error::TooManyErrors { .. })
+        ) {
+            return;
+        }
+        let timings = self.timings.into_inner().expect("Lock is never poisoned");
+        let (deleted_files, deleted_directories) =
+            self.deleted.into_inner().expect("Lock is never poisoned");
+        let record = RunRecord {
+            kind: self.kind,
+            started: self
+                .started
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            duration: self.timer.elapsed(),
+            files_copied: self.files_copied.into_inner(),
+            bytes_copied: self.bytes_copied.into_inner(),
+            run_id: Some(self.run_id),
+        };
+        self.message_sender
+            .send(crate::Message::Info(crate::Info::Report(RunReport {
+                run_id: self.run_id,
+                duration: record.duration,
+                scan: timings.scan,
+                hash: timings.hash,
+                copy: timings.copy,
+                purge: timings.purge,
+                files_copied: record.files_copied,
+                bytes_copied: record.bytes_copied,
+                categories: self
+                    .categories
+                    .into_inner()
+                    .expect("Lock is never poisoned"),
+                copy_reasons: self
+                    .copy_reasons
+                    .into_inner()
+                    .expect("Lock is never poisoned"),
+                deleted_files,
+                deleted_directories,
+            })));
+        let attention = self
+            .attention
+            .into_inner()
+            .expect("Lock is never poisoned")
+            .finish();
+        if !attention.is_empty() {
+            self.message_sender
+                .send(crate::Message::Info(crate::Info::NeedsAttention(attention)));
+        }
+        let suggestions = self
+            .suggestions
+            .into_inner()
+            .expect("Lock is never poisoned")
+            .finish();
+        if !suggestions.is_empty() {
+            self.message_sender
+                .send(crate::Message::Info(crate::Info::ExcludeSuggestions(
+                    suggestions,
+                )));
+        }
+        // NOTE: An aborted run would distort the estimate of the next one
+        if matches!(result, Err(crate::Error::TooManyErrors { .. })) {
+            return;
+        }
+        let path = history_path(destination_root);
+        if let Err(e) = append(&path, &record) {
+            self.message_sender.send(crate::Message::Warning(
+                crate::Warning::CannotWriteRunHistory {
+                    path,
+                    io_error: e.to_string(),
+                },
+            ));
+        }
+    }
+}
+
+fn append(path: &std::path::Path, record: &RunRecord) -> std::io::Result<()> {
+    let mut records: Vec<_> = std::fs::read_to_string(path)
+        .map(|history| history.lines().map(ToOwned::to_owned).collect())
+        .unwrap_or_default();
+    records.push(record.to_string());
+    let keep = records.len().saturating_sub(MAX_HISTORY);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut content = records[keep..].join("\n");
+    content.push('\n');
+    std::fs::write(path, content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_from_history() {
+        let record = |kind: RunKind, seconds, bytes| RunRecord {
+            kind,
+            started: 0,
+            duration: std::time::Duration::from_secs(seconds),
+            files_copied: 1,
+            bytes_copied: bytes,
+            run_id: Some(crate::RunId::new()),
+        };
+        let records = [
+            // NOTE: Runs of older versions have no ID
+            RunRecord {
+                run_id: None,
+                ..record(RunKind::Backup, 10, 100)
+            },
+            record(RunKind::Backup, 30, 300),
+            record(RunKind::Sync, 1000, 1000),
+            record(RunKind::Backup, 20, 200),
+        ];
+        for record in &records {
+            assert_eq!(RunRecord::parse(&record.to_string()).as_ref(), Some(record));
+        }
+
+        let estimate = estimate(&records, RunKind::Backup).unwrap();
+        assert_eq!(estimate.runs, 3);
+        assert_eq!(estimate.duration, std::time::Duration::from_secs(20));
+        assert_eq!(estimate.bytes_copied, 200);
+        assert_eq!(super::estimate(&records[..0], RunKind::Sync), None);
+    }
+
+    #[test]
+    fn test_run_report() {
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let destination = tempfile::tempdir().unwrap();
+        let recorder = Recorder::start(
+            std::path::Path::new("source"),
+            destination.path(),
+            RunKind::Sync,
+            crate::RunId::new(),
+            &message_sender,
+        );
+        let progress = |progress| crate::Message::Progress(progress);
+        crate::MessageSender::send(
+            &recorder,
+            progress(crate::Progress::Start(1, crate::ProgressType::CopingFiles)),
+        );
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        crate::MessageSender::send(
+            &recorder,
+            progress(crate::Progress::IncrementSuccess(
+                crate::Increment::FileCopied {
+                    source: "a".into(),
+                    destination: "b".into(),
+                    bytes: 1000,
+                    reason: crate::CopyReason::SizeChanged,
+                },
+            )),
+        );
+        crate::MessageSender::send(
+            &recorder,
+            progress(crate::Progress::IncrementSuccess(
+                crate::Increment::SkippingFileNoModification {
+                    source: "photo.jpg".into(),
+                    destination: "copy.jpg".into(),
+                    bytes: 10,
+                    reason: crate::SkipReason::SameMetadata,
+                },
+            )),
+        );
+        crate::MessageSender::compared(&recorder, std::time::Duration::from_secs(1));
+        crate::MessageSender::send(
+            &recorder,
+            progress(crate::Progress::EndSuccess(
+                crate::ProgressType::CopingFiles,
+            )),
+        );
+        for increment in [
+            crate::Increment::DeletedFile("old.txt".into()),
+            crate::Increment::DeletedDir("old".into()),
+            crate::Increment::FileAlreadyDeleted("gone.txt".into()),
+        ] {
+            crate::MessageSender::send(
+                &recorder,
+                progress(crate::Progress::IncrementSuccess(increment)),
+            );
+        }
+        recorder.finish(destination.path(), &Ok(()));
+
+        let report = std::iter::from_fn(|| message_receiver.try_recv().ok())
+            .find_map(|message| match message {
+                crate::Message::Info(crate::Info::Report(report)) => Some(report),
+                _ => None,
+            })
+            .unwrap();
+        assert!(report.copy >= std::time::Duration::from_millis(10));
+        assert_eq!(report.hash, std::time::Duration::from_secs(1));
+        assert_eq!(report.purge, std::time::Duration::ZERO);
+        assert_eq!(report.deleted_files, [std::path::PathBuf::from("old.txt")]);
+        assert_eq!(
+            report.deleted_directories,
+            [std::path::PathBuf::from("old")]
+        );
+        assert_eq!(report.bytes_copied, 1000);
+        assert!(report.throughput().unwrap() <= 100_000.0);
+        assert_eq!(
+            report.categories[&crate::FileCategory::Other].bytes_copied,
+            1000
+        );
+        assert_eq!(
+            report.categories[&crate::FileCategory::Photos].files_skipped,
+            1
+        );
+        assert_eq!(report.copy_reasons[&crate::CopyReason::SizeChanged], 1);
+    }
+}
@@ -1,12 +1,81 @@
 #![allow(clippy::missing_errors_doc)]
 
+mod archive;
+mod attention;
+mod checkpoint;
+mod clock;
+mod comparator;
+mod copier;
+mod destination_id;
+mod doctor;
+mod error_report;
+mod file_attributes;
+mod file_types;
+mod filter;
+mod governor;
+mod hash_cache;
+mod history;
+mod lock;
+mod manifest;
+mod multi_sender;
+mod ownership;
+mod parity;
+mod plan;
+mod prune;
+mod read_only;
+mod repo;
+mod restore_target;
+mod run_id;
+mod scan;
+mod scan_cache;
+mod scrub;
+mod size_limit;
+mod special_bits;
+mod special_files;
+mod split;
+mod suggest;
+mod template;
+mod verify;
+mod xattrs;
+
+pub use attention::{Attention, AttentionReason};
+pub use checkpoint::Checkpoint;
+pub use clock::ClockSkew;
+pub use comparator::{
+    Comparator, CompareStrategy, CopyReason, Decision, MetadataAndHash, SkipReason,
+};
+pub use destination_id::known_destinations_path;
+pub use doctor::{Finding, Paths};
+pub use error_report::{ErrorReport, FailedPath};
+pub use file_types::{CategoryStats, FileCategory};
+pub use filter::{PathFilter, exclude_pattern};
+pub use governor::PowerState;
+pub use history::{Estimate, RunReport};
+pub use manifest::{FileRecord, Manifest};
+pub use multi_sender::{MultiSender, Severity};
+pub use ownership::Owner;
+pub use plan::{Plan, PlannedAction, RestoreDiff};
+pub use prune::{PruneReport, RetentionPolicy};
+pub use repo::{
+    GarbageCollected, KeyAction, KeyKind, RegisteredKey, RepositoryCheck, Secret, SnapshotSummary,
+};
+pub use restore_target::{NonEmptyTarget, RestoreTarget, TargetFilesystem};
+pub use run_id::{RunId, TaggedSender};
+pub use scan::ScanSummary;
+pub use scrub::ScrubReport;
+pub use suggest::{ExcludeReason, ExcludeSuggestion};
+pub use verify::VerifyReport;
+
 pub const MAINTAINER_EMAIL: &str = "christoph.ungricht@outlook.com";
+/// Directory in the root of a backup where safeall keeps its own data.
+/// It is never copied nor deleted.
+pub const METADATA_DIRECTORY: &str = ".safeall";
+/// Directory in the metadata directory where suspicious deletions are moved to.
+pub const QUARANTINE_DIRECTORY: &str = "quarantine";
 
 #[inline]
 fn cpu_count() -> usize {
-    std::thread::available_parallelism()
-        .map(std::num::NonZero::get)
-        .unwrap_or(1)
+    std::thread::available_parallelism().map_or(1, std::num::NonZero::get)
 }
 
 #[derive(Debug)]
@@ -19,13 +88,99 @@ pub enum ReadDirType {
 pub struct RecursiveReadDir {
     for_root: std::path::PathBuf,
     readdir_type: ReadDirType,
-    next_readdirs: std::collections::VecDeque<std::path::PathBuf>,
-    current_readdir: std::fs::ReadDir,
+    next_readdirs:
+        std::collections::VecDeque<(std::path::PathBuf, Option<std::sync::Arc<Ancestors>>)>,
+    current_readdir: scan_cache::Entries,
     current_dirpath: std::path::PathBuf,
+    /// The current directory and its parents up to the root.
+    current_ancestors: Option<std::sync::Arc<Ancestors>>,
+    /// Paths which are yielded before anything else, see [`filter::Filter::only_paths`].
+    pending: std::collections::VecDeque<std::path::PathBuf>,
+    filter: filter::Filter,
+    ignore_files: filter::IgnoreFiles,
+    error_policy: ErrorPolicy,
+    stopped: bool,
+    skip_special_files: bool,
+    cycles: Vec<std::path::PathBuf>,
+    /// Device of the root when the traversal stays on its filesystem.
+    root_device: Option<u64>,
+    max_depth: Option<usize>,
+    read_timeout: Option<std::time::Duration>,
+    scan_cache: Option<std::sync::Arc<scan_cache::ScanCache>>,
+    walk_order: WalkOrder,
+    /// Index in `next_readdirs` of the first subdirectory of the current directory.
+    children_start: usize,
+}
+
+/// Opens `directory` on another thread and gives up after `timeout`. The thread is left
+/// behind when it hangs, as a blocking `read_dir` cannot be cancelled.
+fn read_dir_with_timeout(
+    directory: &std::path::Path,
+    timeout: Option<std::time::Duration>,
+) -> std::io::Result<std::fs::ReadDir> {
+    let Some(timeout) = timeout else {
+        return std::fs::read_dir(directory);
+    };
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let directory = directory.to_owned();
+    std::thread::spawn(move || sender.send(std::fs::read_dir(directory)));
+    receiver.recv_timeout(timeout).unwrap_or_else(|_| {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("no response within {}", format_duration(timeout)),
+        ))
+    })
+}
+
+/// Device and inode of a directory and of its parents, such that a walk can tell whether a
+/// directory leads back to one of its parents. Shared by the subdirectories of a directory.
+#[derive(Debug)]
+struct Ancestors {
+    id: (u64, u64),
+    parent: Option<std::sync::Arc<Ancestors>>,
+}
+
+impl Ancestors {
+    fn root(id: Option<(u64, u64)>) -> Option<std::sync::Arc<Self>> {
+        id.map(|id| std::sync::Arc::new(Self { id, parent: None }))
+    }
+
+    fn contains(self: &std::sync::Arc<Self>, id: (u64, u64)) -> bool {
+        let mut ancestor = Some(self);
+        while let Some(current) = ancestor {
+            if current.id == id {
+                return true;
+            }
+            ancestor = current.parent.as_ref();
+        }
+        false
+    }
+}
+
+/// Device and inode of a directory, which identify it independent of the path to it.
+#[cfg(unix)]
+fn directory_id(path: &std::path::Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn directory_id(_path: &std::path::Path) -> Option<(u64, u64)> {
+    None
 }
 
 pub trait MessageSender {
     fn send(&self, message: Message);
+
+    /// Called with every message of a [`run`] and the ID of the run. Frontends which do not
+    /// need the ID get the message through [`MessageSender::send`].
+    fn send_from_run(&self, _run_id: RunId, message: Message) {
+        self.send(message);
+    }
+
+    /// Called with the time it took to compare a file with its backup, for the run report.
+    fn compared(&self, _duration: std::time::Duration) {}
 }
 
 impl MessageSender for tokio::sync::mpsc::UnboundedSender<Message> {
@@ -45,41 +200,291 @@ impl RecursiveReadDir {
         readdir_type: ReadDirType,
     ) -> Result<Self, std::io::Error> {
         let directory: &std::path::Path = directory.as_ref();
-        let current_readdir = std::fs::read_dir(directory)?;
+        let scan_cache = scan_cache::current();
+        let current_readdir =
+            scan_cache::Entries::open(directory, scan_cache.as_ref(), |d| std::fs::read_dir(d))?;
         Ok(Self {
             for_root: directory.to_owned(),
             readdir_type,
             current_readdir,
             next_readdirs: std::collections::VecDeque::new(),
             current_dirpath: directory.to_owned(),
+            current_ancestors: Ancestors::root(directory_id(directory)),
+            pending: std::collections::VecDeque::new(),
+            filter: filter::Filter::default(),
+            ignore_files: filter::IgnoreFiles::default(),
+            error_policy: ErrorPolicy::default(),
+            stopped: false,
+            skip_special_files: false,
+            cycles: vec![],
+            root_device: None,
+            max_depth: None,
+            read_timeout: None,
+            scan_cache,
+            walk_order: WalkOrder::default(),
+            children_start: 0,
         })
     }
+
+    /// Decides what happens to entries and directories which cannot be read.
+    #[must_use]
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Returns the error unless it is ignored, stops the traversal after it if it fails fast.
+    fn on_error(&mut self, error: ProcessPathError) -> Option<ProcessPathError> {
+        match self.error_policy {
+            ErrorPolicy::Ignore => None,
+            ErrorPolicy::Collect => Some(error),
+            ErrorPolicy::FailFast => {
+                self.stopped = true;
+                Some(error)
+            }
+        }
+    }
+
+    /// Skips all files and directories which are not accepted by the filter.
+    #[must_use]
+    fn with_filter(mut self, filter: filter::Filter) -> Self {
+        if filter.uses_ignore_files() {
+            self.ignore_files.load(&self.for_root);
+        }
+        if filter.stays_on_same_filesystem() {
+            self.root_device = directory_id(&self.for_root).map(|(device, _)| device);
+        }
+        self.max_depth = filter.max_depth();
+        self.walk_order = filter.walk_order();
+        if let Some(paths) = filter.only_paths() {
+            let paths = paths.to_vec();
+            self.filter = filter;
+            self.start_at(&paths);
+        } else {
+            self.filter = filter;
+        }
+        self
+    }
+
+    /// Walks only `paths` and the directories leading to them instead of the whole root. The
+    /// root itself is not read, unless it is one of the paths.
+    fn start_at(&mut self, paths: &[std::path::PathBuf]) {
+        if paths.contains(&self.for_root) {
+            return;
+        }
+        self.current_readdir = scan_cache::Entries::empty(&self.for_root);
+        let mut parents = std::collections::BTreeSet::new();
+        let root = self.for_root.clone();
+        for path in paths.iter().filter(|path| path.starts_with(&root)) {
+            parents.extend(
+                path.ancestors()
+                    .skip(1)
+                    .take_while(|parent| *parent != root)
+                    .map(std::path::Path::to_owned),
+            );
+            if path.is_dir() {
+                self.queue_directory(path.clone());
+            } else if matches!(self.readdir_type, ReadDirType::FilesOnly)
+                && path.symlink_metadata().is_ok()
+                && self.accepts(path, false)
+            {
+                self.pending.push_back(path.clone());
+            }
+        }
+        // NOTE: Sorted, such that every directory comes after its parent
+        if matches!(self.readdir_type, ReadDirType::DirectoriesOnly) {
+            self.pending.extend(parents);
+        }
+    }
+
+    /// Visits the directories breadth-first, the default, or depth-first.
+    #[must_use]
+    pub fn with_walk_order(mut self, walk_order: WalkOrder) -> Self {
+        self.walk_order = walk_order;
+        self
+    }
+
+    /// The directory which is read next.
+    fn pop_next_readdir(
+        &mut self,
+    ) -> Option<(std::path::PathBuf, Option<std::sync::Arc<Ancestors>>)> {
+        let next_readdir = match self.walk_order {
+            WalkOrder::BreadthFirst => self.next_readdirs.pop_front(),
+            WalkOrder::DepthFirst => {
+                // NOTE: The subdirectories of the current directory are reversed on the stack,
+                // such that they are visited in the order they have been read
+                let children: Vec<_> = self.next_readdirs.drain(self.children_start..).collect();
+                self.next_readdirs.extend(children.into_iter().rev());
+                self.next_readdirs.pop_back()
+            }
+        };
+        self.children_start = self.next_readdirs.len();
+        next_readdir
+    }
+
+    /// Only descends `max_depth` levels below the root, where 1 yields only the entries of
+    /// the root itself.
+    #[must_use]
+    pub fn with_max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Number of path components of `path` below the root.
+    fn depth(&self, path: &std::path::Path) -> usize {
+        path.strip_prefix(&self.for_root)
+            .map_or(0, |relative| relative.components().count())
+    }
+
+    /// Reports directories which cannot be opened within `read_timeout` as unreadable
+    /// instead of blocking the traversal, e.g. on a dropped network drive.
+    #[must_use]
+    pub fn with_read_timeout(mut self, read_timeout: Option<std::time::Duration>) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    fn is_too_deep(&self, depth: usize) -> bool {
+        self.max_depth.is_some_and(|max_depth| depth > max_depth)
+    }
+
+    /// Queues an accepted directory unless it is on another filesystem or one of its own
+    /// parents, e.g. through a symbolic link. Other paths to a directory which has already been
+    /// visited are walked like any other directory.
+    fn queue_directory(&mut self, path: std::path::PathBuf) {
+        if !self.accepts(&path, true) {
+            return;
+        }
+        let id = directory_id(&path);
+        if let (Some(root_device), Some((device, _))) = (self.root_device, id)
+            && device != root_device
+        {
+            return;
+        }
+        let Some(id) = id else {
+            self.next_readdirs.push_back((path, None));
+            return;
+        };
+        if self
+            .current_ancestors
+            .as_ref()
+            .is_some_and(|ancestors| ancestors.contains(id))
+        {
+            self.cycles.push(path);
+            return;
+        }
+        let ancestors = std::sync::Arc::new(Ancestors {
+            id,
+            parent: self.current_ancestors.clone(),
+        });
+        self.next_readdirs.push_back((path, Some(ancestors)));
+    }
+
+    /// Does not yield FIFOs, sockets and device nodes.
+    #[must_use]
+    pub fn skipping_special_files(mut self, skip: bool) -> Self {
+        self.skip_special_files = skip;
+        self
+    }
+
+    fn accepts(&self, path: &std::path::Path, is_dir: bool) -> bool {
+        let relative_path = path.strip_prefix(&self.for_root).unwrap_or(path);
+        let accepted = if is_dir {
+            self.filter.accepts_directory(relative_path)
+        } else {
+            self.filter.accepts_file(relative_path)
+        };
+        accepted
+            && !self.filter.excludes_path(path)
+            && !self.ignore_files.is_ignored(path, is_dir)
+            && (!is_dir || !self.filter.is_tagged_cache(path))
+            && self.filter.accepts_metadata(path, is_dir)
+    }
+
+    /// Directories which were not descended into because they lead back to one of their own
+    /// parents, e.g. a symbolic link or a bind mount of a parent directory.
+    #[must_use]
+    pub fn cycles(&self) -> &[std::path::PathBuf] {
+        &self.cycles
+    }
+
+    /// Yields the paths relative to the root directory instead of absolute ones.
+    #[must_use]
+    pub fn relative(self) -> RelativePaths {
+        RelativePaths { inner: self }
+    }
+}
+
+#[derive(Debug)]
+pub struct RelativePaths {
+    inner: RecursiveReadDir,
+}
+
+impl Iterator for RelativePaths {
+    type Item = Result<std::path::PathBuf, ProcessPathError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let path = self.inner.next()?;
+        Some(path.and_then(|path| {
+            strip_root(&self.inner.for_root, &path).map(std::borrow::ToOwned::to_owned)
+        }))
+    }
 }
 
 impl Iterator for RecursiveReadDir {
     type Item = Result<std::path::PathBuf, ProcessPathError>;
 
+    #[allow(clippy::too_many_lines)]
     fn next(&mut self) -> Option<Self::Item> {
         use ProcessPathErrorKind as K;
+        if self.stopped {
+            return None;
+        }
+        if let Some(path) = self.pending.pop_front() {
+            return Some(Ok(path));
+        }
         'drain_current_readdir: loop {
-            for entry in &mut self.current_readdir {
+            while let Some(entry) = self.current_readdir.next() {
                 match entry {
-                    Ok(entry) => {
-                        let path = entry.path();
-                        if path.is_dir() {
-                            self.next_readdirs.push_back(path);
-                        } else if matches!(self.readdir_type, ReadDirType::FilesOnly) {
+                    Ok((path, kind)) => {
+                        if self.current_dirpath == self.for_root
+                            && path.file_name() == Some(METADATA_DIRECTORY.as_ref())
+                        {
+                            continue;
+                        }
+                        if self.is_too_deep(self.depth(&path)) {
+                            continue;
+                        }
+                        // NOTE: The target of a link may have changed without changing the
+                        // directory of the link, so it is always looked up
+                        let is_dir = match kind {
+                            scan_cache::Kind::Directory => true,
+                            scan_cache::Kind::File | scan_cache::Kind::Special => false,
+                            scan_cache::Kind::Symlink | scan_cache::Kind::Unknown => path.is_dir(),
+                        };
+                        if is_dir
+                            && !(self.filter.keeps_directory_symlinks()
+                                && kind == scan_cache::Kind::Symlink)
+                        {
+                            self.queue_directory(path);
+                        } else if matches!(self.readdir_type, ReadDirType::FilesOnly)
+                            && !(self.skip_special_files && kind == scan_cache::Kind::Special)
+                            && self.accepts(&path, false)
+                        {
                             return Some(Ok(path));
                         }
                     }
                     Err(error) => {
-                        return Some(Err(ProcessPathError {
+                        let error = self.on_error(ProcessPathError {
                             not_processed: None,
                             kind: K::CannotGetDirEntry {
                                 in_dir: self.current_dirpath.clone(),
                                 io_error: error.to_string(),
                             },
-                        }));
+                        });
+                        if let Some(error) = error {
+                            return Some(Err(error));
+                        }
                     }
                 }
             }
@@ -87,12 +492,27 @@ impl Iterator for RecursiveReadDir {
                 self.current_readdir.next().is_none(),
                 "The `current_readdir` must be empty so we can create a new one"
             );
-            if let Some(next_readdir) = self.next_readdirs.pop_front() {
-                debug_assert!(next_readdir.is_dir(), "Must be a directory.");
-                match std::fs::read_dir(&next_readdir) {
+            if let Some((next_readdir, ancestors)) = self.pop_next_readdir() {
+                // NOTE: The entries of directories at the maximum depth would all be too deep
+                if self.is_too_deep(self.depth(&next_readdir) + 1) {
+                    match self.readdir_type {
+                        ReadDirType::FilesOnly => continue 'drain_current_readdir,
+                        ReadDirType::DirectoriesOnly => return Some(Ok(next_readdir)),
+                    }
+                }
+                // NOTE: The directory may have been removed since it was queued, which is
+                // reported like any other directory which cannot be read
+                let read_timeout = self.read_timeout;
+                match scan_cache::Entries::open(&next_readdir, self.scan_cache.as_ref(), |d| {
+                    read_dir_with_timeout(d, read_timeout)
+                }) {
                     Ok(readdir) => {
+                        if self.filter.uses_ignore_files() {
+                            self.ignore_files.load(&next_readdir);
+                        }
                         self.current_readdir = readdir;
                         self.current_dirpath.clone_from(&next_readdir);
+                        self.current_ancestors = ancestors;
                         match self.readdir_type {
                             ReadDirType::FilesOnly => continue 'drain_current_readdir,
                             ReadDirType::DirectoriesOnly => {
@@ -101,12 +521,16 @@ impl Iterator for RecursiveReadDir {
                         }
                     }
                     Err(error) => {
-                        return Some(Err(ProcessPathError {
+                        let error = self.on_error(ProcessPathError {
                             not_processed: Some(next_readdir),
                             kind: K::CannotReadDirectoryContent {
                                 io_error: error.to_string(),
                             },
-                        }));
+                        });
+                        match error {
+                            Some(error) => return Some(Err(error)),
+                            None => continue 'drain_current_readdir,
+                        }
                     }
                 }
             }
@@ -130,6 +554,10 @@ pub enum InvariantError {
         path: std::path::PathBuf,
         error: std::path::StripPrefixError,
     },
+    WriteToSource {
+        source_root: std::path::PathBuf,
+        path: std::path::PathBuf,
+    },
 }
 
 impl std::error::Error for InvariantError {}
@@ -149,6 +577,12 @@ impl std::fmt::Display for InvariantError {
                     path.display()
                 )
             }
+            InvariantError::WriteToSource { source_root, path } => write!(
+                f,
+                "refused to write \"{}\" in the source \"{}\"",
+                path.display(),
+                source_root.display()
+            ),
         }
     }
 }
@@ -187,11 +621,39 @@ pub enum ProcessPathErrorKind {
         io_error: String,
     },
     CannotCopyFileDirectoyNotExisting,
+    CannotQuarantine {
+        quarantine: std::path::PathBuf,
+        io_error: String,
+    },
+    CannotCreateSymlink {
+        destination: std::path::PathBuf,
+        io_error: String,
+    },
+    SpecialFile,
+    CannotCreateSpecialFile {
+        destination: std::path::PathBuf,
+        io_error: String,
+    },
+    VerificationFailed {
+        destination: std::path::PathBuf,
+        io_error: String,
+    },
+    /// The file is larger than the filesystem of the destination can store.
+    FileTooLarge {
+        destination: std::path::PathBuf,
+        size: u64,
+        max_size: u64,
+    },
+    CannotKeepOverwritten {
+        destination: std::path::PathBuf,
+        io_error: String,
+    },
 }
 
 impl std::error::Error for ProcessPathError {}
 
 impl std::fmt::Display for ProcessPathError {
+    #[allow(clippy::too_many_lines)]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use ProcessPathErrorKind as K;
         let prefix = if let Some(ref not_processed) = self.not_processed {
@@ -243,6 +705,61 @@ impl std::fmt::Display for ProcessPathError {
             K::CannotCopyFileDirectoyNotExisting => {
                 write!(f, "{prefix}Cannot copy file as directory does not exist.")
             }
+            K::CannotQuarantine {
+                quarantine,
+                io_error,
+            } => write!(
+                f,
+                "{prefix}Cannot move it to the quarantine \"{}\": {io_error}.",
+                quarantine.display()
+            ),
+            K::CannotCreateSymlink {
+                destination,
+                io_error,
+            } => write!(
+                f,
+                "{prefix}Cannot create the symbolic link \"{}\": {io_error}.",
+                destination.display()
+            ),
+            K::SpecialFile => write!(
+                f,
+                "{prefix}It is a FIFO, socket or device which cannot be copied."
+            ),
+            K::CannotCreateSpecialFile {
+                destination,
+                io_error,
+            } => write!(
+                f,
+                "{prefix}Cannot create the special file \"{}\": {io_error}.",
+                destination.display()
+            ),
+            K::VerificationFailed {
+                destination,
+                io_error,
+            } => write!(
+                f,
+                "{prefix}The copy \"{}\" does not match the source: {io_error}.",
+                destination.display()
+            ),
+            K::FileTooLarge {
+                destination,
+                size,
+                max_size,
+            } => write!(
+                f,
+                "{prefix}It has {} but \"{}\" can have {} at most on the filesystem of the destination, e.g. FAT. Skip or split such files or back up to a drive with exFAT, NTFS or another filesystem for large files.",
+                format_bytes(*size),
+                destination.display(),
+                format_bytes(*max_size)
+            ),
+            K::CannotKeepOverwritten {
+                destination,
+                io_error,
+            } => write!(
+                f,
+                "{prefix}Cannot rename \"{}\" to keep it before it is overwritten: {io_error}.",
+                destination.display()
+            ),
         }
     }
 }
@@ -254,9 +771,84 @@ pub enum Error {
         files: Vec<ProcessPathError>,
     },
     SourceRootPathDoesNotExist(std::path::PathBuf),
+    SourceRootIsNotADirectory(std::path::PathBuf),
     CannotReadDirectoryContent(std::path::PathBuf, String),
     CannotCreateRootDestinationDir(std::path::PathBuf, String),
     RootDestinatinIsNotADirectory(std::path::PathBuf),
+    MassChangeDetected {
+        changed: usize,
+        total: usize,
+        threshold: u8,
+    },
+    DestinationIdMismatch {
+        destination_root: std::path::PathBuf,
+        expected: String,
+        found: String,
+    },
+    DestinationIdMissing(std::path::PathBuf),
+    CannotWriteDestinationId(std::path::PathBuf, String),
+    InvalidPattern(String),
+    DestinationNotMounted(std::path::PathBuf),
+    SourceNotMounted(std::path::PathBuf),
+    InvalidPathTemplate {
+        template: String,
+        variable: String,
+    },
+    DestinationLocked(std::path::PathBuf),
+    CannotLockDestination(std::path::PathBuf, String),
+    TooManyErrors {
+        errors: usize,
+        max: usize,
+    },
+    /// A path to back up is absolute outside of the source or leads out of it with `..`.
+    PathNotInSource {
+        path: std::path::PathBuf,
+        source_root: std::path::PathBuf,
+    },
+    DestinationInconsistent(std::path::PathBuf),
+    NoManifest(std::path::PathBuf),
+    DestinationCorrupted(std::path::PathBuf),
+    EmptyRetentionPolicy,
+    NotARepository(std::path::PathBuf),
+    NoSnapshot(std::path::PathBuf),
+    SnapshotNotFound {
+        repository_root: std::path::PathBuf,
+        id: String,
+    },
+    SnapshotDamaged(std::path::PathBuf),
+    CannotWriteSnapshot(std::path::PathBuf, String),
+    CannotCollectGarbage(std::path::PathBuf, String),
+    CannotWriteArchive(std::path::PathBuf, String),
+    /// The doctor found this many problems which would make runs fail.
+    ProblemsFound(usize),
+    /// The repository is encrypted, but neither a passphrase nor a key file has been given.
+    SecretRequired(std::path::PathBuf),
+    WrongSecret(std::path::PathBuf),
+    /// A passphrase or key file has been given for a repository which is not encrypted.
+    RepositoryNotEncrypted(std::path::PathBuf),
+    CannotReadKeyFile(std::path::PathBuf, String),
+    CannotWriteKeys(std::path::PathBuf, String),
+    KeyNotFound {
+        repository_root: std::path::PathBuf,
+        id: String,
+    },
+    /// Without any key, nobody could decrypt the repository anymore.
+    CannotRemoveLastKey(std::path::PathBuf),
+    UnsupportedRepositoryVersion {
+        repository_root: std::path::PathBuf,
+        version: String,
+    },
+    RepositoryDamaged(std::path::PathBuf),
+    BackupDoesNotExist(std::path::PathBuf),
+    RestoreTargetNotEmpty(std::path::PathBuf),
+    RestoreTargetOnOtherFilesystem {
+        target_root: std::path::PathBuf,
+        backup_root: std::path::PathBuf,
+    },
+    RestoreTargetOnBackupFilesystem {
+        target_root: std::path::PathBuf,
+        backup_root: std::path::PathBuf,
+    },
 }
 
 impl Error {
@@ -269,11 +861,21 @@ impl Error {
         }
         Err(Self::ProcessPathErrors { directories, files })
     }
+
+    /// Aborts a run once more than `max_errors` paths failed, which usually means that the
+    /// drive has been disconnected rather than that many files have problems.
+    fn check_limit(errors: usize, max_errors: Option<usize>) -> Result<(), Self> {
+        match max_errors {
+            Some(max) if errors > max => Err(Self::TooManyErrors { errors, max }),
+            _ => Ok(()),
+        }
+    }
 }
 
 impl std::error::Error for Error {}
 
 impl std::fmt::Display for Error {
+    #[allow(clippy::too_many_lines)]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::ProcessPathErrors { directories, files } => {
@@ -292,6 +894,11 @@ impl std::fmt::Display for Error {
                 "The specified source directory \"{}\" does not exists.",
                 path.display()
             ),
+            Error::SourceRootIsNotADirectory(path) => write!(
+                f,
+                "Specified source \"{}\" is not a directory but a file.",
+                path.display()
+            ),
             Error::CannotCreateRootDestinationDir(path_buf, io_error) => write!(
                 f,
                 "Cannot create a new destination directory \"{}\": {io_error}.",
@@ -307,204 +914,879 @@ impl std::fmt::Display for Error {
                 "Cannot iterate through directory\"{}\": {error}.",
                 path.display()
             ),
-        }
-    }
-}
-
-async fn backup_all_files(
-    source_directory_root: &std::path::Path,
-    destination_directory_root: &std::path::Path,
-    failed_source_directories: &[&std::path::Path],
-    message_sender: &impl MessageSender,
-) -> Result<Vec<ProcessPathError>, Error> {
-    use futures::stream::StreamExt;
-
-    debug_assert!(source_directory_root.is_dir(), "Source is not a dir");
-    debug_assert!(
-        destination_directory_root.is_dir(),
-        "Destination is not a dir"
-    );
-    let source_recurse_files =
-        RecursiveReadDir::try_new(source_directory_root, ReadDirType::FilesOnly).map_err(|e| {
-            Error::CannotReadDirectoryContent(source_directory_root.to_owned(), e.to_string())
-        })?;
-
-    let num_files = futures::stream::iter(source_recurse_files).count().await;
-    message_sender.send(Message::Progress(Progress::Start(
-        num_files,
-        ProgressType::CopingFiles,
-    )));
-    let source_recurse_files =
-        RecursiveReadDir::try_new(source_directory_root, ReadDirType::FilesOnly).map_err(|e| {
-            Error::CannotReadDirectoryContent(source_directory_root.to_owned(), e.to_string())
-        })?;
-    let errors: Vec<_> = futures::stream::iter(source_recurse_files)
-        .map(async |source_file| {
-            let source_file = source_file?;
-            if failed_source_directories
-                .iter()
-                .any(|d| source_file.starts_with(d))
-            {
-                // Do not try to copy files for directories that do not exist, fail instantly
-                let error = ProcessPathError {
-                    not_processed: Some(source_file.clone()),
-                    kind: ProcessPathErrorKind::CannotCopyFileDirectoyNotExisting,
-                };
-                message_sender.send(Message::Progress(Progress::IncrementFail(error.clone())));
-                return Err(error);
+            Error::MassChangeDetected {
+                changed,
+                total,
+                threshold,
+            } => write!(
+                f,
+                "ABORTED: The sync would modify or delete {changed} of {total} files in the destination, \
+                which is more than {threshold}%. This happens when the source has been encrypted by \
+                ransomware or replaced by accident. Nothing has been changed. Check your source and \
+                override the limit if the changes are intended."
+            ),
+            Error::DestinationIdMismatch {
+                destination_root,
+                expected,
+                found,
+            } => write!(
+                f,
+                "ABORTED: The destination \"{}\" has the ID {found} but the last run used {expected}. \
+                Check that the right drive is mounted and accept the new destination if it has been \
+                replaced on purpose.",
+                destination_root.display()
+            ),
+            Error::DestinationIdMissing(destination_root) => write!(
+                f,
+                "ABORTED: The destination \"{}\" has lost its ID. Check that the drive is mounted \
+                and accept the new destination if it has been replaced on purpose.",
+                destination_root.display()
+            ),
+            Error::CannotWriteDestinationId(path, io_error) => write!(
+                f,
+                "Cannot write the ID of the destination to \"{}\": {io_error}.",
+                path.display()
+            ),
+            Error::InvalidPattern(error) => {
+                write!(f, "Invalid include or exclude pattern: {error}.")
             }
-            backup_single_file(
-                source_directory_root,
-                destination_directory_root,
-                source_file,
-                message_sender,
-            )
-            .await
-            .inspect_err(|e| {
-                message_sender.send(Message::Progress(Progress::IncrementFail(e.clone())));
-            })
-        })
-        .buffer_unordered(cpu_count())
-        .filter_map(async move |res| res.err())
-        .collect()
-        .await;
-    if errors.is_empty() {
-        message_sender.send(Message::Progress(Progress::EndSuccess(
-            ProgressType::CopingFiles,
-        )));
-    } else {
-        message_sender.send(Message::Progress(Progress::EndFail(
-            errors.len(),
-            ProgressType::CopingFiles,
-        )));
-    }
-    Ok(errors)
-}
-
-async fn backup_single_file(
-    source_directory_root: &std::path::Path,
-    destination_directory_root: &std::path::Path,
-    source_file: std::path::PathBuf,
-    message_sender: &impl MessageSender,
-) -> Result<(), ProcessPathError> {
-    debug_assert!(!source_file.is_dir(), "Must be a file or a symbolic link");
-
-    let new_destination_file = get_destination_file_path(
-        destination_directory_root,
-        source_directory_root,
-        &source_file,
-    )?;
-
-    copy_or_skip_if_same(&source_file, &new_destination_file, message_sender).await
+            Error::DestinationNotMounted(path) => write!(
+                f,
+                "ABORTED: The destination \"{}\" is not on a mounted drive. Mount the drive and try again.",
+                path.display()
+            ),
+            Error::SourceNotMounted(path) => write!(
+                f,
+                "ABORTED: The source \"{}\" is not on a mounted drive and syncing would delete the backup. Mount the drive and try again.",
+                path.display()
+            ),
+            Error::InvalidPathTemplate { template, variable } => write!(
+                f,
+                "Cannot expand \"${{{variable}}}\" in \"{template}\": The variable is not set or not closed."
+            ),
+            Error::DestinationLocked(path) => write!(
+                f,
+                "ABORTED: Another run of safeall is using the destination \"{}\". Wait until it has finished.",
+                path.display()
+            ),
+            Error::CannotLockDestination(path, io_error) => write!(
+                f,
+                "Cannot lock the destination \"{}\": {io_error}.",
+                path.display()
+            ),
+            Error::TooManyErrors { errors, max } => write!(
+                f,
+                "ABORTED: {errors} errors occured, more than the allowed {max}. Check that the source and destination are still connected."
+            ),
+            Error::PathNotInSource { path, source_root } => write!(
+                f,
+                "Cannot back up \"{}\": It is not in the source \"{}\".",
+                path.display(),
+                source_root.display()
+            ),
+            Error::DestinationInconsistent(path) => write!(
+                f,
+                "The destination \"{}\" does not match the source. Run a sync to repair it.",
+                path.display()
+            ),
+            Error::NoManifest(path) => write!(
+                f,
+                "The destination \"{}\" has no manifest with hashes. Run a backup to create it.",
+                path.display()
+            ),
+            Error::DestinationCorrupted(path) => write!(
+                f,
+                "Files in the destination \"{}\" are corrupted or missing.",
+                path.display()
+            ),
+            Error::EmptyRetentionPolicy => write!(
+                f,
+                "The retention policy would remove all snapshots. Keep at least one."
+            ),
+            Error::NotARepository(path) => write!(
+                f,
+                "\"{}\" is not a repository. Create it with a snapshot first.",
+                path.display()
+            ),
+            Error::NoSnapshot(path) => {
+                write!(f, "The repository \"{}\" has no snapshots.", path.display())
+            }
+            Error::SnapshotNotFound {
+                repository_root,
+                id,
+            } => write!(
+                f,
+                "The repository \"{}\" has no snapshot \"{id}\".",
+                repository_root.display()
+            ),
+            Error::SnapshotDamaged(path) => {
+                write!(f, "The snapshot \"{}\" is damaged.", path.display())
+            }
+            Error::CannotWriteSnapshot(path, io_error) => write!(
+                f,
+                "Cannot write the snapshot \"{}\": {io_error}.",
+                path.display()
+            ),
+            Error::CannotWriteArchive(path, io_error) => write!(
+                f,
+                "Cannot write the archive \"{}\": {io_error}.",
+                path.display()
+            ),
+            Error::ProblemsFound(problems) => write!(
+                f,
+                "Found {problems} problems which would make a backup fail, see above."
+            ),
+            Error::CannotCollectGarbage(path, io_error) => write!(
+                f,
+                "Cannot collect the garbage of the repository \"{}\": {io_error}.",
+                path.display()
+            ),
+            Error::SecretRequired(path) => write!(
+                f,
+                "The repository \"{}\" is encrypted, give its passphrase or key file.",
+                path.display()
+            ),
+            Error::WrongSecret(path) => write!(
+                f,
+                "The passphrase or key file does not unlock the repository \"{}\".",
+                path.display()
+            ),
+            Error::RepositoryNotEncrypted(path) => write!(
+                f,
+                "The repository \"{}\" is not encrypted, only new repositories can be encrypted.",
+                path.display()
+            ),
+            Error::CannotReadKeyFile(path, io_error) => write!(
+                f,
+                "Cannot read the key file \"{}\": {io_error}.",
+                path.display()
+            ),
+            Error::CannotWriteKeys(path, io_error) => write!(
+                f,
+                "Cannot write the keys of the repository \"{}\": {io_error}.",
+                path.display()
+            ),
+            Error::KeyNotFound {
+                repository_root,
+                id,
+            } => write!(
+                f,
+                "The repository \"{}\" has no key with the ID {id}.",
+                repository_root.display()
+            ),
+            Error::CannotRemoveLastKey(path) => write!(
+                f,
+                "Cannot remove the last key of the repository \"{}\", nobody could decrypt it anymore.",
+                path.display()
+            ),
+            Error::UnsupportedRepositoryVersion {
+                repository_root,
+                version,
+            } => write!(
+                f,
+                "The repository \"{}\" has the format version \"{version}\", but this version of safeall only reads versions up to {}. Update safeall.",
+                repository_root.display(),
+                repo::REPOSITORY_VERSION
+            ),
+            Error::RepositoryDamaged(path) => write!(
+                f,
+                "Snapshots of the repository \"{}\" cannot be restored completely.",
+                path.display()
+            ),
+            Error::BackupDoesNotExist(path) => write!(
+                f,
+                "There is no backup at \"{}\" to restore from.",
+                path.display()
+            ),
+            Error::RestoreTargetNotEmpty(path) => write!(
+                f,
+                "ABORTED: \"{}\" is not empty. Restore into an empty directory or allow merging.",
+                path.display()
+            ),
+            Error::RestoreTargetOnOtherFilesystem {
+                target_root,
+                backup_root,
+            } => write!(
+                f,
+                "ABORTED: \"{}\" is not on the same drive as the backup \"{}\".",
+                target_root.display(),
+                backup_root.display()
+            ),
+            Error::RestoreTargetOnBackupFilesystem {
+                target_root,
+                backup_root,
+            } => write!(
+                f,
+                "ABORTED: \"{}\" is on the same drive as the backup \"{}\". Check the paths and that the target drive is mounted.",
+                target_root.display(),
+                backup_root.display()
+            ),
+        }
+    }
 }
 
-async fn create_all_directories_in_destination(
+#[allow(clippy::too_many_lines)]
+async fn backup_all_files(
     source_directory_root: &std::path::Path,
     destination_directory_root: &std::path::Path,
+    failed_source_directories: &[&std::path::Path],
+    previous_errors: usize,
+    filter: &filter::Filter,
+    options: &BackupOptions,
     message_sender: &impl MessageSender,
 ) -> Result<Vec<ProcessPathError>, Error> {
-    use futures::StreamExt;
+    use futures::stream::StreamExt;
 
     debug_assert!(source_directory_root.is_dir(), "Source is not a dir");
     debug_assert!(
         destination_directory_root.is_dir(),
         "Destination is not a dir"
     );
-    let source_recurse_directories =
-        RecursiveReadDir::try_new(source_directory_root, ReadDirType::DirectoriesOnly).map_err(
-            |e| Error::CannotReadDirectoryContent(source_directory_root.to_owned(), e.to_string()),
-        )?;
+    let source_recurse_files =
+        RecursiveReadDir::try_new(source_directory_root, ReadDirType::FilesOnly)
+            .map_err(|e| {
+                Error::CannotReadDirectoryContent(source_directory_root.to_owned(), e.to_string())
+            })?
+            .with_filter(filter.clone())
+            .with_error_policy(options.read_errors)
+            .with_read_timeout(options.stall_timeout)
+            .skipping_special_files(options.special_files == SpecialFilePolicy::Skip);
 
-    let num_dirs = futures::stream::iter(source_recurse_directories)
-        .count()
-        .await;
+    let mut scan_progress = scan::ScanProgress::start(message_sender);
+    let (num_files, num_bytes) =
+        source_recurse_files.fold((0, 0), |(files, bytes), source_file| {
+            scan_progress.entry(&source_file);
+            let size = source_file
+                .ok()
+                .and_then(|source_file| std::fs::metadata(source_file).ok())
+                .map_or(0, |metadata| metadata.len());
+            (files + 1, bytes + size)
+        });
+    scan_progress.end();
     message_sender.send(Message::Progress(Progress::Start(
-        num_dirs,
-        ProgressType::CreatingDirectories,
+        num_files,
+        ProgressType::CopingFiles,
     )));
-
-    let source_recurse_directories =
-        RecursiveReadDir::try_new(source_directory_root, ReadDirType::DirectoriesOnly).map_err(
-            |e| Error::CannotReadDirectoryContent(source_directory_root.to_owned(), e.to_string()),
-        )?;
-    let mut source_stream = futures::stream::iter(source_recurse_directories);
-    let mut errors = vec![];
-    while let Some(source_directory) = source_stream.next().await {
-        match source_directory {
-            Ok(source_directory) => {
-                if errors.iter().any(|e: &ProcessPathError| {
-                    e.not_processed
-                        .as_ref()
-                        .is_some_and(|p| source_directory.starts_with(p))
-                }) {
-                    continue;
+    message_sender.send(Message::Progress(Progress::TotalBytes(
+        num_bytes,
+        ProgressType::CopingFiles,
+    )));
+    let source_recurse_files =
+        RecursiveReadDir::try_new(source_directory_root, ReadDirType::FilesOnly)
+            .map_err(|e| {
+                Error::CannotReadDirectoryContent(source_directory_root.to_owned(), e.to_string())
+            })?
+            .with_filter(filter.clone())
+            .with_error_policy(options.read_errors)
+            .with_read_timeout(options.stall_timeout)
+            .skipping_special_files(options.special_files == SpecialFilePolicy::Skip);
+    let unchanged = compare_existing_files(
+        source_directory_root,
+        destination_directory_root,
+        failed_source_directories,
+        filter,
+        options,
+        message_sender,
+    )
+    .await?;
+    let governor = governor::Governor::new(options.power_aware_throttling, cpu_count());
+    let hard_links = std::sync::Mutex::new(std::collections::HashMap::new());
+    let mut results = futures::stream::iter(source_recurse_files)
+        .map(async |source_file| {
+            let source_file = source_file?;
+            if failed_source_directories
+                .iter()
+                .any(|d| source_file.starts_with(d))
+            {
+                // Do not try to copy files for directories that do not exist, fail instantly
+                let error = ProcessPathError {
+                    not_processed: Some(source_file.clone()),
+                    kind: ProcessPathErrorKind::CannotCopyFileDirectoyNotExisting,
+                };
+                message_sender.send(Message::Progress(Progress::IncrementFail(error.clone())));
+                return Err(error);
+            }
+            if options.preserve_hard_links
+                && let Some(id) = hard_link_id(&source_file).await
+            {
+                let mut hard_links = hard_links.lock().expect("Lock is never poisoned");
+                if let Some(first) = hard_links.get(&id) {
+                    let outcome = CopyOutcome::HardLinkOf(std::path::PathBuf::clone(first));
+                    return Ok((source_file, outcome));
                 }
-                if let Err(err) = create_single_directory_if_not_exists(
-                    source_directory_root,
-                    destination_directory_root,
-                    source_directory,
-                    message_sender,
-                )
-                .await
+                hard_links.insert(id, source_file.clone());
+            }
+            if let Some(&reason) = unchanged.get(&source_file) {
+                message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                    Increment::SkippingFileNoModification {
+                        destination: get_destination_file_path(
+                            destination_directory_root,
+                            source_directory_root,
+                            &source_file,
+                        )?,
+                        bytes: file_size(&source_file).await,
+                        source: source_file.clone(),
+                        reason,
+                    },
+                )));
+                return Ok((source_file, CopyOutcome::Consistent));
+            }
+            let permit = governor.acquire(message_sender).await;
+            let result = backup_single_file(
+                source_directory_root,
+                destination_directory_root,
+                source_file.clone(),
+                options,
+                &permit.meter(message_sender),
+            )
+            .await
+            .inspect_err(|e| {
+                message_sender.send(Message::Progress(Progress::IncrementFail(e.clone())));
+            });
+            permit.limit_bandwidth().await;
+            result.map(|outcome| (source_file, outcome))
+        })
+        .buffer_unordered(cpu_count());
+    let mut errors = vec![];
+    let mut changed_files = vec![];
+    let mut hard_linked_files = vec![];
+    while let Some(result) = results.next().await {
+        match result {
+            Ok((source_file, CopyOutcome::SourceChanged)) => changed_files.push(source_file),
+            Ok((source_file, CopyOutcome::HardLinkOf(first))) => {
+                hard_linked_files.push((source_file, first));
+            }
+            Ok((_, CopyOutcome::Consistent)) => {}
+            Err(error) => {
+                errors.push(error);
+                if let Err(error) =
+                    Error::check_limit(previous_errors + errors.len(), options.max_errors)
                 {
-                    message_sender.send(Message::Progress(Progress::IncrementFail(err.clone())));
-                    errors.push(err);
+                    message_sender.send(Message::Progress(Progress::EndFail(
+                        errors.len(),
+                        ProgressType::CopingFiles,
+                    )));
+                    return Err(error);
                 }
             }
-            Err(err) => {
-                message_sender.send(Message::Progress(Progress::IncrementFail(err.clone())));
-                errors.push(err);
-            }
+        }
+    }
+    // NOTE: Linking only after all files are copied ensures that the linked file exists
+    for (source_file, first) in hard_linked_files {
+        if let Err(error) = backup_hard_link(
+            source_directory_root,
+            destination_directory_root,
+            source_file,
+            &first,
+            options,
+            message_sender,
+        )
+        .await
+        {
+            message_sender.send(Message::Progress(Progress::IncrementFail(error.clone())));
+            errors.push(error);
         }
     }
     if errors.is_empty() {
         message_sender.send(Message::Progress(Progress::EndSuccess(
-            ProgressType::CreatingDirectories,
+            ProgressType::CopingFiles,
         )));
     } else {
         message_sender.send(Message::Progress(Progress::EndFail(
             errors.len(),
-            ProgressType::CreatingDirectories,
+            ProgressType::CopingFiles,
         )));
     }
+    for _ in 0..options.retry_passes {
+        if errors.iter().all(|e| !is_retryable(e)) && changed_files.is_empty() {
+            break;
+        }
+        (errors, changed_files) = retry_files(
+            source_directory_root,
+            destination_directory_root,
+            errors,
+            changed_files,
+            options,
+            message_sender,
+        )
+        .await;
+    }
     Ok(errors)
 }
 
-async fn create_single_directory_if_not_exists(
+/// Compares the files which already exist in the destination in a phase of their own when
+/// the comparator reads them, as this takes most of the time of a run with few changes.
+/// Returns the source files which do not have to be copied, with the reason.
+///
+/// Links, special files and `SQLite` databases copied with their write-ahead log are left to
+/// the copy phase.
+async fn compare_existing_files(
     source_directory_root: &std::path::Path,
     destination_directory_root: &std::path::Path,
-    source_directory: std::path::PathBuf,
+    failed_source_directories: &[&std::path::Path],
+    filter: &filter::Filter,
+    options: &BackupOptions,
     message_sender: &impl MessageSender,
-) -> Result<(), ProcessPathError> {
-    debug_assert!(source_directory.is_dir(), "Must be a directory");
-    let new_destination_dir = get_destination_file_path(
-        destination_directory_root,
-        source_directory_root,
-        &source_directory,
-    )?;
+) -> Result<std::collections::HashMap<std::path::PathBuf, SkipReason>, Error> {
+    use futures::stream::StreamExt;
 
-    if new_destination_dir.exists() {
-        // If it already exists it must be a directory too
-        if new_destination_dir.is_dir() {
+    if !options.comparator.reads_content() {
+        return Ok(std::collections::HashMap::new());
+    }
+    // NOTE: Unreadable directories are reported by the copy phase
+    let source_recurse_files =
+        RecursiveReadDir::try_new(source_directory_root, ReadDirType::FilesOnly)
+            .map_err(|e| {
+                Error::CannotReadDirectoryContent(source_directory_root.to_owned(), e.to_string())
+            })?
+            .with_filter(filter.clone())
+            .with_error_policy(ErrorPolicy::Ignore)
+            .with_read_timeout(options.stall_timeout);
+    let mut existing = vec![];
+    for source_file in source_recurse_files.flatten() {
+        if failed_source_directories
+            .iter()
+            .any(|d| source_file.starts_with(d))
+            || options.sqlite_consistent_copy && is_sqlite_database_with_wal(&source_file)
+        {
+            continue;
+        }
+        let Ok(destination_file) = get_destination_file_path(
+            destination_directory_root,
+            source_directory_root,
+            &source_file,
+        ) else {
+            continue;
+        };
+        let is_file = |path: &std::path::Path| {
+            std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_file())
+        };
+        if is_file(&source_file) && is_file(&destination_file) {
+            existing.push((source_file, destination_file));
+        }
+    }
+    if existing.is_empty() {
+        return Ok(std::collections::HashMap::new());
+    }
+
+    message_sender.send(Message::Progress(Progress::Start(
+        existing.len(),
+        ProgressType::Hashing,
+    )));
+    let unchanged = futures::stream::iter(existing)
+        .map(async |(source_file, destination_file)| {
+            let decision =
+                decide_copy(&source_file, &destination_file, options, message_sender).await;
+            let bytes = tokio::fs::metadata(&source_file)
+                .await
+                .map_or(0, |metadata| metadata.len());
             message_sender.send(Message::Progress(Progress::IncrementSuccess(
-                Increment::DestinationDirAlreadyExists {
-                    source: source_directory.clone(),
-                    destination: new_destination_dir.clone(),
+                Increment::Hashed {
+                    source: source_file.clone(),
+                    bytes,
                 },
             )));
-            Ok(())
-        } else {
-            Err(ProcessPathError {
-                not_processed: Some(source_directory.clone()),
-                kind: ProcessPathErrorKind::DestinationForSourceDirExistsAsFile {
-                    destination: new_destination_dir,
-                },
-            })
+            match decision {
+                Decision::Skip(reason) => Some((source_file, reason)),
+                Decision::Copy(_) => None,
+            }
+        })
+        .buffer_unordered(cpu_count())
+        .filter_map(std::future::ready)
+        .collect()
+        .await;
+    message_sender.send(Message::Progress(Progress::EndSuccess(
+        ProgressType::Hashing,
+    )));
+    Ok(unchanged)
+}
+
+/// Whether copying the file might succeed later, e.g. when it is not locked by another
+/// program anymore.
+fn is_retryable(error: &ProcessPathError) -> bool {
+    error.not_processed.is_some()
+        && matches!(
+            error.kind,
+            ProcessPathErrorKind::CannotCopyFile { .. }
+                | ProcessPathErrorKind::VerificationFailed { .. }
+        )
+}
+
+/// Copies the files again which failed or changed while they were copied, such that
+/// transient contention does not leave holes in the backup.
+/// Returns the remaining errors and the files which changed again.
+async fn retry_files(
+    source_directory_root: &std::path::Path,
+    destination_directory_root: &std::path::Path,
+    errors: Vec<ProcessPathError>,
+    changed_files: Vec<std::path::PathBuf>,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) -> (Vec<ProcessPathError>, Vec<std::path::PathBuf>) {
+    let (retryable, mut remaining_errors): (Vec<_>, Vec<_>) =
+        errors.into_iter().partition(is_retryable);
+    let files: Vec<_> = retryable
+        .into_iter()
+        .filter_map(|e| e.not_processed)
+        .chain(changed_files)
+        .collect();
+    message_sender.send(Message::Progress(Progress::Start(
+        files.len(),
+        ProgressType::RetryingFiles,
+    )));
+    let mut errors = vec![];
+    let mut changed_files = vec![];
+    for source_file in files {
+        match backup_single_file(
+            source_directory_root,
+            destination_directory_root,
+            source_file.clone(),
+            options,
+            message_sender,
+        )
+        .await
+        {
+            Ok(CopyOutcome::Consistent | CopyOutcome::HardLinkOf(_)) => {}
+            Ok(CopyOutcome::SourceChanged) => changed_files.push(source_file),
+            Err(error) => {
+                message_sender.send(Message::Progress(Progress::IncrementFail(error.clone())));
+                errors.push(error);
+            }
         }
+    }
+    if errors.is_empty() {
+        message_sender.send(Message::Progress(Progress::EndSuccess(
+            ProgressType::RetryingFiles,
+        )));
     } else {
-        message_sender.send(Message::Info(Info::StartCreatingDir {
-            source: source_directory.clone(),
-            destination: new_destination_dir.clone(),
+        message_sender.send(Message::Progress(Progress::EndFail(
+            errors.len(),
+            ProgressType::RetryingFiles,
+        )));
+    }
+    remaining_errors.extend(errors);
+    (remaining_errors, changed_files)
+}
+
+/// Whether the source file stayed the same while it was copied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CopyOutcome {
+    Consistent,
+    SourceChanged,
+    /// The file is a hard link of the given file, which has been copied instead.
+    HardLinkOf(std::path::PathBuf),
+}
+
+/// Device and inode of a file with more than one hard link.
+#[cfg(unix)]
+async fn hard_link_id(path: &std::path::Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+async fn hard_link_id(_path: &std::path::Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// Links the destination of `source_file` to the destination of `first`, which is the same
+/// file in the source. Falls back to a copy when the destination does not support hard links.
+async fn backup_hard_link(
+    source_directory_root: &std::path::Path,
+    destination_directory_root: &std::path::Path,
+    source_file: std::path::PathBuf,
+    first: &std::path::Path,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) -> Result<(), ProcessPathError> {
+    let destination_file = get_destination_file_path(
+        destination_directory_root,
+        source_directory_root,
+        &source_file,
+    )?;
+    let link_to =
+        get_destination_file_path(destination_directory_root, source_directory_root, first)?;
+    let destination_id = hard_link_id(&destination_file).await;
+    if destination_id.is_some() && destination_id == hard_link_id(&link_to).await {
+        message_sender.send(Message::Progress(Progress::IncrementSuccess(
+            Increment::SkippingFileNoModification {
+                bytes: file_size(&source_file).await,
+                source: source_file,
+                destination: destination_file,
+                reason: SkipReason::AlreadyLinked,
+            },
+        )));
+        return Ok(());
+    }
+    read_only::check(&destination_file)?;
+    let linked = async {
+        if tokio::fs::symlink_metadata(&destination_file).await.is_ok() {
+            tokio::fs::remove_file(&destination_file).await?;
+        }
+        tokio::fs::hard_link(&link_to, &destination_file).await
+    }
+    .await;
+    if linked.is_ok() {
+        message_sender.send(Message::Progress(Progress::IncrementSuccess(
+            Increment::HardLinked {
+                source: source_file,
+                destination: destination_file,
+                link_to,
+            },
+        )));
+        return Ok(());
+    }
+    backup_single_file(
+        source_directory_root,
+        destination_directory_root,
+        source_file,
+        options,
+        message_sender,
+    )
+    .await
+    .map(|_| ())
+}
+
+async fn backup_single_file(
+    source_directory_root: &std::path::Path,
+    destination_directory_root: &std::path::Path,
+    source_file: std::path::PathBuf,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) -> Result<CopyOutcome, ProcessPathError> {
+    debug_assert!(
+        !source_file.is_dir() || source_file.is_symlink(),
+        "Must be a file or a symbolic link"
+    );
+
+    let new_destination_file = get_destination_file_path(
+        destination_directory_root,
+        source_directory_root,
+        &source_file,
+    )?;
+
+    // NOTE: Only names next to the descriptor of their original are parts of a split file
+    if let Some(original) = split::original(&source_file)
+        && let Some(destination_original) = split::original(&new_destination_file)
+        && split::descriptor_path(&original).is_file()
+    {
+        if split::is_descriptor(&source_file) {
+            return join_or_skip_if_same(
+                &source_file,
+                &original,
+                &destination_original,
+                options,
+                message_sender,
+            )
+            .await;
+        }
+        message_sender.send(Message::Progress(Progress::IncrementSuccess(
+            Increment::PartOfSplitFile {
+                source: source_file,
+                original,
+            },
+        )));
+        return Ok(CopyOutcome::Consistent);
+    }
+
+    if options.symlinks != SymlinkPolicy::Follow
+        && tokio::fs::symlink_metadata(&source_file)
+            .await
+            .is_ok_and(|metadata| metadata.file_type().is_symlink())
+    {
+        backup_symlink(
+            &source_file,
+            &new_destination_file,
+            options.symlinks,
+            message_sender,
+        )
+        .await?;
+        set_owner(&new_destination_file, options, message_sender);
+        return Ok(CopyOutcome::Consistent);
+    }
+
+    if let Ok(metadata) = tokio::fs::metadata(&source_file).await
+        && special_files::is_special(metadata.file_type())
+    {
+        backup_special_file(
+            &source_file,
+            &new_destination_file,
+            options.special_files,
+            message_sender,
+        )
+        .await?;
+        set_owner(&new_destination_file, options, message_sender);
+        return Ok(CopyOutcome::Consistent);
+    }
+
+    if options.sqlite_consistent_copy {
+        if let Some(database) = sqlite_database_of_wal(&source_file) {
+            // NOTE: The write-ahead log is copied together with its database
+            message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                Increment::CopiedWithSqliteDatabase {
+                    source: source_file,
+                    database,
+                },
+            )));
+            return Ok(CopyOutcome::Consistent);
+        }
+        if sqlite_wal_path(&source_file).exists() && is_sqlite_database(&source_file) {
+            return copy_sqlite_database_consistently(
+                &source_file,
+                &new_destination_file,
+                options,
+                message_sender,
+            )
+            .await
+            .map(|()| CopyOutcome::Consistent);
+        }
+    }
+
+    copy_or_skip_if_same(&source_file, &new_destination_file, options, message_sender).await
+}
+
+async fn create_all_directories_in_destination(
+    source_directory_root: &std::path::Path,
+    destination_directory_root: &std::path::Path,
+    filter: &filter::Filter,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) -> Result<Vec<ProcessPathError>, Error> {
+    use futures::StreamExt;
+
+    debug_assert!(source_directory_root.is_dir(), "Source is not a dir");
+    debug_assert!(
+        destination_directory_root.is_dir(),
+        "Destination is not a dir"
+    );
+    let source_recurse_directories =
+        RecursiveReadDir::try_new(source_directory_root, ReadDirType::DirectoriesOnly)
+            .map_err(|e| {
+                Error::CannotReadDirectoryContent(source_directory_root.to_owned(), e.to_string())
+            })?
+            .with_filter(filter.clone())
+            .with_error_policy(options.read_errors)
+            .with_read_timeout(options.stall_timeout);
+
+    let mut scan_progress = scan::ScanProgress::start(message_sender);
+    let num_dirs = source_recurse_directories
+        .inspect(|directory| scan_progress.entry(directory))
+        .count();
+    scan_progress.end();
+    message_sender.send(Message::Progress(Progress::Start(
+        num_dirs,
+        ProgressType::CreatingDirectories,
+    )));
+
+    let mut source_recurse_directories =
+        RecursiveReadDir::try_new(source_directory_root, ReadDirType::DirectoriesOnly)
+            .map_err(|e| {
+                Error::CannotReadDirectoryContent(source_directory_root.to_owned(), e.to_string())
+            })?
+            .with_filter(filter.clone())
+            .with_error_policy(options.read_errors)
+            .with_read_timeout(options.stall_timeout);
+    let mut source_stream = futures::stream::iter(&mut source_recurse_directories);
+    let mut errors = vec![];
+    while let Some(source_directory) = source_stream.next().await {
+        match source_directory {
+            Ok(source_directory) => {
+                if errors.iter().any(|e: &ProcessPathError| {
+                    e.not_processed
+                        .as_ref()
+                        .is_some_and(|p| source_directory.starts_with(p))
+                }) {
+                    continue;
+                }
+                if let Err(err) = create_single_directory_if_not_exists(
+                    source_directory_root,
+                    destination_directory_root,
+                    source_directory,
+                    message_sender,
+                )
+                .await
+                {
+                    message_sender.send(Message::Progress(Progress::IncrementFail(err.clone())));
+                    errors.push(err);
+                }
+            }
+            Err(err) => {
+                message_sender.send(Message::Progress(Progress::IncrementFail(err.clone())));
+                if options.read_errors == ErrorPolicy::FailFast {
+                    message_sender.send(Message::Progress(Progress::EndFail(
+                        1,
+                        ProgressType::CreatingDirectories,
+                    )));
+                    return Err(Error::ProcessPathErrors {
+                        directories: vec![err],
+                        files: vec![],
+                    });
+                }
+                errors.push(err);
+            }
+        }
+        if let Err(error) = Error::check_limit(errors.len(), options.max_errors) {
+            message_sender.send(Message::Progress(Progress::EndFail(
+                errors.len(),
+                ProgressType::CreatingDirectories,
+            )));
+            return Err(error);
+        }
+    }
+    // NOTE: The files pass walks the same directories, so cycles are only reported here
+    for path in source_recurse_directories.cycles() {
+        message_sender.send(Message::Warning(Warning::CycleDetected(path.clone())));
+    }
+    if errors.is_empty() {
+        message_sender.send(Message::Progress(Progress::EndSuccess(
+            ProgressType::CreatingDirectories,
+        )));
+    } else {
+        message_sender.send(Message::Progress(Progress::EndFail(
+            errors.len(),
+            ProgressType::CreatingDirectories,
+        )));
+    }
+    Ok(errors)
+}
+
+async fn create_single_directory_if_not_exists(
+    source_directory_root: &std::path::Path,
+    destination_directory_root: &std::path::Path,
+    source_directory: std::path::PathBuf,
+    message_sender: &impl MessageSender,
+) -> Result<(), ProcessPathError> {
+    debug_assert!(source_directory.is_dir(), "Must be a directory");
+    let new_destination_dir = get_destination_file_path(
+        destination_directory_root,
+        source_directory_root,
+        &source_directory,
+    )?;
+
+    if new_destination_dir.exists() {
+        // If it already exists it must be a directory too
+        if new_destination_dir.is_dir() {
+            message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                Increment::DestinationDirAlreadyExists {
+                    source: source_directory.clone(),
+                    destination: new_destination_dir.clone(),
+                },
+            )));
+            Ok(())
+        } else {
+            Err(ProcessPathError {
+                not_processed: Some(source_directory.clone()),
+                kind: ProcessPathErrorKind::DestinationForSourceDirExistsAsFile {
+                    destination: new_destination_dir,
+                },
+            })
+        }
+    } else {
+        read_only::check(&new_destination_dir)?;
+        message_sender.send(Message::Info(Info::StartCreatingDir {
+            source: source_directory.clone(),
+            destination: new_destination_dir.clone(),
         }));
         tokio::fs::create_dir(&new_destination_dir)
             .await
@@ -547,25 +1829,19 @@ impl FileMetaData {
     }
 }
 
-async fn get_hash(path: &std::path::Path) -> Option<blake3::Hash> {
-    let path = path.to_owned();
-    tokio::task::spawn_blocking(move || {
-        let mut hasher = blake3::Hasher::new();
-        let mut file = std::fs::File::open(path).ok()?;
-        std::io::copy(&mut file, &mut hasher).ok()?;
-        let res = hasher.finalize();
-        Some(res)
-    })
-    .await
-    .ok()?
-}
-
 #[derive(Debug, Clone)]
 pub enum ProgressType {
     CreatingDirectories,
+    Hashing,
     CopingFiles,
+    RetryingFiles,
     DeletingDirs,
     DeletingFiles,
+    Verifying,
+    Scrubbing,
+    /// Counting the entries of the source before processing them. Its
+    /// [`Progress::Start`] has no total.
+    Scanning,
 }
 
 #[derive(Debug, Clone)]
@@ -573,10 +1849,14 @@ pub enum Increment {
     SkippingFileNoModification {
         source: std::path::PathBuf,
         destination: std::path::PathBuf,
+        bytes: u64,
+        reason: SkipReason,
     },
     FileCopied {
         source: std::path::PathBuf,
         destination: std::path::PathBuf,
+        bytes: u64,
+        reason: CopyReason,
     },
     DirCreated {
         source: std::path::PathBuf,
@@ -590,6 +1870,64 @@ pub enum Increment {
     DeletedDir(std::path::PathBuf),
     DirectoryAlreadyDeleted(std::path::PathBuf),
     FileAlreadyDeleted(std::path::PathBuf),
+    CopiedWithSqliteDatabase {
+        source: std::path::PathBuf,
+        database: std::path::PathBuf,
+    },
+    Quarantined {
+        path: std::path::PathBuf,
+        quarantine: std::path::PathBuf,
+    },
+    KeptSuspicious(std::path::PathBuf),
+    SymlinkCreated {
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+        target: std::path::PathBuf,
+    },
+    SymlinkSkipped(std::path::PathBuf),
+    HardLinked {
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+        link_to: std::path::PathBuf,
+    },
+    SpecialFileCreated {
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+    },
+    SpecialFileSkipped(std::path::PathBuf),
+    /// The file is larger than the destination can store.
+    OversizedFileSkipped {
+        source: std::path::PathBuf,
+        size: u64,
+    },
+    /// The file is a part of a split file, which is restored as a whole with its descriptor.
+    PartOfSplitFile {
+        source: std::path::PathBuf,
+        original: std::path::PathBuf,
+    },
+    Hashed {
+        source: std::path::PathBuf,
+        bytes: u64,
+    },
+    /// Sent every few thousand entries while scanning.
+    Scanned {
+        /// The entry which has just been counted.
+        path: std::path::PathBuf,
+        entries: usize,
+    },
+}
+
+impl Increment {
+    /// Size of the file which has been processed, 0 for directories, links and deletions.
+    #[must_use]
+    pub fn bytes(&self) -> u64 {
+        match self {
+            Increment::SkippingFileNoModification { bytes, .. }
+            | Increment::FileCopied { bytes, .. }
+            | Increment::Hashed { bytes, .. } => *bytes,
+            _ => 0,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -603,6 +1941,9 @@ pub enum ProgressEnd {
 #[derive(Debug, Clone)]
 pub enum Progress {
     Start(usize, ProgressType),
+    /// Sent after [`Progress::Start`] if the size of all files of the phase is known, such
+    /// that frontends can weight the progress with [`Increment::bytes`].
+    TotalBytes(u64, ProgressType),
     IncrementSuccess(Increment),
     IncrementFail(ProcessPathError),
     EndSuccess(ProgressType),
@@ -613,6 +1954,9 @@ pub enum Progress {
 impl std::fmt::Display for Progress {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Progress::TotalBytes(bytes, _) => {
+                write!(f, "The files have ~{} in total.", format_bytes(*bytes))
+            }
             Progress::Start(total, progress_type) => match progress_type {
                 ProgressType::CreatingDirectories => {
                     let name = if *total > 1 {
@@ -622,10 +1966,18 @@ impl std::fmt::Display for Progress {
                     };
                     write!(f, "Start creating {total} {name}.")
                 }
+                ProgressType::Hashing => {
+                    let name = if *total > 1 { "files" } else { "file" };
+                    write!(f, "Start comparing {total} {name} with their backup.")
+                }
                 ProgressType::CopingFiles => {
                     let name = if *total > 1 { "files" } else { "file" };
                     write!(f, "Start coping {total} {name}.")
                 }
+                ProgressType::RetryingFiles => {
+                    let name = if *total > 1 { "files" } else { "file" };
+                    write!(f, "Start retrying {total} {name}.")
+                }
                 ProgressType::DeletingDirs => {
                     let name = if *total > 1 {
                         "directories"
@@ -638,31 +1990,51 @@ impl std::fmt::Display for Progress {
                     let name = if *total > 1 { "files" } else { "file" };
                     write!(f, "Start deleting {total} {name}.")
                 }
+                ProgressType::Verifying => {
+                    let name = if *total > 1 { "files" } else { "file" };
+                    write!(f, "Start verifying {total} {name} against their backup.")
+                }
+                ProgressType::Scrubbing => {
+                    let name = if *total > 1 { "files" } else { "file" };
+                    write!(f, "Start scrubbing {total} {name} against the manifest.")
+                }
+                ProgressType::Scanning => write!(f, "Start counting the entries of the source."),
             },
             Progress::EndSuccess(progress_type) => match progress_type {
                 ProgressType::CreatingDirectories => {
                     write!(f, "Finished creating all directories.")
                 }
+                ProgressType::Hashing => write!(f, "Finished comparing all files."),
                 ProgressType::CopingFiles => write!(f, "Finished coping all files."),
+                ProgressType::RetryingFiles => {
+                    write!(f, "Finished retrying all files.")
+                }
                 ProgressType::DeletingDirs => write!(f, "Finished deleting all directories."),
                 ProgressType::DeletingFiles => write!(f, "Finished deleting all files."),
+                ProgressType::Verifying => write!(f, "Finished verifying all files."),
+                ProgressType::Scrubbing => write!(f, "Finished scrubbing all files."),
+                ProgressType::Scanning => write!(f, "Finished counting the entries."),
             },
             Progress::IncrementSuccess(increment) => match increment {
                 Increment::SkippingFileNoModification {
                     source,
                     destination,
+                    reason,
+                    ..
                 } => write!(
                     f,
-                    "Not coping \"{}\" because \"{}\" is up to date.",
+                    "Not coping \"{}\" because \"{}\" is up to date ({reason}).",
                     source.display(),
                     destination.display()
                 ),
                 Increment::FileCopied {
                     source,
                     destination,
+                    reason,
+                    ..
                 } => write!(
                     f,
-                    "Copied \"{}\" to \"{}\".",
+                    "Copied \"{}\" to \"{}\" ({reason}).",
                     source.display(),
                     destination.display()
                 ),
@@ -696,34 +2068,131 @@ impl std::fmt::Display for Progress {
                 Increment::FileAlreadyDeleted(path) => {
                     write!(f, "File \"{}\" has already been deleted.", path.display())
                 }
-            },
-            Progress::IncrementFail(error) => write!(f, "{error}"),
-            Progress::EndFail(failed, progress_type) => match progress_type {
-                ProgressType::CreatingDirectories => {
-                    let name = if *failed > 1 {
-                        "directories"
-                    } else {
-                        "directory"
-                    };
-                    write!(f, "Could not create {failed} {name}.")
-                }
-                ProgressType::CopingFiles => {
-                    let name = if *failed > 1 { "files" } else { "file" };
-
-                    write!(f, "Could not copy {failed} {name}.")
-                }
-                ProgressType::DeletingDirs => {
-                    let name = if *failed > 1 {
-                        "directories"
-                    } else {
-                        "directory"
-                    };
-                    write!(f, "Could not delete {failed} {name}.")
+                Increment::CopiedWithSqliteDatabase { source, database } => write!(
+                    f,
+                    "\"{}\" is copied together with the database \"{}\".",
+                    source.display(),
+                    database.display()
+                ),
+                Increment::Quarantined { path, quarantine } => write!(
+                    f,
+                    "Moved \"{}\" to the quarantine \"{}\" instead of deleting it.",
+                    path.display(),
+                    quarantine.display()
+                ),
+                Increment::KeptSuspicious(path) => write!(
+                    f,
+                    "Kept \"{}\" as it has never been backed up by safeall.",
+                    path.display()
+                ),
+                Increment::SymlinkCreated {
+                    source,
+                    destination,
+                    target,
+                } => write!(
+                    f,
+                    "Created symbolic link \"{}\" to \"{}\" to backup \"{}\".",
+                    destination.display(),
+                    target.display(),
+                    source.display()
+                ),
+                Increment::SymlinkSkipped(path) => {
+                    write!(f, "Skipped symbolic link \"{}\".", path.display())
+                }
+                Increment::HardLinked {
+                    source,
+                    destination,
+                    link_to,
+                } => write!(
+                    f,
+                    "Linked \"{}\" to \"{}\" to backup \"{}\".",
+                    destination.display(),
+                    link_to.display(),
+                    source.display()
+                ),
+                Increment::SpecialFileCreated {
+                    source,
+                    destination,
+                } => write!(
+                    f,
+                    "Created special file \"{}\" like \"{}\".",
+                    destination.display(),
+                    source.display()
+                ),
+                Increment::SpecialFileSkipped(path) => {
+                    write!(f, "Skipped special file \"{}\".", path.display())
+                }
+                Increment::OversizedFileSkipped { source, size } => write!(
+                    f,
+                    "Skipped \"{}\" ({}) which is too large for the destination.",
+                    source.display(),
+                    format_bytes(*size)
+                ),
+                Increment::PartOfSplitFile { source, original } => write!(
+                    f,
+                    "\"{}\" is restored together with the other parts of \"{}\".",
+                    source.display(),
+                    original.display()
+                ),
+                Increment::Hashed { source, bytes } => write!(
+                    f,
+                    "Compared \"{}\" with its backup ({}).",
+                    source.display(),
+                    format_bytes(*bytes)
+                ),
+                Increment::Scanned { path, entries } => write!(
+                    f,
+                    "Counted {entries} entries, the last one is \"{}\".",
+                    path.display()
+                ),
+            },
+            Progress::IncrementFail(error) => write!(f, "{error}"),
+            Progress::EndFail(failed, progress_type) => match progress_type {
+                ProgressType::CreatingDirectories => {
+                    let name = if *failed > 1 {
+                        "directories"
+                    } else {
+                        "directory"
+                    };
+                    write!(f, "Could not create {failed} {name}.")
+                }
+                ProgressType::Hashing => {
+                    let name = if *failed > 1 { "files" } else { "file" };
+                    write!(f, "Could not compare {failed} {name}.")
+                }
+                ProgressType::CopingFiles => {
+                    let name = if *failed > 1 { "files" } else { "file" };
+
+                    write!(f, "Could not copy {failed} {name}.")
+                }
+                ProgressType::RetryingFiles => {
+                    let name = if *failed > 1 { "files" } else { "file" };
+                    write!(f, "Could not copy {failed} {name} after retrying.")
+                }
+                ProgressType::DeletingDirs => {
+                    let name = if *failed > 1 {
+                        "directories"
+                    } else {
+                        "directory"
+                    };
+                    write!(f, "Could not delete {failed} {name}.")
                 }
                 ProgressType::DeletingFiles => {
                     let name = if *failed > 1 { "files" } else { "file" };
                     write!(f, "Could not delete {failed} {name}.")
                 }
+                ProgressType::Verifying => {
+                    let name = if *failed > 1 { "files" } else { "file" };
+                    write!(f, "Could not verify {failed} {name}.")
+                }
+                ProgressType::Scrubbing => {
+                    let name = if *failed > 1 { "files" } else { "file" };
+                    write!(f, "Could not scrub {failed} {name}.")
+                }
+                ProgressType::Scanning => {
+                    let name = if *failed > 1 { "entries" } else { "entry" };
+                    write!(f, "Could not count {failed} {name}.")
+                }
             },
         }
     }
@@ -743,9 +2212,83 @@ pub enum Info {
         source: std::path::PathBuf,
         destination: std::path::PathBuf,
     },
+    StartCopingSqliteDatabase {
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+    },
+    ThrottlingStarted(PowerState),
+    ThrottlingStopped,
+    /// The first message of every run.
+    RunStarted(RunId),
+    Estimate(Estimate),
+    Planned(PlannedAction),
+    RestoreDiff(RestoreDiff),
+    Scanned {
+        root: std::path::PathBuf,
+        summary: ScanSummary,
+    },
+    Report(RunReport),
+    Verified(VerifyReport),
+    Scrubbed(ScrubReport),
+    Pruned(PruneReport),
+    SnapshotCreated {
+        snapshot: SnapshotSummary,
+        /// Size of the chunks which were not in the repository yet.
+        added_bytes: u64,
+        /// Size of these chunks in the repository, which is less if they are compressed.
+        stored_bytes: u64,
+    },
+    Snapshots(Vec<SnapshotSummary>),
+    /// What the doctor found, nothing if there are no problems.
+    Diagnosis(Vec<Finding>),
+    ArchiveCreated {
+        archive: std::path::PathBuf,
+        files: usize,
+        /// Size of the files before compression.
+        bytes: u64,
+    },
+    GarbageCollected(GarbageCollected),
+    /// The passphrases and key files which unlock a repository.
+    Keys(Vec<RegisteredKey>),
+    KeyAdded(RegisteredKey),
+    /// The ID of the removed key.
+    KeyRemoved(String),
+    RepositoryChecked(RepositoryCheck),
+    Repaired {
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+    },
+    /// All files have been copied and the run waits for the deletions to be approved, see
+    /// [`BackupOptions::interactive_delete`].
+    PurgeCheckpoint {
+        destination_root: std::path::PathBuf,
+        checkpoint: Checkpoint,
+    },
+    PurgeSkipped(std::path::PathBuf),
+    ExcludeSuggestions(Vec<ExcludeSuggestion>),
+    /// Files which have been backed up with a caveat or not at all, sent at the end of a run.
+    NeedsAttention(Vec<Attention>),
+    /// A file has been renamed instead of being overwritten, see
+    /// [`BackupOptions::keep_overwritten`].
+    KeptOverwritten {
+        path: std::path::PathBuf,
+        kept: std::path::PathBuf,
+    },
+    /// A file has been stored in parts as it is larger than the destination can store, see
+    /// [`OversizedFilePolicy::Split`].
+    FileSplit {
+        destination: std::path::PathBuf,
+        parts: u64,
+    },
+    /// The parts of a split file have been joined again.
+    FileJoined {
+        destination: std::path::PathBuf,
+        parts: u64,
+    },
 }
 
 impl std::fmt::Display for Info {
+    #[allow(clippy::too_many_lines)]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Info::CreatingDestinationDir(path) => {
@@ -778,6 +2321,222 @@ impl std::fmt::Display for Info {
                 destination.display(),
                 source.display()
             ),
+            Info::StartCopingSqliteDatabase {
+                source,
+                destination,
+            } => write!(
+                f,
+                "Start coping database \"{}\" with its write-ahead log to \"{}\".",
+                source.display(),
+                destination.display()
+            ),
+            Info::ThrottlingStarted(state) => {
+                let reason = match (state.on_battery, state.thermal_pressure) {
+                    (true, true) => "on battery and under thermal pressure",
+                    (true, false) => "on battery",
+                    (false, _) => "under thermal pressure",
+                };
+                write!(f, "Slowing down the backup as the machine is {reason}.")
+            }
+            Info::ThrottlingStopped => write!(f, "Continuing the backup at full speed."),
+            Info::RunStarted(run_id) => write!(f, "Started run {run_id}."),
+            Info::Estimate(estimate) => {
+                let name = if estimate.runs > 1 { "runs" } else { "run" };
+                write!(
+                    f,
+                    "Based on the last {} {name} this usually takes ~{} and copies ~{} in {} files.",
+                    estimate.runs,
+                    format_duration(estimate.duration),
+                    format_bytes(estimate.bytes_copied),
+                    estimate.files_copied
+                )
+            }
+            Info::Planned(action) => write!(f, "{action}"),
+            Info::RestoreDiff(diff) => write!(f, "{diff}"),
+            Info::Verified(report) => write!(f, "{report}"),
+            Info::Scrubbed(report) => write!(f, "{report}"),
+            Info::Pruned(report) => write!(f, "{report}"),
+            Info::SnapshotCreated {
+                snapshot,
+                added_bytes,
+                stored_bytes,
+            } => {
+                write!(
+                    f,
+                    "Created snapshot {snapshot}, which added ~{} to the repository",
+                    format_bytes(*stored_bytes)
+                )?;
+                if stored_bytes < added_bytes {
+                    #[allow(clippy::cast_precision_loss)]
+                    let ratio = *added_bytes as f64 / *stored_bytes as f64;
+                    write!(
+                        f,
+                        " (~{} before compression, a ratio of {ratio:.1}:1)",
+                        format_bytes(*added_bytes)
+                    )?;
+                }
+                write!(f, ".")
+            }
+            Info::ArchiveCreated {
+                archive,
+                files,
+                bytes,
+            } => write!(
+                f,
+                "Created the archive \"{}\" with {files} files, ~{} before compression.",
+                archive.display(),
+                format_bytes(*bytes)
+            ),
+            Info::Diagnosis(findings) => {
+                if findings.is_empty() {
+                    return write!(f, "Found no problems.");
+                }
+                write!(f, "Found {} possible problems:", findings.len())?;
+                for finding in findings {
+                    let severity = if finding.severity() == Severity::Error {
+                        "Error"
+                    } else {
+                        "Warning"
+                    };
+                    write!(f, "\n  {severity}: {finding}")?;
+                }
+                Ok(())
+            }
+            Info::Snapshots(snapshots) => {
+                write!(f, "The repository has {} snapshots:", snapshots.len())?;
+                for snapshot in snapshots {
+                    write!(f, "\n  {snapshot}")?;
+                }
+                Ok(())
+            }
+            Info::GarbageCollected(collected) => write!(f, "{collected}"),
+            Info::Keys(keys) => {
+                write!(f, "The repository has {} keys:", keys.len())?;
+                for key in keys {
+                    write!(f, "\n  {key}")?;
+                }
+                Ok(())
+            }
+            Info::KeyAdded(key) => write!(f, "Added the key {key}."),
+            Info::KeyRemoved(id) => write!(f, "Removed the key {id}."),
+            Info::RepositoryChecked(report) => write!(f, "{report}"),
+            Info::PurgeCheckpoint {
+                destination_root, ..
+            } => write!(
+                f,
+                "All files have been copied. Delete what is not in the source anymore from \"{}\"?",
+                destination_root.display()
+            ),
+            Info::PurgeSkipped(destination_root) => write!(
+                f,
+                "Did not delete anything from \"{}\" because the deletions have not been approved.",
+                destination_root.display()
+            ),
+            Info::Repaired {
+                source,
+                destination,
+            } => write!(
+                f,
+                "Repaired \"{}\" from \"{}\".",
+                destination.display(),
+                source.display()
+            ),
+            Info::NeedsAttention(files) => {
+                write!(f, "{} files need attention:", files.len())?;
+                for file in files {
+                    write!(f, "\n  \"{}\": {}", file.path.display(), file.reason)?;
+                }
+                Ok(())
+            }
+            Info::KeptOverwritten { path, kept } => write!(
+                f,
+                "Kept the previous version of \"{}\" as \"{}\".",
+                path.display(),
+                kept.display()
+            ),
+            Info::FileSplit { destination, parts } => write!(
+                f,
+                "Stored \"{}\" in {parts} parts as the destination cannot store it as a whole.",
+                destination.display()
+            ),
+            Info::FileJoined { destination, parts } => write!(
+                f,
+                "Joined {parts} parts into \"{}\".",
+                destination.display()
+            ),
+            Info::ExcludeSuggestions(suggestions) => {
+                write!(f, "Consider excluding what has been copied in this run:")?;
+                for suggestion in suggestions {
+                    write!(
+                        f,
+                        "\n  \"{}\" ({}): {} files with ~{}",
+                        suggestion.pattern,
+                        suggestion.reason,
+                        suggestion.files,
+                        format_bytes(suggestion.bytes)
+                    )?;
+                }
+                Ok(())
+            }
+            Info::Scanned { root, summary } => write!(
+                f,
+                "\"{}\" contains {} files in {} directories with ~{}.",
+                root.display(),
+                summary.files,
+                summary.dirs,
+                format_bytes(summary.bytes)
+            ),
+            Info::Report(report) => {
+                write!(
+                    f,
+                    "Run {} finished in {}: scanning {}, comparing {}, copying {}, deleting {}.",
+                    report.run_id,
+                    format_duration(report.duration),
+                    format_duration(report.scan),
+                    format_duration(report.hash),
+                    format_duration(report.copy),
+                    format_duration(report.purge)
+                )?;
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                if report.bytes_copied > 0
+                    && let Some(throughput) = report.throughput()
+                {
+                    write!(
+                        f,
+                        " Copied {} at {}/s.",
+                        format_bytes(report.bytes_copied),
+                        format_bytes(throughput as u64)
+                    )?;
+                }
+                for (category, stats) in &report.categories {
+                    write!(
+                        f,
+                        "\n  {category}: {} files copied ({}), {} unchanged ({}).",
+                        stats.files_copied,
+                        format_bytes(stats.bytes_copied),
+                        stats.files_skipped,
+                        format_bytes(stats.bytes_skipped)
+                    )?;
+                }
+                if !report.copy_reasons.is_empty() {
+                    let reasons = report
+                        .copy_reasons
+                        .iter()
+                        .map(|(reason, files)| format!("{reason} ({files})"))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    write!(f, "\n  Copied because of: {reasons}.")?;
+                }
+                if !report.deleted_files.is_empty() || !report.deleted_directories.is_empty() {
+                    write!(
+                        f,
+                        "\n  Deleted {} files and {} directories with everything in them, as they are not in the source anymore.",
+                        report.deleted_files.len(),
+                        report.deleted_directories.len()
+                    )?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -798,8 +2557,108 @@ pub enum Warning {
         source: std::path::PathBuf,
         destination: std::path::PathBuf,
     },
+    CannotCopyPermissions {
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+        io_error: String,
+    },
+    CannotSetOwner {
+        path: std::path::PathBuf,
+        owner: Owner,
+        io_error: String,
+    },
+    ClockSkew {
+        destination_root: std::path::PathBuf,
+        skew: ClockSkew,
+    },
+    CannotReadBackup {
+        path: std::path::PathBuf,
+        io_error: String,
+    },
+    CannotWriteParity {
+        destination_root: std::path::PathBuf,
+        io_error: String,
+    },
+    CannotPrune {
+        snapshot: std::path::PathBuf,
+        io_error: String,
+    },
+    SourceChangedSinceBackup {
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+    },
+    SqliteDatabaseChangedDuringCopy {
+        source: std::path::PathBuf,
+        attempts: usize,
+    },
+    CannotWriteRunHistory {
+        path: std::path::PathBuf,
+        io_error: String,
+    },
+    SuspiciousDeletions {
+        count: usize,
+        policy: SuspiciousDeletionPolicy,
+    },
+    CannotWriteManifest {
+        destination_root: std::path::PathBuf,
+        io_error: String,
+    },
+    CannotWriteHashCache {
+        destination_root: std::path::PathBuf,
+        io_error: String,
+    },
+    CannotWriteScanCache {
+        destination_root: std::path::PathBuf,
+        io_error: String,
+    },
+    CannotRememberDestination {
+        path: std::path::PathBuf,
+        io_error: String,
+    },
+    SourceChangedDuringCopy(std::path::PathBuf),
+    /// The source has been deleted between the scan and its copy.
+    SourceVanished(std::path::PathBuf),
+    SymlinkSkipped(std::path::PathBuf),
+    /// A file larger than the filesystem of the destination can store is not backed up.
+    OversizedFileSkipped {
+        path: std::path::PathBuf,
+        size: u64,
+        max_size: u64,
+    },
+    /// The parts of a file which used to be split could not be removed after copying it as a
+    /// whole.
+    CannotRemoveSplitFile {
+        path: std::path::PathBuf,
+        io_error: String,
+    },
+    CannotCopyExtendedAttributes {
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+        io_error: String,
+    },
+    CannotCopyFileAttributes {
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+        io_error: String,
+    },
+    SpecialPermissionsDropped {
+        destination: std::path::PathBuf,
+        dropped: Vec<&'static str>,
+    },
+    CannotCompare {
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+        io_error: String,
+    },
+    CycleDetected(std::path::PathBuf),
+    OperationStalled {
+        activity: Activity,
+        timeout: std::time::Duration,
+        cancelled: bool,
+    },
 }
 
+#[allow(clippy::too_many_lines)]
 impl std::fmt::Display for Warning {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -847,65 +2706,462 @@ impl std::fmt::Display for Warning {
                 source.display(),
                 destination.display()
             ),
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
-pub enum Message {
-    Warning(Warning),
-    Info(Info),
-    Progress(Progress),
-}
-
-async fn skip_copy(
-    source_file: &std::path::Path,
-    destination_file: &std::path::Path,
-    source_metadata: Option<&FileMetaData>,
-    message_sender: &impl MessageSender,
-) -> bool {
-    if !destination_file.exists() {
-        return false;
-    }
-    let destination_metadata = FileMetaData::try_new(destination_file).await;
-    if destination_metadata.is_none() && source_metadata.is_some()
-        || destination_metadata.is_some() && source_metadata.is_none()
-    {
-        message_sender.send(Message::Warning(Warning::CannotGetMetadata {
-            source: source_file.to_owned(),
-            destination: destination_file.to_owned(),
-            copy_anyway: true,
-        }));
-        return false;
-    }
-
-    if source_metadata != FileMetaData::try_new(destination_file).await.as_ref() {
-        return false;
-    }
-
-    if let (Some(src_hash), Some(dest_hash)) = (
-        get_hash(source_file).await,
-        get_hash(destination_file).await,
-    ) {
-        if src_hash != dest_hash {
-            return false;
-        }
-    } else {
-        message_sender.send(Message::Warning(Warning::CannotGetHash {
-            source: source_file.to_owned(),
-            destination: destination_file.to_owned(),
-            copy_anyway: true,
-        }));
-    }
-
-    true
+            Warning::CannotCopyPermissions {
+                source,
+                destination,
+                io_error,
+            } => write!(
+                f,
+                "Cannot copy the permissions from \"{}\" to \"{}\": {io_error}.",
+                source.display(),
+                destination.display()
+            ),
+            Warning::CannotSetOwner {
+                path,
+                owner,
+                io_error,
+            } => write!(
+                f,
+                "Cannot change the owner of \"{}\" to {owner}: {io_error}.",
+                path.display()
+            ),
+            Warning::ClockSkew {
+                destination_root,
+                skew,
+            } => write!(
+                f,
+                "The clock of the destination \"{}\" is {skew} of this machine. Modification \
+                times it sets itself are off by as much, check the time settings of the server.",
+                destination_root.display()
+            ),
+            Warning::CannotReadBackup { path, io_error } => write!(
+                f,
+                "Cannot read \"{}\" in the backup: {io_error}.",
+                path.display()
+            ),
+            Warning::CannotWriteParity {
+                destination_root,
+                io_error,
+            } => write!(
+                f,
+                "Cannot write the parity of the files in \"{}\": {io_error}.",
+                destination_root.display()
+            ),
+            Warning::CannotPrune { snapshot, io_error } => write!(
+                f,
+                "Cannot remove the snapshot \"{}\": {io_error}.",
+                snapshot.display()
+            ),
+            Warning::SourceChangedSinceBackup {
+                source,
+                destination,
+            } => write!(
+                f,
+                "Cannot repair \"{}\" because \"{}\" has changed since it was backed up. Run a backup to copy it again.",
+                destination.display(),
+                source.display()
+            ),
+            Warning::CannotCopyExtendedAttributes {
+                source,
+                destination,
+                io_error,
+            } => write!(
+                f,
+                "Cannot copy the extended attributes from \"{}\" to \"{}\": {io_error}.",
+                source.display(),
+                destination.display()
+            ),
+            Warning::CannotCopyFileAttributes {
+                source,
+                destination,
+                io_error,
+            } => write!(
+                f,
+                "Cannot copy the file attributes from \"{}\" to \"{}\": {io_error}.",
+                source.display(),
+                destination.display()
+            ),
+            Warning::SpecialPermissionsDropped {
+                destination,
+                dropped,
+            } => write!(
+                f,
+                "Dropped the {} of \"{}\". Run with more privileges to keep them.",
+                dropped.join(", "),
+                destination.display()
+            ),
+            Warning::CannotCompare {
+                source,
+                destination,
+                io_error,
+            } => write!(
+                f,
+                "Cannot compare \"{}\" with \"{}\": {io_error}. We try to copy the file anyway.",
+                source.display(),
+                destination.display()
+            ),
+            Warning::OperationStalled {
+                activity,
+                timeout,
+                cancelled,
+            } => {
+                let (operation, source, connector, destination) = match activity {
+                    Activity::Comparing {
+                        source,
+                        destination,
+                    } => ("Comparing", source, "with", destination),
+                    Activity::Copying {
+                        source,
+                        destination,
+                    } => ("Copying", source, "to", destination),
+                };
+                let outcome = if *cancelled {
+                    " It has been cancelled and is tried again later."
+                } else {
+                    ""
+                };
+                write!(
+                    f,
+                    "{operation} \"{}\" {connector} \"{}\" takes longer than {}, the drive might be failing or disconnected.{outcome}",
+                    source.display(),
+                    destination.display(),
+                    format_duration(*timeout)
+                )
+            }
+            Warning::CycleDetected(path) => write!(
+                f,
+                "The directory \"{}\" is not backed up because it leads back to one of its parent directories, e.g. through a symbolic link.",
+                path.display()
+            ),
+            Warning::SqliteDatabaseChangedDuringCopy { source, attempts } => write!(
+                f,
+                "The database \"{}\" changed during all {attempts} copy attempts. The backup of it might be inconsistent.",
+                source.display()
+            ),
+            Warning::CannotWriteRunHistory { path, io_error } => write!(
+                f,
+                "Cannot write the run history to \"{}\": {io_error}.",
+                path.display()
+            ),
+            Warning::SuspiciousDeletions { count, policy } => {
+                let name = if *count > 1 { "files" } else { "file" };
+                write!(
+                    f,
+                    "{count} {name} to delete have never been backed up by safeall and will be {policy}."
+                )
+            }
+            Warning::CannotWriteManifest {
+                destination_root,
+                io_error,
+            } => write!(
+                f,
+                "Cannot update the manifest of \"{}\": {io_error}.",
+                destination_root.display()
+            ),
+            Warning::CannotWriteHashCache {
+                destination_root,
+                io_error,
+            } => write!(
+                f,
+                "Cannot save the hashes of the files in \"{}\": {io_error}.",
+                destination_root.display()
+            ),
+            Warning::CannotWriteScanCache {
+                destination_root,
+                io_error,
+            } => write!(
+                f,
+                "Cannot save the listings of the directories for \"{}\": {io_error}.",
+                destination_root.display()
+            ),
+            Warning::CannotRememberDestination { path, io_error } => write!(
+                f,
+                "Cannot remember the ID of the destination in \"{}\": {io_error}.",
+                path.display()
+            ),
+            Warning::SourceChangedDuringCopy(source) => write!(
+                f,
+                "The file \"{}\" changed while it was copied.",
+                source.display()
+            ),
+            Warning::SourceVanished(source) => write!(
+                f,
+                "The file \"{}\" has been deleted before it could be copied.",
+                source.display()
+            ),
+            Warning::SymlinkSkipped(path) => write!(
+                f,
+                "The symbolic link \"{}\" is not backed up.",
+                path.display()
+            ),
+            Warning::OversizedFileSkipped {
+                path,
+                size,
+                max_size,
+            } => write!(
+                f,
+                "The file \"{}\" is not backed up as it has {} but the destination stores files up to {} only.",
+                path.display(),
+                format_bytes(*size),
+                format_bytes(*max_size)
+            ),
+            Warning::CannotRemoveSplitFile { path, io_error } => write!(
+                f,
+                "Cannot remove the parts of \"{}\" from when it was split: {io_error}",
+                path.display()
+            ),
+        }
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+fn format_duration(duration: std::time::Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds < 60 {
+        format!("{seconds} s")
+    } else if seconds < 60 * 60 {
+        format!("{} min", seconds.div_ceil(60))
+    } else {
+        format!("{} h {} min", seconds / 3600, seconds % 3600 / 60)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Warning(Warning),
+    Info(Info),
+    Progress(Progress),
+    /// Sent periodically during long operations on a single file, such that frontends can
+    /// tell a busy run from a hung one.
+    Heartbeat(Activity),
+}
+
+/// Time between two heartbeats of the same operation.
+const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub enum Activity {
+    Comparing {
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+    },
+    Copying {
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+    },
+}
+
+impl std::fmt::Display for Activity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Activity::Comparing {
+                source,
+                destination,
+            } => write!(
+                f,
+                "Still comparing \"{}\" with \"{}\".",
+                source.display(),
+                destination.display()
+            ),
+            Activity::Copying {
+                source,
+                destination,
+            } => write!(
+                f,
+                "Still copying \"{}\" to \"{}\".",
+                source.display(),
+                destination.display()
+            ),
+        }
+    }
+}
+
+/// How long an operation on a single file may take before it is reported as stalled.
+#[derive(Debug, Clone, Copy)]
+struct Watchdog {
+    heartbeat: std::time::Duration,
+    stall_timeout: Option<std::time::Duration>,
+    cancel: bool,
+}
+
+impl Watchdog {
+    fn new(options: &BackupOptions) -> Self {
+        Self {
+            heartbeat: HEARTBEAT_INTERVAL,
+            stall_timeout: options.stall_timeout,
+            cancel: options.cancel_stalled,
+        }
+    }
+}
+
+/// Runs `future` and sends a heartbeat with `activity` periodically until it is done.
+/// Warns once it takes longer than the stall timeout and returns `None` if the watchdog
+/// cancels it then.
+async fn watch<T>(
+    activity: Activity,
+    watchdog: Watchdog,
+    future: impl Future<Output = T>,
+    message_sender: &impl MessageSender,
+) -> Option<T> {
+    let mut future = std::pin::pin!(future);
+    let mut heartbeats = tokio::time::interval_at(
+        tokio::time::Instant::now() + watchdog.heartbeat,
+        watchdog.heartbeat,
+    );
+    let mut stall = std::pin::pin!(tokio::time::sleep(
+        watchdog.stall_timeout.unwrap_or_default()
+    ));
+    let mut stalled = watchdog.stall_timeout.is_none();
+    loop {
+        tokio::select! {
+            output = &mut future => return Some(output),
+            _ = heartbeats.tick() => {
+                message_sender.send(Message::Heartbeat(activity.clone()));
+            }
+            () = &mut stall, if !stalled => {
+                stalled = true;
+                message_sender.send(Message::Warning(Warning::OperationStalled {
+                    activity: activity.clone(),
+                    timeout: watchdog.stall_timeout.unwrap_or_default(),
+                    cancelled: watchdog.cancel,
+                }));
+                if watchdog.cancel {
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Suffix of the files which [`BackupOptions::keep_overwritten`] keeps.
+pub const PRE_RESTORE_SUFFIX: &str = "pre-restore";
+
+fn pre_restore_path(path: &std::path::Path, number: usize) -> std::path::PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".{PRE_RESTORE_SUFFIX}"));
+    if number > 1 {
+        name.push(format!("-{number}"));
+    }
+    path.with_file_name(name)
+}
+
+/// Renames the file to `<name>.pre-restore`, or `<name>.pre-restore-2` and so on if an
+/// earlier restore already kept a version, such that the first kept version is never lost.
+/// `None` if there is no file to keep.
+async fn keep_overwritten(
+    destination_file: &std::path::Path,
+) -> std::io::Result<Option<std::path::PathBuf>> {
+    if tokio::fs::symlink_metadata(destination_file).await.is_err() {
+        return Ok(None);
+    }
+    let mut number = 1;
+    let mut kept = pre_restore_path(destination_file, number);
+    while tokio::fs::symlink_metadata(&kept).await.is_ok() {
+        number += 1;
+        kept = pre_restore_path(destination_file, number);
+    }
+    tokio::fs::rename(destination_file, &kept).await?;
+    Ok(Some(kept))
+}
+
+async fn file_size(path: &std::path::Path) -> u64 {
+    tokio::fs::metadata(path)
+        .await
+        .map_or(0, |metadata| metadata.len())
+}
+
+/// Whether the file has to be copied. Files which cannot be compared are copied.
+async fn decide_copy(
+    source_file: &std::path::Path,
+    destination_file: &std::path::Path,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) -> Decision {
+    if !destination_file.exists() {
+        return Decision::Copy(CopyReason::NewFile);
+    }
+    let (Ok(source_metadata), Ok(destination_metadata)) = (
+        tokio::fs::metadata(source_file).await,
+        tokio::fs::metadata(destination_file).await,
+    ) else {
+        message_sender.send(Message::Warning(Warning::CannotGetMetadata {
+            source: source_file.to_owned(),
+            destination: destination_file.to_owned(),
+            copy_anyway: true,
+        }));
+        return Decision::Copy(CopyReason::Forced);
+    };
+
+    // NOTE: Hashing both sides of huge files on every run would dominate the run
+    let comparator: std::sync::Arc<dyn Comparator> = if options
+        .hash_max_size
+        .is_some_and(|max| source_metadata.len() > max)
+    {
+        std::sync::Arc::new(CompareStrategy::NeverHash)
+    } else {
+        options.comparator.clone()
+    };
+    let (source, destination) = (source_file.to_owned(), destination_file.to_owned());
+    let hash_cache = hash_cache::current();
+    let started = std::time::Instant::now();
+    let decision = watch(
+        Activity::Comparing {
+            source: source_file.to_owned(),
+            destination: destination_file.to_owned(),
+        },
+        Watchdog::new(options),
+        tokio::task::spawn_blocking(move || {
+            hash_cache::enter(hash_cache, || {
+                comparator.compare(
+                    &source,
+                    &source_metadata,
+                    &destination,
+                    &destination_metadata,
+                )
+            })
+        }),
+        message_sender,
+    )
+    .await
+    .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::TimedOut))
+    .and_then(|joined| joined.map_err(std::io::Error::other))
+    .flatten();
+    message_sender.compared(started.elapsed());
+    match decision {
+        Ok(decision) => decision,
+        Err(e) => {
+            message_sender.send(Message::Warning(Warning::CannotCompare {
+                source: source_file.to_owned(),
+                destination: destination_file.to_owned(),
+                io_error: e.to_string(),
+            }));
+            Decision::Copy(CopyReason::Forced)
+        }
+    }
 }
 
+#[allow(clippy::too_many_lines)]
 async fn copy_or_skip_if_same(
     source_file: &std::path::Path,
     destination_file: &std::path::Path,
+    options: &BackupOptions,
     message_sender: &impl MessageSender,
-) -> Result<(), ProcessPathError> {
+) -> Result<CopyOutcome, ProcessPathError> {
     debug_assert!(
         !source_file.is_dir(),
         "Source file must not be a directory."
@@ -916,56 +3172,799 @@ async fn copy_or_skip_if_same(
     );
 
     let source_metadata = FileMetaData::try_new(source_file).await;
-    if skip_copy(
-        source_file,
-        destination_file,
-        source_metadata.as_ref(),
-        message_sender,
-    )
-    .await
+    if let Some(max_size) = options.destination_max_file_size
+        && let Some(size) = source_metadata.as_ref().map(|metadata| metadata.length)
+        && size > max_size
     {
-        message_sender.send(Message::Progress(Progress::IncrementSuccess(
-            Increment::SkippingFileNoModification {
-                source: source_file.to_owned(),
-                destination: destination_file.to_owned(),
-            },
-        )));
-        return Ok(());
+        match options.oversized_files {
+            OversizedFilePolicy::Error => {
+                return Err(ProcessPathError {
+                    not_processed: Some(source_file.to_owned()),
+                    kind: ProcessPathErrorKind::FileTooLarge {
+                        destination: destination_file.to_owned(),
+                        size,
+                        max_size,
+                    },
+                });
+            }
+            OversizedFilePolicy::Skip => {
+                message_sender.send(Message::Warning(Warning::OversizedFileSkipped {
+                    path: source_file.to_owned(),
+                    size,
+                    max_size,
+                }));
+                message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                    Increment::OversizedFileSkipped {
+                        source: source_file.to_owned(),
+                        size,
+                    },
+                )));
+                return Ok(CopyOutcome::Consistent);
+            }
+            OversizedFilePolicy::Split => {
+                return split_or_skip_if_same(
+                    source_file,
+                    destination_file,
+                    source_metadata,
+                    max_size,
+                    options,
+                    message_sender,
+                )
+                .await;
+            }
+        }
     }
+    let reason = match decide_copy(source_file, destination_file, options, message_sender).await {
+        Decision::Skip(reason) => {
+            message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                Increment::SkippingFileNoModification {
+                    source: source_file.to_owned(),
+                    destination: destination_file.to_owned(),
+                    bytes: source_metadata
+                        .as_ref()
+                        .map_or(0, |metadata| metadata.length),
+                    reason,
+                },
+            )));
+            return Ok(CopyOutcome::Consistent);
+        }
+        Decision::Copy(reason) => reason,
+    };
 
+    read_only::check(destination_file)?;
+    hash_cache::forget(destination_file);
+    if options.keep_overwritten && reason != CopyReason::NewFile {
+        match keep_overwritten(destination_file).await {
+            Ok(Some(kept)) => message_sender.send(Message::Info(Info::KeptOverwritten {
+                path: destination_file.to_owned(),
+                kept,
+            })),
+            Ok(None) => {}
+            Err(e) => {
+                return Err(ProcessPathError {
+                    not_processed: Some(source_file.to_owned()),
+                    kind: ProcessPathErrorKind::CannotKeepOverwritten {
+                        destination: destination_file.to_owned(),
+                        io_error: e.to_string(),
+                    },
+                });
+            }
+        }
+    }
     message_sender.send(Message::Info(Info::StartCopingFile {
         source: source_file.to_owned(),
         destination: destination_file.to_owned(),
     }));
-    tokio::fs::copy(source_file, destination_file)
-        .await
-        .map_err(|e| ProcessPathError {
+    let copy = async {
+        file_attributes::make_writable(destination_file)?;
+        copy_file(source_file, destination_file, options).await
+    };
+    let copied = watch(
+        Activity::Copying {
+            source: source_file.to_owned(),
+            destination: destination_file.to_owned(),
+        },
+        Watchdog::new(options),
+        copy,
+        message_sender,
+    )
+    .await
+    .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::TimedOut))
+    .flatten();
+    let bytes = match copied {
+        Ok(bytes) => bytes,
+        // NOTE: Deleting a file during the run is no reason to fail it
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !source_file.exists() => {
+            message_sender.send(Message::Warning(Warning::SourceVanished(
+                source_file.to_owned(),
+            )));
+            return Ok(CopyOutcome::Consistent);
+        }
+        Err(e) => {
+            return Err(ProcessPathError {
+                not_processed: Some(source_file.to_owned()),
+                kind: ProcessPathErrorKind::CannotCopyFile {
+                    to: destination_file.to_owned(),
+                    io_error: e.to_string(),
+                },
+            });
+        }
+    };
+    // NOTE: A source which changed during the copy is detected below and copied again
+    if options.verify_writes
+        && let Err(e) = verify_write(source_file, destination_file).await
+        && FileMetaData::try_new(source_file).await == source_metadata
+    {
+        hash_cache::forget(destination_file);
+        return Err(ProcessPathError {
             not_processed: Some(source_file.to_owned()),
-            kind: ProcessPathErrorKind::CannotCopyFile {
-                to: destination_file.to_owned(),
+            kind: ProcessPathErrorKind::VerificationFailed {
+                destination: destination_file.to_owned(),
                 io_error: e.to_string(),
             },
+        });
+    }
+    message_sender.send(Message::Progress(Progress::IncrementSuccess(
+        Increment::FileCopied {
+            source: source_file.to_owned(),
+            destination: destination_file.to_owned(),
+            bytes,
+            reason,
+        },
+    )));
+    // NOTE: The parts from when the file was larger than the destination could store
+    if let Err(e) = split::remove(destination_file) {
+        message_sender.send(Message::Warning(Warning::CannotRemoveSplitFile {
+            path: destination_file.to_owned(),
+            io_error: e.to_string(),
+        }));
+    }
+
+    if set_modified_time(source_metadata.as_ref(), destination_file)
+        .await
+        .is_none()
+    {
+        message_sender.send(Message::Warning(Warning::CannotCopyModifiedTime {
+            source: source_file.to_owned(),
+            destination: destination_file.to_owned(),
+        }));
+    }
+
+    if options.preserve_xattrs {
+        copy_extended_attributes(source_file, destination_file, message_sender).await;
+    }
+    preserve_special_bits(source_file, destination_file, message_sender).await;
+    if let Err(e) = file_attributes::copy(source_file, destination_file) {
+        message_sender.send(Message::Warning(Warning::CannotCopyFileAttributes {
+            source: source_file.to_owned(),
+            destination: destination_file.to_owned(),
+            io_error: e.to_string(),
+        }));
+    }
+    set_owner(destination_file, options, message_sender);
+
+    // NOTE: The destination keeps the modified time from before the copy, so a torn copy is
+    // detected as outdated by the next run even if the retry fails
+    if FileMetaData::try_new(source_file).await != source_metadata {
+        message_sender.send(Message::Warning(Warning::SourceChangedDuringCopy(
+            source_file.to_owned(),
+        )));
+        return Ok(CopyOutcome::SourceChanged);
+    }
+
+    Ok(CopyOutcome::Consistent)
+}
+
+/// Backs up a file which is larger than the destination can store in parts, see
+/// [`OversizedFilePolicy::Split`].
+async fn split_or_skip_if_same(
+    source_file: &std::path::Path,
+    destination_file: &std::path::Path,
+    source_metadata: Option<FileMetaData>,
+    part_size: u64,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) -> Result<CopyOutcome, ProcessPathError> {
+    let cannot_copy = |e: std::io::Error| ProcessPathError {
+        not_processed: Some(source_file.to_owned()),
+        kind: ProcessPathErrorKind::CannotCopyFile {
+            to: destination_file.to_owned(),
+            io_error: e.to_string(),
+        },
+    };
+    let metadata = tokio::fs::metadata(source_file)
+        .await
+        .map_err(cannot_copy)?;
+    let reason = match split::Descriptor::read(destination_file) {
+        Ok(Some(descriptor)) if descriptor.matches(&metadata) => {
+            message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                Increment::SkippingFileNoModification {
+                    source: source_file.to_owned(),
+                    destination: destination_file.to_owned(),
+                    bytes: metadata.len(),
+                    reason: SkipReason::SameMetadata,
+                },
+            )));
+            return Ok(CopyOutcome::Consistent);
+        }
+        Ok(Some(descriptor)) if descriptor.length != metadata.len() => CopyReason::SizeChanged,
+        Ok(Some(_)) => CopyReason::ModifiedTimeChanged,
+        Ok(None) => CopyReason::NewFile,
+        Err(_) => CopyReason::Forced,
+    };
+
+    read_only::check(destination_file)?;
+    // NOTE: A copy from when the destination had no limit is replaced by the parts
+    if tokio::fs::metadata(destination_file).await.is_ok() {
+        tokio::fs::remove_file(destination_file)
+            .await
+            .map_err(cannot_copy)?;
+    }
+    message_sender.send(Message::Info(Info::StartCopingFile {
+        source: source_file.to_owned(),
+        destination: destination_file.to_owned(),
+    }));
+    let (source, destination) = (source_file.to_owned(), destination_file.to_owned());
+    let descriptor = watch(
+        Activity::Copying {
+            source: source_file.to_owned(),
+            destination: destination_file.to_owned(),
+        },
+        Watchdog::new(options),
+        tokio::task::spawn_blocking(move || split::split(&source, &destination, part_size)),
+        message_sender,
+    )
+    .await
+    .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::TimedOut))
+    .and_then(|joined| joined.map_err(std::io::Error::other))
+    .flatten()
+    .map_err(cannot_copy)?;
+    message_sender.send(Message::Info(Info::FileSplit {
+        destination: destination_file.to_owned(),
+        parts: descriptor.parts,
+    }));
+    message_sender.send(Message::Progress(Progress::IncrementSuccess(
+        Increment::FileCopied {
+            source: source_file.to_owned(),
+            destination: destination_file.to_owned(),
+            bytes: descriptor.length,
+            reason,
+        },
+    )));
+
+    if FileMetaData::try_new(source_file).await != source_metadata {
+        message_sender.send(Message::Warning(Warning::SourceChangedDuringCopy(
+            source_file.to_owned(),
+        )));
+        return Ok(CopyOutcome::SourceChanged);
+    }
+    Ok(CopyOutcome::Consistent)
+}
+
+/// Restores a file which has been backed up in parts from its `descriptor`.
+async fn join_or_skip_if_same(
+    descriptor: &std::path::Path,
+    split_file: &std::path::Path,
+    destination_file: &std::path::Path,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) -> Result<CopyOutcome, ProcessPathError> {
+    let cannot_copy = |e: std::io::Error| ProcessPathError {
+        not_processed: Some(descriptor.to_owned()),
+        kind: ProcessPathErrorKind::CannotCopyFile {
+            to: destination_file.to_owned(),
+            io_error: e.to_string(),
+        },
+    };
+    let existing = tokio::fs::metadata(destination_file).await.ok();
+    let reason = match (split::Descriptor::read(split_file), &existing) {
+        (Ok(Some(split)), Some(existing)) if split.matches(existing) => {
+            message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                Increment::SkippingFileNoModification {
+                    source: split_file.to_owned(),
+                    destination: destination_file.to_owned(),
+                    bytes: split.length,
+                    reason: SkipReason::SameMetadata,
+                },
+            )));
+            return Ok(CopyOutcome::Consistent);
+        }
+        (Ok(Some(_)), None) => CopyReason::NewFile,
+        (Ok(Some(split)), Some(existing)) if split.length != existing.len() => {
+            CopyReason::SizeChanged
+        }
+        (Ok(Some(_)), Some(_)) => CopyReason::ModifiedTimeChanged,
+        (Ok(None), _) => return Err(cannot_copy(std::io::ErrorKind::NotFound.into())),
+        (Err(e), _) => return Err(cannot_copy(e)),
+    };
+
+    read_only::check(destination_file)?;
+    if options.keep_overwritten && existing.is_some() {
+        match keep_overwritten(destination_file).await {
+            Ok(Some(kept)) => message_sender.send(Message::Info(Info::KeptOverwritten {
+                path: destination_file.to_owned(),
+                kept,
+            })),
+            Ok(None) => {}
+            Err(e) => {
+                return Err(ProcessPathError {
+                    not_processed: Some(descriptor.to_owned()),
+                    kind: ProcessPathErrorKind::CannotKeepOverwritten {
+                        destination: destination_file.to_owned(),
+                        io_error: e.to_string(),
+                    },
+                });
+            }
+        }
+    }
+    message_sender.send(Message::Info(Info::StartCopingFile {
+        source: split_file.to_owned(),
+        destination: destination_file.to_owned(),
+    }));
+    let (source, destination) = (split_file.to_owned(), destination_file.to_owned());
+    let joined = watch(
+        Activity::Copying {
+            source: split_file.to_owned(),
+            destination: destination_file.to_owned(),
+        },
+        Watchdog::new(options),
+        tokio::task::spawn_blocking(move || split::join(&source, &destination)),
+        message_sender,
+    )
+    .await
+    .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::TimedOut))
+    .and_then(|joined| joined.map_err(std::io::Error::other))
+    .flatten()
+    .map_err(cannot_copy)?;
+    message_sender.send(Message::Info(Info::FileJoined {
+        destination: destination_file.to_owned(),
+        parts: joined.parts,
+    }));
+    message_sender.send(Message::Progress(Progress::IncrementSuccess(
+        Increment::FileCopied {
+            source: split_file.to_owned(),
+            destination: destination_file.to_owned(),
+            bytes: joined.length,
+            reason,
+        },
+    )));
+    if let Some(modified) = joined.modified_time() {
+        let destination = destination_file.to_owned();
+        let result = tokio::task::spawn_blocking(move || {
+            std::fs::File::open(destination)?.set_modified(modified)
         })
-        .inspect(|_| {
+        .await;
+        if !matches!(result, Ok(Ok(()))) {
+            message_sender.send(Message::Warning(Warning::CannotCopyModifiedTime {
+                source: split_file.to_owned(),
+                destination: destination_file.to_owned(),
+            }));
+        }
+    }
+    set_owner(destination_file, options, message_sender);
+    Ok(CopyOutcome::Consistent)
+}
+
+async fn backup_special_file(
+    source_file: &std::path::Path,
+    destination_file: &std::path::Path,
+    policy: SpecialFilePolicy,
+    message_sender: &impl MessageSender,
+) -> Result<(), ProcessPathError> {
+    match policy {
+        SpecialFilePolicy::Skip => {
             message_sender.send(Message::Progress(Progress::IncrementSuccess(
-                Increment::FileCopied {
+                Increment::SpecialFileSkipped(source_file.to_owned()),
+            )));
+            Ok(())
+        }
+        SpecialFilePolicy::Error => Err(ProcessPathError {
+            not_processed: Some(source_file.to_owned()),
+            kind: ProcessPathErrorKind::SpecialFile,
+        }),
+        SpecialFilePolicy::Recreate => {
+            read_only::check(destination_file)?;
+            let source = source_file.to_owned();
+            let destination = destination_file.to_owned();
+            tokio::task::spawn_blocking(move || special_files::recreate(&source, &destination))
+                .await
+                .map_err(std::io::Error::other)
+                .flatten()
+                .map_err(|e| ProcessPathError {
+                    not_processed: Some(source_file.to_owned()),
+                    kind: ProcessPathErrorKind::CannotCreateSpecialFile {
+                        destination: destination_file.to_owned(),
+                        io_error: e.to_string(),
+                    },
+                })?;
+            message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                Increment::SpecialFileCreated {
                     source: source_file.to_owned(),
                     destination: destination_file.to_owned(),
                 },
             )));
-        })?;
+            Ok(())
+        }
+    }
+}
 
-    if set_modified_time(source_metadata.as_ref(), destination_file)
+async fn backup_symlink(
+    source_file: &std::path::Path,
+    destination_file: &std::path::Path,
+    policy: SymlinkPolicy,
+    message_sender: &impl MessageSender,
+) -> Result<(), ProcessPathError> {
+    if policy == SymlinkPolicy::Skip {
+        message_sender.send(Message::Warning(Warning::SymlinkSkipped(
+            source_file.to_owned(),
+        )));
+        message_sender.send(Message::Progress(Progress::IncrementSuccess(
+            Increment::SymlinkSkipped(source_file.to_owned()),
+        )));
+        return Ok(());
+    }
+
+    let cannot_create = |e: std::io::Error| ProcessPathError {
+        not_processed: Some(source_file.to_owned()),
+        kind: ProcessPathErrorKind::CannotCreateSymlink {
+            destination: destination_file.to_owned(),
+            io_error: e.to_string(),
+        },
+    };
+    let target = tokio::fs::read_link(source_file)
         .await
-        .is_none()
+        .map_err(cannot_create)?;
+    if tokio::fs::read_link(destination_file)
+        .await
+        .is_ok_and(|existing| existing == target)
     {
-        message_sender.send(Message::Warning(Warning::CannotCopyModifiedTime {
+        message_sender.send(Message::Progress(Progress::IncrementSuccess(
+            Increment::SkippingFileNoModification {
+                source: source_file.to_owned(),
+                destination: destination_file.to_owned(),
+                bytes: 0,
+                reason: SkipReason::AlreadyLinked,
+            },
+        )));
+        return Ok(());
+    }
+    read_only::check(destination_file)?;
+    if tokio::fs::symlink_metadata(destination_file).await.is_ok() {
+        tokio::fs::remove_file(destination_file)
+            .await
+            .map_err(cannot_create)?;
+    }
+    create_symlink(source_file, &target, destination_file)
+        .await
+        .map_err(cannot_create)?;
+    message_sender.send(Message::Progress(Progress::IncrementSuccess(
+        Increment::SymlinkCreated {
+            source: source_file.to_owned(),
+            destination: destination_file.to_owned(),
+            target,
+        },
+    )));
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn create_symlink(
+    _source_file: &std::path::Path,
+    target: &std::path::Path,
+    destination_file: &std::path::Path,
+) -> std::io::Result<()> {
+    tokio::fs::symlink(target, destination_file).await
+}
+
+#[cfg(windows)]
+async fn create_symlink(
+    source_file: &std::path::Path,
+    target: &std::path::Path,
+    destination_file: &std::path::Path,
+) -> std::io::Result<()> {
+    // NOTE: Windows distinguishes between links to files and to directories
+    if tokio::fs::metadata(source_file)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir())
+    {
+        tokio::fs::symlink_dir(target, destination_file).await
+    } else {
+        tokio::fs::symlink_file(target, destination_file).await
+    }
+}
+
+/// Reads the destination back from the disk and compares its hash with the source.
+async fn verify_write(
+    source_file: &std::path::Path,
+    destination_file: &std::path::Path,
+) -> std::io::Result<()> {
+    let source = source_file.to_owned();
+    let destination = destination_file.to_owned();
+    tokio::task::spawn_blocking(move || {
+        copier::drop_from_page_cache(&destination)?;
+        if comparator::hash_file(&source)? == comparator::hash_file(&destination)? {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "the content differs after reading it back",
+            ))
+        }
+    })
+    .await
+    .map_err(std::io::Error::other)
+    .flatten()
+}
+
+/// Copies the file to its staging path and moves it over `destination_file` once it is
+/// complete, such that a failed copy keeps the previous one, see
+/// [`BackupOptions::staging_directory`].
+async fn copy_file(
+    source_file: &std::path::Path,
+    destination_file: &std::path::Path,
+    options: &BackupOptions,
+) -> std::io::Result<u64> {
+    let staging_directory = options.staging_directory.as_deref();
+    if let Some(directory) = staging_directory {
+        tokio::fs::create_dir_all(directory).await?;
+    }
+    let staging = staging_path(destination_file, staging_directory);
+    let writing = std::sync::Arc::new(Writing::start(&staging)?);
+    let copied = async {
+        let (bytes, hash) = copy_file_to(source_file, &writing, options).await?;
+        let (from, to) = (staging.clone(), destination_file.to_owned());
+        tokio::task::spawn_blocking(move || copier::move_path(&from, &to))
+            .await
+            .map_err(std::io::Error::other)??;
+        if let (Some(hash_cache), Some(hash)) = (hash_cache::current(), hash) {
+            hash_cache.record(destination_file, hash);
+        }
+        Ok(bytes)
+    }
+    .await;
+    if copied.is_err() {
+        tokio::fs::remove_file(&staging).await.ok();
+    }
+    copied
+}
+
+/// Destinations which a blocking copy writes to, see [`Writing`].
+static WRITING: std::sync::Mutex<std::collections::BTreeSet<std::path::PathBuf>> =
+    std::sync::Mutex::new(std::collections::BTreeSet::new());
+
+/// Reserves a file for the blocking copy which holds it. A copy cancelled by the watchdog
+/// only stops after its current chunk, so a retry must not write to the same file until
+/// then.
+struct Writing(std::path::PathBuf);
+
+impl Writing {
+    fn start(path: &std::path::Path) -> std::io::Result<Self> {
+        if !WRITING
+            .lock()
+            .expect("Lock is never poisoned")
+            .insert(path.to_owned())
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ResourceBusy,
+                "a cancelled copy is still writing to it",
+            ));
+        }
+        Ok(Self(path.to_owned()))
+    }
+
+    /// Removes the partial file if the copy has been cancelled.
+    fn finish<T>(&self, result: std::io::Result<T>) -> std::io::Result<T> {
+        if result
+            .as_ref()
+            .is_err_and(|e| e.kind() == std::io::ErrorKind::Interrupted)
+        {
+            std::fs::remove_file(&self.0).ok();
+        }
+        result
+    }
+}
+
+impl Drop for Writing {
+    fn drop(&mut self) {
+        WRITING
+            .lock()
+            .expect("Lock is never poisoned")
+            .remove(&self.0);
+    }
+}
+
+/// Stops the blocking copy once the future which waits for it is dropped, e.g. by the
+/// watchdog.
+#[derive(Default)]
+struct CancelOnDrop(std::sync::Arc<std::sync::atomic::AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Copies the file with the kernel's copy routine unless the copy is tuned by the options or
+/// can be cancelled by the watchdog. Returns the hash of the content as well if it has been
+/// computed during the copy for the hash cache.
+async fn copy_file_to(
+    source_file: &std::path::Path,
+    destination: &std::sync::Arc<Writing>,
+    options: &BackupOptions,
+) -> std::io::Result<(u64, Option<blake3::Hash>)> {
+    let destination_file = destination.0.as_path();
+    let cancel = CancelOnDrop::default();
+    if options.reflink {
+        let source = source_file.to_owned();
+        let destination = destination.clone();
+        let cloned =
+            tokio::task::spawn_blocking(move || copier::clone_file(&source, &destination.0))
+                .await
+                .map_err(std::io::Error::other)??;
+        if cloned {
+            return Ok((tokio::fs::metadata(destination_file).await?.len(), None));
+        }
+    }
+    // NOTE: Without a custom buffer `tokio::fs::copy` already uses `copy_file_range` on Linux
+    if options.preserve_sparse_files
+        && tokio::fs::metadata(source_file)
+            .await
+            .is_ok_and(|metadata| copier::is_sparse(&metadata))
+    {
+        let source = source_file.to_owned();
+        let (destination, cancelled) = (destination.clone(), cancel.0.clone());
+        let copied = tokio::task::spawn_blocking(move || {
+            destination.finish(copier::copy_sparse_file(
+                &source,
+                &destination.0,
+                &cancelled,
+            ))
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+        if let Some(copied) = copied {
+            return Ok((copied, None));
+        }
+    }
+    // NOTE: The blocking thread of `tokio::fs::copy` would keep writing after a cancellation
+    if options.copy_buffer_size.is_none() && !options.bypass_page_cache && !options.cancel_stalled {
+        return Ok((tokio::fs::copy(source_file, destination_file).await?, None));
+    }
+    let tuning = copier::CopyTuning {
+        buffer_size: options
+            .copy_buffer_size
+            .unwrap_or(copier::DEFAULT_BUFFER_SIZE),
+        bypass_page_cache: options.bypass_page_cache,
+    };
+    let source_file = source_file.to_owned();
+    let (destination, cancelled) = (destination.clone(), cancel.0.clone());
+    let mut hasher = hash_cache::current().map(|_| blake3::Hasher::new());
+    tokio::task::spawn_blocking(move || {
+        let copied = destination.finish(copier::copy_file(
+            &source_file,
+            &destination.0,
+            tuning,
+            hasher.as_mut(),
+            &cancelled,
+        ))?;
+        Ok((copied, hasher.map(|hasher| hasher.finalize())))
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+async fn copy_extended_attributes(
+    source_file: &std::path::Path,
+    destination_file: &std::path::Path,
+    message_sender: &impl MessageSender,
+) {
+    let source = source_file.to_owned();
+    let destination = destination_file.to_owned();
+    let result = tokio::task::spawn_blocking(move || xattrs::copy(&source, &destination))
+        .await
+        .map_err(std::io::Error::other)
+        .flatten();
+    if let Err(e) = result {
+        message_sender.send(Message::Warning(Warning::CannotCopyExtendedAttributes {
             source: source_file.to_owned(),
             destination: destination_file.to_owned(),
+            io_error: e.to_string(),
         }));
     }
+}
 
-    Ok(())
+async fn preserve_special_bits(
+    source_file: &std::path::Path,
+    destination_file: &std::path::Path,
+    message_sender: &impl MessageSender,
+) {
+    let source = source_file.to_owned();
+    let destination = destination_file.to_owned();
+    let dropped =
+        tokio::task::spawn_blocking(move || special_bits::preserve(&source, &destination))
+            .await
+            .map_err(std::io::Error::other)
+            .flatten()
+            .unwrap_or_default();
+    if !dropped.is_empty() {
+        message_sender.send(Message::Warning(Warning::SpecialPermissionsDropped {
+            destination: destination_file.to_owned(),
+            dropped,
+        }));
+    }
+}
+
+/// Copies the permissions and modification times of the source directories, deepest first
+/// such that creating and deleting entries in the destination does not change them anymore.
+async fn copy_directory_metadata(
+    source_directory_root: &std::path::Path,
+    destination_directory_root: &std::path::Path,
+    filter: &filter::Filter,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) {
+    let Ok(source_recurse_directories) =
+        RecursiveReadDir::try_new(source_directory_root, ReadDirType::DirectoriesOnly)
+    else {
+        return;
+    };
+    let mut source_directories: Vec<_> = source_recurse_directories
+        .with_filter(filter.clone())
+        .flatten()
+        .collect();
+    source_directories.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+    for source_directory in source_directories {
+        let Ok(destination_directory) = get_destination_file_path(
+            destination_directory_root,
+            source_directory_root,
+            &source_directory,
+        ) else {
+            continue;
+        };
+        let Some(source_metadata) = FileMetaData::try_new(&source_directory).await else {
+            continue;
+        };
+        if !destination_directory.is_dir() {
+            continue;
+        }
+        if let Err(e) =
+            tokio::fs::set_permissions(&destination_directory, source_metadata.permissions.clone())
+                .await
+        {
+            message_sender.send(Message::Warning(Warning::CannotCopyPermissions {
+                source: source_directory.clone(),
+                destination: destination_directory.clone(),
+                io_error: e.to_string(),
+            }));
+        }
+        set_owner(&destination_directory, options, message_sender);
+        if set_modified_time(Some(&source_metadata), &destination_directory)
+            .await
+            .is_none()
+        {
+            message_sender.send(Message::Warning(Warning::CannotCopyModifiedTime {
+                source: source_directory,
+                destination: destination_directory,
+            }));
+        }
+    }
+}
+
+/// Gives a file written to the destination the configured owner. Files which have not been
+/// created, e.g. skipped links, are ignored.
+fn set_owner(path: &std::path::Path, options: &BackupOptions, message_sender: &impl MessageSender) {
+    let Some(owner) = options.destination_owner else {
+        return;
+    };
+    if let Err(e) = ownership::set(path, owner)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        message_sender.send(Message::Warning(Warning::CannotSetOwner {
+            path: path.to_owned(),
+            owner,
+            io_error: e.to_string(),
+        }));
+    }
 }
 
 async fn set_modified_time(
@@ -988,56 +3987,297 @@ async fn set_modified_time(
     }
 }
 
+/// Every `SQLite` database starts with this header, whatever the name of its file is.
+const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+const SQLITE_WAL_SUFFIX: &str = "-wal";
+const SQLITE_COPY_ATTEMPTS: usize = 3;
+const STAGING_SUFFIX: &str = ".safeall-partial";
+
+fn is_sqlite_database(path: &std::path::Path) -> bool {
+    use std::io::Read;
+
+    // NOTE: Opening a named pipe would block
+    if !std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_file()) {
+        return false;
+    }
+    let mut header = [0; SQLITE_HEADER.len()];
+    std::fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .is_ok_and(|()| header == *SQLITE_HEADER)
+}
+
+/// Whether `path` is a `SQLite` database which is copied together with its write-ahead log,
+/// or such a log.
+fn is_sqlite_database_with_wal(path: &std::path::Path) -> bool {
+    sqlite_database_of_wal(path).is_some()
+        || sqlite_wal_path(path).exists() && is_sqlite_database(path)
+}
+
+fn sqlite_wal_path(database: &std::path::Path) -> std::path::PathBuf {
+    let mut wal = database.as_os_str().to_owned();
+    wal.push(SQLITE_WAL_SUFFIX);
+    wal.into()
+}
+
+/// Returns the database if `wal` is the write-ahead log of an existing `SQLite` database.
+fn sqlite_database_of_wal(wal: &std::path::Path) -> Option<std::path::PathBuf> {
+    let database_name = wal.file_name()?.to_str()?.strip_suffix(SQLITE_WAL_SUFFIX)?;
+    let database = wal.with_file_name(database_name);
+    is_sqlite_database(&database).then_some(database)
+}
+
+/// Where `destination_file` is written before it is moved into place, next to it unless
+/// there is a staging directory.
+fn staging_path(
+    destination_file: &std::path::Path,
+    staging_directory: Option<&std::path::Path>,
+) -> std::path::PathBuf {
+    let mut staging = match staging_directory {
+        // NOTE: Files with the same name in different directories must not share a staging file
+        Some(directory) => directory
+            .join(
+                blake3::hash(destination_file.as_os_str().as_encoded_bytes())
+                    .to_hex()
+                    .as_str(),
+            )
+            .into_os_string(),
+        None => destination_file.as_os_str().to_owned(),
+    };
+    staging.push(STAGING_SUFFIX);
+    staging.into()
+}
+
+/// Copies `source` to `staging`. Returns `false` if the source vanished in the meantime.
+async fn copy_to_staging(
+    source: &std::path::Path,
+    staging: &std::path::Path,
+    destination: &std::path::Path,
+    options: &BackupOptions,
+) -> Result<bool, ProcessPathError> {
+    read_only::check(staging)?;
+    let copied = match Writing::start(staging) {
+        Ok(writing) => copy_file_to(source, &std::sync::Arc::new(writing), options).await,
+        Err(e) => Err(e),
+    };
+    match copied {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !source.exists() => Ok(false),
+        Err(e) => Err(ProcessPathError {
+            not_processed: Some(source.to_owned()),
+            kind: ProcessPathErrorKind::CannotCopyFile {
+                to: destination.to_owned(),
+                io_error: e.to_string(),
+            },
+        }),
+    }
+}
+
+async fn install_staged_file(
+    source: &std::path::Path,
+    staging: &std::path::Path,
+    destination: &std::path::Path,
+) -> Result<(), ProcessPathError> {
+    let (from, to) = (staging.to_owned(), destination.to_owned());
+    tokio::task::spawn_blocking(move || copier::move_path(&from, &to))
+        .await
+        .map_err(std::io::Error::other)
+        .flatten()
+        .map_err(|e| ProcessPathError {
+            not_processed: Some(source.to_owned()),
+            kind: ProcessPathErrorKind::CannotCopyFile {
+                to: destination.to_owned(),
+                io_error: e.to_string(),
+            },
+        })
+}
+
+/// Copies a `SQLite` database and its write-ahead log as one unit.
+///
+/// Both files are copied to staging files first and only moved into place when
+/// neither of them changed during the copy, so the destination never ends up
+/// with a database that does not match its write-ahead log.
+#[allow(clippy::too_many_lines)]
+async fn copy_sqlite_database_consistently(
+    source_database: &std::path::Path,
+    destination_database: &std::path::Path,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) -> Result<(), ProcessPathError> {
+    let source_wal = sqlite_wal_path(source_database);
+    let destination_wal = sqlite_wal_path(destination_database);
+
+    let source_metadata = FileMetaData::try_new(source_database).await;
+    let source_wal_metadata = FileMetaData::try_new(&source_wal).await;
+    // NOTE: The reason of the database wins, the log only matters if the database is the same
+    let reason = match (
+        decide_copy(
+            source_database,
+            destination_database,
+            options,
+            message_sender,
+        )
+        .await,
+        decide_copy(&source_wal, &destination_wal, options, message_sender).await,
+    ) {
+        (Decision::Skip(reason), Decision::Skip(_)) => {
+            message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                Increment::SkippingFileNoModification {
+                    source: source_database.to_owned(),
+                    destination: destination_database.to_owned(),
+                    bytes: file_size(source_database).await + file_size(&source_wal).await,
+                    reason,
+                },
+            )));
+            return Ok(());
+        }
+        (Decision::Copy(reason), _) | (Decision::Skip(_), Decision::Copy(reason)) => reason,
+    };
+
+    read_only::check(destination_database)?;
+    hash_cache::forget(destination_database);
+    hash_cache::forget(&destination_wal);
+    message_sender.send(Message::Info(Info::StartCopingSqliteDatabase {
+        source: source_database.to_owned(),
+        destination: destination_database.to_owned(),
+    }));
+
+    let staging_directory = options.staging_directory.as_deref();
+    if let Some(directory) = staging_directory {
+        read_only::check(directory)?;
+        tokio::fs::create_dir_all(directory)
+            .await
+            .map_err(|e| ProcessPathError {
+                not_processed: Some(source_database.to_owned()),
+                kind: ProcessPathErrorKind::CannotCopyFile {
+                    to: directory.to_owned(),
+                    io_error: e.to_string(),
+                },
+            })?;
+    }
+    let staging_database = staging_path(destination_database, staging_directory);
+    let staging_wal = staging_path(&destination_wal, staging_directory);
+    let mut consistent = false;
+    let mut wal_copied = false;
+    let mut snapshot = (source_metadata, source_wal_metadata);
+    for _ in 0..SQLITE_COPY_ATTEMPTS {
+        snapshot = (
+            FileMetaData::try_new(source_database).await,
+            FileMetaData::try_new(&source_wal).await,
+        );
+        let copied = async {
+            copy_to_staging(
+                source_database,
+                &staging_database,
+                destination_database,
+                options,
+            )
+            .await?;
+            copy_to_staging(&source_wal, &staging_wal, &destination_wal, options).await
+        }
+        .await;
+        match copied {
+            Ok(copied) => wal_copied = copied,
+            Err(error) => {
+                tokio::fs::remove_file(&staging_database).await.ok();
+                tokio::fs::remove_file(&staging_wal).await.ok();
+                return Err(error);
+            }
+        }
+        let after = (
+            FileMetaData::try_new(source_database).await,
+            FileMetaData::try_new(&source_wal).await,
+        );
+        if snapshot == after {
+            consistent = true;
+            break;
+        }
+    }
+
+    if !consistent {
+        message_sender.send(Message::Warning(Warning::SqliteDatabaseChangedDuringCopy {
+            source: source_database.to_owned(),
+            attempts: SQLITE_COPY_ATTEMPTS,
+        }));
+    }
+
+    // NOTE: A stale write-ahead log next to a new database corrupts it, so the log goes first
+    if wal_copied {
+        install_staged_file(&source_wal, &staging_wal, &destination_wal).await?;
+    } else if destination_wal.exists() {
+        tokio::fs::remove_file(&destination_wal)
+            .await
+            .map_err(|e| ProcessPathError {
+                not_processed: Some(source_database.to_owned()),
+                kind: ProcessPathErrorKind::CannotDeleteFile {
+                    io_error: e.to_string(),
+                },
+            })?;
+    }
+    install_staged_file(source_database, &staging_database, destination_database).await?;
+    set_owner(destination_database, options, message_sender);
+    if wal_copied {
+        set_owner(&destination_wal, options, message_sender);
+    }
+    let (database_metadata, wal_metadata) = snapshot;
+    message_sender.send(Message::Progress(Progress::IncrementSuccess(
+        Increment::FileCopied {
+            source: source_database.to_owned(),
+            destination: destination_database.to_owned(),
+            bytes: [&database_metadata, &wal_metadata]
+                .into_iter()
+                .flatten()
+                .map(|m| m.length)
+                .sum(),
+            reason,
+        },
+    )));
+
+    if set_modified_time(database_metadata.as_ref(), destination_database)
+        .await
+        .is_none()
+        || wal_copied
+            && set_modified_time(wal_metadata.as_ref(), &destination_wal)
+                .await
+                .is_none()
+    {
+        message_sender.send(Message::Warning(Warning::CannotCopyModifiedTime {
+            source: source_database.to_owned(),
+            destination: destination_database.to_owned(),
+        }));
+    }
+
+    Ok(())
+}
+
 async fn get_paths_in_destinatination_but_not_in_source(
     recurse_source: RecursiveReadDir,
     recurse_destination: RecursiveReadDir,
 ) -> Result<Vec<std::path::PathBuf>, ProcessPathError> {
     use futures::stream::TryStreamExt;
 
-    let source_root_path = recurse_source.root_directory().to_owned();
-    let source_root_path = &source_root_path;
     let destination_root_path = recurse_destination.root_directory().to_owned();
-    let destination_root_path = &destination_root_path;
-    let source_files: std::collections::HashSet<_> = futures::stream::iter(recurse_source)
-        .and_then(async |path| {
-            path.strip_prefix(source_root_path)
-                .map(std::borrow::ToOwned::to_owned)
-                .map_err(|e| ProcessPathError {
-                    not_processed: Some(path.clone()),
-                    kind: ProcessPathErrorKind::InvariantBroken(
-                        InvariantError::CannotStripPrefixOfPath {
-                            path_root: source_root_path.to_owned(),
-                            path: path.clone(),
-                            error: e,
-                        },
-                    ),
-                })
-        })
-        .try_collect()
-        .await?;
-
+    let source_files: std::collections::HashSet<_> =
+        futures::stream::iter(recurse_source.relative())
+            .try_collect()
+            .await?;
     let destination_files: std::collections::HashSet<_> =
-        futures::stream::iter(recurse_destination)
-            .and_then(async |path| {
-                path.strip_prefix(destination_root_path)
-                    .map(std::borrow::ToOwned::to_owned)
-                    .map_err(|e| ProcessPathError {
-                        not_processed: Some(path.clone()),
-                        kind: ProcessPathErrorKind::InvariantBroken(
-                            InvariantError::CannotStripPrefixOfPath {
-                                path_root: destination_root_path.clone(),
-                                path: path.clone(),
-                                error: e,
-                            },
-                        ),
-                    })
-            })
+        futures::stream::iter(recurse_destination.relative())
             .try_collect()
             .await?;
 
-    let mut res: Vec<_> = (&destination_files - &source_files)
+    // NOTE: The descriptor and the parts of a split file stand for the file itself
+    let source_files: std::collections::HashSet<_> = source_files
+        .iter()
+        .filter_map(|p| split::original(p))
+        .chain(source_files.iter().cloned())
+        .collect();
+    let mut res: Vec<_> = destination_files
         .iter()
-        .map(|p| [destination_root_path, p].iter().collect())
+        .filter(|p| {
+            !source_files.contains(*p)
+                && split::original(p).is_none_or(|original| !source_files.contains(&original))
+        })
+        .map(|p| destination_root_path.join(p))
         .collect();
     res.sort(); // Such that foo/bar/baz is after foo/bar
     Ok(res)
@@ -1055,6 +4295,18 @@ fn validate_or_create_root_paths<P: AsRef<std::path::Path>>(
             source_directory_root.to_owned(),
         ));
     }
+    if !source_directory_root.is_dir() {
+        return Err(Error::SourceRootIsNotADirectory(
+            source_directory_root.to_owned(),
+        ));
+    }
+    validate_or_create_destination_root(destination_directory_root, message_sender)
+}
+
+fn validate_or_create_destination_root(
+    destination_directory_root: &std::path::Path,
+    message_sender: &impl MessageSender,
+) -> Result<(), Error> {
     if !destination_directory_root.exists() {
         message_sender.send(Message::Info(Info::CreatingDestinationDir(
             destination_directory_root.to_owned(),
@@ -1079,17 +4331,77 @@ fn validate_or_create_root_paths<P: AsRef<std::path::Path>>(
     }
     Ok(())
 }
+
+/// Whether `path`, or the part of it which already exists, is on another filesystem than
+/// the root filesystem. An unmounted drive leaves an empty directory on the root filesystem.
+#[cfg(unix)]
+fn is_on_mounted_filesystem(path: &std::path::Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let Ok(root) = std::fs::metadata("/") else {
+        return false;
+    };
+    path.ancestors()
+        .find_map(|p| std::fs::metadata(p).ok())
+        .is_some_and(|metadata| metadata.dev() != root.dev())
+}
+
+#[cfg(not(unix))]
+fn is_on_mounted_filesystem(_path: &std::path::Path) -> bool {
+    // NOTE: Drives have their own letter on Windows, there is no empty mount point to write to
+    true
+}
+
+/// Warns if the destination sets modification times with a clock that is off, e.g. a
+/// network share whose server has the wrong time.
+async fn check_clock(destination_root: &std::path::Path, message_sender: &impl MessageSender) {
+    let destination = destination_root.to_owned();
+    let skew = tokio::task::spawn_blocking(move || clock::measure(&destination))
+        .await
+        .map_err(std::io::Error::other)
+        .flatten();
+    // NOTE: A destination which cannot be written to is reported by the run itself
+    if let Ok(Some(skew)) = skew {
+        message_sender.send(Message::Warning(Warning::ClockSkew {
+            destination_root: destination_root.to_owned(),
+            skew,
+        }));
+    }
+}
+
+fn check_mounted(destination_root: &std::path::Path, options: &BackupOptions) -> Result<(), Error> {
+    if options.require_mounted && !is_on_mounted_filesystem(destination_root) {
+        return Err(Error::DestinationNotMounted(destination_root.to_owned()));
+    }
+    Ok(())
+}
+
+/// Syncing from an unmounted drive would delete everything in the destination.
+fn check_source_mounted(
+    source_root: &std::path::Path,
+    options: &BackupOptions,
+) -> Result<(), Error> {
+    if options.require_mounted && !is_on_mounted_filesystem(source_root) {
+        return Err(Error::SourceNotMounted(source_root.to_owned()));
+    }
+    Ok(())
+}
+
 async fn backup<P: AsRef<std::path::Path>>(
     source_directory_root: P,
     destination_directory_root: P,
+    options: &BackupOptions,
     message_sender: &impl MessageSender,
 ) -> Result<(), Error> {
     let source_directory_root = source_directory_root.as_ref();
     let destination_directory_root = destination_directory_root.as_ref();
+    let filter = options.filter(&[source_directory_root, destination_directory_root])?;
+    let options = &with_max_file_size(options, destination_directory_root);
 
     let create_directories_errors = create_all_directories_in_destination(
         source_directory_root,
         destination_directory_root,
+        &filter,
+        options,
         message_sender,
     )
     .await?;
@@ -1103,6 +4415,9 @@ async fn backup<P: AsRef<std::path::Path>>(
         source_directory_root,
         destination_directory_root,
         &failed_source_directories,
+        create_directories_errors.len(),
+        &filter,
+        options,
         message_sender,
     )
     .await?;
@@ -1110,422 +4425,4221 @@ async fn backup<P: AsRef<std::path::Path>>(
     Error::from_processing_results(create_directories_errors, file_backup_result)
 }
 
-pub enum Command {
-    Backup {
-        source_root: std::path::PathBuf,
-        destination_root: std::path::PathBuf,
-    },
-    Sync {
-        source_root: std::path::PathBuf,
-        destination_root: std::path::PathBuf,
-    },
-    Restore {
-        source_root: std::path::PathBuf,
-        destination_root: std::path::PathBuf,
-        delete_files: bool,
-    },
+/// The options with the largest file size of the destination, unless they set one.
+fn with_max_file_size(
+    options: &BackupOptions,
+    destination_directory_root: &std::path::Path,
+) -> BackupOptions {
+    let mut options = options.clone();
+    if options.destination_max_file_size.is_none() {
+        options.destination_max_file_size = size_limit::max_file_size(destination_directory_root);
+    }
+    options
 }
 
-pub async fn run(commands: Command, message_sender: impl MessageSender) -> Result<(), Error> {
-    match commands {
-        Command::Backup {
-            source_root,
-            destination_root,
-        } => {
-            validate_or_create_root_paths(&source_root, &destination_root, &message_sender)?;
-            backup(&source_root, &destination_root, &message_sender).await
-        }
-        Command::Sync {
-            source_root,
-            destination_root,
-        } => {
-            validate_or_create_root_paths(&source_root, &destination_root, &message_sender)?;
-            backup(&source_root, &destination_root, &message_sender).await?;
-            purge_files_and_dirs_in_destination(&source_root, &destination_root, &message_sender)
-                .await?;
+/// Backs up a single file into `destination_directory_root` under its file name.
+async fn backup_file(
+    source_file: &std::path::Path,
+    destination_directory_root: &std::path::Path,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) -> Result<(), Error> {
+    // NOTE: The parent of a relative file name is the empty path, which strips nothing
+    let source_directory_root = source_file.parent().unwrap_or(std::path::Path::new(""));
+    let options = &with_max_file_size(options, destination_directory_root);
+    message_sender.send(Message::Progress(Progress::Start(
+        1,
+        ProgressType::CopingFiles,
+    )));
+    match backup_single_file(
+        source_directory_root,
+        destination_directory_root,
+        source_file.to_owned(),
+        options,
+        message_sender,
+    )
+    .await
+    {
+        Ok(_) => {
+            message_sender.send(Message::Progress(Progress::EndSuccess(
+                ProgressType::CopingFiles,
+            )));
             Ok(())
         }
-        Command::Restore {
-            source_root,
-            destination_root,
-            delete_files,
-        } => {
-            validate_or_create_root_paths(&source_root, &destination_root, &message_sender)?;
-            // NOTE: Same as sync but switch arguments
-            backup(&destination_root, &source_root, &message_sender).await?;
-            if delete_files {
-                purge_files_and_dirs_in_destination(
-                    &destination_root,
-                    &source_root,
-                    &message_sender,
-                )
-                .await?;
-            }
-            Ok(())
+        Err(error) => {
+            message_sender.send(Message::Progress(Progress::IncrementFail(error.clone())));
+            message_sender.send(Message::Progress(Progress::EndFail(
+                1,
+                ProgressType::CopingFiles,
+            )));
+            Error::from_processing_results(vec![], vec![error])
         }
     }
 }
 
-#[inline]
-fn get_destination_file_path(
-    destination_root: &std::path::Path,
-    source_root: &std::path::Path,
-    source_path: &std::path::Path,
-) -> Result<std::path::PathBuf, ProcessPathError> {
-    let path_end = source_path
-        .strip_prefix(source_root)
-        .map_err(|e| ProcessPathError {
-            not_processed: Some(source_path.to_owned()),
-            kind: ProcessPathErrorKind::InvariantBroken(InvariantError::CannotStripPrefixOfPath {
-                path_root: source_root.to_owned(),
-                path: source_path.to_owned(),
-                error: e,
-            }),
-        })?;
-
-    Ok([destination_root, path_end].iter().collect())
+#[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct BackupOptions {
+    /// Copy `SQLite` databases together with their write-ahead log (`-wal`) and
+    /// retry when the database changes during the copy.
+    pub sqlite_consistent_copy: bool,
+    /// Copy files in chunks of this many bytes instead of using the copy routine
+    /// of the operating system.
+    pub copy_buffer_size: Option<usize>,
+    /// Bypass the page cache while copying (`O_DIRECT` on Linux, `F_NOCACHE` on macOS)
+    /// so large backups do not evict the cached files of the user.
+    pub bypass_page_cache: bool,
+    /// Process one file at a time with limited bandwidth while the machine runs
+    /// on battery or is under thermal pressure.
+    pub power_aware_throttling: bool,
+    /// What a sync does with files in the destination which safeall has never backed up.
+    pub suspicious_deletions: SuspiciousDeletionPolicy,
+    /// Abort a sync which would modify or delete more than this percentage of the files
+    /// in the destination, e.g. because the source has been encrypted by ransomware.
+    /// `None` disables the check.
+    pub mass_change_threshold: Option<u8>,
+    /// How many times files which failed or changed while they were copied are copied
+    /// again after all other files.
+    pub retry_passes: usize,
+    /// Run even if the destination carries a different ID than on the last run, or none at all,
+    /// and remember its new ID.
+    pub accept_new_destination: bool,
+    /// File in which the IDs of the destinations are remembered between runs, e.g.
+    /// [`known_destinations_path`]. `None` does not check the IDs of the destinations.
+    pub known_destinations: Option<std::path::PathBuf>,
+    /// Glob patterns of the files to back up. All files are backed up if it is empty.
+    pub include: Vec<String>,
+    /// Glob patterns of files and directories which are neither backed up nor deleted.
+    pub exclude: Vec<String>,
+    /// Honor `.safeallignore` files (gitignore syntax) in the source and its directories.
+    pub ignore_files: bool,
+    /// Exclude directories marked with a `CACHEDIR.TAG` and the usual cache and build
+    /// directories, see [`CACHE_DIRECTORIES`].
+    pub exclude_caches: bool,
+    /// Only report what would be done through `Info::Planned` without writing anything.
+    pub dry_run: bool,
+    /// Refuse to run unless the destination (the backup when restoring) is on a mounted drive
+    /// and not in the empty directory underneath the mount point. A sync also requires this of
+    /// the source, as its files decide what is deleted.
+    pub require_mounted: bool,
+    /// How symbolic links to files and directories are backed up.
+    pub symlinks: SymlinkPolicy,
+    /// Create hard links in the destination for files which are hard links of each other
+    /// in the source instead of copying them several times.
+    pub preserve_hard_links: bool,
+    /// Copy extended attributes like tags and security labels. Requires the `xattr` feature.
+    pub preserve_xattrs: bool,
+    /// Keep the holes of sparse files such as VM images instead of writing them out as zeros.
+    pub preserve_sparse_files: bool,
+    /// What happens when a directory in the source cannot be read.
+    pub read_errors: ErrorPolicy,
+    /// Share the data blocks of unchanged parts with the source on copy-on-write filesystems
+    /// like btrfs or APFS instead of copying the data, when both are on the same filesystem.
+    pub reflink: bool,
+    /// How FIFOs, sockets and device nodes are backed up.
+    pub special_files: SpecialFilePolicy,
+    /// The largest file the destination can store. Detected for FAT filesystems if `None`.
+    pub destination_max_file_size: Option<u64>,
+    /// How files larger than [`BackupOptions::destination_max_file_size`] are backed up.
+    pub oversized_files: OversizedFilePolicy,
+    /// Decides whether files which already exist in the destination are copied again, e.g.
+    /// a [`CompareStrategy`] to trade accuracy for speed.
+    pub comparator: std::sync::Arc<dyn Comparator>,
+    /// Do not descend into directories on other filesystems than the source, e.g. `/proc`,
+    /// network drives or the destination drive when backing up `/`. Only on Unix.
+    pub same_filesystem: bool,
+    /// Abort the run once more than this many files or directories failed, which usually
+    /// means that a drive has been disconnected. `None` never aborts.
+    pub max_errors: Option<usize>,
+    /// Only back up this many levels of the source, where 1 backs up only the files and
+    /// directories directly in it. `None` backs up everything.
+    pub max_depth: Option<usize>,
+    /// Neither back up nor delete files smaller than this many bytes.
+    pub min_size: Option<u64>,
+    /// Neither back up nor delete files larger than this many bytes, e.g. disk images.
+    pub max_size: Option<u64>,
+    /// Warn about copies and comparisons of a single file which take longer than this, as
+    /// happens with dying disks and dropped network drives. Directories which cannot be
+    /// opened within it are reported as unreadable.
+    pub stall_timeout: Option<std::time::Duration>,
+    /// Cancel copies and comparisons which take longer than `stall_timeout` such that they
+    /// are retried in the retry passes. The blocked read or write itself cannot be aborted, a
+    /// cancelled copy stops after it and is only retried once it stopped.
+    pub cancel_stalled: bool,
+    /// Neither back up nor delete files last modified before this time, e.g. to only back up
+    /// what changed since the last run of an external scheduler.
+    pub modified_after: Option<std::time::SystemTime>,
+    /// Neither back up nor delete files last modified at or after this time.
+    pub modified_before: Option<std::time::SystemTime>,
+    /// Rule of the application on top of the include and exclude patterns.
+    pub path_filter: Option<std::sync::Arc<dyn PathFilter>>,
+    /// Where files are copied to before they are moved over the file they replace, instead of
+    /// next to it. Must be on the same filesystem as the destination.
+    pub staging_directory: Option<std::path::PathBuf>,
+    /// Compare files larger than this many bytes by their metadata only, whatever the
+    /// comparator, e.g. for media libraries where hashing dominates the run.
+    pub hash_max_size: Option<u64>,
+    /// Remember the hashes of the files in the destination such that the next run does not
+    /// read files whose size and modification time did not change.
+    pub hash_cache: bool,
+    /// Give everything written to the destination this owner, e.g. when running as root to
+    /// read all files of the source. Only on Unix.
+    pub destination_owner: Option<Owner>,
+    /// Remember the listings of the directories such that the next run does not read
+    /// directories again whose modification time did not change.
+    pub scan_cache: bool,
+    /// Read every copied file back and compare its hash with the source, e.g. for flaky USB
+    /// drives. A mismatch is reported as an error and the file is copied again.
+    pub verify_writes: bool,
+    /// Copy damaged files found by a verify or scrub from the source again.
+    pub repair: bool,
+    /// Wait for the frontend to approve the deletions of a sync or restore once all files
+    /// have been copied, see [`Info::PurgeCheckpoint`].
+    pub interactive_delete: bool,
+    /// Write parity data of this percentage of every backed up file, such that a scrub with
+    /// [`BackupOptions::repair`] can reconstruct as much damaged data without the source.
+    /// Requires the `parity` feature.
+    pub parity: Option<u8>,
+    /// Rename a file which differs from the backup to `<name>.pre-restore` instead of
+    /// overwriting it, such that restoring the wrong backup can be undone. Meant for
+    /// restores, whose destination is the source.
+    pub keep_overwritten: bool,
+    /// Order in which the directories of the source and the destination are visited.
+    pub walk_order: WalkOrder,
+    /// Level of zstd with which snapshots compress the chunks they add to a repository, from
+    /// 1 (fastest) to 22 (smallest). `None` stores the chunks uncompressed.
+    pub compression_level: Option<i32>,
+    /// Passphrase or key file of an encrypted repository. A snapshot into a new repository
+    /// encrypts it with this secret, more can be added with [`Command::ManageKeys`].
+    pub secret: Option<Secret>,
 }
 
-#[allow(clippy::too_many_lines)]
-async fn purge_files_and_dirs_in_destination<P: AsRef<std::path::Path>>(
-    source_root: P,
-    destination_root: P,
-    message_sender: &impl MessageSender,
-) -> Result<(), Error> {
-    use futures::stream::StreamExt;
+impl Default for BackupOptions {
+    fn default() -> Self {
+        Self {
+            sqlite_consistent_copy: false,
+            copy_buffer_size: None,
+            bypass_page_cache: false,
+            power_aware_throttling: false,
+            suspicious_deletions: SuspiciousDeletionPolicy::default(),
+            mass_change_threshold: Some(DEFAULT_MASS_CHANGE_THRESHOLD),
+            retry_passes: DEFAULT_RETRY_PASSES,
+            accept_new_destination: false,
+            known_destinations: None,
+            include: vec![],
+            exclude: vec![],
+            ignore_files: true,
+            exclude_caches: false,
+            dry_run: false,
+            require_mounted: false,
+            symlinks: SymlinkPolicy::default(),
+            preserve_hard_links: true,
+            preserve_xattrs: true,
+            preserve_sparse_files: true,
+            read_errors: ErrorPolicy::default(),
+            reflink: true,
+            special_files: SpecialFilePolicy::default(),
+            destination_max_file_size: None,
+            oversized_files: OversizedFilePolicy::default(),
+            comparator: std::sync::Arc::new(MetadataAndHash),
+            same_filesystem: false,
+            max_errors: None,
+            max_depth: None,
+            min_size: None,
+            max_size: None,
+            stall_timeout: None,
+            cancel_stalled: false,
+            modified_after: None,
+            modified_before: None,
+            path_filter: None,
+            staging_directory: None,
+            hash_max_size: None,
+            hash_cache: false,
+            destination_owner: None,
+            scan_cache: false,
+            verify_writes: false,
+            repair: false,
+            interactive_delete: false,
+            parity: None,
+            keep_overwritten: false,
+            walk_order: WalkOrder::default(),
+            compression_level: Some(DEFAULT_COMPRESSION_LEVEL),
+            secret: None,
+        }
+    }
+}
 
-    let source_root = source_root.as_ref();
-    let destination_root = destination_root.as_ref();
-    let source_recurse_directories =
-        RecursiveReadDir::try_new(source_root, ReadDirType::DirectoriesOnly).map_err(|e| {
-            Error::CannotReadDirectoryContent(source_root.to_owned(), e.to_string())
-        })?;
-    let destination_recurse_directories =
-        RecursiveReadDir::try_new(destination_root, ReadDirType::DirectoriesOnly).map_err(|e| {
-            Error::CannotReadDirectoryContent(destination_root.to_owned(), e.to_string())
-        })?;
+impl BackupOptions {
+    /// The filter of a run between `roots`, each of which is excluded from the walk of the
+    /// others in case one is inside another.
+    fn filter(&self, roots: &[&std::path::Path]) -> Result<filter::Filter, Error> {
+        let filter = filter::Filter::try_new(&self.include, &self.exclude, self.ignore_files)
+            .map_err(|e| Error::InvalidPattern(e.to_string()))?
+            .excluding_caches(self.exclude_caches)
+            .on_same_filesystem(self.same_filesystem)
+            .with_max_depth(self.max_depth)
+            .with_size_limits(self.min_size, self.max_size)
+            .with_modified_range(self.modified_after, self.modified_before)
+            .with_path_filter(self.path_filter.clone())
+            .keeping_directory_symlinks(self.symlinks != SymlinkPolicy::Follow)
+            .with_walk_order(self.walk_order);
+        Ok(roots
+            .iter()
+            .map(|root| root.to_path_buf())
+            .chain(always_excluded_paths())
+            .chain(self.staging_directory.clone())
+            .fold(filter, |filter, path| filter.excluding(&path)))
+    }
+}
 
-    let dirs_to_delete = get_paths_in_destinatination_but_not_in_source(
-        source_recurse_directories,
-        destination_recurse_directories,
-    )
-    .await
-    .map_err(|e| Error::ProcessPathErrors {
-        directories: vec![e],
-        files: vec![],
-    })?;
+pub const DEFAULT_MASS_CHANGE_THRESHOLD: u8 = 40;
+pub const DEFAULT_RETRY_PASSES: usize = 1;
 
-    let num_dirs = futures::stream::iter(dirs_to_delete.clone()).count().await;
-    message_sender.send(Message::Progress(Progress::Start(
-        num_dirs,
-        ProgressType::DeletingDirs,
-    )));
+/// Level of zstd which compresses well while being faster than most drives.
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+/// Destinations with fewer files are not checked for mass changes as a handful of
+/// changed files would already exceed the threshold.
+const MIN_FILES_FOR_MASS_CHANGE_CHECK: usize = 20;
 
-    let mut errors_directory = vec![];
-    let mut deleted_dirs = vec![];
-    let mut dir_stream = futures::stream::iter(dirs_to_delete);
-    while let Some(dir) = dir_stream.next().await {
-        if deleted_dirs.iter().any(|d| dir.starts_with(d)) {
-            message_sender.send(Message::Progress(Progress::IncrementSuccess(
-                Increment::DirectoryAlreadyDeleted(dir),
-            )));
+/// Counts the files in the destination which would be modified or deleted by a sync.
+fn count_changed_destination_files(
+    source_root: &std::path::Path,
+    destination_root: &std::path::Path,
+    filter: filter::Filter,
+) -> Result<(usize, usize), Error> {
+    let destination_recurse_files =
+        RecursiveReadDir::try_new(destination_root, ReadDirType::FilesOnly)
+            .map_err(|e| {
+                Error::CannotReadDirectoryContent(destination_root.to_owned(), e.to_string())
+            })?
+            .with_filter(filter);
+    let mut total = 0;
+    let mut changed = 0;
+    for destination_file in destination_recurse_files.flatten() {
+        total += 1;
+        let Ok(source_file) =
+            get_destination_file_path(source_root, destination_root, &destination_file)
+        else {
+            continue;
+        };
+        let (Ok(source_metadata), Ok(destination_metadata)) = (
+            std::fs::metadata(&source_file),
+            std::fs::metadata(&destination_file),
+        ) else {
+            changed += 1;
             continue;
+        };
+        if source_metadata.len() != destination_metadata.len()
+            || source_metadata.modified().ok() != destination_metadata.modified().ok()
+        {
+            changed += 1;
         }
-        message_sender.send(Message::Info(Info::StartDeletingDir(dir.clone())));
-        if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
-            let error = ProcessPathError {
-                not_processed: Some(dir),
-                kind: ProcessPathErrorKind::CannotDeleteDirectory {
-                    io_error: e.to_string(),
-                },
-            };
-            message_sender.send(Message::Progress(Progress::IncrementFail(error.clone())));
-            errors_directory.push(error);
-        } else {
+    }
+    Ok((changed, total))
+}
+
+async fn check_mass_change(
+    source_root: &std::path::Path,
+    destination_root: &std::path::Path,
+    filter: &filter::Filter,
+    threshold: Option<u8>,
+) -> Result<(), Error> {
+    let Some(threshold) = threshold else {
+        return Ok(());
+    };
+    let source = source_root.to_owned();
+    let destination = destination_root.to_owned();
+    let filter = filter.clone();
+    let (changed, total) = tokio::task::spawn_blocking(move || {
+        count_changed_destination_files(&source, &destination, filter)
+    })
+    .await
+    .map_err(|e| Error::CannotReadDirectoryContent(destination_root.to_owned(), e.to_string()))??;
+    if total >= MIN_FILES_FOR_MASS_CHANGE_CHECK && changed * 100 > usize::from(threshold) * total {
+        return Err(Error::MassChangeDetected {
+            changed,
+            total,
+            threshold,
+        });
+    }
+    Ok(())
+}
+
+/// Files which a sync would delete, but which have never been backed up by safeall, are
+/// suspicious. They might be data which the user put into the destination or the
+/// destination is not the backup the user thinks it is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SuspiciousDeletionPolicy {
+    /// Move them into the quarantine in the metadata directory of the destination.
+    #[default]
+    Quarantine,
+    /// Leave them in the destination.
+    Keep,
+    /// Delete them like any other file.
+    Delete,
+}
+
+impl std::fmt::Display for SuspiciousDeletionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SuspiciousDeletionPolicy::Quarantine => write!(f, "moved to the quarantine"),
+            SuspiciousDeletionPolicy::Keep => write!(f, "kept"),
+            SuspiciousDeletionPolicy::Delete => write!(f, "deleted"),
+        }
+    }
+}
+
+/// What happens when a directory or one of its entries cannot be read during the traversal
+/// of the source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Abort the run at the first error.
+    FailFast,
+    /// Report the error at the end and continue with the rest.
+    #[default]
+    Collect,
+    /// Skip what cannot be read without reporting it.
+    Ignore,
+}
+
+/// How FIFOs, sockets and device nodes are backed up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpecialFilePolicy {
+    /// Leave them out of the backup.
+    #[default]
+    Skip,
+    /// Create a special file of the same type in the destination.
+    Recreate,
+    /// Report them as errors.
+    Error,
+}
+
+/// How files are backed up which are larger than the destination can store. They are found
+/// before anything is copied, so a copy never fails halfway because of the size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OversizedFilePolicy {
+    /// Report them as errors.
+    #[default]
+    Error,
+    /// Leave them out of the backup with a warning.
+    Skip,
+    /// Store them in parts which the destination can store, next to a descriptor of the
+    /// whole file. Restores and verifies join the parts again.
+    Split,
+}
+
+/// In which order the directories of a tree are visited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalkOrder {
+    /// All directories of a level before the next level.
+    #[default]
+    BreadthFirst,
+    /// All of a directory before its next sibling. Only the siblings of the directories on
+    /// the way down are pending, which is far fewer than a level of a very wide tree.
+    DepthFirst,
+}
+
+/// How symbolic links to files and directories are backed up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Copy the file the link points to.
+    #[default]
+    Follow,
+    /// Recreate the link with the same target in the destination.
+    Preserve,
+    /// Do not back up the link and warn about it.
+    Skip,
+}
+
+/// Where a backup writes the files of the source to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    /// A directory which mirrors the source, later backups only copy what changed.
+    Directory(std::path::PathBuf),
+    /// A tar archive which every backup writes anew. It is compressed with gzip or zstd if its
+    /// name ends with `.gz`/`.tgz` or `.zst`/`.tzst`, and a zip archive if it ends with `.zip`.
+    Archive(std::path::PathBuf),
+}
+
+impl Destination {
+    #[must_use]
+    pub fn path(&self) -> &std::path::Path {
+        match self {
+            Destination::Directory(path) | Destination::Archive(path) => path,
+        }
+    }
+}
+
+impl From<std::path::PathBuf> for Destination {
+    fn from(path: std::path::PathBuf) -> Self {
+        Destination::Directory(path)
+    }
+}
+
+pub enum Command {
+    Backup {
+        source_root: std::path::PathBuf,
+        destination: Destination,
+        options: BackupOptions,
+    },
+    /// Backs up only `paths` and everything in them to where a backup of the whole source puts
+    /// them, e.g. to retry the paths which a run failed on. Relative paths are relative to the
+    /// source root.
+    BackupPaths {
+        paths: Vec<std::path::PathBuf>,
+        source_root: std::path::PathBuf,
+        destination_root: std::path::PathBuf,
+        options: BackupOptions,
+    },
+    Sync {
+        source_root: std::path::PathBuf,
+        destination_root: std::path::PathBuf,
+        options: BackupOptions,
+    },
+    Restore {
+        source_root: std::path::PathBuf,
+        destination_root: std::path::PathBuf,
+        delete_files: bool,
+        /// What to do depending on the state of the source which is restored into.
+        target: RestoreTarget,
+        options: BackupOptions,
+    },
+    /// Compares the destination with the source without modifying either.
+    Verify {
+        source_root: std::path::PathBuf,
+        destination_root: std::path::PathBuf,
+        options: BackupOptions,
+    },
+    /// Compares the destination with the hashes of its manifest, without needing the source.
+    Scrub {
+        /// Source to repair corrupted files from with [`BackupOptions::repair`].
+        source_root: Option<std::path::PathBuf>,
+        destination_root: std::path::PathBuf,
+        options: BackupOptions,
+    },
+    /// Removes the snapshots of the quarantine or of the repository at the destination which
+    /// the policy does not keep.
+    Prune {
+        destination_root: std::path::PathBuf,
+        policy: RetentionPolicy,
+        options: BackupOptions,
+    },
+    /// Stores a deduplicated snapshot of the source in the repository at the destination.
+    Snapshot {
+        source_root: std::path::PathBuf,
+        destination_root: std::path::PathBuf,
+        options: BackupOptions,
+    },
+    /// Restores a snapshot of the repository at the destination into the source.
+    RestoreSnapshot {
+        source_root: std::path::PathBuf,
+        destination_root: std::path::PathBuf,
+        /// The most recent snapshot if `None`.
+        snapshot: Option<String>,
+        options: BackupOptions,
+    },
+    ListSnapshots {
+        destination_root: std::path::PathBuf,
+        options: BackupOptions,
+    },
+    /// Removes the chunks of the repository at the destination which no snapshot refers to.
+    CollectGarbage {
+        destination_root: std::path::PathBuf,
+        options: BackupOptions,
+    },
+    /// Checks the structure of the repository at the destination without reading its chunks.
+    CheckRepository {
+        destination_root: std::path::PathBuf,
+        options: BackupOptions,
+    },
+    /// Lists, adds or removes the keys of the encrypted repository at the destination. It is
+    /// unlocked with the secret of the options.
+    ManageKeys {
+        destination_root: std::path::PathBuf,
+        action: KeyAction,
+        options: BackupOptions,
+    },
+    /// Checks the source and the destination for common problems before a backup or sync.
+    Doctor {
+        source_root: std::path::PathBuf,
+        destination_root: std::path::PathBuf,
+        options: BackupOptions,
+    },
+}
+
+impl Command {
+    /// Expands the variables in the root paths, e.g. `${HOME}` or `${DATE}`.
+    #[allow(clippy::too_many_lines)]
+    fn expand_path_templates(self) -> Result<Self, Error> {
+        Ok(match self {
+            Command::Backup {
+                source_root,
+                destination,
+                options,
+            } => Command::Backup {
+                source_root: template::expand(&source_root)?,
+                destination: match destination {
+                    Destination::Directory(path) => {
+                        Destination::Directory(template::expand(&path)?)
+                    }
+                    Destination::Archive(path) => Destination::Archive(template::expand(&path)?),
+                },
+                options,
+            },
+            Command::BackupPaths {
+                paths,
+                source_root,
+                destination_root,
+                options,
+            } => Command::BackupPaths {
+                paths,
+                source_root: template::expand(&source_root)?,
+                destination_root: template::expand(&destination_root)?,
+                options,
+            },
+            Command::Sync {
+                source_root,
+                destination_root,
+                options,
+            } => Command::Sync {
+                source_root: template::expand(&source_root)?,
+                destination_root: template::expand(&destination_root)?,
+                options,
+            },
+            Command::Restore {
+                source_root,
+                destination_root,
+                delete_files,
+                target,
+                options,
+            } => Command::Restore {
+                source_root: template::expand(&source_root)?,
+                destination_root: template::expand(&destination_root)?,
+                delete_files,
+                target,
+                options,
+            },
+            Command::Verify {
+                source_root,
+                destination_root,
+                options,
+            } => Command::Verify {
+                source_root: template::expand(&source_root)?,
+                destination_root: template::expand(&destination_root)?,
+                options,
+            },
+            Command::Scrub {
+                source_root,
+                destination_root,
+                options,
+            } => Command::Scrub {
+                source_root: source_root
+                    .map(|source_root| template::expand(&source_root))
+                    .transpose()?,
+                destination_root: template::expand(&destination_root)?,
+                options,
+            },
+            Command::Prune {
+                destination_root,
+                policy,
+                options,
+            } => Command::Prune {
+                destination_root: template::expand(&destination_root)?,
+                policy,
+                options,
+            },
+            Command::Snapshot {
+                source_root,
+                destination_root,
+                options,
+            } => Command::Snapshot {
+                source_root: template::expand(&source_root)?,
+                destination_root: template::expand(&destination_root)?,
+                options,
+            },
+            Command::RestoreSnapshot {
+                source_root,
+                destination_root,
+                snapshot,
+                options,
+            } => Command::RestoreSnapshot {
+                source_root: template::expand(&source_root)?,
+                destination_root: template::expand(&destination_root)?,
+                snapshot,
+                options,
+            },
+            Command::ListSnapshots {
+                destination_root,
+                options,
+            } => Command::ListSnapshots {
+                destination_root: template::expand(&destination_root)?,
+                options,
+            },
+            Command::CollectGarbage {
+                destination_root,
+                options,
+            } => Command::CollectGarbage {
+                destination_root: template::expand(&destination_root)?,
+                options,
+            },
+            Command::CheckRepository {
+                destination_root,
+                options,
+            } => Command::CheckRepository {
+                destination_root: template::expand(&destination_root)?,
+                options,
+            },
+            Command::ManageKeys {
+                destination_root,
+                action,
+                options,
+            } => Command::ManageKeys {
+                destination_root: template::expand(&destination_root)?,
+                action,
+                options,
+            },
+            Command::Doctor {
+                source_root,
+                destination_root,
+                options,
+            } => Command::Doctor {
+                source_root: template::expand(&source_root)?,
+                destination_root: template::expand(&destination_root)?,
+                options,
+            },
+        })
+    }
+}
+
+impl Command {
+    fn options(&self) -> &BackupOptions {
+        match self {
+            Command::Backup { options, .. }
+            | Command::BackupPaths { options, .. }
+            | Command::Sync { options, .. }
+            | Command::Restore { options, .. }
+            | Command::Verify { options, .. }
+            | Command::Scrub { options, .. }
+            | Command::Prune { options, .. }
+            | Command::Snapshot { options, .. }
+            | Command::RestoreSnapshot { options, .. }
+            | Command::ListSnapshots { options, .. }
+            | Command::CollectGarbage { options, .. }
+            | Command::CheckRepository { options, .. }
+            | Command::ManageKeys { options, .. }
+            | Command::Doctor { options, .. } => options,
+        }
+    }
+
+    /// The root whose files are copied, which is the backup when restoring, scrubbing or
+    /// pruning.
+    fn copied_root(&self) -> &std::path::Path {
+        match self {
+            Command::Backup { source_root, .. }
+            | Command::BackupPaths { source_root, .. }
+            | Command::Sync { source_root, .. }
+            | Command::Verify { source_root, .. }
+            | Command::Snapshot { source_root, .. }
+            | Command::Doctor { source_root, .. } => source_root,
+            Command::Restore {
+                destination_root, ..
+            }
+            | Command::Scrub {
+                destination_root, ..
+            }
+            | Command::Prune {
+                destination_root, ..
+            }
+            | Command::RestoreSnapshot {
+                destination_root, ..
+            }
+            | Command::ListSnapshots {
+                destination_root, ..
+            }
+            | Command::CollectGarbage {
+                destination_root, ..
+            }
+            | Command::CheckRepository {
+                destination_root, ..
+            }
+            | Command::ManageKeys {
+                destination_root, ..
+            } => destination_root,
+        }
+    }
+
+    fn destination_root(&self) -> &std::path::Path {
+        match self {
+            Command::Backup { destination, .. } => destination.path(),
+            Command::BackupPaths {
+                destination_root, ..
+            }
+            | Command::Sync {
+                destination_root, ..
+            }
+            | Command::Restore {
+                destination_root, ..
+            }
+            | Command::Verify {
+                destination_root, ..
+            }
+            | Command::Scrub {
+                destination_root, ..
+            }
+            | Command::Prune {
+                destination_root, ..
+            }
+            | Command::Snapshot {
+                destination_root, ..
+            }
+            | Command::RestoreSnapshot {
+                destination_root, ..
+            }
+            | Command::ListSnapshots {
+                destination_root, ..
+            }
+            | Command::CollectGarbage {
+                destination_root, ..
+            }
+            | Command::CheckRepository {
+                destination_root, ..
+            }
+            | Command::ManageKeys {
+                destination_root, ..
+            }
+            | Command::Doctor {
+                destination_root, ..
+            } => destination_root,
+        }
+    }
+}
+
+/// Patterns of files which are never backed up, see [`always_excluded_paths`] for the rest.
+pub const ALWAYS_EXCLUDED_PATTERNS: [&str; 3] = filter::ALWAYS_EXCLUDED;
+
+/// Cache and build directories which [`BackupOptions::exclude_caches`] excludes.
+pub const CACHE_DIRECTORIES: [&str; 13] = filter::CACHE_DIRECTORIES;
+
+/// Files of safeall on this machine which are never backed up: the running executable and
+/// the data directory of safeall. Additionally the destination is never backed up.
+#[must_use]
+pub fn always_excluded_paths() -> Vec<std::path::PathBuf> {
+    std::env::current_exe()
+        .ok()
+        .into_iter()
+        .chain(destination_id::data_directory())
+        .collect()
+}
+
+/// Counts the files and directories below `root` which a run with `options` would back up.
+pub async fn scan_summary(
+    root: &std::path::Path,
+    options: &BackupOptions,
+) -> Result<ScanSummary, Error> {
+    let filter = options.filter(&[root])?;
+    let owned_root = root.to_owned();
+    tokio::task::spawn_blocking(move || scan::summary(&owned_root, &filter))
+        .await
+        .map_err(|e| Error::CannotReadDirectoryContent(root.to_owned(), e.to_string()))?
+}
+
+/// Determines what the command would do without writing anything.
+pub async fn plan(command: Command, message_sender: impl MessageSender) -> Result<Plan, Error> {
+    plan::create(&command.expand_path_templates()?, &message_sender).await
+}
+
+/// Compares the backup with the current source before a restore, such that the user can see
+/// which files would be overwritten. `None` for commands other than a restore.
+pub async fn restore_diff(
+    command: Command,
+    message_sender: impl MessageSender,
+) -> Result<Option<RestoreDiff>, Error> {
+    plan::diff_restore(&command.expand_path_templates()?, &message_sender).await
+}
+
+#[allow(clippy::too_many_lines)]
+pub async fn run(commands: Command, message_sender: impl MessageSender) -> Result<(), Error> {
+    let run_id = RunId::new();
+    let message_sender = run_id::RunSender::new(run_id, &message_sender);
+    message_sender.send(Message::Info(Info::RunStarted(run_id)));
+    let commands = commands.expand_path_templates()?;
+    if commands.options().dry_run {
+        let plan = plan::create(&commands, &message_sender).await?;
+        let root = commands.copied_root().to_owned();
+        let summary = scan_summary(&root, commands.options()).await?;
+        message_sender.send(Message::Info(Info::Scanned { root, summary }));
+        if let Some(diff) = plan::diff_restore(&commands, &message_sender).await? {
+            message_sender.send(Message::Info(Info::RestoreDiff(diff)));
+        }
+        for action in plan.actions {
+            message_sender.send(Message::Info(Info::Planned(action)));
+        }
+        return Ok(());
+    }
+    // NOTE: A restore writes to the source on purpose
+    let read_only = match &commands {
+        Command::Backup {
+            source_root,
+            destination,
+            ..
+        } => Some(read_only::ReadOnlySource::new(
+            source_root,
+            destination.path(),
+        )),
+        Command::BackupPaths {
+            source_root,
+            destination_root,
+            ..
+        }
+        | Command::Sync {
+            source_root,
+            destination_root,
+            ..
+        }
+        | Command::Verify {
+            source_root,
+            destination_root,
+            ..
+        }
+        | Command::Scrub {
+            source_root: Some(source_root),
+            destination_root,
+            ..
+        }
+        | Command::Snapshot {
+            source_root,
+            destination_root,
+            ..
+        }
+        | Command::Doctor {
+            source_root,
+            destination_root,
+            ..
+        } => Some(read_only::ReadOnlySource::new(
+            source_root,
+            destination_root,
+        )),
+        Command::Restore { .. }
+        | Command::Scrub { .. }
+        | Command::Prune { .. }
+        | Command::RestoreSnapshot { .. }
+        | Command::ListSnapshots { .. }
+        | Command::CollectGarbage { .. }
+        | Command::CheckRepository { .. }
+        | Command::ManageKeys { .. } => None,
+    };
+    // NOTE: Verifying and scrubbing have to read every file again, a repository does not
+    // compare files and an archive is written anew
+    let hash_cache = (commands.options().hash_cache
+        && !matches!(
+            commands,
+            Command::Backup {
+                destination: Destination::Archive(_),
+                ..
+            } | Command::Verify { .. }
+                | Command::Scrub { .. }
+                | Command::Snapshot { .. }
+                | Command::RestoreSnapshot { .. }
+                | Command::ListSnapshots { .. }
+                | Command::CollectGarbage { .. }
+                | Command::CheckRepository { .. }
+                | Command::ManageKeys { .. }
+                | Command::Doctor { .. }
+        ))
+    .then(|| std::sync::Arc::new(hash_cache::HashCache::load(commands.destination_root())));
+    let scan_cache = (commands.options().scan_cache
+        && !matches!(
+            commands,
+            Command::Backup {
+                destination: Destination::Archive(_),
+                ..
+            } | Command::Doctor { .. }
+        ))
+    .then(|| std::sync::Arc::new(scan_cache::ScanCache::load(commands.destination_root())));
+    let result = read_only::scope(
+        read_only,
+        hash_cache::scope(
+            hash_cache.clone(),
+            scan_cache::scope(
+                scan_cache.clone(),
+                Box::pin(execute(commands, run_id, &message_sender)),
+            ),
+        ),
+    )
+    .await;
+    if let Some(hash_cache) = hash_cache {
+        save_hash_cache(hash_cache, &message_sender).await;
+    }
+    if let Some(scan_cache) = scan_cache {
+        save_scan_cache(scan_cache, &message_sender).await;
+    }
+    result
+}
+
+async fn save_scan_cache(
+    scan_cache: std::sync::Arc<scan_cache::ScanCache>,
+    message_sender: &impl MessageSender,
+) {
+    let destination_root = scan_cache.root().to_owned();
+    let result = tokio::task::spawn_blocking(move || scan_cache.save())
+        .await
+        .map_err(std::io::Error::other)
+        .flatten();
+    if let Err(e) = result {
+        message_sender.send(Message::Warning(Warning::CannotWriteScanCache {
+            destination_root,
+            io_error: e.to_string(),
+        }));
+    }
+}
+
+async fn save_hash_cache(
+    hash_cache: std::sync::Arc<hash_cache::HashCache>,
+    message_sender: &impl MessageSender,
+) {
+    let destination_root = hash_cache.root().to_owned();
+    let result = tokio::task::spawn_blocking(move || hash_cache.save())
+        .await
+        .map_err(std::io::Error::other)
+        .flatten();
+    if let Err(e) = result {
+        message_sender.send(Message::Warning(Warning::CannotWriteHashCache {
+            destination_root,
+            io_error: e.to_string(),
+        }));
+    }
+}
+
+/// The backup of the whole source which only visits `paths`. Paths outside of the source are
+/// rejected.
+fn backup_of_paths(
+    paths: &[std::path::PathBuf],
+    source_root: &std::path::Path,
+    destination_root: &std::path::Path,
+    options: &BackupOptions,
+) -> Result<Command, Error> {
+    if let Some(path) = paths.iter().find(|path| {
+        path.components()
+            .any(|component| matches!(component, std::path::Component::ParentDir))
+            || !source_root.join(path).starts_with(source_root)
+    }) {
+        return Err(Error::PathNotInSource {
+            path: path.clone(),
+            source_root: source_root.to_owned(),
+        });
+    }
+    let path_filter = filter::ExplicitPaths::new(source_root, paths, options.path_filter.clone());
+    Ok(Command::Backup {
+        source_root: source_root.to_owned(),
+        destination: Destination::Directory(destination_root.to_owned()),
+        options: BackupOptions {
+            path_filter: Some(std::sync::Arc::new(path_filter)),
+            ..options.clone()
+        },
+    })
+}
+
+/// Runs a command whose paths have already been expanded.
+#[allow(clippy::too_many_lines)]
+async fn execute(
+    commands: Command,
+    run_id: RunId,
+    message_sender: &impl MessageSender,
+) -> Result<(), Error> {
+    match commands {
+        Command::Backup {
+            source_root,
+            destination: Destination::Archive(archive),
+            options,
+        } => {
+            let filter = options.filter(&[&source_root, &archive])?;
+            check_mounted(&archive, &options)?;
+            archive::create(&source_root, &archive, &filter, &options, message_sender).await
+        }
+        Command::Backup {
+            source_root,
+            destination: Destination::Directory(destination_root),
+            options,
+        } => {
+            let filter = options.filter(&[&source_root, &destination_root])?;
+            check_mounted(&destination_root, &options)?;
+            destination_id::verify(
+                &destination_root,
+                options.known_destinations.as_deref(),
+                options.accept_new_destination,
+                message_sender,
+            )?;
+            let single_file = source_root.is_file();
+            if single_file {
+                validate_or_create_destination_root(&destination_root, message_sender)?;
+            } else {
+                validate_or_create_root_paths(&source_root, &destination_root, message_sender)?;
+            }
+            let _lock = lock::DestinationLock::acquire(&destination_root)?;
+            destination_id::assign(
+                &destination_root,
+                options.known_destinations.as_deref(),
+                message_sender,
+            )?;
+            check_clock(&destination_root, message_sender).await;
+            let recorder = history::Recorder::start(
+                &source_root,
+                &destination_root,
+                history::RunKind::Backup,
+                run_id,
+                message_sender,
+            );
+            for path in filter.only_paths().unwrap_or_default() {
+                if tokio::fs::symlink_metadata(path).await.is_err() {
+                    recorder.send(Message::Warning(Warning::SourceVanished(path.clone())));
+                }
+            }
+            if single_file {
+                let result =
+                    backup_file(&source_root, &destination_root, &options, &recorder).await;
+                recorder.finish(&destination_root, &result);
+                return result;
+            }
+            let result = backup(&source_root, &destination_root, &options, &recorder).await;
+            copy_directory_metadata(
+                &source_root,
+                &destination_root,
+                &filter,
+                &options,
+                &recorder,
+            )
+            .await;
+            update_manifest(&source_root, &destination_root, run_id, message_sender).await;
+            if let Some(percent) = options.parity {
+                update_parity(&destination_root, percent, message_sender).await;
+            }
+            recorder.finish(&destination_root, &result);
+            result
+        }
+        Command::BackupPaths {
+            paths,
+            source_root,
+            destination_root,
+            options,
+        } => {
+            let backup = backup_of_paths(&paths, &source_root, &destination_root, &options)?;
+            Box::pin(execute(backup, run_id, message_sender)).await
+        }
+        Command::Sync {
+            source_root,
+            destination_root,
+            options,
+        } => {
+            let filter = options.filter(&[&source_root, &destination_root])?;
+            check_mounted(&destination_root, &options)?;
+            check_source_mounted(&source_root, &options)?;
+            destination_id::verify(
+                &destination_root,
+                options.known_destinations.as_deref(),
+                options.accept_new_destination,
+                message_sender,
+            )?;
+            validate_or_create_root_paths(&source_root, &destination_root, message_sender)?;
+            let _lock = lock::DestinationLock::acquire(&destination_root)?;
+            destination_id::assign(
+                &destination_root,
+                options.known_destinations.as_deref(),
+                message_sender,
+            )?;
+            check_clock(&destination_root, message_sender).await;
+            check_mass_change(
+                &source_root,
+                &destination_root,
+                &filter,
+                options.mass_change_threshold,
+            )
+            .await?;
+            let recorder = history::Recorder::start(
+                &source_root,
+                &destination_root,
+                history::RunKind::Sync,
+                run_id,
+                message_sender,
+            );
+            let result = async {
+                backup(&source_root, &destination_root, &options, &recorder).await?;
+                if !approve_purge(&destination_root, &options, message_sender).await {
+                    return Ok(());
+                }
+                purge_files_and_dirs_in_destination(
+                    &source_root,
+                    &destination_root,
+                    &filter,
+                    Some(options.suspicious_deletions),
+                    options.max_errors,
+                    &recorder,
+                )
+                .await
+            }
+            .await;
+            copy_directory_metadata(
+                &source_root,
+                &destination_root,
+                &filter,
+                &options,
+                &recorder,
+            )
+            .await;
+            update_manifest(&source_root, &destination_root, run_id, message_sender).await;
+            if let Some(percent) = options.parity {
+                update_parity(&destination_root, percent, message_sender).await;
+            }
+            recorder.finish(&destination_root, &result);
+            result
+        }
+        Command::Restore {
+            source_root,
+            destination_root,
+            delete_files,
+            target,
+            mut options,
+        } => {
+            // NOTE: Kept versions are neither restored nor deleted as missing from the backup
+            if options.keep_overwritten {
+                options.exclude.extend([
+                    format!("*.{PRE_RESTORE_SUFFIX}"),
+                    format!("*.{PRE_RESTORE_SUFFIX}-*"),
+                ]);
+            }
+            let filter = options.filter(&[&source_root, &destination_root])?;
+            check_mounted(&destination_root, &options)?;
+            // NOTE: Restoring from an unmounted drive with `delete_files` would wipe the source
+            destination_id::verify(
+                &destination_root,
+                options.known_destinations.as_deref(),
+                options.accept_new_destination,
+                message_sender,
+            )?;
+            restore_target::prepare(&source_root, &destination_root, target, message_sender)?;
+            let _lock = lock::DestinationLock::acquire(&destination_root)?;
+            // NOTE: Same as sync but switch arguments
+            let result = async {
+                backup(&destination_root, &source_root, &options, message_sender).await?;
+                if delete_files && approve_purge(&source_root, &options, message_sender).await {
+                    purge_files_and_dirs_in_destination(
+                        &destination_root,
+                        &source_root,
+                        &filter,
+                        None,
+                        options.max_errors,
+                        message_sender,
+                    )
+                    .await?;
+                }
+                Ok(())
+            }
+            .await;
+            copy_directory_metadata(
+                &destination_root,
+                &source_root,
+                &filter,
+                &options,
+                message_sender,
+            )
+            .await;
+            result
+        }
+        Command::Verify {
+            source_root,
+            destination_root,
+            options,
+        } => {
+            check_mounted(&destination_root, &options)?;
+            destination_id::verify(
+                &destination_root,
+                options.known_destinations.as_deref(),
+                options.accept_new_destination,
+                message_sender,
+            )?;
+            let mut report =
+                verify::verify(&source_root, &destination_root, &options, message_sender).await?;
+            if options.repair {
+                verify::repair(
+                    &mut report,
+                    &source_root,
+                    &destination_root,
+                    &options,
+                    message_sender,
+                )
+                .await;
+            }
+            let consistent = report.is_consistent();
+            message_sender.send(Message::Info(Info::Verified(report)));
+            if consistent {
+                Ok(())
+            } else {
+                Err(Error::DestinationInconsistent(destination_root))
+            }
+        }
+        Command::Scrub {
+            source_root,
+            destination_root,
+            options,
+        } => {
+            check_mounted(&destination_root, &options)?;
+            destination_id::verify(
+                &destination_root,
+                options.known_destinations.as_deref(),
+                options.accept_new_destination,
+                message_sender,
+            )?;
+            let mut report = scrub::scrub(&destination_root, message_sender).await?;
+            if options.repair {
+                scrub::reconstruct(&mut report, &destination_root, message_sender).await;
+            }
+            if options.repair
+                && let Some(source_root) = source_root
+            {
+                scrub::repair(
+                    &mut report,
+                    &source_root,
+                    &destination_root,
+                    &options,
+                    message_sender,
+                )
+                .await;
+            }
+            let intact = report.is_intact();
+            message_sender.send(Message::Info(Info::Scrubbed(report)));
+            if intact {
+                Ok(())
+            } else {
+                Err(Error::DestinationCorrupted(destination_root))
+            }
+        }
+        Command::Prune {
+            destination_root,
+            policy,
+            options,
+        } => {
+            check_mounted(&destination_root, &options)?;
+            destination_id::verify(
+                &destination_root,
+                options.known_destinations.as_deref(),
+                options.accept_new_destination,
+                message_sender,
+            )?;
+            let _lock = lock::DestinationLock::acquire(&destination_root)?;
+            let report = prune::prune(
+                &destination_root,
+                &policy,
+                options.secret.as_ref(),
+                message_sender,
+            )
+            .await?;
+            message_sender.send(Message::Info(Info::Pruned(report)));
+            Ok(())
+        }
+        Command::Snapshot {
+            source_root,
+            destination_root,
+            options,
+        } => {
+            let filter = options.filter(&[&source_root, &destination_root])?;
+            check_mounted(&destination_root, &options)?;
+            destination_id::verify(
+                &destination_root,
+                options.known_destinations.as_deref(),
+                options.accept_new_destination,
+                message_sender,
+            )?;
+            validate_or_create_root_paths(&source_root, &destination_root, message_sender)?;
+            let _lock = lock::DestinationLock::acquire(&destination_root)?;
+            destination_id::assign(
+                &destination_root,
+                options.known_destinations.as_deref(),
+                message_sender,
+            )?;
+            repo::backup(
+                &source_root,
+                &destination_root,
+                &filter,
+                options.compression_level,
+                options.secret.as_ref(),
+                message_sender,
+            )
+            .await
+        }
+        Command::RestoreSnapshot {
+            source_root,
+            destination_root,
+            snapshot,
+            options,
+        } => {
+            check_mounted(&destination_root, &options)?;
+            destination_id::verify(
+                &destination_root,
+                options.known_destinations.as_deref(),
+                options.accept_new_destination,
+                message_sender,
+            )?;
+            validate_or_create_destination_root(&source_root, message_sender)?;
+            let _lock = lock::DestinationLock::acquire(&destination_root)?;
+            repo::restore(
+                &destination_root,
+                snapshot.as_deref(),
+                &source_root,
+                options.secret.as_ref(),
+                message_sender,
+            )
+            .await
+        }
+        Command::ListSnapshots {
+            destination_root,
+            options,
+        } => {
+            check_mounted(&destination_root, &options)?;
+            let snapshots = repo::list(&destination_root, options.secret.as_ref())?;
+            message_sender.send(Message::Info(Info::Snapshots(snapshots)));
+            Ok(())
+        }
+        Command::CollectGarbage {
+            destination_root,
+            options,
+        } => {
+            check_mounted(&destination_root, &options)?;
+            destination_id::verify(
+                &destination_root,
+                options.known_destinations.as_deref(),
+                options.accept_new_destination,
+                message_sender,
+            )?;
+            let _lock = lock::DestinationLock::acquire(&destination_root)?;
+            let collected =
+                repo::collect_garbage(&destination_root, options.secret.as_ref()).await?;
+            message_sender.send(Message::Info(Info::GarbageCollected(collected)));
+            Ok(())
+        }
+        Command::CheckRepository {
+            destination_root,
+            options,
+        } => {
+            check_mounted(&destination_root, &options)?;
+            // NOTE: A garbage collection at the same time would look like damage
+            let _lock = lock::DestinationLock::acquire(&destination_root)?;
+            let report = repo::check(&destination_root, options.secret.as_ref()).await?;
+            let intact = report.is_intact();
+            message_sender.send(Message::Info(Info::RepositoryChecked(report)));
+            if intact {
+                Ok(())
+            } else {
+                Err(Error::RepositoryDamaged(destination_root))
+            }
+        }
+        Command::ManageKeys {
+            destination_root,
+            action,
+            options,
+        } => {
+            check_mounted(&destination_root, &options)?;
+            let _lock = if action == KeyAction::List {
+                None
+            } else {
+                destination_id::verify(
+                    &destination_root,
+                    options.known_destinations.as_deref(),
+                    options.accept_new_destination,
+                    message_sender,
+                )?;
+                Some(lock::DestinationLock::acquire(&destination_root)?)
+            };
+            repo::manage_keys(
+                &destination_root,
+                options.secret.as_ref(),
+                &action,
+                message_sender,
+            )
+        }
+        Command::Doctor {
+            source_root,
+            destination_root,
+            options,
+        } => {
+            let findings = doctor::diagnose(&source_root, &destination_root, &options).await?;
+            let problems = findings
+                .iter()
+                .filter(|finding| finding.severity() == Severity::Error)
+                .count();
+            message_sender.send(Message::Info(Info::Diagnosis(findings)));
+            if problems == 0 {
+                Ok(())
+            } else {
+                Err(Error::ProblemsFound(problems))
+            }
+        }
+    }
+}
+
+/// Waits for the frontend to approve the deletions in `root` if the options ask for it.
+async fn approve_purge(
+    root: &std::path::Path,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) -> bool {
+    if !options.interactive_delete {
+        return true;
+    }
+    let approved = checkpoint::wait(message_sender, |checkpoint| Info::PurgeCheckpoint {
+        destination_root: root.to_owned(),
+        checkpoint,
+    })
+    .await;
+    if !approved {
+        message_sender.send(Message::Info(Info::PurgeSkipped(root.to_owned())));
+    }
+    approved
+}
+
+/// Copies the sources of damaged files of the destination again. Their content is compared
+/// as bit rot keeps the size and modification time. Returns the repaired destination files.
+async fn repair_files(
+    files: Vec<(std::path::PathBuf, std::path::PathBuf)>,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) -> Vec<std::path::PathBuf> {
+    let options = BackupOptions {
+        comparator: std::sync::Arc::new(CompareStrategy::AlwaysHash),
+        hash_max_size: None,
+        ..options.clone()
+    };
+    message_sender.send(Message::Progress(Progress::Start(
+        files.len(),
+        ProgressType::CopingFiles,
+    )));
+    let mut repaired = vec![];
+    let mut failed = 0;
+    for (source, destination) in files {
+        // NOTE: The parts of a split file cannot be compared one by one, so it is split again
+        tokio::fs::remove_file(split::descriptor_path(&destination))
+            .await
+            .ok();
+        match copy_or_skip_if_same(&source, &destination, &options, message_sender).await {
+            Ok(CopyOutcome::Consistent) => {
+                message_sender.send(Message::Info(Info::Repaired {
+                    source,
+                    destination: destination.clone(),
+                }));
+                repaired.push(destination);
+            }
+            // NOTE: The next run copies a source which changed during the repair
+            Ok(CopyOutcome::SourceChanged | CopyOutcome::HardLinkOf(_)) => {}
+            Err(e) => {
+                failed += 1;
+                message_sender.send(Message::Progress(Progress::IncrementFail(e)));
+            }
+        }
+    }
+    if failed > 0 {
+        message_sender.send(Message::Progress(Progress::EndFail(
+            failed,
+            ProgressType::CopingFiles,
+        )));
+    } else {
+        message_sender.send(Message::Progress(Progress::EndSuccess(
+            ProgressType::CopingFiles,
+        )));
+    }
+    repaired
+}
+
+async fn update_manifest(
+    source_root: &std::path::Path,
+    destination_root: &std::path::Path,
+    run_id: RunId,
+    message_sender: &impl MessageSender,
+) {
+    let source = source_root.to_owned();
+    let destination = destination_root.to_owned();
+    let hash_cache = hash_cache::current();
+    let result = tokio::task::spawn_blocking(move || {
+        hash_cache::enter(hash_cache, || {
+            manifest::Manifest::update(&source, &destination, run_id)
+        })
+    })
+    .await
+    .map_err(std::io::Error::other)
+    .flatten();
+    if let Err(e) = result {
+        message_sender.send(Message::Warning(Warning::CannotWriteManifest {
+            destination_root: destination_root.to_owned(),
+            io_error: e.to_string(),
+        }));
+    }
+}
+
+async fn update_parity(
+    destination_root: &std::path::Path,
+    percent: u8,
+    message_sender: &impl MessageSender,
+) {
+    let destination = destination_root.to_owned();
+    let result = tokio::task::spawn_blocking(move || parity::update(&destination, percent))
+        .await
+        .map_err(std::io::Error::other)
+        .flatten();
+    if let Err(e) = result {
+        message_sender.send(Message::Warning(Warning::CannotWriteParity {
+            destination_root: destination_root.to_owned(),
+            io_error: e.to_string(),
+        }));
+    }
+}
+
+#[inline]
+fn get_destination_file_path(
+    destination_root: &std::path::Path,
+    source_root: &std::path::Path,
+    source_path: &std::path::Path,
+) -> Result<std::path::PathBuf, ProcessPathError> {
+    let path_end = strip_root(source_root, source_path)?;
+    Ok([destination_root, path_end].iter().collect())
+}
+
+#[inline]
+fn strip_root<'a>(
+    root: &std::path::Path,
+    path: &'a std::path::Path,
+) -> Result<&'a std::path::Path, ProcessPathError> {
+    path.strip_prefix(root).map_err(|e| ProcessPathError {
+        not_processed: Some(path.to_owned()),
+        kind: ProcessPathErrorKind::InvariantBroken(InvariantError::CannotStripPrefixOfPath {
+            path_root: root.to_owned(),
+            path: path.to_owned(),
+            error: e,
+        }),
+    })
+}
+
+/// Protects files in the destination which safeall has never backed up from being deleted.
+struct DeletionGuard<'a> {
+    policy: SuspiciousDeletionPolicy,
+    destination_root: &'a std::path::Path,
+    quarantine: std::path::PathBuf,
+    suspicious: Vec<std::path::PathBuf>,
+}
+
+impl<'a> DeletionGuard<'a> {
+    async fn try_new(
+        source_root: &std::path::Path,
+        destination_root: &'a std::path::Path,
+        filter: &filter::Filter,
+        policy: SuspiciousDeletionPolicy,
+        message_sender: &impl MessageSender,
+    ) -> Result<Self, Error> {
+        let source_recurse_files = RecursiveReadDir::try_new(source_root, ReadDirType::FilesOnly)
+            .map_err(|e| Error::CannotReadDirectoryContent(source_root.to_owned(), e.to_string()))?
+            .with_filter(filter.clone());
+        let destination_recurse_files =
+            RecursiveReadDir::try_new(destination_root, ReadDirType::FilesOnly)
+                .map_err(|e| {
+                    Error::CannotReadDirectoryContent(destination_root.to_owned(), e.to_string())
+                })?
+                .with_filter(filter.clone());
+        let files_to_delete = get_paths_in_destinatination_but_not_in_source(
+            source_recurse_files,
+            destination_recurse_files,
+        )
+        .await
+        .map_err(|e| Error::ProcessPathErrors {
+            directories: vec![],
+            files: vec![e],
+        })?;
+
+        let manifest = manifest::Manifest::load(destination_root);
+        let suspicious: Vec<_> = files_to_delete
+            .into_iter()
+            .filter(|file| {
+                file.strip_prefix(destination_root)
+                    .map_or(true, |relative_path| !manifest.contains(relative_path))
+            })
+            .collect();
+        if !suspicious.is_empty() {
+            message_sender.send(Message::Warning(Warning::SuspiciousDeletions {
+                count: suspicious.len(),
+                policy,
+            }));
+        }
+
+        let started = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Ok(Self {
+            policy,
+            destination_root,
+            quarantine: destination_root
+                .join(METADATA_DIRECTORY)
+                .join(QUARANTINE_DIRECTORY)
+                .join(started.to_string()),
+            suspicious,
+        })
+    }
+
+    /// Whether `path` is a suspicious file or a directory containing one.
+    fn is_suspicious(&self, path: &std::path::Path) -> bool {
+        self.suspicious.iter().any(|s| s.starts_with(path))
+    }
+
+    async fn handle(&self, path: &std::path::Path) -> Result<Increment, ProcessPathError> {
+        match self.policy {
+            SuspiciousDeletionPolicy::Keep | SuspiciousDeletionPolicy::Delete => {
+                Ok(Increment::KeptSuspicious(path.to_owned()))
+            }
+            SuspiciousDeletionPolicy::Quarantine => {
+                let quarantine =
+                    get_destination_file_path(&self.quarantine, self.destination_root, path)?;
+                read_only::check(path)?;
+                read_only::check(&quarantine)?;
+                let moved = async {
+                    if let Some(parent) = quarantine.parent() {
+                        tokio::fs::create_dir_all(parent).await?;
+                    }
+                    let (from, to) = (path.to_owned(), quarantine.clone());
+                    tokio::task::spawn_blocking(move || copier::move_path(&from, &to))
+                        .await
+                        .map_err(std::io::Error::other)
+                        .flatten()
+                }
+                .await;
+                moved
+                    .map(|()| Increment::Quarantined {
+                        path: path.to_owned(),
+                        quarantine: quarantine.clone(),
+                    })
+                    .map_err(|e| ProcessPathError {
+                        not_processed: Some(path.to_owned()),
+                        kind: ProcessPathErrorKind::CannotQuarantine {
+                            quarantine,
+                            io_error: e.to_string(),
+                        },
+                    })
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+async fn purge_files_and_dirs_in_destination<P: AsRef<std::path::Path>>(
+    source_root: P,
+    destination_root: P,
+    filter: &filter::Filter,
+    suspicious_deletions: Option<SuspiciousDeletionPolicy>,
+    max_errors: Option<usize>,
+    message_sender: &impl MessageSender,
+) -> Result<(), Error> {
+    use futures::stream::StreamExt;
+
+    let source_root = source_root.as_ref();
+    let destination_root = destination_root.as_ref();
+    let guard = match suspicious_deletions {
+        Some(policy) if policy != SuspiciousDeletionPolicy::Delete => Some(
+            DeletionGuard::try_new(
+                source_root,
+                destination_root,
+                filter,
+                policy,
+                message_sender,
+            )
+            .await?,
+        ),
+        _ => None,
+    };
+    let source_recurse_directories =
+        RecursiveReadDir::try_new(source_root, ReadDirType::DirectoriesOnly)
+            .map_err(|e| Error::CannotReadDirectoryContent(source_root.to_owned(), e.to_string()))?
+            .with_filter(filter.clone());
+    let destination_recurse_directories =
+        RecursiveReadDir::try_new(destination_root, ReadDirType::DirectoriesOnly)
+            .map_err(|e| {
+                Error::CannotReadDirectoryContent(destination_root.to_owned(), e.to_string())
+            })?
+            .with_filter(filter.clone());
+
+    let dirs_to_delete = get_paths_in_destinatination_but_not_in_source(
+        source_recurse_directories,
+        destination_recurse_directories,
+    )
+    .await
+    .map_err(|e| Error::ProcessPathErrors {
+        directories: vec![e],
+        files: vec![],
+    })?;
+
+    let num_dirs = futures::stream::iter(dirs_to_delete.clone()).count().await;
+    message_sender.send(Message::Progress(Progress::Start(
+        num_dirs,
+        ProgressType::DeletingDirs,
+    )));
+
+    let mut errors_directory = vec![];
+    let mut deleted_dirs = vec![];
+    let mut dir_stream = futures::stream::iter(dirs_to_delete);
+    while let Some(dir) = dir_stream.next().await {
+        if deleted_dirs.iter().any(|d| dir.starts_with(d)) {
+            message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                Increment::DirectoryAlreadyDeleted(dir),
+            )));
+            continue;
+        }
+        if let Some(guard) = &guard
+            && guard.is_suspicious(&dir)
+        {
+            match guard.handle(&dir).await {
+                Ok(increment) => {
+                    if matches!(increment, Increment::Quarantined { .. }) {
+                        deleted_dirs.push(dir);
+                    }
+                    message_sender.send(Message::Progress(Progress::IncrementSuccess(increment)));
+                }
+                Err(error) => {
+                    message_sender.send(Message::Progress(Progress::IncrementFail(error.clone())));
+                    errors_directory.push(error);
+                }
+            }
+            continue;
+        }
+        if let Err(error) = read_only::check(&dir) {
+            message_sender.send(Message::Progress(Progress::IncrementFail(error.clone())));
+            errors_directory.push(error);
+            continue;
+        }
+        message_sender.send(Message::Info(Info::StartDeletingDir(dir.clone())));
+        if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+            let error = ProcessPathError {
+                not_processed: Some(dir),
+                kind: ProcessPathErrorKind::CannotDeleteDirectory {
+                    io_error: e.to_string(),
+                },
+            };
+            message_sender.send(Message::Progress(Progress::IncrementFail(error.clone())));
+            errors_directory.push(error);
+        } else {
             message_sender.send(Message::Progress(Progress::IncrementSuccess(
                 Increment::DeletedDir(dir.clone()),
             )));
             deleted_dirs.push(dir);
         }
+        if let Err(error) = Error::check_limit(errors_directory.len(), max_errors) {
+            message_sender.send(Message::Progress(Progress::EndFail(
+                errors_directory.len(),
+                ProgressType::DeletingDirs,
+            )));
+            return Err(error);
+        }
+    }
+    if errors_directory.is_empty() {
+        message_sender.send(Message::Progress(Progress::EndSuccess(
+            ProgressType::DeletingDirs,
+        )));
+    } else {
+        message_sender.send(Message::Progress(Progress::EndFail(
+            errors_directory.len(),
+            ProgressType::DeletingDirs,
+        )));
+    }
+
+    let source_recurse_files = RecursiveReadDir::try_new(source_root, ReadDirType::FilesOnly)
+        .map_err(|e| Error::CannotReadDirectoryContent(source_root.to_owned(), e.to_string()))?
+        .with_filter(filter.clone());
+    let destination_recurse_files =
+        RecursiveReadDir::try_new(destination_root, ReadDirType::FilesOnly)
+            .map_err(|e| {
+                Error::CannotReadDirectoryContent(destination_root.to_owned(), e.to_string())
+            })?
+            .with_filter(filter.clone());
+
+    let files_to_delete = get_paths_in_destinatination_but_not_in_source(
+        source_recurse_files,
+        destination_recurse_files,
+    )
+    .await
+    .map_err(|e| Error::ProcessPathErrors {
+        directories: vec![],
+        files: vec![e],
+    })?;
+    let num_files = futures::stream::iter(files_to_delete.clone()).count().await;
+    message_sender.send(Message::Progress(Progress::Start(
+        num_files,
+        ProgressType::DeletingFiles,
+    )));
+
+    let mut results = futures::stream::iter(files_to_delete)
+        .map(async |file| {
+            if deleted_dirs.iter().any(|d| file.starts_with(d)) {
+                message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                    Increment::FileAlreadyDeleted(file),
+                )));
+                return Ok(());
+            }
+            if let Some(guard) = &guard
+                && guard.is_suspicious(&file)
+            {
+                return guard
+                    .handle(&file)
+                    .await
+                    .inspect(|increment| {
+                        message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                            increment.clone(),
+                        )));
+                    })
+                    .inspect_err(|e| {
+                        message_sender.send(Message::Progress(Progress::IncrementFail(e.clone())));
+                    })
+                    .map(|_| ());
+            }
+            read_only::check(&file).inspect_err(|e| {
+                message_sender.send(Message::Progress(Progress::IncrementFail(e.clone())));
+            })?;
+            message_sender.send(Message::Info(Info::StartDeletingFile(file.clone())));
+            tokio::fs::remove_file(&file)
+                .await
+                .map_err(|e| ProcessPathError {
+                    not_processed: Some(file.clone()),
+                    kind: ProcessPathErrorKind::CannotDeleteFile {
+                        io_error: e.to_string(),
+                    },
+                })
+                .inspect(|()| {
+                    message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                        Increment::DeletedFile(file),
+                    )));
+                })
+                .inspect_err(|e| {
+                    message_sender.send(Message::Progress(Progress::IncrementFail(e.clone())));
+                })
+        })
+        .buffer_unordered(cpu_count());
+    let mut errors_file = vec![];
+    while let Some(result) = results.next().await {
+        let Err(error) = result else {
+            continue;
+        };
+        errors_file.push(error);
+        if let Err(error) =
+            Error::check_limit(errors_directory.len() + errors_file.len(), max_errors)
+        {
+            message_sender.send(Message::Progress(Progress::EndFail(
+                errors_file.len(),
+                ProgressType::DeletingFiles,
+            )));
+            return Err(error);
+        }
+    }
+    if errors_file.is_empty() {
+        message_sender.send(Message::Progress(Progress::EndSuccess(
+            ProgressType::DeletingFiles,
+        )));
+    } else {
+        message_sender.send(Message::Progress(Progress::EndFail(
+            errors_file.len(),
+            ProgressType::DeletingFiles,
+        )));
+    }
+
+    Error::from_processing_results(errors_directory, errors_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WRONG_TEST_DIR: &str = "-------";
+    const TEST_DIR: &str = "testdir";
+    const TEST_DIR_LESS: &str = "testdir_less";
+    const TEST_DIR_LESS_AND_ADDITIONAL: &str = "testdir_less_and_additional";
+    const TEST_DIR_ADDITIONAL: &str = "testdir_additional";
+    const TEST_DIR_FILES: [&str; 12] = [
+        // TODO: Check if this order is the same on all platforms
+        "testdir/03_a",
+        "testdir/05_directory.csv",
+        "testdir/04_test.py",
+        "testdir/01_This.txt",
+        "testdir/02_is.o",
+        "testdir/more/wèirder,name.txt",
+        "testdir/more/weird name.txt",
+        "testdir/more2/some more file",
+        "testdir/more2/some file",
+        "testdir/more/even-mörer/all-solutions.bak",
+        "testdir/more/even-möre/wèirder,name.txt",
+        "testdir/more2/moredir/epic.file",
+    ];
+    const TEST_DIR_DIRECTORIES: [&str; 5] = [
+        // TODO: Check if this order is the same on all platforms
+        "testdir/more/",
+        "testdir/more2/",
+        "testdir/more/even-mörer/",
+        "testdir/more/even-möre/",
+        "testdir/more2/moredir/",
+    ];
+
+    #[test]
+    fn test_recurse_files() {
+        let file_entry = RecursiveReadDir::try_new(TEST_DIR, ReadDirType::FilesOnly).unwrap();
+        let mut files = file_entry
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(dbg!(&files).len(), dbg!(&TEST_DIR_FILES).len());
+        // NOTE: The order of `read_dir` depends on the platform and filesystem
+        let mut expected = TEST_DIR_FILES
+            .iter()
+            .map(std::path::Path::new)
+            .collect::<Vec<_>>();
+        expected.sort();
+        files.sort();
+        assert_eq!(files, expected);
+    }
+    #[test]
+    fn test_recurse_directories() {
+        let file_entry = RecursiveReadDir::try_new(TEST_DIR, ReadDirType::DirectoriesOnly).unwrap();
+        let mut files = file_entry
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(dbg!(&files).len(), dbg!(&TEST_DIR_DIRECTORIES).len());
+        // NOTE: The order of `read_dir` depends on the platform and filesystem
+        let mut expected = TEST_DIR_DIRECTORIES
+            .iter()
+            .map(std::path::Path::new)
+            .collect::<Vec<_>>();
+        expected.sort();
+        files.sort();
+        assert_eq!(files, expected);
+    }
+    #[test]
+    fn test_recurse_relative_files() {
+        let file_entry = RecursiveReadDir::try_new(TEST_DIR, ReadDirType::FilesOnly).unwrap();
+        let mut files = file_entry
+            .relative()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let mut expected = TEST_DIR_FILES
+            .iter()
+            .map(|f| std::path::Path::new(f).strip_prefix(TEST_DIR).unwrap())
+            .collect::<Vec<_>>();
+        expected.sort();
+        files.sort();
+        assert_eq!(files, expected);
+    }
+    #[test]
+    fn test_recursive_readdir_error_policy() {
+        for (error_policy, expected_errors) in [
+            (ErrorPolicy::Collect, 2),
+            (ErrorPolicy::FailFast, 1),
+            (ErrorPolicy::Ignore, 0),
+        ] {
+            let root = tempfile::tempdir().unwrap();
+            for name in ["a", "b", "c"] {
+                std::fs::create_dir(root.path().join(name)).unwrap();
+            }
+            let mut directories =
+                RecursiveReadDir::try_new(root.path(), ReadDirType::DirectoriesOnly)
+                    .unwrap()
+                    .with_error_policy(error_policy);
+            let first = directories.next().unwrap().unwrap();
+            // NOTE: The remaining directories are already queued and cannot be read anymore
+            for name in ["a", "b", "c"] {
+                let path = root.path().join(name);
+                if path != first {
+                    std::fs::remove_dir(path).unwrap();
+                }
+            }
+            let rest: Vec<_> = directories.collect();
+            assert_eq!(rest.len(), expected_errors, "{error_policy:?}");
+            assert!(rest.iter().all(Result::is_err));
+        }
+    }
+    #[cfg(unix)]
+    #[test]
+    fn test_recursive_readdir_cycles() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("a/b")).unwrap();
+        std::fs::write(root.path().join("a/b/file.txt"), b"content").unwrap();
+        std::os::unix::fs::symlink(root.path(), root.path().join("a/b/loop")).unwrap();
+        std::os::unix::fs::symlink(root.path().join("a"), root.path().join("also_a")).unwrap();
+
+        let mut directories =
+            RecursiveReadDir::try_new(root.path(), ReadDirType::DirectoriesOnly).unwrap();
+        let mut visited: Vec<_> = directories.by_ref().map(Result::unwrap).collect();
+        visited.sort();
+        let mut cycles = directories.cycles().to_vec();
+        cycles.sort();
+        // NOTE: The sibling link to "a" is not a loop, so it is walked like "a" itself
+        assert_eq!(
+            visited,
+            ["a", "a/b", "also_a", "also_a/b"].map(|path| root.path().join(path))
+        );
+        assert_eq!(
+            cycles,
+            ["a/b/loop", "also_a/b/loop"].map(|path| root.path().join(path))
+        );
+        let files = RecursiveReadDir::try_new(root.path(), ReadDirType::FilesOnly)
+            .unwrap()
+            .count();
+        assert_eq!(files, 2);
+    }
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sync_keeps_sibling_symlink_to_directory() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(source.path().join("a/b")).unwrap();
+        std::fs::write(source.path().join("a/b/file.txt"), b"content").unwrap();
+        std::os::unix::fs::symlink(source.path().join("a"), source.path().join("also_a")).unwrap();
+        let sync = || Command::Sync {
+            source_root: source.path().to_owned(),
+            destination_root: destination.path().to_owned(),
+            options: BackupOptions::default(),
+        };
+
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(sync(), message_sender.clone()).await.unwrap();
+        run(sync(), message_sender).await.unwrap();
+        for file in ["a/b/file.txt", "also_a/b/file.txt"] {
+            assert_eq!(
+                std::fs::read(destination.path().join(file)).unwrap(),
+                b"content"
+            );
+        }
+    }
+    #[test]
+    fn test_recursive_readdir_max_depth() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("a/b/c")).unwrap();
+        std::fs::write(root.path().join("top.txt"), b"").unwrap();
+        std::fs::write(root.path().join("a/middle.txt"), b"").unwrap();
+        std::fs::write(root.path().join("a/b/c/deep.txt"), b"").unwrap();
+
+        let walk = |readdir_type| {
+            let mut paths: Vec<_> = RecursiveReadDir::try_new(root.path(), readdir_type)
+                .unwrap()
+                .with_max_depth(Some(2))
+                .relative()
+                .map(Result::unwrap)
+                .collect();
+            paths.sort();
+            paths
+        };
+        assert_eq!(
+            walk(ReadDirType::DirectoriesOnly),
+            [std::path::Path::new("a"), std::path::Path::new("a/b")]
+        );
+        assert_eq!(
+            walk(ReadDirType::FilesOnly),
+            [
+                std::path::Path::new("a/middle.txt"),
+                std::path::Path::new("top.txt")
+            ]
+        );
+    }
+    #[test]
+    fn test_recursive_readdir_walk_order() {
+        let root = tempfile::tempdir().unwrap();
+        for directory in ["a/a1", "a/a2", "b/b1"] {
+            std::fs::create_dir_all(root.path().join(directory)).unwrap();
+        }
+        let walk = |walk_order| {
+            RecursiveReadDir::try_new(root.path(), ReadDirType::DirectoriesOnly)
+                .unwrap()
+                .with_walk_order(walk_order)
+                .relative()
+                .map(|path| path.unwrap().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        let breadth_first = walk(WalkOrder::BreadthFirst);
+        let depth_first = walk(WalkOrder::DepthFirst);
+        assert_eq!(breadth_first.len(), 5);
+        assert!(breadth_first[..2].iter().all(|path| !path.contains('/')));
+        // NOTE: The order of siblings is the one of the file system
+        let position = |path: &str| depth_first.iter().position(|p| p == path).unwrap();
+        let (first, second) = if position("a") < position("b") {
+            ("a", "b")
+        } else {
+            ("b", "a")
+        };
+        assert!(
+            depth_first
+                .iter()
+                .filter(|path| path.starts_with(first))
+                .all(|path| position(path) < position(second)),
+            "{depth_first:?}"
+        );
+    }
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_recursive_readdir_same_filesystem() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("a")).unwrap();
+        std::os::unix::fs::symlink("/proc", root.path().join("proc")).unwrap();
+
+        let directories: Vec<_> =
+            RecursiveReadDir::try_new(root.path(), ReadDirType::DirectoriesOnly)
+                .unwrap()
+                .with_filter(filter::Filter::default().on_same_filesystem(true))
+                .map(Result::unwrap)
+                .collect();
+        assert_eq!(directories, [root.path().join("a")]);
+    }
+    #[test]
+    fn test_recursive_readdir_fail() {
+        let file_entry = RecursiveReadDir::try_new(WRONG_TEST_DIR, ReadDirType::DirectoriesOnly);
+        assert!(dbg!(file_entry).is_err());
+        let file_entry = RecursiveReadDir::try_new(WRONG_TEST_DIR, ReadDirType::FilesOnly);
+        assert!(dbg!(file_entry).is_err());
+    }
+
+    #[test]
+    fn test_get_destination_path() {
+        let destination_path = get_destination_file_path(
+            std::path::Path::new("destination/root"),
+            std::path::Path::new("source/root"),
+            std::path::Path::new("source/root/some/file.txt"),
+        )
+        .unwrap();
+        assert_eq!(
+            destination_path,
+            std::path::Path::new("destination/root/some/file.txt")
+        );
+
+        let destination_path = get_destination_file_path(
+            std::path::Path::new("destination/root"),
+            std::path::Path::new("source/root"),
+            std::path::Path::new("source/root/some/dir/"),
+        )
+        .unwrap();
+        assert_eq!(
+            destination_path,
+            std::path::Path::new("destination/root/some/dir/")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_paths_in_destinatination_but_not_in_source_directories_less_and_additional() {
+        let recurse_testdir =
+            RecursiveReadDir::try_new(TEST_DIR, ReadDirType::DirectoriesOnly).unwrap();
+        let recurse_testdir2 =
+            RecursiveReadDir::try_new(TEST_DIR_LESS_AND_ADDITIONAL, ReadDirType::DirectoriesOnly)
+                .unwrap();
+        let res = get_paths_in_destinatination_but_not_in_source(recurse_testdir2, recurse_testdir)
+            .await
+            .unwrap();
+
+        let mut difference = [
+            std::path::Path::new("testdir/more2"),
+            std::path::Path::new("testdir/more/even-mörer"),
+            std::path::Path::new("testdir/more2/moredir"),
+        ];
+        difference.sort();
+        assert_eq!(res, difference);
+    }
+
+    #[tokio::test]
+    async fn test_get_paths_in_destinatination_but_not_in_source_files_less_and_additional() {
+        let recurse_testdir = RecursiveReadDir::try_new(TEST_DIR, ReadDirType::FilesOnly).unwrap();
+        let recurse_testdir2 =
+            RecursiveReadDir::try_new(TEST_DIR_LESS_AND_ADDITIONAL, ReadDirType::FilesOnly)
+                .unwrap();
+        let res = get_paths_in_destinatination_but_not_in_source(recurse_testdir2, recurse_testdir)
+            .await
+            .unwrap();
+
+        let mut difference = [
+            std::path::Path::new("testdir/03_a"),
+            std::path::Path::new("testdir/more/weird name.txt"),
+            std::path::Path::new("testdir/more2/some more file"),
+            std::path::Path::new("testdir/more2/some file"),
+            std::path::Path::new("testdir/more/even-mörer/all-solutions.bak"),
+            std::path::Path::new("testdir/more2/moredir/epic.file"),
+        ];
+        difference.sort();
+        assert_eq!(res, difference);
+    }
+    #[tokio::test]
+    async fn test_get_paths_in_destinatination_but_not_in_source_directories_less() {
+        let recurse_testdir =
+            RecursiveReadDir::try_new(TEST_DIR, ReadDirType::DirectoriesOnly).unwrap();
+        let recurse_testdir2 =
+            RecursiveReadDir::try_new(TEST_DIR_LESS, ReadDirType::DirectoriesOnly).unwrap();
+        let res = get_paths_in_destinatination_but_not_in_source(recurse_testdir2, recurse_testdir)
+            .await
+            .unwrap();
+
+        let mut difference = [
+            std::path::Path::new("testdir/more2"),
+            std::path::Path::new("testdir/more/even-mörer"),
+            std::path::Path::new("testdir/more2/moredir"),
+        ];
+        difference.sort();
+        assert_eq!(res, difference);
+    }
+
+    #[tokio::test]
+    async fn test_get_paths_in_destinatination_but_not_in_source_files_less() {
+        let recurse_testdir = RecursiveReadDir::try_new(TEST_DIR, ReadDirType::FilesOnly).unwrap();
+        let recurse_testdir2 =
+            RecursiveReadDir::try_new(TEST_DIR_LESS, ReadDirType::FilesOnly).unwrap();
+        let res = get_paths_in_destinatination_but_not_in_source(recurse_testdir2, recurse_testdir)
+            .await
+            .unwrap();
+
+        let mut difference = [
+            std::path::Path::new("testdir/03_a"),
+            std::path::Path::new("testdir/more/weird name.txt"),
+            std::path::Path::new("testdir/more2/some more file"),
+            std::path::Path::new("testdir/more2/some file"),
+            std::path::Path::new("testdir/more/even-mörer/all-solutions.bak"),
+            std::path::Path::new("testdir/more2/moredir/epic.file"),
+        ];
+        difference.sort();
+        assert_eq!(res, difference);
+    }
+
+    #[tokio::test]
+    async fn test_get_paths_in_destinatination_but_not_in_source_directories_additional() {
+        let recurse_testdir =
+            RecursiveReadDir::try_new(TEST_DIR, ReadDirType::DirectoriesOnly).unwrap();
+        let recurse_testdir2 =
+            RecursiveReadDir::try_new(TEST_DIR_ADDITIONAL, ReadDirType::DirectoriesOnly).unwrap();
+        let res = get_paths_in_destinatination_but_not_in_source(recurse_testdir2, recurse_testdir)
+            .await
+            .unwrap();
+
+        assert!(dbg!(res).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_paths_in_destinatination_but_not_in_source_files_additional() {
+        let recurse_testdir = RecursiveReadDir::try_new(TEST_DIR, ReadDirType::FilesOnly).unwrap();
+        let recurse_testdir2 =
+            RecursiveReadDir::try_new(TEST_DIR_ADDITIONAL, ReadDirType::FilesOnly).unwrap();
+        let res = get_paths_in_destinatination_but_not_in_source(recurse_testdir2, recurse_testdir)
+            .await
+            .unwrap();
+
+        assert!(dbg!(res).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_backup_sqlite_database_with_wal() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let database = [SQLITE_HEADER.as_slice(), b"database"].concat();
+        std::fs::write(source.path().join("app.sqlite"), &database).unwrap();
+        std::fs::write(source.path().join("app.sqlite-wal"), b"log").unwrap();
+        std::fs::write(source.path().join("other-wal"), b"no database").unwrap();
+        std::fs::write(source.path().join("Thumbs.db"), b"thumbnails").unwrap();
+        std::fs::write(source.path().join("Thumbs.db-wal"), b"no log").unwrap();
+
+        assert!(is_sqlite_database(&source.path().join("app.sqlite")));
+        assert!(!is_sqlite_database(&source.path().join("Thumbs.db")));
+        assert!(!is_sqlite_database(&source.path().join("missing.db")));
+        assert_eq!(
+            sqlite_database_of_wal(&source.path().join("app.sqlite-wal")),
+            Some(source.path().join("app.sqlite"))
+        );
+        assert_eq!(
+            sqlite_database_of_wal(&source.path().join("other-wal")),
+            None
+        );
+        assert_eq!(
+            sqlite_database_of_wal(&source.path().join("Thumbs.db-wal")),
+            None
+        );
+
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(
+            Command::Backup {
+                source_root: source.path().to_owned(),
+                destination: Destination::Directory(destination.path().to_owned()),
+                options: BackupOptions {
+                    sqlite_consistent_copy: true,
+                    ..BackupOptions::default()
+                },
+            },
+            message_sender,
+        )
+        .await
+        .unwrap();
+
+        for file in [
+            "app.sqlite",
+            "app.sqlite-wal",
+            "other-wal",
+            "Thumbs.db",
+            "Thumbs.db-wal",
+        ] {
+            assert_eq!(
+                std::fs::read(source.path().join(file)).unwrap(),
+                std::fs::read(destination.path().join(file)).unwrap()
+            );
+        }
+        assert!(!staging_path(&destination.path().join("app.sqlite"), None).exists());
     }
-    if errors_directory.is_empty() {
-        message_sender.send(Message::Progress(Progress::EndSuccess(
-            ProgressType::DeletingDirs,
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sync_requires_mounted_source() {
+        let options = BackupOptions {
+            require_mounted: true,
+            ..BackupOptions::default()
+        };
+        // NOTE: The root directory is never on a mounted drive
+        assert!(matches!(
+            check_source_mounted(std::path::Path::new("/"), &options),
+            Err(Error::SourceNotMounted(_))
+        ));
+        assert!(check_source_mounted(std::path::Path::new("/"), &BackupOptions::default()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_staging_directory() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let staging = destination.path().join("staging");
+        let database = [SQLITE_HEADER.as_slice(), b"database"].concat();
+        std::fs::write(source.path().join("app.sqlite"), &database).unwrap();
+        std::fs::write(source.path().join("app.sqlite-wal"), b"log").unwrap();
+        std::fs::write(source.path().join("notes.txt"), b"new notes").unwrap();
+        std::fs::write(destination.path().join("notes.txt"), b"old").unwrap();
+        assert_ne!(
+            staging_path(&source.path().join("a/app.sqlite"), Some(&staging)),
+            staging_path(&source.path().join("b/app.sqlite"), Some(&staging))
+        );
+
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(
+            Command::Backup {
+                source_root: source.path().to_owned(),
+                destination: Destination::Directory(destination.path().to_owned()),
+                options: BackupOptions {
+                    sqlite_consistent_copy: true,
+                    staging_directory: Some(staging.clone()),
+                    ..BackupOptions::default()
+                },
+            },
+            message_sender,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read(destination.path().join("app.sqlite")).unwrap(),
+            database
+        );
+        assert_eq!(
+            std::fs::read(destination.path().join("notes.txt")).unwrap(),
+            b"new notes"
+        );
+        assert_eq!(std::fs::read_dir(&staging).unwrap().count(), 0);
+        assert!(!staging_path(&destination.path().join("notes.txt"), None).exists());
+    }
+
+    #[tokio::test]
+    async fn test_sync_quarantines_files_never_backed_up() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("kept.txt"), b"kept").unwrap();
+        std::fs::write(source.path().join("removed.txt"), b"removed").unwrap();
+        let sync = || Command::Sync {
+            source_root: source.path().to_owned(),
+            destination_root: destination.path().to_owned(),
+            options: BackupOptions::default(),
+        };
+
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(sync(), message_sender.clone()).await.unwrap();
+        std::fs::remove_file(source.path().join("removed.txt")).unwrap();
+        std::fs::write(destination.path().join("foreign.txt"), b"foreign").unwrap();
+        run(sync(), message_sender).await.unwrap();
+
+        assert!(destination.path().join("kept.txt").exists());
+        assert!(!destination.path().join("removed.txt").exists());
+        assert!(!destination.path().join("foreign.txt").exists());
+        let quarantine = destination
+            .path()
+            .join(METADATA_DIRECTORY)
+            .join(QUARANTINE_DIRECTORY);
+        let quarantined: Vec<_> = RecursiveReadDir::try_new(&quarantine, ReadDirType::FilesOnly)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert!(quarantined[0].ends_with("foreign.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_sync_aborts_on_mass_change() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        for i in 0..25 {
+            std::fs::write(source.path().join(format!("{i}.txt")), b"original").unwrap();
+        }
+        let sync = |mass_change_threshold| Command::Sync {
+            source_root: source.path().to_owned(),
+            destination_root: destination.path().to_owned(),
+            options: BackupOptions {
+                mass_change_threshold,
+                ..BackupOptions::default()
+            },
+        };
+
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(sync(Some(40)), message_sender.clone()).await.unwrap();
+        for i in 0..15 {
+            std::fs::write(source.path().join(format!("{i}.txt")), b"encrypted!").unwrap();
+        }
+        let result = run(sync(Some(40)), message_sender.clone()).await;
+        assert!(matches!(
+            result,
+            Err(Error::MassChangeDetected {
+                changed: 15,
+                total: 25,
+                threshold: 40
+            })
+        ));
+        assert_eq!(
+            std::fs::read(destination.path().join("0.txt")).unwrap(),
+            b"original"
+        );
+        run(sync(None), message_sender).await.unwrap();
+        assert_eq!(
+            std::fs::read(destination.path().join("0.txt")).unwrap(),
+            b"encrypted!"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_refuses_destination_without_id() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("file.txt"), b"content").unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let sync = |accept_new_destination| Command::Sync {
+            source_root: source.path().to_owned(),
+            destination_root: destination.path().to_owned(),
+            options: BackupOptions {
+                accept_new_destination,
+                known_destinations: Some(data.path().join("destinations")),
+                ..BackupOptions::default()
+            },
+        };
+
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(sync(false), message_sender.clone()).await.unwrap();
+        run(sync(false), message_sender.clone()).await.unwrap();
+
+        // NOTE: Looks like an empty mount point of a drive which is not mounted
+        std::fs::remove_dir_all(destination.path().join(METADATA_DIRECTORY)).unwrap();
+        std::fs::remove_file(destination.path().join("file.txt")).unwrap();
+        let result = run(sync(false), message_sender.clone()).await;
+        assert!(matches!(result, Err(Error::DestinationIdMissing(_))));
+        assert!(!destination.path().join("file.txt").exists());
+        assert!(!destination.path().join(METADATA_DIRECTORY).exists());
+
+        run(sync(true), message_sender.clone()).await.unwrap();
+        run(sync(false), message_sender).await.unwrap();
+        assert!(destination.path().join("file.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_sync_skips_excluded_files() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::create_dir(source.path().join("target")).unwrap();
+        std::fs::write(source.path().join("target/binary"), b"binary").unwrap();
+        std::fs::write(source.path().join("notes.txt"), b"notes").unwrap();
+        std::fs::write(source.path().join("notes.tmp"), b"temporary").unwrap();
+        std::fs::write(destination.path().join("old.tmp"), b"old").unwrap();
+
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let sync = Command::Sync {
+            source_root: source.path().to_owned(),
+            destination_root: destination.path().to_owned(),
+            options: BackupOptions {
+                exclude: vec!["target".to_owned(), "*.tmp".to_owned()],
+                ..BackupOptions::default()
+            },
+        };
+        run(sync, message_sender).await.unwrap();
+
+        assert!(destination.path().join("notes.txt").exists());
+        assert!(!destination.path().join("notes.tmp").exists());
+        assert!(!destination.path().join("target").exists());
+        // NOTE: Excluded files are not deleted either
+        assert!(destination.path().join("old.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_files() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("small.txt"), b"small").unwrap();
+        std::fs::write(source.path().join("movie.mkv"), vec![0; 2000]).unwrap();
+        let backup = |oversized_files| Command::Backup {
+            source_root: source.path().to_owned(),
+            destination: Destination::Directory(destination.path().to_owned()),
+            options: BackupOptions {
+                destination_max_file_size: Some(1000),
+                oversized_files,
+                ..BackupOptions::default()
+            },
+        };
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let plan = plan(backup(OversizedFilePolicy::Error), message_sender.clone())
+            .await
+            .unwrap();
+        assert!(plan.actions.contains(&PlannedAction::OversizedFile {
+            source: source.path().join("movie.mkv"),
+            size: 2000,
+            max_size: 1000,
+        }));
+        let Err(Error::ProcessPathErrors { files, .. }) =
+            run(backup(OversizedFilePolicy::Error), message_sender.clone()).await
+        else {
+            panic!("The oversized file must fail the backup");
+        };
+        assert!(matches!(
+            files.as_slice(),
+            [ProcessPathError {
+                kind: ProcessPathErrorKind::FileTooLarge {
+                    size: 2000,
+                    max_size: 1000,
+                    ..
+                },
+                ..
+            }]
+        ));
+        assert!(destination.path().join("small.txt").exists());
+        assert!(!destination.path().join("movie.mkv").exists());
+
+        while message_receiver.try_recv().is_ok() {}
+        run(backup(OversizedFilePolicy::Skip), message_sender)
+            .await
+            .unwrap();
+        assert!(!destination.path().join("movie.mkv").exists());
+        let mut skipped = false;
+        while let Ok(message) = message_receiver.try_recv() {
+            skipped |= matches!(
+                message,
+                Message::Warning(Warning::OversizedFileSkipped { .. })
+            );
+        }
+        assert!(skipped);
+    }
+
+    #[tokio::test]
+    async fn test_split_oversized_files() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let restored = tempfile::tempdir().unwrap();
+        let movie: Vec<u8> = (0..=255).cycle().take(2500).collect();
+        std::fs::write(source.path().join("movie.mkv"), &movie).unwrap();
+        std::fs::write(source.path().join("small.txt"), b"small").unwrap();
+        let options = BackupOptions {
+            destination_max_file_size: Some(1000),
+            oversized_files: OversizedFilePolicy::Split,
+            ..BackupOptions::default()
+        };
+        let sync = || Command::Sync {
+            source_root: source.path().to_owned(),
+            destination_root: destination.path().to_owned(),
+            options: options.clone(),
+        };
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        run(sync(), message_sender.clone()).await.unwrap();
+        assert!(!destination.path().join("movie.mkv").exists());
+        assert!(destination.path().join("movie.mkv.safeall-split").exists());
+        assert_eq!(
+            std::fs::read(destination.path().join("movie.mkv.safeall-part-0003"))
+                .unwrap()
+                .len(),
+            500
+        );
+
+        // NOTE: The parts are neither deleted by the sync nor copied again
+        while message_receiver.try_recv().is_ok() {}
+        run(sync(), message_sender.clone()).await.unwrap();
+        assert!(
+            destination
+                .path()
+                .join("movie.mkv.safeall-part-0001")
+                .exists()
+        );
+        while let Ok(message) = message_receiver.try_recv() {
+            assert!(!matches!(
+                message,
+                Message::Progress(Progress::IncrementSuccess(
+                    Increment::FileCopied { .. } | Increment::DeletedFile(_)
+                ))
+            ));
+        }
+
+        run(
+            Command::Verify {
+                source_root: source.path().to_owned(),
+                destination_root: destination.path().to_owned(),
+                options: options.clone(),
+            },
+            message_sender.clone(),
+        )
+        .await
+        .unwrap();
+
+        run(
+            Command::Restore {
+                source_root: restored.path().to_owned(),
+                destination_root: destination.path().to_owned(),
+                delete_files: true,
+                target: RestoreTarget::default(),
+                options: BackupOptions::default(),
+            },
+            message_sender,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            std::fs::read(restored.path().join("movie.mkv")).unwrap(),
+            movie
+        );
+        assert_eq!(
+            std::fs::metadata(restored.path().join("movie.mkv"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            std::fs::metadata(source.path().join("movie.mkv"))
+                .unwrap()
+                .modified()
+                .unwrap()
+        );
+        assert!(restored.path().join("small.txt").exists());
+        assert!(!restored.path().join("movie.mkv.safeall-split").exists());
+        assert!(!restored.path().join("movie.mkv.safeall-part-0001").exists());
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_sync() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("a.txt"), b"a").unwrap();
+        std::fs::write(source.path().join("b.txt"), b"b").unwrap();
+        let sync = |dry_run| Command::Sync {
+            source_root: source.path().to_owned(),
+            destination_root: destination.path().to_owned(),
+            options: BackupOptions {
+                dry_run,
+                ..BackupOptions::default()
+            },
+        };
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(sync(false), message_sender.clone()).await.unwrap();
+
+        std::fs::remove_file(source.path().join("b.txt")).unwrap();
+        std::fs::write(source.path().join("c.txt"), b"c").unwrap();
+        std::fs::write(destination.path().join("foreign.txt"), b"foreign").unwrap();
+        let planned = plan(sync(true), message_sender.clone()).await.unwrap();
+        assert_eq!(
+            planned.actions,
+            vec![
+                PlannedAction::CopyFile {
+                    source: source.path().join("c.txt"),
+                    destination: destination.path().join("c.txt"),
+                },
+                PlannedAction::DeleteFile(destination.path().join("b.txt")),
+                PlannedAction::Quarantine(destination.path().join("foreign.txt")),
+            ]
+        );
+
+        run(sync(true), message_sender).await.unwrap();
+        assert!(destination.path().join("b.txt").exists());
+        assert!(!destination.path().join("c.txt").exists());
+        assert!(destination.path().join("foreign.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        for name in ["same.txt", "rotten.txt", "deleted.txt"] {
+            std::fs::write(source.path().join(name), name).unwrap();
+        }
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let verify = || Command::Verify {
+            source_root: source.path().to_owned(),
+            destination_root: destination.path().to_owned(),
+            options: BackupOptions::default(),
+        };
+        let verified = |message_receiver: &mut tokio::sync::mpsc::UnboundedReceiver<Message>| {
+            std::iter::from_fn(|| message_receiver.try_recv().ok())
+                .find_map(|message| match message {
+                    Message::Info(Info::Verified(report)) => Some(report),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        run(
+            Command::Backup {
+                source_root: source.path().to_owned(),
+                destination: Destination::Directory(destination.path().to_owned()),
+                options: BackupOptions::default(),
+            },
+            message_sender.clone(),
+        )
+        .await
+        .unwrap();
+        run(verify(), message_sender.clone()).await.unwrap();
+        assert!(verified(&mut message_receiver).is_consistent());
+
+        // NOTE: Same size and modification time, only the content has changed
+        let rotten = destination.path().join("rotten.txt");
+        let modified = std::fs::metadata(&rotten).unwrap().modified().unwrap();
+        std::fs::write(&rotten, "ROTTEN.txt").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&rotten)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        std::fs::remove_file(destination.path().join("deleted.txt")).unwrap();
+        std::fs::write(destination.path().join("extra.txt"), b"extra").unwrap();
+        let result = run(verify(), message_sender.clone()).await;
+        assert!(matches!(result, Err(Error::DestinationInconsistent(_))));
+        assert_eq!(
+            verified(&mut message_receiver),
+            VerifyReport {
+                verified: 2,
+                mismatched: vec![rotten.clone()],
+                missing: vec![destination.path().join("deleted.txt")],
+                extra: vec![destination.path().join("extra.txt")],
+                unreadable: vec![],
+                repaired: vec![],
+            }
+        );
+        assert_eq!(
+            std::fs::read(source.path().join("rotten.txt")).unwrap(),
+            b"rotten.txt"
+        );
+
+        let result = run(
+            Command::Verify {
+                source_root: source.path().to_owned(),
+                destination_root: destination.path().to_owned(),
+                options: BackupOptions {
+                    repair: true,
+                    ..BackupOptions::default()
+                },
+            },
+            message_sender,
+        )
+        .await;
+        // NOTE: Only damaged files are repaired, missing and extra files are left as they are
+        assert!(matches!(result, Err(Error::DestinationInconsistent(_))));
+        let report = verified(&mut message_receiver);
+        assert_eq!(report.mismatched, Vec::<std::path::PathBuf>::new());
+        assert_eq!(report.repaired, vec![rotten.clone()]);
+        assert_eq!(std::fs::read(&rotten).unwrap(), b"rotten.txt");
+        assert!(!destination.path().join("deleted.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_sync_interactive_delete() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("new.txt"), b"new").unwrap();
+        for approve in [false, true] {
+            std::fs::write(destination.path().join("old.txt"), b"old").unwrap();
+            let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+            let frontend = tokio::spawn(async move {
+                while let Some(message) = message_receiver.recv().await {
+                    if let Message::Info(Info::PurgeCheckpoint { checkpoint, .. }) = message {
+                        if approve {
+                            checkpoint.approve();
+                        } else {
+                            checkpoint.cancel();
+                        }
+                    }
+                }
+            });
+            run(
+                Command::Sync {
+                    source_root: source.path().to_owned(),
+                    destination_root: destination.path().to_owned(),
+                    options: BackupOptions {
+                        interactive_delete: true,
+                        accept_new_destination: true,
+                        ..BackupOptions::default()
+                    },
+                },
+                message_sender,
+            )
+            .await
+            .unwrap();
+            frontend.await.unwrap();
+            assert!(destination.path().join("new.txt").exists());
+            assert_eq!(destination.path().join("old.txt").exists(), !approve);
+        }
+    }
+
+    #[cfg(feature = "parity")]
+    #[tokio::test]
+    async fn test_scrub_reconstructs_from_parity() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("file.txt"), "content".repeat(1000)).unwrap();
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let options = BackupOptions {
+            parity: Some(10),
+            repair: true,
+            ..BackupOptions::default()
+        };
+        run(
+            Command::Backup {
+                source_root: source.path().to_owned(),
+                destination: Destination::Directory(destination.path().to_owned()),
+                options: options.clone(),
+            },
+            message_sender.clone(),
+        )
+        .await
+        .unwrap();
+
+        let file = destination.path().join("file.txt");
+        let modified = std::fs::metadata(&file).unwrap().modified().unwrap();
+        std::fs::write(&file, "CONTENT".repeat(1000)).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        // NOTE: The source is not needed
+        std::fs::remove_file(source.path().join("file.txt")).unwrap();
+        run(
+            Command::Scrub {
+                source_root: None,
+                destination_root: destination.path().to_owned(),
+                options,
+            },
+            message_sender,
+        )
+        .await
+        .unwrap();
+        let report = std::iter::from_fn(|| message_receiver.try_recv().ok())
+            .find_map(|message| match message {
+                Message::Info(Info::Scrubbed(report)) => Some(report),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(report.repaired, vec![file.clone()]);
+        assert_eq!(
+            std::fs::read_to_string(&file).unwrap(),
+            "content".repeat(1000)
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::too_many_lines)]
+    async fn test_repository_snapshots() {
+        let source = tempfile::tempdir().unwrap();
+        let repository = tempfile::tempdir().unwrap();
+        let restored = tempfile::tempdir().unwrap();
+        // NOTE: Random data such that the chunks are not compressed into packs
+        let mut large = vec![0; 3 * 1024 * 1024];
+        blake3::Hasher::new().finalize_xof().fill(&mut large);
+        std::fs::create_dir(source.path().join("documents")).unwrap();
+        std::fs::write(source.path().join("documents/large.bin"), &large).unwrap();
+        std::fs::write(source.path().join("notes.txt"), b"first").unwrap();
+        std::fs::write(source.path().join("empty"), b"").unwrap();
+        let snapshot = || Command::Snapshot {
+            source_root: source.path().to_owned(),
+            destination_root: repository.path().join("repository"),
+            options: BackupOptions {
+                accept_new_destination: true,
+                ..BackupOptions::default()
+            },
+        };
+        let created = |messages: Vec<Message>| {
+            messages
+                .into_iter()
+                .find_map(|message| match message {
+                    Message::Info(Info::SnapshotCreated {
+                        snapshot,
+                        added_bytes,
+                        ..
+                    }) => Some((snapshot, added_bytes)),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        let run_collecting = async |command| {
+            let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+            let result = run(command, message_sender).await;
+            let mut messages = vec![];
+            while let Some(message) = message_receiver.recv().await {
+                messages.push(message);
+            }
+            (result, messages)
+        };
+
+        let (result, messages) = run_collecting(snapshot()).await;
+        result.unwrap();
+        let (first, added_bytes) = created(messages);
+        assert_eq!(first.files, 3);
+        assert_eq!(added_bytes, large.len() as u64 + 5);
+
+        std::fs::write(source.path().join("notes.txt"), b"second").unwrap();
+        let (result, messages) = run_collecting(snapshot()).await;
+        result.unwrap();
+        let (second, added_bytes) = created(messages);
+        assert_eq!(added_bytes, 6);
+        assert_eq!(second.bytes, large.len() as u64 + 6);
+        let (result, messages) = run_collecting(Command::ListSnapshots {
+            destination_root: repository.path().join("repository"),
+            options: BackupOptions::default(),
+        })
+        .await;
+        result.unwrap();
+        assert!(messages.iter().any(|message| matches!(
+            message,
+            Message::Info(Info::Snapshots(snapshots)) if snapshots.len() == 2
         )));
-    } else {
-        message_sender.send(Message::Progress(Progress::EndFail(
-            errors_directory.len(),
-            ProgressType::DeletingDirs,
+
+        let restore = |snapshot| Command::RestoreSnapshot {
+            source_root: restored.path().to_owned(),
+            destination_root: repository.path().join("repository"),
+            snapshot,
+            options: BackupOptions::default(),
+        };
+        let (result, _) = run_collecting(restore(None)).await;
+        result.unwrap();
+        assert_eq!(
+            std::fs::read(restored.path().join("documents/large.bin")).unwrap(),
+            large
+        );
+        assert_eq!(
+            std::fs::read(restored.path().join("notes.txt")).unwrap(),
+            b"second"
+        );
+        assert!(restored.path().join("empty").is_file());
+        let (result, _) = run_collecting(restore(Some(first.id))).await;
+        result.unwrap();
+        assert_eq!(
+            std::fs::read(restored.path().join("notes.txt")).unwrap(),
+            b"first"
+        );
+        let (result, _) = run_collecting(restore(Some("missing".to_owned()))).await;
+        assert!(matches!(result, Err(Error::SnapshotNotFound { .. })));
+
+        let (result, messages) = run_collecting(Command::Prune {
+            destination_root: repository.path().join("repository"),
+            policy: RetentionPolicy {
+                keep_last: 1,
+                ..RetentionPolicy::default()
+            },
+            options: BackupOptions::default(),
+        })
+        .await;
+        result.unwrap();
+        assert!(messages.iter().any(|message| matches!(
+            message,
+            Message::Info(Info::Pruned(report)) if report.removed.len() == 1
+        )));
+        let (result, messages) = run_collecting(Command::CollectGarbage {
+            destination_root: repository.path().join("repository"),
+            options: BackupOptions::default(),
+        })
+        .await;
+        result.unwrap();
+        // NOTE: Only the pack with the first version of the notes is not used anymore, which
+        // has the byte of the encoding in front of the text
+        assert!(messages.iter().any(|message| matches!(
+            message,
+            Message::Info(Info::GarbageCollected(collected)) if *collected == GarbageCollected {
+                removed_packs: 1,
+                freed_bytes: 6,
+                ..GarbageCollected::default()
+            }
         )));
+        let (result, _) = run_collecting(restore(Some(second.id))).await;
+        result.unwrap();
+        assert_eq!(
+            std::fs::read(restored.path().join("notes.txt")).unwrap(),
+            b"second"
+        );
     }
 
-    let source_recurse_files = RecursiveReadDir::try_new(source_root, ReadDirType::FilesOnly)
-        .map_err(|e| Error::CannotReadDirectoryContent(source_root.to_owned(), e.to_string()))?;
-    let destination_recurse_files =
-        RecursiveReadDir::try_new(destination_root, ReadDirType::FilesOnly).map_err(|e| {
-            Error::CannotReadDirectoryContent(destination_root.to_owned(), e.to_string())
-        })?;
+    #[tokio::test]
+    async fn test_check_repository() {
+        let source = tempfile::tempdir().unwrap();
+        let repository = tempfile::tempdir().unwrap();
+        let mut large = vec![0; 1024 * 1024];
+        blake3::Hasher::new().finalize_xof().fill(&mut large);
+        std::fs::write(source.path().join("large.bin"), &large).unwrap();
+        std::fs::write(source.path().join("small.txt"), b"small").unwrap();
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(
+            Command::Snapshot {
+                source_root: source.path().to_owned(),
+                destination_root: repository.path().to_owned(),
+                options: BackupOptions {
+                    accept_new_destination: true,
+                    ..BackupOptions::default()
+                },
+            },
+            message_sender.clone(),
+        )
+        .await
+        .unwrap();
+        let check = || Command::CheckRepository {
+            destination_root: repository.path().to_owned(),
+            options: BackupOptions::default(),
+        };
+        let checked = |message_receiver: &mut tokio::sync::mpsc::UnboundedReceiver<Message>| {
+            std::iter::from_fn(|| message_receiver.try_recv().ok())
+                .find_map(|message| match message {
+                    Message::Info(Info::RepositoryChecked(report)) => Some(report),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        run(check(), message_sender.clone()).await.unwrap();
+        let report = checked(&mut message_receiver);
+        assert!(report.is_intact());
+        assert_eq!((report.snapshots, report.chunks), (1, 2));
 
-    let files_to_delete = get_paths_in_destinatination_but_not_in_source(
-        source_recurse_files,
-        destination_recurse_files,
-    )
-    .await
-    .map_err(|e| Error::ProcessPathErrors {
-        directories: vec![],
-        files: vec![e],
-    })?;
-    let num_files = futures::stream::iter(files_to_delete.clone()).count().await;
-    message_sender.send(Message::Progress(Progress::Start(
-        num_files,
-        ProgressType::DeletingFiles,
-    )));
+        let chunk =
+            RecursiveReadDir::try_new(repository.path().join("chunks"), ReadDirType::FilesOnly)
+                .unwrap()
+                .flatten()
+                .next()
+                .unwrap();
+        std::fs::remove_file(chunk).unwrap();
+        assert!(matches!(
+            run(check(), message_sender.clone()).await,
+            Err(Error::RepositoryDamaged(_))
+        ));
+        assert_eq!(checked(&mut message_receiver).incomplete_snapshots.len(), 1);
 
-    let errors_file: Vec<_> = futures::stream::iter(files_to_delete)
-        .map(async |file| {
-            if deleted_dirs.iter().any(|d| file.starts_with(d)) {
-                message_sender.send(Message::Progress(Progress::IncrementSuccess(
-                    Increment::FileAlreadyDeleted(file),
-                )));
-                return Ok(());
+        let newer = (repo::REPOSITORY_VERSION + 1).to_string();
+        std::fs::write(repository.path().join("version"), &newer).unwrap();
+        assert!(matches!(
+            run(check(), message_sender).await,
+            Err(Error::UnsupportedRepositoryVersion { version, .. }) if version == newer
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_repository() {
+        let source = tempfile::tempdir().unwrap();
+        let repository = tempfile::tempdir().unwrap();
+        let restored = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("secret notes.txt"), b"confidential").unwrap();
+        let options = |passphrase: Option<&str>| BackupOptions {
+            accept_new_destination: true,
+            secret: passphrase.map(|passphrase: &str| Secret::Passphrase(passphrase.to_owned())),
+            ..BackupOptions::default()
+        };
+        let snapshot = |passphrase| Command::Snapshot {
+            source_root: source.path().to_owned(),
+            destination_root: repository.path().to_owned(),
+            options: options(passphrase),
+        };
+        let list = |passphrase| Command::ListSnapshots {
+            destination_root: repository.path().to_owned(),
+            options: options(passphrase),
+        };
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(snapshot(Some("passphrase")), message_sender.clone())
+            .await
+            .unwrap();
+        for file in RecursiveReadDir::try_new(repository.path(), ReadDirType::FilesOnly).unwrap() {
+            let content = std::fs::read(file.unwrap()).unwrap();
+            for plain in [&b"confidential"[..], b"secret notes"] {
+                assert!(!content.windows(plain.len()).any(|window| window == plain));
             }
-            message_sender.send(Message::Info(Info::StartDeletingFile(file.clone())));
-            tokio::fs::remove_file(&file)
-                .await
-                .map_err(|e| ProcessPathError {
-                    not_processed: Some(file.clone()),
-                    kind: ProcessPathErrorKind::CannotDeleteFile {
-                        io_error: e.to_string(),
-                    },
-                })
-                .inspect(|()| {
-                    message_sender.send(Message::Progress(Progress::IncrementSuccess(
-                        Increment::DeletedFile(file),
-                    )));
-                })
-                .inspect_err(|e| {
-                    message_sender.send(Message::Progress(Progress::IncrementFail(e.clone())));
+        }
+
+        assert!(matches!(
+            run(list(None), message_sender.clone()).await,
+            Err(Error::SecretRequired(_))
+        ));
+        assert!(matches!(
+            run(list(Some("wrong")), message_sender.clone()).await,
+            Err(Error::WrongSecret(_))
+        ));
+        run(snapshot(Some("passphrase")), message_sender.clone())
+            .await
+            .unwrap();
+        run(
+            Command::RestoreSnapshot {
+                source_root: restored.path().to_owned(),
+                destination_root: repository.path().to_owned(),
+                snapshot: None,
+                options: options(Some("passphrase")),
+            },
+            message_sender.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            std::fs::read(restored.path().join("secret notes.txt")).unwrap(),
+            b"confidential"
+        );
+
+        let plain = tempfile::tempdir().unwrap();
+        let plain_snapshot = |passphrase| Command::Snapshot {
+            source_root: source.path().to_owned(),
+            destination_root: plain.path().to_owned(),
+            options: options(passphrase),
+        };
+        run(plain_snapshot(None), message_sender.clone())
+            .await
+            .unwrap();
+        assert!(matches!(
+            run(plain_snapshot(Some("passphrase")), message_sender).await,
+            Err(Error::RepositoryNotEncrypted(_))
+        ));
+    }
+
+    #[tokio::test]
+    #[allow(clippy::too_many_lines)]
+    async fn test_repository_keys() {
+        let source = tempfile::tempdir().unwrap();
+        let repository = tempfile::tempdir().unwrap();
+        let keys = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("file.txt"), b"content").unwrap();
+        let passphrase = Secret::Passphrase("passphrase".to_owned());
+        let key_file = Secret::KeyFile(keys.path().join("usb.key"));
+        let options = |secret: &Secret| BackupOptions {
+            accept_new_destination: true,
+            secret: Some(secret.clone()),
+            ..BackupOptions::default()
+        };
+        let manage = |secret: &Secret, action| Command::ManageKeys {
+            destination_root: repository.path().to_owned(),
+            action,
+            options: options(secret),
+        };
+        let list = |secret: &Secret| Command::ListSnapshots {
+            destination_root: repository.path().to_owned(),
+            options: options(secret),
+        };
+        let run_collecting = async |command| {
+            let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+            let result = run(command, message_sender).await;
+            let mut messages = vec![];
+            while let Some(message) = message_receiver.recv().await {
+                messages.push(message);
+            }
+            (result, messages)
+        };
+        let registered = async || {
+            let (result, messages) = run_collecting(manage(&passphrase, KeyAction::List)).await;
+            result.unwrap();
+            messages
+                .into_iter()
+                .find_map(|message| match message {
+                    Message::Info(Info::Keys(keys)) => Some(keys),
+                    _ => None,
                 })
-        })
-        .buffer_unordered(cpu_count())
-        .filter_map(async move |res| res.err())
-        .collect()
-        .await;
-    if errors_file.is_empty() {
-        message_sender.send(Message::Progress(Progress::EndSuccess(
-            ProgressType::DeletingFiles,
-        )));
-    } else {
-        message_sender.send(Message::Progress(Progress::EndFail(
-            errors_file.len(),
-            ProgressType::DeletingFiles,
-        )));
+                .unwrap()
+        };
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(
+            Command::Snapshot {
+                source_root: source.path().to_owned(),
+                destination_root: repository.path().to_owned(),
+                options: options(&passphrase),
+            },
+            message_sender.clone(),
+        )
+        .await
+        .unwrap();
+        let first = registered().await;
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].kind, KeyKind::Passphrase);
+        assert!(matches!(
+            run(
+                manage(&passphrase, KeyAction::Remove(first[0].id.clone())),
+                message_sender.clone()
+            )
+            .await,
+            Err(Error::CannotRemoveLastKey(_))
+        ));
+
+        run(
+            manage(&passphrase, KeyAction::Add(key_file.clone())),
+            message_sender.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(registered().await.len(), 2);
+        run(list(&key_file), message_sender.clone()).await.unwrap();
+
+        let rotated = Secret::Passphrase("rotated".to_owned());
+        run(
+            manage(&key_file, KeyAction::Rotate(rotated.clone())),
+            message_sender.clone(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            run(list(&key_file), message_sender.clone()).await,
+            Err(Error::WrongSecret(_))
+        ));
+        run(list(&rotated), message_sender.clone()).await.unwrap();
+        let keys = registered().await;
+        assert_eq!(keys.len(), 2);
+        assert!(keys.iter().all(|key| key.kind == KeyKind::Passphrase));
+
+        assert!(matches!(
+            run(
+                manage(&passphrase, KeyAction::Remove("00000000".to_owned())),
+                message_sender.clone()
+            )
+            .await,
+            Err(Error::KeyNotFound { .. })
+        ));
+        run(
+            manage(&rotated, KeyAction::Remove(first[0].id.clone())),
+            message_sender.clone(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            run(list(&passphrase), message_sender).await,
+            Err(Error::WrongSecret(_))
+        ));
     }
 
-    Error::from_processing_results(errors_directory, errors_file)
-}
+    #[tokio::test]
+    async fn test_backup_into_archive() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(source.path().join("docs/empty")).unwrap();
+        std::fs::write(source.path().join("docs/notes.txt"), b"notes").unwrap();
+        std::fs::write(source.path().join("photo.jpg"), vec![7; 100_000]).unwrap();
+        std::fs::write(source.path().join("debug.log"), b"log").unwrap();
+        let entries = |archive: &std::path::Path| {
+            let file = std::fs::File::open(archive).unwrap();
+            let reader: Box<dyn std::io::Read> = if archive
+                .extension()
+                .is_some_and(|extension| extension == "gz")
+            {
+                Box::new(flate2::read::GzDecoder::new(file))
+            } else {
+                Box::new(zstd::stream::read::Decoder::new(file).unwrap())
+            };
+            let mut entries = std::collections::BTreeMap::new();
+            for entry in tar::Archive::new(reader).entries().unwrap() {
+                let mut entry = entry.unwrap();
+                let mut content = vec![];
+                std::io::Read::read_to_end(&mut entry, &mut content).unwrap();
+                entries.insert(entry.path().unwrap().into_owned(), content);
+            }
+            entries
+        };
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        for name in ["backup.tar.gz", "backup.tar.zst"] {
+            let archive = destination.path().join("nested").join(name);
+            let backup = || Command::Backup {
+                source_root: source.path().to_owned(),
+                destination: Destination::Archive(archive.clone()),
+                options: BackupOptions {
+                    exclude: vec!["*.log".to_owned()],
+                    ..BackupOptions::default()
+                },
+            };
+            run(backup(), message_sender.clone()).await.unwrap();
+            let path = std::path::PathBuf::from;
+            let content = entries(&archive);
+            assert_eq!(content[&path("docs/notes.txt")], b"notes");
+            assert_eq!(content[&path("photo.jpg")], vec![7; 100_000]);
+            assert!(content.contains_key(&path("docs/empty")));
+            assert!(!content.contains_key(&path("debug.log")));
+            assert!(std::fs::metadata(&archive).unwrap().len() < 10_000);
 
-    const WRONG_TEST_DIR: &str = "-------";
-    const TEST_DIR: &str = "testdir";
-    const TEST_DIR_LESS: &str = "testdir_less";
-    const TEST_DIR_LESS_AND_ADDITIONAL: &str = "testdir_less_and_additional";
-    const TEST_DIR_ADDITIONAL: &str = "testdir_additional";
-    const TEST_DIR_FILES: [&str; 12] = [
-        // TODO: Check if this order is the same on all platforms
-        "testdir/03_a",
-        "testdir/05_directory.csv",
-        "testdir/04_test.py",
-        "testdir/01_This.txt",
-        "testdir/02_is.o",
-        "testdir/more/wèirder,name.txt",
-        "testdir/more/weird name.txt",
-        "testdir/more2/some more file",
-        "testdir/more2/some file",
-        "testdir/more/even-mörer/all-solutions.bak",
-        "testdir/more/even-möre/wèirder,name.txt",
-        "testdir/more2/moredir/epic.file",
-    ];
-    const TEST_DIR_DIRECTORIES: [&str; 5] = [
-        // TODO: Check if this order is the same on all platforms
-        "testdir/more/",
-        "testdir/more2/",
-        "testdir/more/even-mörer/",
-        "testdir/more/even-möre/",
-        "testdir/more2/moredir/",
-    ];
+            std::fs::write(source.path().join("docs/notes.txt"), b"changed").unwrap();
+            run(backup(), message_sender.clone()).await.unwrap();
+            assert_eq!(entries(&archive)[&path("docs/notes.txt")], b"changed");
+            std::fs::write(source.path().join("docs/notes.txt"), b"notes").unwrap();
+        }
+        assert!(!destination.path().join("nested/.safeall").exists());
+        assert!(matches!(
+            run(
+                Command::Backup {
+                    source_root: source.path().to_owned(),
+                    destination: Destination::Archive(destination.path().to_owned()),
+                    options: BackupOptions::default(),
+                },
+                message_sender,
+            )
+            .await,
+            Err(Error::CannotWriteArchive(..))
+        ));
+    }
 
-    #[test]
-    fn test_recurse_files() {
-        let file_entry = RecursiveReadDir::try_new(TEST_DIR, ReadDirType::FilesOnly).unwrap();
-        let files = file_entry
-            .into_iter()
-            .collect::<Result<Vec<_>, _>>()
+    #[tokio::test]
+    async fn test_scrub() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        for name in ["same.txt", "rotten.txt", "deleted.txt", "edited.txt"] {
+            std::fs::write(source.path().join(name), name).unwrap();
+        }
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let scrub = || Command::Scrub {
+            source_root: None,
+            destination_root: destination.path().to_owned(),
+            options: BackupOptions::default(),
+        };
+        let scrubbed = |message_receiver: &mut tokio::sync::mpsc::UnboundedReceiver<Message>| {
+            std::iter::from_fn(|| message_receiver.try_recv().ok())
+                .find_map(|message| match message {
+                    Message::Info(Info::Scrubbed(report)) => Some(report),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        let result = run(scrub(), message_sender.clone()).await;
+        assert!(matches!(result, Err(Error::NoManifest(_))));
+        run(
+            Command::Backup {
+                source_root: source.path().to_owned(),
+                destination: Destination::Directory(destination.path().to_owned()),
+                options: BackupOptions::default(),
+            },
+            message_sender.clone(),
+        )
+        .await
+        .unwrap();
+        run(scrub(), message_sender.clone()).await.unwrap();
+        assert!(scrubbed(&mut message_receiver).is_intact());
+
+        // NOTE: Same size and modification time, only the content has changed
+        let rotten = destination.path().join("rotten.txt");
+        let modified = std::fs::metadata(&rotten).unwrap().modified().unwrap();
+        std::fs::write(&rotten, "ROTTEN.txt").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&rotten)
+            .unwrap()
+            .set_modified(modified)
             .unwrap();
-        assert_eq!(dbg!(&files).len(), dbg!(&TEST_DIR_FILES).len());
+        std::fs::remove_file(destination.path().join("deleted.txt")).unwrap();
+        std::fs::write(destination.path().join("edited.txt"), "longer content").unwrap();
+        let result = run(scrub(), message_sender.clone()).await;
+        assert!(matches!(result, Err(Error::DestinationCorrupted(_))));
+        assert_eq!(
+            scrubbed(&mut message_receiver),
+            ScrubReport {
+                scrubbed: 2,
+                corrupted: vec![rotten.clone()],
+                missing: vec![destination.path().join("deleted.txt")],
+                modified: vec![destination.path().join("edited.txt")],
+                unreadable: vec![],
+                repaired: vec![],
+            }
+        );
+
+        std::fs::write(source.path().join("same.txt"), "changed").unwrap();
+        let same = destination.path().join("same.txt");
+        let modified = std::fs::metadata(&same).unwrap().modified().unwrap();
+        std::fs::write(&same, "SAME.txt").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&same)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let repair = Command::Scrub {
+            source_root: Some(source.path().to_owned()),
+            destination_root: destination.path().to_owned(),
+            options: BackupOptions {
+                repair: true,
+                ..BackupOptions::default()
+            },
+        };
+        let _ = run(repair, message_sender).await;
+        let report = scrubbed(&mut message_receiver);
+        assert_eq!(report.repaired, vec![rotten.clone()]);
+        // NOTE: A source which changed since the backup is not what the manifest describes
+        assert_eq!(report.corrupted, vec![same.clone()]);
+        assert_eq!(std::fs::read(&rotten).unwrap(), b"rotten.txt");
+        assert_eq!(std::fs::read(&same).unwrap(), b"SAME.txt");
+    }
+
+    #[tokio::test]
+    async fn test_restore_diff() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("same.txt"), b"same").unwrap();
+        std::fs::write(source.path().join("changed.txt"), b"old").unwrap();
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(
+            Command::Backup {
+                source_root: source.path().to_owned(),
+                destination: Destination::Directory(destination.path().to_owned()),
+                options: BackupOptions::default(),
+            },
+            message_sender.clone(),
+        )
+        .await
+        .unwrap();
+
+        std::fs::write(source.path().join("changed.txt"), b"new").unwrap();
+        std::fs::write(source.path().join("new.txt"), b"new").unwrap();
+        let restore = Command::Restore {
+            source_root: source.path().to_owned(),
+            destination_root: destination.path().to_owned(),
+            delete_files: true,
+            target: RestoreTarget::default(),
+            options: BackupOptions::default(),
+        };
+        let diff = restore_diff(restore, message_sender.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            diff,
+            RestoreDiff {
+                overwritten: vec![source.path().join("changed.txt")],
+                missing_from_backup: vec![source.path().join("new.txt")],
+                deletes_missing: true,
+            }
+        );
+        let backup = Command::Backup {
+            source_root: source.path().to_owned(),
+            destination: Destination::Directory(destination.path().to_owned()),
+            options: BackupOptions::default(),
+        };
+        assert!(
+            restore_diff(backup, message_sender)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_backup_directory_symlinks() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::create_dir(source.path().join("real")).unwrap();
+        std::fs::write(source.path().join("real/file.txt"), b"content").unwrap();
+        std::os::unix::fs::symlink("real", source.path().join("link")).unwrap();
+
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let sync = || Command::Sync {
+            source_root: source.path().to_owned(),
+            destination_root: destination.path().to_owned(),
+            options: BackupOptions {
+                symlinks: SymlinkPolicy::Preserve,
+                ..BackupOptions::default()
+            },
+        };
+        run(sync(), message_sender.clone()).await.unwrap();
+        // NOTE: The link must neither be copied into nor be deleted by the second run
+        run(sync(), message_sender).await.unwrap();
+
+        let link = destination.path().join("link");
+        assert_eq!(
+            std::fs::read_link(&link).unwrap(),
+            std::path::Path::new("real")
+        );
+        assert_eq!(std::fs::read(link.join("file.txt")).unwrap(), b"content");
+        assert!(destination.path().join("real").is_dir());
+        assert!(!destination.path().join("real").is_symlink());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_backup_symlinks() {
+        let source = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("file.txt"), b"content").unwrap();
+        std::os::unix::fs::symlink("file.txt", source.path().join("link")).unwrap();
+        std::os::unix::fs::symlink("missing", source.path().join("broken")).unwrap();
+
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        for symlinks in [SymlinkPolicy::Preserve, SymlinkPolicy::Skip] {
+            let destination = tempfile::tempdir().unwrap();
+            let backup = || Command::Backup {
+                source_root: source.path().to_owned(),
+                destination: Destination::Directory(destination.path().to_owned()),
+                options: BackupOptions {
+                    symlinks,
+                    ..BackupOptions::default()
+                },
+            };
+            run(backup(), message_sender.clone()).await.unwrap();
+            // NOTE: Running twice must not fail on the existing links
+            run(backup(), message_sender.clone()).await.unwrap();
+
+            let link = std::fs::read_link(destination.path().join("link"));
+            let broken = std::fs::read_link(destination.path().join("broken"));
+            if symlinks == SymlinkPolicy::Preserve {
+                assert_eq!(link.unwrap(), std::path::Path::new("file.txt"));
+                assert_eq!(broken.unwrap(), std::path::Path::new("missing"));
+            } else {
+                assert!(link.is_err());
+                assert!(broken.is_err());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backup_into_source() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = source.path().join("backup");
+        std::fs::write(source.path().join("file.txt"), b"content").unwrap();
+        let backup = || Command::Backup {
+            source_root: source.path().to_owned(),
+            destination: Destination::Directory(destination.clone()),
+            options: BackupOptions {
+                accept_new_destination: true,
+                ..BackupOptions::default()
+            },
+        };
+
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(backup(), message_sender.clone()).await.unwrap();
+        run(backup(), message_sender).await.unwrap();
+
+        assert!(destination.join("file.txt").exists());
+        assert!(!destination.join("backup").exists());
+    }
+
+    #[tokio::test]
+    async fn test_backup_single_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("notes.txt");
+        let destination = directory.path().join("destination");
+        std::fs::write(&file, b"content").unwrap();
+        let backup = || Command::Backup {
+            source_root: file.clone(),
+            destination: Destination::Directory(destination.clone()),
+            options: BackupOptions {
+                accept_new_destination: true,
+                ..BackupOptions::default()
+            },
+        };
+
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(backup(), message_sender.clone()).await.unwrap();
         assert_eq!(
-            files,
-            TEST_DIR_FILES
-                .iter()
-                .map(std::path::Path::new)
-                .collect::<Vec<_>>()
+            std::fs::read(destination.join("notes.txt")).unwrap(),
+            b"content"
         );
+
+        run(backup(), message_sender).await.unwrap();
+        let mut skipped = 0;
+        while let Ok(message) = message_receiver.try_recv() {
+            if let Message::Progress(Progress::IncrementSuccess(
+                Increment::SkippingFileNoModification { .. },
+            )) = message
+            {
+                skipped += 1;
+            }
+        }
+        assert_eq!(skipped, 1);
     }
-    #[test]
-    fn test_recurse_directories() {
-        let file_entry = RecursiveReadDir::try_new(TEST_DIR, ReadDirType::DirectoriesOnly).unwrap();
-        let files = file_entry
-            .into_iter()
-            .collect::<Result<Vec<_>, _>>()
-            .unwrap();
-        assert_eq!(dbg!(&files).len(), dbg!(&TEST_DIR_DIRECTORIES).len());
+
+    #[tokio::test]
+    async fn test_backup_paths() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        for path in ["docs/a.txt", "docs/b.txt", "other/deep/c.txt", "top.txt"] {
+            let path = source.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, b"content").unwrap();
+        }
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        run(
+            Command::BackupPaths {
+                paths: vec![
+                    "docs/a.txt".into(),
+                    source.path().join("other"),
+                    "gone.txt".into(),
+                ],
+                source_root: source.path().to_owned(),
+                destination_root: destination.path().to_owned(),
+                options: BackupOptions {
+                    accept_new_destination: true,
+                    ..BackupOptions::default()
+                },
+            },
+            message_sender,
+        )
+        .await
+        .unwrap();
+
+        let mut backed_up: Vec<_> =
+            RecursiveReadDir::try_new(destination.path(), ReadDirType::FilesOnly)
+                .unwrap()
+                .with_filter(BackupOptions::default().filter(&[]).unwrap())
+                .map(|file| file.unwrap())
+                .collect();
+        backed_up.sort();
         assert_eq!(
-            files,
-            TEST_DIR_DIRECTORIES
-                .iter()
-                .map(std::path::Path::new)
-                .collect::<Vec<_>>()
+            backed_up,
+            ["docs/a.txt", "other/deep/c.txt"].map(|path| destination.path().join(path))
         );
+        assert!(destination.path().join(METADATA_DIRECTORY).is_dir());
+        assert!(
+            !destination
+                .path()
+                .join("docs")
+                .join(METADATA_DIRECTORY)
+                .exists()
+        );
+        let mut vanished = vec![];
+        while let Ok(message) = message_receiver.try_recv() {
+            if let Message::Warning(Warning::SourceVanished(path)) = message {
+                vanished.push(path);
+            }
+        }
+        assert_eq!(vanished, [source.path().join("gone.txt")]);
     }
-    #[test]
-    fn test_recursive_readdir_fail() {
-        let file_entry = RecursiveReadDir::try_new(WRONG_TEST_DIR, ReadDirType::DirectoriesOnly);
-        assert!(dbg!(file_entry).is_err());
-        let file_entry = RecursiveReadDir::try_new(WRONG_TEST_DIR, ReadDirType::FilesOnly);
-        assert!(dbg!(file_entry).is_err());
+
+    #[tokio::test]
+    async fn test_backup_paths_rejects_paths_outside_of_source() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let destination = directory.path().join("destination");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(directory.path().join("outside.txt"), b"content").unwrap();
+
+        for path in [
+            directory.path().join("outside.txt"),
+            "../outside.txt".into(),
+            "docs/../../outside.txt".into(),
+        ] {
+            let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+            let result = run(
+                Command::BackupPaths {
+                    paths: vec![path.clone()],
+                    source_root: source.clone(),
+                    destination_root: destination.clone(),
+                    options: BackupOptions {
+                        accept_new_destination: true,
+                        ..BackupOptions::default()
+                    },
+                },
+                message_sender,
+            )
+            .await;
+            assert!(
+                matches!(&result, Err(Error::PathNotInSource { path: rejected, .. }) if *rejected == path),
+                "{result:?}"
+            );
+        }
+        assert!(!destination.exists());
     }
 
-    #[test]
-    fn test_get_destination_path() {
-        let destination_path = get_destination_file_path(
-            std::path::Path::new("destination/root"),
-            std::path::Path::new("source/root"),
-            std::path::Path::new("source/root/some/file.txt"),
+    #[tokio::test]
+    async fn test_sync_refuses_file_as_source() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("file.txt");
+        std::fs::write(&file, b"content").unwrap();
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let result = run(
+            Command::Sync {
+                source_root: file,
+                destination_root: directory.path().join("destination"),
+                options: BackupOptions::default(),
+            },
+            message_sender,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::SourceRootIsNotADirectory(_))));
+        assert!(!directory.path().join("destination").exists());
+    }
+
+    #[tokio::test]
+    async fn test_restore_preserves_directory_times() {
+        let backup = tempfile::tempdir().unwrap();
+        let restored = tempfile::tempdir().unwrap();
+        let nested = backup.path().join("nested");
+        std::fs::create_dir_all(nested.join("deeper")).unwrap();
+        std::fs::write(nested.join("deeper/file.txt"), b"content").unwrap();
+        let modified =
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        for directory in [nested.join("deeper"), nested.clone()] {
+            std::fs::File::open(directory)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        run(
+            Command::Restore {
+                source_root: restored.path().to_owned(),
+                destination_root: backup.path().to_owned(),
+                delete_files: false,
+                target: RestoreTarget::default(),
+                options: BackupOptions::default(),
+            },
+            message_sender,
         )
+        .await
         .unwrap();
-        assert_eq!(
-            destination_path,
-            std::path::Path::new("destination/root/some/file.txt")
-        );
 
-        let destination_path = get_destination_file_path(
-            std::path::Path::new("destination/root"),
-            std::path::Path::new("source/root"),
-            std::path::Path::new("source/root/some/dir/"),
+        for directory in ["nested", "nested/deeper"] {
+            let metadata = std::fs::metadata(restored.path().join(directory)).unwrap();
+            assert_eq!(metadata.modified().unwrap(), modified, "{directory}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restore_keep_overwritten() {
+        let source = tempfile::tempdir().unwrap();
+        let backup = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("file.txt"), b"backup").unwrap();
+        std::fs::write(source.path().join("same.txt"), b"same").unwrap();
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let backup_command = Command::Backup {
+            source_root: source.path().to_owned(),
+            destination: Destination::Directory(backup.path().to_owned()),
+            options: BackupOptions::default(),
+        };
+        run(backup_command, message_sender.clone()).await.unwrap();
+        std::fs::write(source.path().join("file.txt"), b"current").unwrap();
+        std::fs::write(source.path().join("file.txt.pre-restore"), b"earlier").unwrap();
+
+        let restore = Command::Restore {
+            source_root: source.path().to_owned(),
+            destination_root: backup.path().to_owned(),
+            delete_files: true,
+            target: RestoreTarget::default(),
+            options: BackupOptions {
+                keep_overwritten: true,
+                ..BackupOptions::default()
+            },
+        };
+        run(restore, message_sender).await.unwrap();
+
+        let read = |name: &str| std::fs::read(source.path().join(name)).unwrap();
+        assert_eq!(read("file.txt"), b"backup");
+        assert_eq!(read("file.txt.pre-restore"), b"earlier");
+        assert_eq!(read("file.txt.pre-restore-2"), b"current");
+        assert!(!source.path().join("same.txt.pre-restore").exists());
+    }
+
+    #[tokio::test]
+    async fn test_backup_verify_writes() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("file.txt"), b"content").unwrap();
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(
+            Command::Backup {
+                source_root: source.path().to_owned(),
+                destination: Destination::Directory(destination.path().to_owned()),
+                options: BackupOptions {
+                    verify_writes: true,
+                    ..BackupOptions::default()
+                },
+            },
+            message_sender,
         )
+        .await
         .unwrap();
         assert_eq!(
-            destination_path,
-            std::path::Path::new("destination/root/some/dir/")
+            std::fs::read(destination.path().join("file.txt")).unwrap(),
+            b"content"
+        );
+        let copied = std::iter::from_fn(|| message_receiver.try_recv().ok())
+            .filter(|message| {
+                matches!(
+                    message,
+                    Message::Progress(Progress::IncrementSuccess(Increment::FileCopied { .. }))
+                )
+            })
+            .count();
+        assert_eq!(copied, 1);
+        assert!(
+            verify_write(
+                &source.path().join("file.txt"),
+                &destination.path().join("file.txt")
+            )
+            .await
+            .is_ok()
+        );
+        std::fs::write(destination.path().join("file.txt"), b"CONTENT").unwrap();
+        assert!(
+            verify_write(
+                &source.path().join("file.txt"),
+                &destination.path().join("file.txt")
+            )
+            .await
+            .is_err()
         );
     }
 
     #[tokio::test]
-    async fn test_get_paths_in_destinatination_but_not_in_source_directories_less_and_additional() {
-        let recurse_testdir =
-            RecursiveReadDir::try_new(TEST_DIR, ReadDirType::DirectoriesOnly).unwrap();
-        let recurse_testdir2 =
-            RecursiveReadDir::try_new(TEST_DIR_LESS_AND_ADDITIONAL, ReadDirType::DirectoriesOnly)
+    async fn test_backup_scan_cache() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let nested = source.path().join("nested");
+        std::fs::create_dir(&nested).unwrap();
+        std::fs::write(nested.join("old.txt"), b"old").unwrap();
+        let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_hours(1);
+        for directory in [source.path(), &nested] {
+            std::fs::File::open(directory)
+                .unwrap()
+                .set_modified(an_hour_ago)
                 .unwrap();
-        let res = get_paths_in_destinatination_but_not_in_source(recurse_testdir2, recurse_testdir)
-            .await
-            .unwrap();
+        }
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let backup = || Command::Backup {
+            source_root: source.path().to_owned(),
+            destination: Destination::Directory(destination.path().to_owned()),
+            options: BackupOptions {
+                scan_cache: true,
+                ..BackupOptions::default()
+            },
+        };
+        run(backup(), message_sender.clone()).await.unwrap();
+        let listings =
+            std::fs::read_to_string(destination.path().join(".safeall/listings")).unwrap();
+        assert!(listings.contains(&manifest::escape(&nested)));
 
-        let mut difference = [
-            std::path::Path::new("testdir/more2"),
-            std::path::Path::new("testdir/more/even-mörer"),
-            std::path::Path::new("testdir/more2/moredir"),
-        ];
-        difference.sort();
-        assert_eq!(res, difference);
+        // NOTE: Adding a file changes the modification time of its directory
+        std::fs::write(nested.join("new.txt"), b"new").unwrap();
+        run(backup(), message_sender).await.unwrap();
+        assert_eq!(
+            std::fs::read(destination.path().join("nested/new.txt")).unwrap(),
+            b"new"
+        );
     }
 
     #[tokio::test]
-    async fn test_get_paths_in_destinatination_but_not_in_source_files_less_and_additional() {
-        let recurse_testdir = RecursiveReadDir::try_new(TEST_DIR, ReadDirType::FilesOnly).unwrap();
-        let recurse_testdir2 =
-            RecursiveReadDir::try_new(TEST_DIR_LESS_AND_ADDITIONAL, ReadDirType::FilesOnly)
-                .unwrap();
-        let res = get_paths_in_destinatination_but_not_in_source(recurse_testdir2, recurse_testdir)
-            .await
-            .unwrap();
+    async fn test_backup_hashing_phase() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("unchanged.txt"), b"same").unwrap();
+        std::fs::write(source.path().join("changed.txt"), b"old").unwrap();
+        let backup = || Command::Backup {
+            source_root: source.path().to_owned(),
+            destination: Destination::Directory(destination.path().to_owned()),
+            options: BackupOptions::default(),
+        };
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(backup(), message_sender).await.unwrap();
 
-        let mut difference = [
-            std::path::Path::new("testdir/03_a"),
-            std::path::Path::new("testdir/more/weird name.txt"),
-            std::path::Path::new("testdir/more2/some more file"),
-            std::path::Path::new("testdir/more2/some file"),
-            std::path::Path::new("testdir/more/even-mörer/all-solutions.bak"),
-            std::path::Path::new("testdir/more2/moredir/epic.file"),
-        ];
-        difference.sort();
-        assert_eq!(res, difference);
+        std::fs::write(source.path().join("changed.txt"), b"new").unwrap();
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(backup(), message_sender).await.unwrap();
+        let mut messages = vec![];
+        while let Some(message) = message_receiver.recv().await {
+            messages.push(message);
+        }
+        assert!(messages.iter().any(|m| matches!(
+            m,
+            Message::Progress(Progress::Start(2, ProgressType::Hashing))
+        )));
+        assert!(messages.iter().any(|m| matches!(
+            m,
+            Message::Progress(Progress::TotalBytes(7, ProgressType::CopingFiles))
+        )));
+        let copied_bytes: u64 = messages
+            .iter()
+            .filter_map(|m| match m {
+                Message::Progress(Progress::IncrementSuccess(
+                    increment @ (Increment::FileCopied { .. }
+                    | Increment::SkippingFileNoModification { .. }),
+                )) => Some(increment.bytes()),
+                _ => None,
+            })
+            .sum();
+        assert_eq!(copied_bytes, 7);
+        let hashed = messages
+            .iter()
+            .filter(|m| {
+                matches!(
+                    m,
+                    Message::Progress(Progress::IncrementSuccess(Increment::Hashed { .. }))
+                )
+            })
+            .count();
+        assert_eq!(hashed, 2);
+        assert_eq!(
+            std::fs::read(destination.path().join("changed.txt")).unwrap(),
+            b"new"
+        );
     }
+
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_get_paths_in_destinatination_but_not_in_source_directories_less() {
-        let recurse_testdir =
-            RecursiveReadDir::try_new(TEST_DIR, ReadDirType::DirectoriesOnly).unwrap();
-        let recurse_testdir2 =
-            RecursiveReadDir::try_new(TEST_DIR_LESS, ReadDirType::DirectoriesOnly).unwrap();
-        let res = get_paths_in_destinatination_but_not_in_source(recurse_testdir2, recurse_testdir)
-            .await
-            .unwrap();
+    async fn test_backup_destination_owner() {
+        use std::os::unix::fs::MetadataExt;
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::create_dir(source.path().join("nested")).unwrap();
+        std::fs::write(source.path().join("nested/file.txt"), b"content").unwrap();
+        let metadata = std::fs::metadata(source.path()).unwrap();
+        // NOTE: Only root can give files to other users
+        let owner = if metadata.uid() == 0 {
+            Owner {
+                uid: 65534,
+                gid: 65534,
+            }
+        } else {
+            Owner {
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+            }
+        };
 
-        let mut difference = [
-            std::path::Path::new("testdir/more2"),
-            std::path::Path::new("testdir/more/even-mörer"),
-            std::path::Path::new("testdir/more2/moredir"),
-        ];
-        difference.sort();
-        assert_eq!(res, difference);
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        run(
+            Command::Backup {
+                source_root: source.path().to_owned(),
+                destination: Destination::Directory(destination.path().to_owned()),
+                options: BackupOptions {
+                    destination_owner: Some(owner),
+                    ..BackupOptions::default()
+                },
+            },
+            message_sender,
+        )
+        .await
+        .unwrap();
+
+        for path in ["nested", "nested/file.txt"] {
+            let metadata = std::fs::metadata(destination.path().join(path)).unwrap();
+            assert_eq!((metadata.uid(), metadata.gid()), (owner.uid, owner.gid));
+        }
     }
 
     #[tokio::test]
-    async fn test_get_paths_in_destinatination_but_not_in_source_files_less() {
-        let recurse_testdir = RecursiveReadDir::try_new(TEST_DIR, ReadDirType::FilesOnly).unwrap();
-        let recurse_testdir2 =
-            RecursiveReadDir::try_new(TEST_DIR_LESS, ReadDirType::FilesOnly).unwrap();
-        let res = get_paths_in_destinatination_but_not_in_source(recurse_testdir2, recurse_testdir)
-            .await
+    async fn test_decide_copy_hash_max_size() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let destination = directory.path().join("destination");
+        std::fs::write(&source, b"content").unwrap();
+        std::fs::write(&destination, b"CONTENT").unwrap();
+        let modified = std::fs::metadata(&source).unwrap().modified().unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&destination)
+            .unwrap()
+            .set_modified(modified)
             .unwrap();
 
-        let mut difference = [
-            std::path::Path::new("testdir/03_a"),
-            std::path::Path::new("testdir/more/weird name.txt"),
-            std::path::Path::new("testdir/more2/some more file"),
-            std::path::Path::new("testdir/more2/some file"),
-            std::path::Path::new("testdir/more/even-mörer/all-solutions.bak"),
-            std::path::Path::new("testdir/more2/moredir/epic.file"),
-        ];
-        difference.sort();
-        assert_eq!(res, difference);
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let decide = |hash_max_size| {
+            let options = BackupOptions {
+                hash_max_size,
+                ..BackupOptions::default()
+            };
+            let (source, destination, message_sender) =
+                (source.clone(), destination.clone(), message_sender.clone());
+            async move { decide_copy(&source, &destination, &options, &message_sender).await }
+        };
+        assert_eq!(
+            decide(None).await,
+            Decision::Copy(CopyReason::ContentChanged)
+        );
+        assert_eq!(
+            decide(Some(7)).await,
+            Decision::Copy(CopyReason::ContentChanged)
+        );
+        assert_eq!(
+            decide(Some(6)).await,
+            Decision::Skip(SkipReason::SameMetadata)
+        );
     }
 
     #[tokio::test]
-    async fn test_get_paths_in_destinatination_but_not_in_source_directories_additional() {
-        let recurse_testdir =
-            RecursiveReadDir::try_new(TEST_DIR, ReadDirType::DirectoriesOnly).unwrap();
-        let recurse_testdir2 =
-            RecursiveReadDir::try_new(TEST_DIR_ADDITIONAL, ReadDirType::DirectoriesOnly).unwrap();
-        let res = get_paths_in_destinatination_but_not_in_source(recurse_testdir2, recurse_testdir)
-            .await
-            .unwrap();
+    async fn test_watch() {
+        for (stall_timeout, cancel, expected) in [
+            (None, false, Some(42)),
+            (Some(20), false, Some(42)),
+            (Some(20), true, None),
+        ] {
+            let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+            let activity = Activity::Copying {
+                source: "source".into(),
+                destination: "destination".into(),
+            };
+            let watchdog = Watchdog {
+                heartbeat: std::time::Duration::from_millis(10),
+                stall_timeout: stall_timeout.map(std::time::Duration::from_millis),
+                cancel,
+            };
+            let output = watch(
+                activity,
+                watchdog,
+                async {
+                    tokio::time::sleep(std::time::Duration::from_millis(55)).await;
+                    42
+                },
+                &message_sender,
+            )
+            .await;
+            assert_eq!(output, expected);
+            let messages: Vec<_> =
+                std::iter::from_fn(|| message_receiver.try_recv().ok()).collect();
+            let heartbeats = messages
+                .iter()
+                .filter(|message| matches!(message, Message::Heartbeat(Activity::Copying { .. })))
+                .count();
+            let stalls = messages
+                .iter()
+                .filter(|message| {
+                    matches!(message, Message::Warning(Warning::OperationStalled { .. }))
+                })
+                .count();
+            assert!(heartbeats >= if cancel { 1 } else { 3 }, "{heartbeats}");
+            assert_eq!(stalls, usize::from(stall_timeout.is_some()));
+        }
+    }
 
-        assert!(dbg!(res).is_empty());
+    #[tokio::test]
+    async fn test_cancelled_copy_keeps_file_reserved() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let staging = directory.path().join("staging");
+        std::fs::write(&source, b"content").unwrap();
+
+        let writing = std::sync::Arc::new(Writing::start(&staging).unwrap());
+        let thread = writing.clone();
+        assert_eq!(
+            Writing::start(&staging).err().map(|e| e.kind()),
+            Some(std::io::ErrorKind::ResourceBusy)
+        );
+        drop(writing);
+        // NOTE: The thread of a cancelled copy still holds the file
+        assert!(Writing::start(&staging).is_err());
+        std::fs::write(&staging, b"partial").unwrap();
+        let cancelled = thread.finish::<()>(Err(std::io::ErrorKind::Interrupted.into()));
+        assert!(cancelled.is_err());
+        assert!(!staging.exists());
+        drop(thread);
+
+        let options = BackupOptions {
+            cancel_stalled: true,
+            ..BackupOptions::default()
+        };
+        let writing = std::sync::Arc::new(Writing::start(&staging).unwrap());
+        let (copied, _) = copy_file_to(&source, &writing, &options).await.unwrap();
+        assert_eq!(copied, 7);
+        assert_eq!(std::fs::read(&staging).unwrap(), b"content");
     }
 
     #[tokio::test]
-    async fn test_get_paths_in_destinatination_but_not_in_source_files_additional() {
-        let recurse_testdir = RecursiveReadDir::try_new(TEST_DIR, ReadDirType::FilesOnly).unwrap();
-        let recurse_testdir2 =
-            RecursiveReadDir::try_new(TEST_DIR_ADDITIONAL, ReadDirType::FilesOnly).unwrap();
-        let res = get_paths_in_destinatination_but_not_in_source(recurse_testdir2, recurse_testdir)
+    async fn test_backup_max_errors() {
+        for (max_errors, too_many) in [(Some(1), true), (Some(3), false), (None, false)] {
+            let source = tempfile::tempdir().unwrap();
+            let destination = tempfile::tempdir().unwrap();
+            for name in ["a", "b", "c"] {
+                std::fs::create_dir(source.path().join(name)).unwrap();
+                std::fs::write(destination.path().join(name), b"not a directory").unwrap();
+            }
+
+            let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+            let result = run(
+                Command::Backup {
+                    source_root: source.path().to_owned(),
+                    destination: Destination::Directory(destination.path().to_owned()),
+                    options: BackupOptions {
+                        max_errors,
+                        accept_new_destination: true,
+                        ..BackupOptions::default()
+                    },
+                },
+                message_sender,
+            )
+            .await;
+            assert_eq!(
+                matches!(result, Err(Error::TooManyErrors { errors: 2, max: 1 })),
+                too_many,
+                "{max_errors:?}"
+            );
+            assert_eq!(
+                matches!(result, Err(Error::ProcessPathErrors { .. })),
+                !too_many
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_backup_special_files() {
+        for (special_files, exists) in [
+            (SpecialFilePolicy::Skip, false),
+            (SpecialFilePolicy::Recreate, true),
+        ] {
+            let source = tempfile::tempdir().unwrap();
+            let destination = tempfile::tempdir().unwrap();
+            std::fs::write(source.path().join("file.txt"), b"content").unwrap();
+            let fifo =
+                std::ffi::CString::new(source.path().join("fifo").to_str().unwrap()).unwrap();
+            // SAFETY: The path is a valid nul terminated string
+            assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+            let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+            run(
+                Command::Backup {
+                    source_root: source.path().to_owned(),
+                    destination: Destination::Directory(destination.path().to_owned()),
+                    options: BackupOptions {
+                        special_files,
+                        accept_new_destination: true,
+                        ..BackupOptions::default()
+                    },
+                },
+                message_sender,
+            )
             .await
             .unwrap();
 
-        assert!(dbg!(res).is_empty());
+            assert!(destination.path().join("file.txt").exists());
+            assert_eq!(
+                std::fs::symlink_metadata(destination.path().join("fifo")).is_ok(),
+                exists
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_backup_preserves_hard_links() {
+        use std::os::unix::fs::MetadataExt;
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::create_dir(source.path().join("nested")).unwrap();
+        std::fs::write(source.path().join("original"), b"content").unwrap();
+        std::fs::hard_link(
+            source.path().join("original"),
+            source.path().join("nested/link"),
+        )
+        .unwrap();
+
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let backup = || Command::Backup {
+            source_root: source.path().to_owned(),
+            destination: Destination::Directory(destination.path().to_owned()),
+            options: BackupOptions::default(),
+        };
+        run(backup(), message_sender.clone()).await.unwrap();
+        run(backup(), message_sender).await.unwrap();
+
+        let original = std::fs::metadata(destination.path().join("original")).unwrap();
+        let link = std::fs::metadata(destination.path().join("nested/link")).unwrap();
+        assert_eq!(original.ino(), link.ino());
+        assert_eq!(
+            std::fs::read(destination.path().join("nested/link")).unwrap(),
+            b"content"
+        );
     }
 }
@@ -0,0 +1,67 @@
+//! Lock on the destination such that two runs never write to the same backup at once.
+
+const LOCK_FILE: &str = "lock";
+
+/// Held for the whole run, the lock is released when it is dropped.
+#[derive(Debug)]
+pub struct DestinationLock {
+    _file: std::fs::File,
+}
+
+impl DestinationLock {
+    pub fn acquire(destination_root: &std::path::Path) -> Result<Self, crate::Error> {
+        let path = destination_root
+            .join(crate::METADATA_DIRECTORY)
+            .join(LOCK_FILE);
+        let cannot_lock = |e: &dyn std::fmt::Display| {
+            crate::Error::CannotLockDestination(destination_root.to_owned(), e.to_string())
+        };
+        // NOTE: Only the metadata directory is created, such that locking an empty mount point
+        // of a drive which is not mounted fails instead of creating the destination
+        match std::fs::create_dir(destination_root.join(crate::METADATA_DIRECTORY)) {
+            Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(cannot_lock(&e)),
+            _ => {}
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|e| cannot_lock(&e))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(std::fs::TryLockError::WouldBlock) => {
+                Err(crate::Error::DestinationLocked(destination_root.to_owned()))
+            }
+            Err(std::fs::TryLockError::Error(e)) => Err(cannot_lock(&e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_destination_lock() {
+        let destination = tempfile::tempdir().unwrap();
+        let lock = DestinationLock::acquire(destination.path()).unwrap();
+        assert!(matches!(
+            DestinationLock::acquire(destination.path()),
+            Err(crate::Error::DestinationLocked(_))
+        ));
+        drop(lock);
+        DestinationLock::acquire(destination.path()).unwrap();
+    }
+
+    #[test]
+    fn test_lock_does_not_create_destination() {
+        let parent = tempfile::tempdir().unwrap();
+        let destination = parent.path().join("unmounted");
+        assert!(matches!(
+            DestinationLock::acquire(&destination),
+            Err(crate::Error::CannotLockDestination(..))
+        ));
+        assert!(!destination.exists());
+    }
+}
@@ -0,0 +1,271 @@
+//! Manifest of all files safeall has backed up into a destination, with their size,
+//! modification time and hash such that a backup can be verified without its source.
+
+const MANIFEST_FILE: &str = "manifest";
+/// First line of manifests with records. Older manifests only list the paths.
+const HEADER: &str = "# safeall manifest 2";
+/// Start of the line after the header with the ID of the run which wrote the manifest.
+const RUN_PREFIX: &str = "# run ";
+
+/// Size, modification time in nanoseconds and hash of a file in the destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRecord {
+    pub size: u64,
+    pub modified: u128,
+    /// `None` if the file could not be read.
+    pub hash: Option<blake3::Hash>,
+}
+
+/// Relative paths of the files in the destination that came from a source.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    /// The record is `None` for files of a manifest without records.
+    files: std::collections::HashMap<std::path::PathBuf, Option<FileRecord>>,
+    /// `None` for manifests of older versions.
+    run_id: Option<crate::RunId>,
+}
+
+fn manifest_path(destination_root: &std::path::Path) -> std::path::PathBuf {
+    destination_root
+        .join(crate::METADATA_DIRECTORY)
+        .join(MANIFEST_FILE)
+}
+
+/// Escapes a path such that it fits on one line.
+pub fn escape(path: &std::path::Path) -> String {
+    path.to_string_lossy()
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+}
+
+pub fn unescape(line: &str) -> std::path::PathBuf {
+    let mut path = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                path.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                path.push('\\');
+                chars.next();
+            }
+            _ => path.push(c),
+        }
+    }
+    path.into()
+}
+
+fn parse_record(line: &str) -> Option<(std::path::PathBuf, Option<FileRecord>)> {
+    let mut parts = line.splitn(4, ' ');
+    let hash = match parts.next()? {
+        "-" => None,
+        hash => Some(blake3::Hash::from_hex(hash).ok()?),
+    };
+    let record = FileRecord {
+        size: parts.next()?.parse().ok()?,
+        modified: parts.next()?.parse().ok()?,
+        hash,
+    };
+    Some((unescape(parts.next()?), Some(record)))
+}
+
+/// Modification time in nanoseconds as it is recorded in the manifest.
+pub fn modified(metadata: &std::fs::Metadata) -> u128 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_nanos())
+}
+
+/// The record of `path` from `previous` if the file has not changed since, otherwise a new
+/// one. `None` if the file does not exist.
+fn record(path: &std::path::Path, previous: Option<&FileRecord>) -> Option<FileRecord> {
+    let metadata = std::fs::metadata(path)
+        .ok()
+        .filter(std::fs::Metadata::is_file)?;
+    let size = metadata.len();
+    let modified = modified(&metadata);
+    if let Some(previous) = previous
+        && previous.hash.is_some()
+        && (previous.size, previous.modified) == (size, modified)
+    {
+        return Some(previous.clone());
+    }
+    Some(FileRecord {
+        size,
+        modified,
+        hash: crate::hash_cache::cached(path, crate::comparator::hash_file).ok(),
+    })
+}
+
+impl Manifest {
+    /// Loads the manifest of the destination. It is empty if there is none.
+    #[must_use]
+    pub fn load(destination_root: &std::path::Path) -> Self {
+        let manifest = std::fs::read_to_string(manifest_path(destination_root)).unwrap_or_default();
+        let mut lines = manifest.lines().peekable();
+        if lines.next_if_eq(&HEADER).is_none() {
+            return Self {
+                files: lines.map(|line| (unescape(line), None)).collect(),
+                run_id: None,
+            };
+        }
+        let run_id = lines
+            .next_if(|line| line.starts_with(RUN_PREFIX))
+            .and_then(|line| line[RUN_PREFIX.len()..].parse().ok());
+        Self {
+            files: lines.filter_map(parse_record).collect(),
+            run_id,
+        }
+    }
+
+    /// The run which has last updated the manifest.
+    #[must_use]
+    pub fn run_id(&self) -> Option<crate::RunId> {
+        self.run_id
+    }
+
+    #[must_use]
+    pub fn contains(&self, relative_path: &std::path::Path) -> bool {
+        self.files.contains_key(relative_path)
+    }
+
+    /// All files with their record, if the manifest has records.
+    pub fn records(&self) -> impl Iterator<Item = (&std::path::Path, Option<&FileRecord>)> {
+        self.files
+            .iter()
+            .map(|(path, record)| (path.as_path(), record.as_ref()))
+    }
+
+    /// The size, modification time and hash of a file when it was last backed up.
+    #[must_use]
+    pub fn record(&self, relative_path: &std::path::Path) -> Option<&FileRecord> {
+        self.files.get(relative_path)?.as_ref()
+    }
+
+    /// Adds all files of the source that exist in the destination and removes the files which
+    /// are not in the destination anymore. Only new and changed files are hashed.
+    pub fn update(
+        source_root: &std::path::Path,
+        destination_root: &std::path::Path,
+        run_id: crate::RunId,
+    ) -> Result<(), std::io::Error> {
+        let mut manifest = Self::load(destination_root);
+        let mut relative_paths: Vec<_> = manifest.files.keys().cloned().collect();
+        for source_file in
+            crate::RecursiveReadDir::try_new(source_root, crate::ReadDirType::FilesOnly)?.flatten()
+        {
+            if let Ok(relative_path) = source_file.strip_prefix(source_root) {
+                // NOTE: A file which has been split is recorded by its descriptor and parts
+                relative_paths.extend(
+                    crate::split::artifacts(&destination_root.join(relative_path))
+                        .iter()
+                        .filter_map(|path| path.strip_prefix(destination_root).ok())
+                        .map(std::path::Path::to_owned),
+                );
+                relative_paths.push(relative_path.to_owned());
+            }
+        }
+        let mut files = std::collections::HashMap::new();
+        for relative_path in relative_paths {
+            if files.contains_key(&relative_path) {
+                continue;
+            }
+            let previous = manifest.record(&relative_path);
+            if let Some(record) = record(&destination_root.join(&relative_path), previous) {
+                files.insert(relative_path, Some(record));
+            }
+        }
+        manifest.files = files;
+        manifest.run_id = Some(run_id);
+        manifest.save(destination_root)
+    }
+
+    fn save(&self, destination_root: &std::path::Path) -> Result<(), std::io::Error> {
+        let path = manifest_path(destination_root);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut files: Vec<_> = self
+            .files
+            .iter()
+            .map(|(path, record)| match record {
+                Some(record) => format!(
+                    "{} {} {} {}",
+                    record
+                        .hash
+                        .map_or_else(|| "-".to_owned(), |hash| hash.to_hex().to_string()),
+                    record.size,
+                    record.modified,
+                    escape(path)
+                ),
+                None => format!("- 0 0 {}", escape(path)),
+            })
+            .collect();
+        files.sort_by(|a, b| a.splitn(4, ' ').nth(3).cmp(&b.splitn(4, ' ').nth(3)));
+        let mut content = HEADER.to_owned();
+        if let Some(run_id) = self.run_id {
+            content.push('\n');
+            content.push_str(RUN_PREFIX);
+            content.push_str(&run_id.to_string());
+        }
+        for file in files {
+            content.push('\n');
+            content.push_str(&file);
+        }
+        content.push('\n');
+        std::fs::write(path, content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_paths() {
+        for path in [
+            "simple/path.txt",
+            "new\nline",
+            "back\\slash\\n",
+            "wèird, name",
+        ] {
+            let path = std::path::Path::new(path);
+            let escaped = escape(path);
+            assert!(!escaped.contains('\n'));
+            assert_eq!(unescape(&escaped), path);
+        }
+    }
+
+    #[test]
+    fn test_manifest_records() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("file"), b"content").unwrap();
+        std::fs::write(destination.path().join("file"), b"content").unwrap();
+        std::fs::write(destination.path().join("old"), b"old").unwrap();
+        // NOTE: Manifests of older versions only list the paths
+        std::fs::create_dir(destination.path().join(crate::METADATA_DIRECTORY)).unwrap();
+        std::fs::write(manifest_path(destination.path()), "old\ngone\n").unwrap();
+
+        let run_id = crate::RunId::new();
+        Manifest::update(source.path(), destination.path(), run_id).unwrap();
+        let manifest = Manifest::load(destination.path());
+        assert_eq!(manifest.run_id(), Some(run_id));
+        let path = std::path::Path::new;
+        assert_eq!(
+            manifest.record(path("file")).unwrap().hash,
+            Some(blake3::hash(b"content"))
+        );
+        assert_eq!(manifest.record(path("old")).unwrap().size, 3);
+        assert!(!manifest.contains(path("gone")));
+        assert!(
+            std::fs::read_to_string(manifest_path(destination.path()))
+                .unwrap()
+                .starts_with(HEADER)
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! Forwarding the messages of one run to several frontends, e.g. a log file, the GUI and a
+//! machine readable stream, each of which only wants messages of a certain severity.
+
+use crate::{Message, MessageSender, Progress, RunId};
+
+/// How important a message is, from the least to the most important.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// Progress of the phases and heartbeats of long operations.
+    Progress,
+    Info,
+    Warning,
+    /// Files and directories which could not be backed up.
+    Error,
+}
+
+impl Message {
+    #[must_use]
+    pub fn severity(&self) -> Severity {
+        match self {
+            Message::Progress(Progress::IncrementFail(_) | Progress::EndFail(_, _)) => {
+                Severity::Error
+            }
+            Message::Progress(_) | Message::Heartbeat(_) => Severity::Progress,
+            Message::Info(_) => Severity::Info,
+            Message::Warning(_) => Severity::Warning,
+        }
+    }
+}
+
+/// Sends every message to all senders whose minimum severity it reaches.
+#[derive(Default)]
+pub struct MultiSender {
+    senders: Vec<(Severity, Box<dyn MessageSender + Send + Sync>)>,
+}
+
+impl MultiSender {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a sender which only receives messages of at least `min_severity`.
+    #[must_use]
+    pub fn with(
+        mut self,
+        min_severity: Severity,
+        sender: impl MessageSender + Send + Sync + 'static,
+    ) -> Self {
+        self.senders.push((min_severity, Box::new(sender)));
+        self
+    }
+}
+
+impl MessageSender for MultiSender {
+    fn send(&self, message: Message) {
+        self.send_from_run(RunId::default(), message);
+    }
+
+    fn send_from_run(&self, run_id: RunId, message: Message) {
+        let severity = message.severity();
+        let mut receivers = self
+            .senders
+            .iter()
+            .filter(|(min_severity, _)| severity >= *min_severity)
+            .map(|(_, sender)| sender)
+            .peekable();
+        // NOTE: The last receiver gets the message itself instead of a clone
+        while let Some(sender) = receivers.next() {
+            if receivers.peek().is_some() {
+                sender.send_from_run(run_id, message.clone());
+            } else {
+                sender.send_from_run(run_id, message);
+                break;
+            }
+        }
+    }
+
+    fn compared(&self, duration: std::time::Duration) {
+        for (_, sender) in &self.senders {
+            sender.compared(duration);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multi_sender() {
+        let (all_sender, mut all) = tokio::sync::mpsc::unbounded_channel();
+        let (warning_sender, mut warnings) = tokio::sync::mpsc::unbounded_channel();
+        let sender = MultiSender::new()
+            .with(Severity::Progress, all_sender)
+            .with(Severity::Warning, warning_sender);
+        sender.send(Message::Info(crate::Info::ThrottlingStopped));
+        sender.send(Message::Warning(crate::Warning::CycleDetected("a".into())));
+        sender.send(Message::Progress(Progress::EndFail(
+            1,
+            crate::ProgressType::CopingFiles,
+        )));
+
+        let received = |receiver: &mut tokio::sync::mpsc::UnboundedReceiver<Message>| {
+            std::iter::from_fn(|| receiver.try_recv().ok())
+                .map(|message| message.severity())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            received(&mut all),
+            vec![Severity::Info, Severity::Warning, Severity::Error]
+        );
+        assert_eq!(
+            received(&mut warnings),
+            vec![Severity::Warning, Severity::Error]
+        );
+    }
+}
@@ -0,0 +1,28 @@
+//! Owner of the files written to the destination, e.g. when a backup running as root writes
+//! to the share of another user.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl std::fmt::Display for Owner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.uid, self.gid)
+    }
+}
+
+/// Changes the owner of `path` itself, not of the file a symbolic link points to.
+#[cfg(unix)]
+pub fn set(path: &std::path::Path, owner: Owner) -> std::io::Result<()> {
+    std::os::unix::fs::lchown(path, Some(owner.uid), Some(owner.gid))
+}
+
+#[cfg(not(unix))]
+pub fn set(_path: &std::path::Path, _owner: Owner) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "owners can only be set on Unix",
+    ))
+}
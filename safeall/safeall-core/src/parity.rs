@@ -0,0 +1,366 @@
+//! Reed-Solomon parity data of the files in a destination, such that a scrub can reconstruct
+//! damaged parts of a file without its source.
+//!
+//! Every file is split into shards which are grouped into stripes, and each stripe gets parity
+//! shards of the configured share of its size. The parity file of a file holds the hash of
+//! every shard, which tells the damaged shards apart, and the parity shards. Parity files are
+//! kept in `.safeall/parity` such that they are not restored with the backup.
+//!
+//! Only available with the `parity` feature, otherwise no parity is written.
+
+const PARITY_DIRECTORY: &str = "parity";
+const PARITY_EXTENSION: &str = "parity";
+const MAGIC: &[u8; 16] = b"SAFEALL-PARITY-1";
+/// Files smaller than this are a single shard of their size.
+#[cfg(feature = "parity")]
+const SHARD_SIZE: u64 = 64 * 1024;
+/// Data shards per stripe, such that data and parity shards stay within the 256 shards of the
+/// Galois field.
+#[cfg(feature = "parity")]
+const MAX_DATA_SHARDS: usize = 128;
+
+/// Result of reconstructing a file from its parity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "parity"), allow(dead_code))]
+pub enum Reconstruction {
+    Intact,
+    Repaired {
+        shards: usize,
+    },
+    /// More shards are damaged than the parity can reconstruct.
+    Unrecoverable,
+}
+
+/// Path of the parity file of the file at `relative_path` in the destination.
+pub fn parity_path(
+    destination_root: &std::path::Path,
+    relative_path: &std::path::Path,
+) -> std::path::PathBuf {
+    let mut path = parity_directory(destination_root).join(relative_path);
+    path.as_mut_os_string().push(format!(".{PARITY_EXTENSION}"));
+    path
+}
+
+fn parity_directory(destination_root: &std::path::Path) -> std::path::PathBuf {
+    destination_root
+        .join(crate::METADATA_DIRECTORY)
+        .join(PARITY_DIRECTORY)
+}
+
+/// Hash of the file the parity file has been written for, `None` if there is no valid one.
+fn written_for(parity_file: &std::path::Path) -> Option<blake3::Hash> {
+    use std::io::Read;
+    let mut header = [0; MAGIC.len() + blake3::OUT_LEN];
+    std::fs::File::open(parity_file)
+        .ok()?
+        .read_exact(&mut header)
+        .ok()?;
+    let (magic, hash) = header.split_at(MAGIC.len());
+    (magic == MAGIC).then(|| blake3::Hash::from_bytes(hash.try_into().expect("Hash has its size")))
+}
+
+/// Writes the parity of every file in the manifest whose parity is missing or outdated and
+/// removes the parity of files which are not in the manifest anymore.
+pub fn update(destination_root: &std::path::Path, percent: u8) -> std::io::Result<()> {
+    let manifest = crate::Manifest::load(destination_root);
+    let mut expected = std::collections::HashSet::new();
+    for (relative_path, record) in manifest.records() {
+        let Some(hash) = record.and_then(|record| record.hash) else {
+            continue;
+        };
+        let parity_file = parity_path(destination_root, relative_path);
+        if written_for(&parity_file) != Some(hash) {
+            write(
+                &destination_root.join(relative_path),
+                &parity_file,
+                hash,
+                percent,
+            )?;
+        }
+        expected.insert(parity_file);
+    }
+    let directory = parity_directory(destination_root);
+    if directory.is_dir() {
+        for parity_file in
+            crate::RecursiveReadDir::try_new(&directory, crate::ReadDirType::FilesOnly)?.flatten()
+        {
+            if !expected.contains(&parity_file) {
+                std::fs::remove_file(&parity_file)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "parity")]
+fn parity_shards(data_shards: usize, percent: u8) -> usize {
+    (data_shards * usize::from(percent.clamp(1, 100))).div_ceil(100)
+}
+
+/// Reads until `buffer` is full or the end of the file and pads the rest with zeros.
+#[cfg(feature = "parity")]
+fn read_shard(reader: &mut impl std::io::Read, buffer: &mut [u8]) -> std::io::Result<()> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    buffer[filled..].fill(0);
+    Ok(())
+}
+
+#[cfg(feature = "parity")]
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    file_size: u64,
+    shard_size: usize,
+    stripes: u64,
+}
+
+#[cfg(feature = "parity")]
+impl Layout {
+    #[allow(clippy::cast_possible_truncation)]
+    fn new(file_size: u64) -> Self {
+        let shard_size = file_size.clamp(1, SHARD_SIZE);
+        let shards = file_size.div_ceil(shard_size);
+        Self {
+            file_size,
+            shard_size: shard_size as usize,
+            stripes: shards.div_ceil(MAX_DATA_SHARDS as u64),
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn data_shards(&self, stripe: u64) -> usize {
+        let shards = self.file_size.div_ceil(self.shard_size as u64);
+        (shards - stripe * MAX_DATA_SHARDS as u64).min(MAX_DATA_SHARDS as u64) as usize
+    }
+
+    fn offset(&self, stripe: u64, shard: usize) -> u64 {
+        (stripe * MAX_DATA_SHARDS as u64 + shard as u64) * self.shard_size as u64
+    }
+}
+
+#[cfg(feature = "parity")]
+fn codec_error(error: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Writes the parity of `file`, whose content has `hash`, with `percent` parity shards per
+/// stripe.
+#[cfg(feature = "parity")]
+pub fn write(
+    file: &std::path::Path,
+    parity_file: &std::path::Path,
+    hash: blake3::Hash,
+    percent: u8,
+) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut reader = std::io::BufReader::new(std::fs::File::open(file)?);
+    let layout = Layout::new(reader.get_ref().metadata()?.len());
+    if let Some(parent) = parity_file.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut temporary = parity_file.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = std::path::PathBuf::from(temporary);
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(&temporary)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(hash.as_bytes())?;
+    writer.write_all(&layout.file_size.to_le_bytes())?;
+    writer.write_all(&[percent.clamp(1, 100)])?;
+    for stripe in 0..layout.stripes {
+        let data_shards = layout.data_shards(stripe);
+        let parity_shards = parity_shards(data_shards, percent);
+        let mut shards = vec![vec![0; layout.shard_size]; data_shards + parity_shards];
+        for shard in &mut shards[..data_shards] {
+            read_shard(&mut reader, shard)?;
+        }
+        reed_solomon_erasure::galois_8::ReedSolomon::new(data_shards, parity_shards)
+            .map_err(codec_error)?
+            .encode(&mut shards)
+            .map_err(codec_error)?;
+        for shard in &shards {
+            writer.write_all(blake3::hash(shard).as_bytes())?;
+        }
+        for shard in &shards[data_shards..] {
+            writer.write_all(shard)?;
+        }
+    }
+    writer
+        .into_inner()
+        .map_err(std::io::IntoInnerError::into_error)?
+        .sync_all()?;
+    std::fs::rename(temporary, parity_file)
+}
+
+/// Checks every shard of `file` and rewrites the damaged ones from the parity. The
+/// modification time of the file is kept.
+#[cfg(feature = "parity")]
+pub fn reconstruct(
+    file: &std::path::Path,
+    parity_file: &std::path::Path,
+) -> std::io::Result<Reconstruction> {
+    use std::io::{Read, Seek, Write};
+
+    let mut parity = std::io::BufReader::new(std::fs::File::open(parity_file)?);
+    let mut header = [0; MAGIC.len() + blake3::OUT_LEN + 8 + 1];
+    parity.read_exact(&mut header)?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(codec_error("not a parity file"));
+    }
+    let size_offset = MAGIC.len() + blake3::OUT_LEN;
+    let file_size = u64::from_le_bytes(
+        header[size_offset..size_offset + 8]
+            .try_into()
+            .expect("Size has 8 bytes"),
+    );
+    let percent = header[size_offset + 8];
+    let mut data = std::fs::File::options().read(true).write(true).open(file)?;
+    let metadata = data.metadata()?;
+    if metadata.len() != file_size {
+        return Err(codec_error("the size of the file differs from its parity"));
+    }
+    let layout = Layout::new(file_size);
+    let mut repaired = 0;
+    let mut unrecoverable = false;
+    for stripe in 0..layout.stripes {
+        let data_shards = layout.data_shards(stripe);
+        let parity_shards = parity_shards(data_shards, percent);
+        let total = data_shards + parity_shards;
+        let mut hashes = vec![[0; blake3::OUT_LEN]; total];
+        for hash in &mut hashes {
+            parity.read_exact(hash)?;
+        }
+        let mut shards = vec![vec![0; layout.shard_size]; total];
+        data.seek(std::io::SeekFrom::Start(layout.offset(stripe, 0)))?;
+        for shard in &mut shards[..data_shards] {
+            read_shard(&mut data, shard)?;
+        }
+        for shard in &mut shards[data_shards..] {
+            parity.read_exact(shard)?;
+        }
+        let mut shards: Vec<_> = shards
+            .into_iter()
+            .zip(&hashes)
+            .map(|(shard, hash)| (blake3::hash(&shard).as_bytes() == hash).then_some(shard))
+            .collect();
+        let damaged: Vec<_> = (0..data_shards).filter(|&i| shards[i].is_none()).collect();
+        if damaged.is_empty() {
+            continue;
+        }
+        let reconstructed =
+            reed_solomon_erasure::galois_8::ReedSolomon::new(data_shards, parity_shards)
+                .map_err(codec_error)?
+                .reconstruct_data(&mut shards);
+        if reconstructed.is_err() {
+            unrecoverable = true;
+            continue;
+        }
+        for shard in damaged {
+            let content = shards[shard]
+                .as_ref()
+                .expect("Shard has been reconstructed");
+            if blake3::hash(content).as_bytes() != &hashes[shard] {
+                unrecoverable = true;
+                continue;
+            }
+            let offset = layout.offset(stripe, shard);
+            // NOTE: The last shard is padded with zeros which are not part of the file
+            let length = (file_size - offset).min(layout.shard_size as u64);
+            data.seek(std::io::SeekFrom::Start(offset))?;
+            data.write_all(&content[..usize::try_from(length).expect("Shard fits in memory")])?;
+            repaired += 1;
+        }
+    }
+    if repaired > 0 {
+        data.sync_all()?;
+        data.set_modified(metadata.modified()?)?;
+    }
+    Ok(if unrecoverable {
+        Reconstruction::Unrecoverable
+    } else if repaired > 0 {
+        Reconstruction::Repaired { shards: repaired }
+    } else {
+        Reconstruction::Intact
+    })
+}
+
+#[cfg(not(feature = "parity"))]
+fn unsupported() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "safeall has been built without the `parity` feature",
+    )
+}
+
+#[cfg(not(feature = "parity"))]
+pub fn write(
+    _file: &std::path::Path,
+    _parity_file: &std::path::Path,
+    _hash: blake3::Hash,
+    _percent: u8,
+) -> std::io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "parity"))]
+pub fn reconstruct(
+    _file: &std::path::Path,
+    _parity_file: &std::path::Path,
+) -> std::io::Result<Reconstruction> {
+    Err(unsupported())
+}
+
+#[cfg(all(test, feature = "parity"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconstruct_from_parity() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("file");
+        let parity_file = parity_path(directory.path(), std::path::Path::new("file"));
+        #[allow(clippy::cast_possible_truncation)]
+        let content: Vec<u8> = (0..SHARD_SIZE * 10 + 123)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(&file, &content).unwrap();
+        write(&file, &parity_file, blake3::hash(&content), 20).unwrap();
+        assert_eq!(written_for(&parity_file), Some(blake3::hash(&content)));
+        assert_eq!(
+            reconstruct(&file, &parity_file).unwrap(),
+            Reconstruction::Intact
+        );
+
+        let mut damaged = content.clone();
+        damaged[10] ^= 0xff;
+        damaged[content.len() - 1] ^= 0xff;
+        std::fs::write(&file, &damaged).unwrap();
+        let modified = std::fs::metadata(&file).unwrap().modified().unwrap();
+        assert_eq!(
+            reconstruct(&file, &parity_file).unwrap(),
+            Reconstruction::Repaired { shards: 2 }
+        );
+        assert_eq!(std::fs::read(&file).unwrap(), content);
+        assert_eq!(
+            std::fs::metadata(&file).unwrap().modified().unwrap(),
+            modified
+        );
+
+        // NOTE: 20 percent of 11 shards are 3 parity shards
+        for shard in 0..4 {
+            damaged[shard * 64 * 1024 + 1] ^= 0xff;
+        }
+        std::fs::write(&file, &damaged).unwrap();
+        assert_eq!(
+            reconstruct(&file, &parity_file).unwrap(),
+            Reconstruction::Unrecoverable
+        );
+    }
+}
@@ -0,0 +1,526 @@
+//! Dry run which determines what a command would do without writing anything.
+
+use crate::{
+    BackupOptions, Command, DeletionGuard, Destination, Error, MessageSender, ReadDirType,
+    RecursiveReadDir, SpecialFilePolicy, SuspiciousDeletionPolicy,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlannedAction {
+    CreateDirectory {
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+    },
+    CopyFile {
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+    },
+    /// The file is larger than the destination can store, see
+    /// [`BackupOptions::oversized_files`].
+    OversizedFile {
+        source: std::path::PathBuf,
+        size: u64,
+        max_size: u64,
+    },
+    DeleteDirectory(std::path::PathBuf),
+    DeleteFile(std::path::PathBuf),
+    Quarantine(std::path::PathBuf),
+}
+
+impl std::fmt::Display for PlannedAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlannedAction::CreateDirectory {
+                source,
+                destination,
+            } => write!(
+                f,
+                "Would create directory \"{}\" to backup \"{}\".",
+                destination.display(),
+                source.display()
+            ),
+            PlannedAction::CopyFile {
+                source,
+                destination,
+            } => write!(
+                f,
+                "Would copy \"{}\" to \"{}\".",
+                source.display(),
+                destination.display()
+            ),
+            PlannedAction::OversizedFile {
+                source,
+                size,
+                max_size,
+            } => write!(
+                f,
+                "Cannot copy \"{}\" ({}) as the destination stores files up to {} only.",
+                source.display(),
+                crate::format_bytes(*size),
+                crate::format_bytes(*max_size)
+            ),
+            PlannedAction::DeleteDirectory(path) => {
+                write!(f, "Would delete directory \"{}\".", path.display())
+            }
+            PlannedAction::DeleteFile(path) => {
+                write!(f, "Would delete file \"{}\".", path.display())
+            }
+            PlannedAction::Quarantine(path) => {
+                write!(f, "Would move \"{}\" to the quarantine.", path.display())
+            }
+        }
+    }
+}
+
+/// What a restore would do to the current content of the source, such that the user can
+/// see which changes they would lose.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestoreDiff {
+    /// Files in the source whose content differs from the backup.
+    pub overwritten: Vec<std::path::PathBuf>,
+    /// Files in the source which are not in the backup.
+    pub missing_from_backup: Vec<std::path::PathBuf>,
+    /// Whether the restore deletes the files which are missing from the backup.
+    pub deletes_missing: bool,
+}
+
+impl std::fmt::Display for RestoreDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Restoring would overwrite the changes to {} files",
+            self.overwritten.len()
+        )?;
+        if self.deletes_missing {
+            write!(
+                f,
+                " and delete {} files which are not in the backup.",
+                self.missing_from_backup.len()
+            )?;
+        } else {
+            write!(
+                f,
+                ", {} files which are not in the backup are kept.",
+                self.missing_from_backup.len()
+            )?;
+        }
+        for path in &self.overwritten {
+            write!(f, "\n  Overwritten: \"{}\"", path.display())?;
+        }
+        for path in &self.missing_from_backup {
+            write!(f, "\n  Not in the backup: \"{}\"", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything a command would change, in the order it would happen.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub actions: Vec<PlannedAction>,
+}
+
+fn read_dir(
+    root: &std::path::Path,
+    readdir_type: ReadDirType,
+    filter: &crate::filter::Filter,
+) -> Result<RecursiveReadDir, Error> {
+    RecursiveReadDir::try_new(root, readdir_type)
+        .map(|r| r.with_filter(filter.clone()))
+        .map_err(|e| Error::CannotReadDirectoryContent(root.to_owned(), e.to_string()))
+}
+
+/// Same checks as a run but without creating the destination.
+fn check_roots(
+    source_root: &std::path::Path,
+    destination_root: &std::path::Path,
+) -> Result<(), Error> {
+    if !source_root.exists() {
+        return Err(Error::SourceRootPathDoesNotExist(source_root.to_owned()));
+    }
+    if !source_root.is_dir() {
+        return Err(Error::SourceRootIsNotADirectory(source_root.to_owned()));
+    }
+    if destination_root.exists() && !destination_root.is_dir() {
+        return Err(Error::RootDestinatinIsNotADirectory(
+            destination_root.to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Creates the plan of a command whose paths have already been expanded.
+#[allow(clippy::too_many_lines)]
+pub async fn create(command: &Command, message_sender: &impl MessageSender) -> Result<Plan, Error> {
+    // NOTE: `None` means nothing is deleted, `Some(None)` deletes without a guard
+    let (source_root, destination_root, options, deletions) = match command {
+        Command::Backup {
+            source_root,
+            destination: Destination::Archive(archive),
+            options,
+        } => return plan_archive(source_root, archive, options),
+        Command::Backup {
+            source_root,
+            destination: Destination::Directory(destination_root),
+            options,
+        } => (source_root, destination_root, options, None),
+        Command::BackupPaths {
+            paths,
+            source_root,
+            destination_root,
+            options,
+        } => {
+            let backup = crate::backup_of_paths(paths, source_root, destination_root, options)?;
+            return Box::pin(create(&backup, message_sender)).await;
+        }
+        Command::Sync {
+            source_root,
+            destination_root,
+            options,
+        } => (
+            source_root,
+            destination_root,
+            options,
+            Some(Some(options.suspicious_deletions)),
+        ),
+        // NOTE: Same as sync but switch arguments
+        Command::Restore {
+            source_root,
+            destination_root,
+            delete_files,
+            target,
+            options,
+        } => {
+            crate::restore_target::check(source_root, destination_root, *target)?;
+            (
+                destination_root,
+                source_root,
+                options,
+                delete_files.then_some(None),
+            )
+        }
+        // NOTE: Verifying and scrubbing do not change anything and a repository does not mirror
+        // the source
+        Command::Verify { .. }
+        | Command::Scrub { .. }
+        | Command::Snapshot { .. }
+        | Command::RestoreSnapshot { .. }
+        | Command::ListSnapshots { .. }
+        | Command::CollectGarbage { .. }
+        | Command::CheckRepository { .. }
+        | Command::ManageKeys { .. }
+        | Command::Doctor { .. } => return Ok(Plan::default()),
+        Command::Prune {
+            destination_root,
+            policy,
+            options,
+        } => {
+            let actions = crate::prune::plan(destination_root, policy, options.secret.as_ref())?
+                .into_iter()
+                .map(|path| {
+                    // NOTE: The snapshots of a repository are files
+                    if path.is_dir() {
+                        PlannedAction::DeleteDirectory(path)
+                    } else {
+                        PlannedAction::DeleteFile(path)
+                    }
+                })
+                .collect();
+            return Ok(Plan { actions });
+        }
+    };
+    let filter = options.filter(&[source_root, destination_root])?;
+    crate::check_mounted(command.destination_root(), options)?;
+    if let Command::Sync { source_root, .. } = command {
+        crate::check_source_mounted(source_root, options)?;
+    }
+    if let Command::Backup { .. } = command
+        && source_root.is_file()
+    {
+        return plan_file(source_root, destination_root, options, message_sender).await;
+    }
+    check_roots(source_root, destination_root)?;
+    let destination_exists = destination_root.is_dir();
+    if destination_exists && matches!(command, Command::Sync { .. }) {
+        crate::check_mass_change(
+            source_root,
+            destination_root,
+            &filter,
+            options.mass_change_threshold,
+        )
+        .await?;
+    }
+
+    let max_file_size = max_file_size(destination_root, options);
+    let mut plan = Plan::default();
+    for source in read_dir(source_root, ReadDirType::DirectoriesOnly, &filter)?.flatten() {
+        let destination = crate::get_destination_file_path(destination_root, source_root, &source)
+            .map_err(|e| Error::ProcessPathErrors {
+                directories: vec![e],
+                files: vec![],
+            })?;
+        if !destination.exists() {
+            plan.actions.push(PlannedAction::CreateDirectory {
+                source,
+                destination,
+            });
+        }
+    }
+    for source in read_dir(source_root, ReadDirType::FilesOnly, &filter)?
+        .skipping_special_files(options.special_files == SpecialFilePolicy::Skip)
+        .flatten()
+    {
+        let destination = crate::get_destination_file_path(destination_root, source_root, &source)
+            .map_err(|e| Error::ProcessPathErrors {
+                directories: vec![],
+                files: vec![e],
+            })?;
+        plan.actions
+            .extend(plan_copy(source, destination, max_file_size, options, message_sender).await);
+    }
+
+    if let Some(policy) = deletions
+        && destination_exists
+    {
+        plan.actions.extend(
+            plan_deletions(
+                source_root,
+                destination_root,
+                &filter,
+                policy,
+                message_sender,
+            )
+            .await?,
+        );
+    }
+    Ok(plan)
+}
+
+/// Compares the backup of a restore with the current source, `None` for other commands.
+pub async fn diff_restore(
+    command: &Command,
+    message_sender: &impl MessageSender,
+) -> Result<Option<RestoreDiff>, Error> {
+    let Command::Restore {
+        source_root,
+        destination_root,
+        delete_files,
+        options,
+        ..
+    } = command
+    else {
+        return Ok(None);
+    };
+    let filter = options.filter(&[source_root, destination_root])?;
+    check_roots(destination_root, source_root)?;
+    let mut diff = RestoreDiff {
+        deletes_missing: *delete_files,
+        ..RestoreDiff::default()
+    };
+    if !source_root.is_dir() {
+        return Ok(Some(diff));
+    }
+    let files_only = || {
+        read_dir(destination_root, ReadDirType::FilesOnly, &filter)
+            .map(|r| r.skipping_special_files(options.special_files == SpecialFilePolicy::Skip))
+    };
+    let into_error = |e| Error::ProcessPathErrors {
+        directories: vec![],
+        files: vec![e],
+    };
+    for backup in files_only()?.flatten() {
+        let current = crate::get_destination_file_path(source_root, destination_root, &backup)
+            .map_err(into_error)?;
+        if tokio::fs::symlink_metadata(&current).await.is_ok()
+            && matches!(
+                crate::decide_copy(&backup, &current, options, message_sender).await,
+                crate::Decision::Copy(_)
+            )
+        {
+            diff.overwritten.push(current);
+        }
+    }
+    diff.missing_from_backup = crate::get_paths_in_destinatination_but_not_in_source(
+        files_only()?,
+        read_dir(source_root, ReadDirType::FilesOnly, &filter)?,
+    )
+    .await
+    .map_err(into_error)?;
+    Ok(Some(diff))
+}
+
+/// Plan of a backup into an archive, which contains every file of the source as it is written
+/// anew.
+fn plan_archive(
+    source_root: &std::path::Path,
+    archive: &std::path::Path,
+    options: &BackupOptions,
+) -> Result<Plan, Error> {
+    if archive.is_dir() {
+        return Err(Error::CannotWriteArchive(
+            archive.to_owned(),
+            "it is a directory".to_owned(),
+        ));
+    }
+    let filter = options.filter(&[source_root, archive])?;
+    let actions = RecursiveReadDir::try_new(source_root, ReadDirType::FilesOnly)
+        .map_err(|e| Error::CannotReadDirectoryContent(source_root.to_owned(), e.to_string()))?
+        .with_filter(filter)
+        .skipping_special_files(true)
+        .filter_map(Result::ok)
+        .map(|source| PlannedAction::CopyFile {
+            destination: archive.join(source.strip_prefix(source_root).unwrap_or(&source)),
+            source,
+        })
+        .collect();
+    Ok(Plan { actions })
+}
+
+/// Plan of a backup whose source is a single file.
+async fn plan_file(
+    source: &std::path::Path,
+    destination_root: &std::path::Path,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) -> Result<Plan, Error> {
+    if destination_root.exists() && !destination_root.is_dir() {
+        return Err(Error::RootDestinatinIsNotADirectory(
+            destination_root.to_owned(),
+        ));
+    }
+    let mut plan = Plan::default();
+    let Some(name) = source.file_name() else {
+        return Ok(plan);
+    };
+    let destination = destination_root.join(name);
+    plan.actions.extend(
+        plan_copy(
+            source.to_owned(),
+            destination,
+            max_file_size(destination_root, options),
+            options,
+            message_sender,
+        )
+        .await,
+    );
+    Ok(plan)
+}
+
+/// The largest file the destination can store, looked up on its nearest existing directory
+/// as it may not have been created yet.
+fn max_file_size(destination_root: &std::path::Path, options: &BackupOptions) -> Option<u64> {
+    options.destination_max_file_size.or_else(|| {
+        destination_root
+            .ancestors()
+            .find(|ancestor| ancestor.is_dir())
+            .and_then(crate::size_limit::max_file_size)
+    })
+}
+
+/// Whether `source` would be copied, files which the destination cannot store are reported
+/// without comparing them.
+async fn plan_copy(
+    source: std::path::PathBuf,
+    destination: std::path::PathBuf,
+    max_file_size: Option<u64>,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) -> Option<PlannedAction> {
+    let metadata = std::fs::metadata(&source).ok();
+    let size = metadata.as_ref().map_or(0, std::fs::Metadata::len);
+    if let Some(max_size) = max_file_size
+        && size > max_size
+    {
+        if options.oversized_files != crate::OversizedFilePolicy::Split {
+            return Some(PlannedAction::OversizedFile {
+                source,
+                size,
+                max_size,
+            });
+        }
+        let unchanged = metadata
+            .zip(crate::split::Descriptor::read(&destination).ok().flatten())
+            .is_some_and(|(metadata, descriptor)| descriptor.matches(&metadata));
+        return (!unchanged).then_some(PlannedAction::CopyFile {
+            source,
+            destination,
+        });
+    }
+    matches!(
+        crate::decide_copy(&source, &destination, options, message_sender).await,
+        crate::Decision::Copy(_)
+    )
+    .then_some(PlannedAction::CopyFile {
+        source,
+        destination,
+    })
+}
+
+async fn plan_deletions(
+    source_root: &std::path::Path,
+    destination_root: &std::path::Path,
+    filter: &crate::filter::Filter,
+    policy: Option<SuspiciousDeletionPolicy>,
+    message_sender: &impl MessageSender,
+) -> Result<Vec<PlannedAction>, Error> {
+    let guard = match policy {
+        Some(policy) if policy != SuspiciousDeletionPolicy::Delete => Some(
+            DeletionGuard::try_new(
+                source_root,
+                destination_root,
+                filter,
+                policy,
+                message_sender,
+            )
+            .await?,
+        ),
+        _ => None,
+    };
+    let mut actions = vec![];
+    let mut deleted_dirs: Vec<std::path::PathBuf> = vec![];
+    for directories in [true, false] {
+        let readdir_type = || {
+            if directories {
+                ReadDirType::DirectoriesOnly
+            } else {
+                ReadDirType::FilesOnly
+            }
+        };
+        let to_delete = crate::get_paths_in_destinatination_but_not_in_source(
+            read_dir(source_root, readdir_type(), filter)?,
+            read_dir(destination_root, readdir_type(), filter)?,
+        )
+        .await
+        .map_err(|e| {
+            if directories {
+                Error::ProcessPathErrors {
+                    directories: vec![e],
+                    files: vec![],
+                }
+            } else {
+                Error::ProcessPathErrors {
+                    directories: vec![],
+                    files: vec![e],
+                }
+            }
+        })?;
+        for path in to_delete {
+            if deleted_dirs.iter().any(|d| path.starts_with(d)) {
+                continue;
+            }
+            let action = match &guard {
+                Some(guard) if guard.is_suspicious(&path) => match guard.policy {
+                    SuspiciousDeletionPolicy::Quarantine => PlannedAction::Quarantine(path.clone()),
+                    SuspiciousDeletionPolicy::Keep | SuspiciousDeletionPolicy::Delete => continue,
+                },
+                _ if directories => PlannedAction::DeleteDirectory(path.clone()),
+                _ => PlannedAction::DeleteFile(path.clone()),
+            };
+            if directories {
+                deleted_dirs.push(path);
+            }
+            actions.push(action);
+        }
+    }
+    Ok(actions)
+}
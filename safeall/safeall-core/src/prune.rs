@@ -0,0 +1,269 @@
+//! Removing old snapshots of a destination by a retention policy. Each sync moves the files
+//! it would delete into a snapshot of the quarantine named after the start of the run. In a
+//! repository the snapshots are the ones of [`crate::repo`], whose chunks are only removed by
+//! a garbage collection afterwards.
+
+use chrono::Datelike;
+
+use crate::{Error, Message, MessageSender, Warning};
+
+/// Which snapshots are kept. A snapshot is kept as soon as one rule keeps it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Number of the most recent snapshots which are kept.
+    pub keep_last: usize,
+    /// Number of days for which the most recent snapshot of the day is kept.
+    pub keep_daily: usize,
+    /// Number of weeks for which the most recent snapshot of the week is kept.
+    pub keep_weekly: usize,
+    /// Number of months for which the most recent snapshot of the month is kept.
+    pub keep_monthly: usize,
+}
+
+impl RetentionPolicy {
+    fn keeps_nothing(&self) -> bool {
+        self.keep_last == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Snapshot {
+    path: std::path::PathBuf,
+    created: std::time::SystemTime,
+}
+
+/// Snapshots of the repository or of the quarantine of `destination_root`, the most recent
+/// first. The secret is the one of an encrypted repository.
+fn snapshots(
+    destination_root: &std::path::Path,
+    secret: Option<&crate::Secret>,
+) -> Result<Vec<Snapshot>, Error> {
+    if crate::repo::is_repository(destination_root) {
+        let mut snapshots: Vec<_> = crate::repo::snapshot_files(destination_root, secret)?
+            .into_iter()
+            .map(|(path, created)| Snapshot { path, created })
+            .collect();
+        snapshots.reverse();
+        return Ok(snapshots);
+    }
+    quarantine_snapshots(destination_root)
+        .map_err(|e| Error::CannotReadDirectoryContent(destination_root.to_owned(), e.to_string()))
+}
+
+fn quarantine_snapshots(destination_root: &std::path::Path) -> std::io::Result<Vec<Snapshot>> {
+    let quarantine = destination_root
+        .join(crate::METADATA_DIRECTORY)
+        .join(crate::QUARANTINE_DIRECTORY);
+    let entries = match std::fs::read_dir(&quarantine) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut snapshots = vec![];
+    for entry in entries {
+        let entry = entry?;
+        // NOTE: Anything else in the quarantine has not been put there by safeall
+        let Some(seconds) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        if entry.file_type()?.is_dir() {
+            snapshots.push(Snapshot {
+                path: entry.path(),
+                created: std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds),
+            });
+        }
+    }
+    snapshots.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.created));
+    Ok(snapshots)
+}
+
+/// Whether each of the snapshots, the most recent first, is kept by the policy.
+fn select(policy: &RetentionPolicy, snapshots: &[Snapshot]) -> Vec<bool> {
+    let local = |snapshot: &Snapshot| chrono::DateTime::<chrono::Local>::from(snapshot.created);
+    let mut keep: Vec<_> = (0..snapshots.len()).map(|i| i < policy.keep_last).collect();
+    let mut keep_per_period = |count: usize, period: &dyn Fn(&Snapshot) -> (i32, u32)| {
+        let mut last_period = None;
+        let mut kept = 0;
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            let period = period(snapshot);
+            if kept < count && last_period != Some(period) {
+                keep[i] = true;
+                kept += 1;
+            }
+            last_period = Some(period);
+        }
+    };
+    keep_per_period(policy.keep_daily, &|snapshot| {
+        let date = local(snapshot).date_naive();
+        (date.year(), date.ordinal())
+    });
+    keep_per_period(policy.keep_weekly, &|snapshot| {
+        let week = local(snapshot).iso_week();
+        (week.year(), week.week())
+    });
+    keep_per_period(policy.keep_monthly, &|snapshot| {
+        let date = local(snapshot);
+        (date.year(), date.month())
+    });
+    keep
+}
+
+fn snapshots_to_remove(
+    destination_root: &std::path::Path,
+    policy: &RetentionPolicy,
+    secret: Option<&crate::Secret>,
+) -> Result<(usize, Vec<std::path::PathBuf>), Error> {
+    if policy.keeps_nothing() {
+        return Err(Error::EmptyRetentionPolicy);
+    }
+    let snapshots = snapshots(destination_root, secret)?;
+    let keep = select(policy, &snapshots);
+    let removed: Vec<_> = snapshots
+        .into_iter()
+        .zip(&keep)
+        .filter(|(_, keep)| !**keep)
+        .map(|(snapshot, _)| snapshot.path)
+        .collect();
+    Ok((keep.len() - removed.len(), removed))
+}
+
+/// Snapshots which [`prune`] would remove.
+pub fn plan(
+    destination_root: &std::path::Path,
+    policy: &RetentionPolicy,
+    secret: Option<&crate::Secret>,
+) -> Result<Vec<std::path::PathBuf>, Error> {
+    snapshots_to_remove(destination_root, policy, secret).map(|(_, removed)| removed)
+}
+
+/// Snapshots which have been removed by a prune.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    pub kept: usize,
+    pub removed: Vec<std::path::PathBuf>,
+    /// Size of the files in the removed snapshots.
+    pub reclaimed_bytes: u64,
+}
+
+impl std::fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Removed {} snapshots and kept {}, which reclaimed ~{}.",
+            self.removed.len(),
+            self.kept,
+            crate::format_bytes(self.reclaimed_bytes)
+        )?;
+        for path in &self.removed {
+            write!(f, "\n  Removed: \"{}\"", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+fn size(snapshot: &std::path::Path) -> u64 {
+    if snapshot.is_file() {
+        return std::fs::metadata(snapshot).map_or(0, |metadata| metadata.len());
+    }
+    crate::RecursiveReadDir::try_new(snapshot, crate::ReadDirType::FilesOnly).map_or(0, |files| {
+        files
+            .flatten()
+            .map(|file| std::fs::symlink_metadata(file).map_or(0, |metadata| metadata.len()))
+            .sum()
+    })
+}
+
+/// Removes the snapshots of the destination which the policy does not keep.
+pub async fn prune(
+    destination_root: &std::path::Path,
+    policy: &RetentionPolicy,
+    secret: Option<&crate::Secret>,
+    message_sender: &impl MessageSender,
+) -> Result<PruneReport, Error> {
+    let (kept, to_remove) = snapshots_to_remove(destination_root, policy, secret)?;
+    let mut report = PruneReport {
+        kept,
+        ..PruneReport::default()
+    };
+    for snapshot in to_remove {
+        let owned_snapshot = snapshot.clone();
+        let removed = tokio::task::spawn_blocking(move || {
+            let bytes = size(&owned_snapshot);
+            if owned_snapshot.is_file() {
+                std::fs::remove_file(&owned_snapshot).map(|()| bytes)
+            } else {
+                std::fs::remove_dir_all(&owned_snapshot).map(|()| bytes)
+            }
+        })
+        .await
+        .map_err(std::io::Error::other)
+        .flatten();
+        match removed {
+            Ok(bytes) => {
+                report.reclaimed_bytes += bytes;
+                report.removed.push(snapshot);
+            }
+            Err(e) => {
+                message_sender.send(Message::Warning(Warning::CannotPrune {
+                    snapshot,
+                    io_error: e.to_string(),
+                }));
+                report.kept += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retention_policy() {
+        let day = 24 * 60 * 60;
+        // NOTE: Noon in UTC, such that the snapshots of a day stay on one day in most time zones
+        let now = 1_750_000_000 / day * day + day / 2;
+        let snapshots: Vec<_> = [0, 3600, day, day + 3600, 2 * day, 40 * day, 80 * day]
+            .into_iter()
+            .map(|age| Snapshot {
+                path: age.to_string().into(),
+                created: std::time::UNIX_EPOCH + std::time::Duration::from_secs(now - age),
+            })
+            .collect();
+        let kept = |policy| {
+            snapshots
+                .iter()
+                .zip(select(&policy, &snapshots))
+                .filter(|(_, keep)| *keep)
+                .map(|(snapshot, _)| snapshot.path.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let last = RetentionPolicy {
+            keep_last: 2,
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(last), ["0", "3600"]);
+        let daily = RetentionPolicy {
+            keep_daily: 2,
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(daily), ["0", "86400"]);
+        let monthly = RetentionPolicy {
+            keep_last: 1,
+            keep_monthly: 12,
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(monthly).len(), 3);
+        assert!(kept(monthly).contains(&"6912000".to_owned()));
+        assert!(RetentionPolicy::default().keeps_nothing());
+    }
+}
@@ -0,0 +1,80 @@
+//! Assertion that a backup or sync never writes to its source, e.g. because two paths
+//! have been swapped somewhere in the engine.
+//!
+//! Every place which writes to the destination checks the path with [`check`] first.
+
+use crate::{InvariantError, ProcessPathError, ProcessPathErrorKind};
+
+tokio::task_local! {
+    static SOURCE: ReadOnlySource;
+}
+
+/// The source of a running backup or sync, apart from the destination if it is inside it.
+#[derive(Debug, Clone)]
+pub struct ReadOnlySource {
+    source_root: std::path::PathBuf,
+    destination_root: std::path::PathBuf,
+}
+
+impl ReadOnlySource {
+    pub fn new(source_root: &std::path::Path, destination_root: &std::path::Path) -> Self {
+        Self {
+            source_root: source_root.to_owned(),
+            destination_root: destination_root.to_owned(),
+        }
+    }
+
+    /// The engine builds all paths by joining them to the roots, so comparing the components
+    /// is enough without resolving symbolic links.
+    fn protects(&self, path: &std::path::Path) -> bool {
+        path.starts_with(&self.source_root) && !path.starts_with(&self.destination_root)
+    }
+}
+
+/// Runs `future` such that [`check`] rejects all writes to `source`. Without a source,
+/// e.g. for a restore which writes to the source on purpose, nothing is rejected.
+pub async fn scope<F: Future>(source: Option<ReadOnlySource>, future: F) -> F::Output {
+    match source {
+        Some(source) => SOURCE.scope(source, future).await,
+        None => future.await,
+    }
+}
+
+/// Fails with an invariant error if writing to `path` would modify the source of the
+/// running backup or sync.
+pub fn check(path: &std::path::Path) -> Result<(), ProcessPathError> {
+    let Ok(Some(source_root)) =
+        SOURCE.try_with(|source| source.protects(path).then(|| source.source_root.clone()))
+    else {
+        return Ok(());
+    };
+    Err(ProcessPathError {
+        not_processed: Some(path.to_owned()),
+        kind: ProcessPathErrorKind::InvariantBroken(InvariantError::WriteToSource {
+            source_root,
+            path: path.to_owned(),
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_check_rejects_writes_to_source() {
+        let source = ReadOnlySource::new("/source".as_ref(), "/source/backup".as_ref());
+        scope(Some(source), async {
+            assert!(check("/source/file".as_ref()).is_err());
+            assert!(check("/source".as_ref()).is_err());
+            assert!(check("/source/backup/file".as_ref()).is_ok());
+            assert!(check("/destination/file".as_ref()).is_ok());
+        })
+        .await;
+        assert!(check("/source/file".as_ref()).is_ok());
+        scope(None, async {
+            assert!(check("/source/file".as_ref()).is_ok());
+        })
+        .await;
+    }
+}
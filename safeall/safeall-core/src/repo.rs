@@ -0,0 +1,800 @@
+//! Repository of deduplicated snapshots, an alternative to mirroring the source into the
+//! destination. Files are split into chunks which are stored once by their hash, such that
+//! files which did not change since the last snapshot take no additional space.
+
+mod chunks;
+mod crypto;
+mod packs;
+mod snapshot;
+
+pub use crypto::{KeyKind, RegisteredKey, Secret};
+pub use snapshot::SnapshotSummary;
+
+use crate::{
+    CopyReason, Error, Increment, Info, Message, MessageSender, ProcessPathError,
+    ProcessPathErrorKind, Progress, ProgressType, ReadDirType, RecursiveReadDir, SkipReason,
+    Warning,
+};
+use chunks::ChunkStore;
+use snapshot::{Entry, Snapshot};
+
+const SNAPSHOTS_DIRECTORY: &str = "snapshots";
+const VERSION_FILE: &str = "version";
+/// Version of the layout of new repositories. It is increased whenever the layout changes
+/// such that older versions of safeall cannot read it anymore.
+///
+/// 2. Chunks start with a byte which says whether they are compressed.
+/// 3. Repositories can be encrypted with passphrases and key files, see [`crypto`].
+pub const REPOSITORY_VERSION: u32 = 3;
+
+/// Whether `root` contains a repository rather than a mirror of a source.
+#[must_use]
+pub fn is_repository(root: &std::path::Path) -> bool {
+    root.join(SNAPSHOTS_DIRECTORY).is_dir()
+}
+
+/// The version of an existing repository. Fails if it has been written by a newer version of
+/// safeall.
+fn version(repository_root: &std::path::Path) -> Result<u32, Error> {
+    let path = repository_root.join(VERSION_FILE);
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        // NOTE: The first repositories did not have a version yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(1),
+        Err(e) => return Err(Error::CannotReadDirectoryContent(path, e.to_string())),
+    };
+    match text.trim().parse::<u32>() {
+        Ok(version) if version <= REPOSITORY_VERSION => Ok(version),
+        _ => Err(Error::UnsupportedRepositoryVersion {
+            repository_root: repository_root.to_owned(),
+            version: text.trim().to_owned(),
+        }),
+    }
+}
+
+/// The bytes of a passphrase or key file. A new key file is generated if `generate` is set.
+fn read_secret(secret: &Secret, generate: bool) -> Result<Vec<u8>, Error> {
+    match secret {
+        Secret::Passphrase(passphrase) => Ok(passphrase.as_bytes().to_vec()),
+        Secret::KeyFile(path) => crypto::read_key_file(path, generate)
+            .map_err(|e| Error::CannotReadKeyFile(path.clone(), e.to_string())),
+    }
+}
+
+/// The key of an encrypted repository and the ID of the entry of the key file which the
+/// secret unlocked.
+fn unlock(
+    repository_root: &std::path::Path,
+    secret: &Secret,
+) -> Result<(crypto::Key, String), Error> {
+    crypto::unlock(repository_root, &read_secret(secret, false)?)
+        .map_err(|e| Error::CannotReadDirectoryContent(repository_root.to_owned(), e.to_string()))?
+        .ok_or_else(|| Error::WrongSecret(repository_root.to_owned()))
+}
+
+/// The key of an encrypted repository, `None` if it is not encrypted.
+fn open_key(
+    repository_root: &std::path::Path,
+    secret: Option<&Secret>,
+) -> Result<Option<crypto::Key>, Error> {
+    let encrypted = crypto::is_encrypted(repository_root);
+    match secret {
+        None if encrypted => Err(Error::SecretRequired(repository_root.to_owned())),
+        None => Ok(None),
+        // NOTE: Only new repositories can be encrypted, the user must not think the snapshots
+        // of an existing one are encrypted
+        Some(_) if !encrypted => Err(Error::RepositoryNotEncrypted(repository_root.to_owned())),
+        Some(secret) => unlock(repository_root, secret).map(|(key, _)| Some(key)),
+    }
+}
+
+fn snapshot_path(repository_root: &std::path::Path, id: &str) -> std::path::PathBuf {
+    repository_root.join(SNAPSHOTS_DIRECTORY).join(id)
+}
+
+fn to_system_time(modified: u128) -> std::time::SystemTime {
+    let nanos_per_second = 1_000_000_000;
+    std::time::UNIX_EPOCH
+        + std::time::Duration::new(
+            u64::try_from(modified / nanos_per_second).unwrap_or(u64::MAX),
+            u32::try_from(modified % nanos_per_second).unwrap_or(0),
+        )
+}
+
+fn snapshots(
+    repository_root: &std::path::Path,
+    key: Option<&crypto::Key>,
+) -> Result<Vec<Snapshot>, Error> {
+    if !is_repository(repository_root) {
+        return Err(Error::NotARepository(repository_root.to_owned()));
+    }
+    version(repository_root)?;
+    let directory = repository_root.join(SNAPSHOTS_DIRECTORY);
+    let read_error =
+        |e: std::io::Error| Error::CannotReadDirectoryContent(directory.clone(), e.to_string());
+    let mut snapshots = vec![];
+    for entry in std::fs::read_dir(&directory).map_err(read_error)? {
+        let entry = entry.map_err(read_error)?;
+        // NOTE: Temporary files of snapshots which are being written have an extension
+        let Some(id) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+        if id.contains('.') {
+            continue;
+        }
+        let data = std::fs::read(entry.path()).map_err(read_error)?;
+        let text = match key {
+            Some(key) => key.decrypt(&data).ok().map(String::from_utf8),
+            None => Some(String::from_utf8(data)),
+        };
+        let snapshot = text
+            .and_then(Result::ok)
+            .and_then(|text| Snapshot::parse(&id, &text))
+            .ok_or_else(|| Error::SnapshotDamaged(entry.path()))?;
+        snapshots.push(snapshot);
+    }
+    snapshots.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.id.cmp(&b.id)));
+    Ok(snapshots)
+}
+
+/// The snapshots of a repository, the oldest first.
+pub fn list(
+    repository_root: &std::path::Path,
+    secret: Option<&Secret>,
+) -> Result<Vec<SnapshotSummary>, Error> {
+    let key = open_key(repository_root, secret)?;
+    Ok(snapshots(repository_root, key.as_ref())?
+        .iter()
+        .map(Snapshot::summary)
+        .collect())
+}
+
+/// The files of the snapshots of a repository with the time they were created, the oldest
+/// first.
+pub fn snapshot_files(
+    repository_root: &std::path::Path,
+    secret: Option<&Secret>,
+) -> Result<Vec<(std::path::PathBuf, std::time::SystemTime)>, Error> {
+    let key = open_key(repository_root, secret)?;
+    Ok(snapshots(repository_root, key.as_ref())?
+        .into_iter()
+        .map(|snapshot| {
+            (
+                snapshot_path(repository_root, &snapshot.id),
+                snapshot.created,
+            )
+        })
+        .collect())
+}
+
+/// What a garbage collection of a repository has removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GarbageCollected {
+    /// Chunks which were stored in files of their own.
+    pub removed_chunks: usize,
+    pub removed_packs: usize,
+    /// Packs which were partly used and whose used chunks have been moved into new packs.
+    pub repacked_packs: usize,
+    pub freed_bytes: u64,
+}
+
+impl std::fmt::Display for GarbageCollected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Removed {} unused chunks and {} unused packs and repacked {} packs, which freed ~{}.",
+            self.removed_chunks,
+            self.removed_packs,
+            self.repacked_packs,
+            crate::format_bytes(self.freed_bytes)
+        )
+    }
+}
+
+/// Removes the chunks which no snapshot refers to anymore, e.g. after a prune. Packs with
+/// unused chunks are removed or written again with only the used ones.
+pub async fn collect_garbage(
+    repository_root: &std::path::Path,
+    secret: Option<&Secret>,
+) -> Result<GarbageCollected, Error> {
+    let key = open_key(repository_root, secret)?;
+    let referenced: std::collections::HashSet<_> = snapshots(repository_root, key.as_ref())?
+        .into_iter()
+        .flat_map(|snapshot| snapshot.entries)
+        .flat_map(|entry| match entry {
+            Entry::File { chunks, .. } => chunks,
+            Entry::Directory { .. } => vec![],
+        })
+        .collect();
+    let version = version(repository_root)?;
+    let owned_root = repository_root.to_owned();
+    tokio::task::spawn_blocking(move || {
+        ChunkStore::open(&owned_root, version, key)?.collect_garbage(&referenced)
+    })
+    .await
+    .map_err(std::io::Error::other)
+    .flatten()
+    .map_err(|e| Error::CannotCollectGarbage(repository_root.to_owned(), e.to_string()))
+}
+
+/// Problems with the structure of a repository, found without reading the chunks.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepositoryCheck {
+    pub snapshots: usize,
+    /// Number of different chunks the snapshots refer to.
+    pub chunks: usize,
+    /// Snapshots which refer to chunks the repository does not have, with the number of
+    /// missing chunks. Their files cannot be restored completely.
+    pub incomplete_snapshots: Vec<(String, usize)>,
+    /// Indexes which cannot be read and packs which are missing or shorter than their index.
+    pub damaged_packs: Vec<std::path::PathBuf>,
+    /// Packs without an index, e.g. after an interrupted snapshot. Nothing refers to them
+    /// and a garbage collection removes them.
+    pub unindexed_packs: Vec<std::path::PathBuf>,
+}
+
+impl RepositoryCheck {
+    /// Whether every snapshot can be restored.
+    #[must_use]
+    pub fn is_intact(&self) -> bool {
+        self.incomplete_snapshots.is_empty() && self.damaged_packs.is_empty()
+    }
+}
+
+impl std::fmt::Display for RepositoryCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Checked {} snapshots with {} chunks: {} snapshots are incomplete, {} packs are damaged and {} packs have no index.",
+            self.snapshots,
+            self.chunks,
+            self.incomplete_snapshots.len(),
+            self.damaged_packs.len(),
+            self.unindexed_packs.len()
+        )?;
+        for (id, missing) in &self.incomplete_snapshots {
+            write!(f, "\n  Incomplete: {id} misses {missing} chunks")?;
+        }
+        for path in &self.damaged_packs {
+            write!(f, "\n  Damaged: \"{}\"", path.display())?;
+        }
+        for path in &self.unindexed_packs {
+            write!(f, "\n  Without index: \"{}\"", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the packs match their indexes and that the snapshots only refer to chunks the
+/// repository has.
+pub async fn check(
+    repository_root: &std::path::Path,
+    secret: Option<&Secret>,
+) -> Result<RepositoryCheck, Error> {
+    let key = open_key(repository_root, secret)?;
+    let snapshots = snapshots(repository_root, key.as_ref())?;
+    let owned_root = repository_root.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let mut report = RepositoryCheck {
+            snapshots: snapshots.len(),
+            ..RepositoryCheck::default()
+        };
+        let packed = packs::check(&owned_root, &mut report)?;
+        let mut stored = std::collections::HashMap::new();
+        for snapshot in snapshots {
+            let mut missing = std::collections::HashSet::new();
+            for entry in snapshot.entries {
+                let Entry::File { chunks: ids, .. } = entry else {
+                    continue;
+                };
+                for id in ids {
+                    let is_stored = *stored.entry(id).or_insert_with(|| {
+                        packed.contains(&id) || chunks::has_file(&owned_root, &id)
+                    });
+                    if !is_stored {
+                        missing.insert(id);
+                    }
+                }
+            }
+            if !missing.is_empty() {
+                report
+                    .incomplete_snapshots
+                    .push((snapshot.id, missing.len()));
+            }
+        }
+        report.chunks = stored.len();
+        Ok(report)
+    })
+    .await
+    .map_err(std::io::Error::other)
+    .flatten()
+    .map_err(|e| Error::CannotReadDirectoryContent(repository_root.to_owned(), e.to_string()))
+}
+
+/// What [`manage_keys`] does with the keys of an encrypted repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyAction {
+    List,
+    /// Registers another passphrase or key file. A key file which does not exist is created
+    /// with random content.
+    Add(Secret),
+    /// Removes the key with this ID, which must not be the last one.
+    Remove(String),
+    /// Replaces the key which unlocked the repository with another passphrase or key file.
+    Rotate(Secret),
+}
+
+/// Lists, adds or removes the passphrases and key files of an encrypted repository. The
+/// repository is unlocked with `secret` unless the keys are only listed.
+///
+/// The master key stays the same, so nothing is encrypted again. Whoever had a removed
+/// passphrase and a copy of the key file from back then can still decrypt the repository.
+pub fn manage_keys(
+    repository_root: &std::path::Path,
+    secret: Option<&Secret>,
+    action: &KeyAction,
+    message_sender: &impl MessageSender,
+) -> Result<(), Error> {
+    if !is_repository(repository_root) {
+        return Err(Error::NotARepository(repository_root.to_owned()));
+    }
+    version(repository_root)?;
+    if !crypto::is_encrypted(repository_root) {
+        return Err(Error::RepositoryNotEncrypted(repository_root.to_owned()));
+    }
+    let read_error = |e: std::io::Error| {
+        Error::CannotReadDirectoryContent(repository_root.to_owned(), e.to_string())
+    };
+    let write_error =
+        |e: std::io::Error| Error::CannotWriteKeys(repository_root.to_owned(), e.to_string());
+    let unlock = || {
+        let secret = secret.ok_or_else(|| Error::SecretRequired(repository_root.to_owned()))?;
+        unlock(repository_root, secret)
+    };
+    let add = |key: &crypto::Key, new: &Secret| -> Result<(), Error> {
+        let added = crypto::add(repository_root, key, &read_secret(new, true)?, new.kind())
+            .map_err(write_error)?;
+        message_sender.send(Message::Info(Info::KeyAdded(added)));
+        Ok(())
+    };
+    let remove = |id: &str| -> Result<(), Error> {
+        crypto::remove(repository_root, id).map_err(write_error)?;
+        message_sender.send(Message::Info(Info::KeyRemoved(id.to_owned())));
+        Ok(())
+    };
+    match action {
+        KeyAction::List => {
+            let keys = crypto::list(repository_root).map_err(read_error)?;
+            message_sender.send(Message::Info(Info::Keys(keys)));
+            Ok(())
+        }
+        KeyAction::Add(new) => add(&unlock()?.0, new),
+        KeyAction::Remove(id) => {
+            unlock()?;
+            let keys = crypto::list(repository_root).map_err(read_error)?;
+            if !keys.iter().any(|key| key.id == *id) {
+                return Err(Error::KeyNotFound {
+                    repository_root: repository_root.to_owned(),
+                    id: id.clone(),
+                });
+            }
+            if keys.len() == 1 {
+                return Err(Error::CannotRemoveLastKey(repository_root.to_owned()));
+            }
+            remove(id)
+        }
+        KeyAction::Rotate(new) => {
+            let (key, id) = unlock()?;
+            // NOTE: The new key is added first, such that an interruption cannot lock the
+            // user out
+            add(&key, new)?;
+            remove(&id)
+        }
+    }
+}
+
+/// A file whose chunks have been put into the store.
+struct StoredFile {
+    size: u64,
+    modified: u128,
+    chunks: Vec<chunks::ChunkId>,
+    /// Size of the chunks which were not in the store yet.
+    added: u64,
+    /// Size of these chunks in the store, after compressing them.
+    stored: u64,
+}
+
+fn store_file(store: &ChunkStore, path: &std::path::Path) -> std::io::Result<StoredFile> {
+    let file = std::fs::File::open(path)?;
+    let mut stored_file = StoredFile {
+        size: 0,
+        modified: crate::manifest::modified(&file.metadata()?),
+        chunks: vec![],
+        added: 0,
+        stored: 0,
+    };
+    chunks::split(std::io::BufReader::new(file), |chunk| {
+        let (id, stored) = store.put(chunk)?;
+        let length = chunk.len() as u64;
+        stored_file.size += length;
+        if stored > 0 {
+            stored_file.added += length;
+            stored_file.stored += stored;
+        }
+        stored_file.chunks.push(id);
+        Ok(())
+    })?;
+    Ok(stored_file)
+}
+
+/// Stores a snapshot of the source in the repository, which is created if it does not exist.
+/// New chunks are compressed with zstd of `compression_level` if it is given. A new
+/// repository is encrypted with the secret if it is given.
+#[allow(clippy::too_many_lines)]
+pub async fn backup(
+    source_root: &std::path::Path,
+    repository_root: &std::path::Path,
+    filter: &crate::filter::Filter,
+    compression_level: Option<i32>,
+    secret: Option<&Secret>,
+    message_sender: &impl MessageSender,
+) -> Result<(), Error> {
+    use futures::stream::StreamExt;
+
+    // NOTE: An existing repository keeps its version, only new ones get the current one
+    let (version, key) = if is_repository(repository_root) {
+        (
+            version(repository_root)?,
+            open_key(repository_root, secret)?,
+        )
+    } else {
+        let key = secret
+            .map(|secret| {
+                crypto::create(repository_root, &read_secret(secret, true)?, secret.kind()).map_err(
+                    |e| {
+                        Error::CannotCreateRootDestinationDir(
+                            repository_root.to_owned(),
+                            e.to_string(),
+                        )
+                    },
+                )
+            })
+            .transpose()?;
+        (REPOSITORY_VERSION, key)
+    };
+    let store = ChunkStore::open(repository_root, version, key.clone())
+        .map(|store| store.with_compression(compression_level))
+        .and_then(|store| store.create().map(|()| store))
+        .and_then(|store| {
+            std::fs::create_dir_all(repository_root.join(SNAPSHOTS_DIRECTORY)).map(|()| store)
+        })
+        .and_then(|store| {
+            let version_file = repository_root.join(VERSION_FILE);
+            if !version_file.exists() {
+                std::fs::write(version_file, format!("{version}\n"))?;
+            }
+            Ok(store)
+        })
+        .map_err(|e| {
+            Error::CannotCreateRootDestinationDir(repository_root.to_owned(), e.to_string())
+        })?;
+    let id = uuid::Uuid::new_v4().simple().to_string()[..8].to_owned();
+    let path = snapshot_path(repository_root, &id);
+    let read_dir = |readdir_type| {
+        RecursiveReadDir::try_new(source_root, readdir_type)
+            .map(|r| r.with_filter(filter.clone()))
+            .map_err(|e| Error::CannotReadDirectoryContent(source_root.to_owned(), e.to_string()))
+    };
+    let relative =
+        |path: &std::path::Path| path.strip_prefix(source_root).unwrap_or(path).to_path_buf();
+
+    let mut entries = vec![];
+    let mut directory_errors = vec![];
+    let mut scan_progress = crate::scan::ScanProgress::start(message_sender);
+    let mut directories = read_dir(ReadDirType::DirectoriesOnly)?;
+    for directory in directories.by_ref() {
+        scan_progress.entry(&directory);
+        match directory {
+            Ok(directory) => entries.push(Entry::Directory {
+                modified: std::fs::metadata(&directory)
+                    .map_or(0, |metadata| crate::manifest::modified(&metadata)),
+                path: relative(&directory),
+            }),
+            Err(e) => directory_errors.push(e),
+        }
+    }
+    for path in directories.cycles() {
+        message_sender.send(Message::Warning(Warning::CycleDetected(path.clone())));
+    }
+    let mut files = vec![];
+    for file in read_dir(ReadDirType::FilesOnly)? {
+        scan_progress.entry(&file);
+        match file {
+            Ok(file) => files.push(file),
+            Err(e) => directory_errors.push(e),
+        }
+    }
+    scan_progress.end();
+
+    message_sender.send(Message::Progress(Progress::Start(
+        files.len(),
+        ProgressType::CopingFiles,
+    )));
+    let results: Vec<_> = futures::stream::iter(files)
+        .map(async |file| {
+            let (owned_store, owned_file) = (store.clone(), file.clone());
+            let stored = tokio::task::spawn_blocking(move || store_file(&owned_store, &owned_file))
+                .await
+                .map_err(std::io::Error::other)
+                .flatten();
+            if let Ok(stored) = &stored {
+                // NOTE: A file whose chunks are all in the store already takes no space
+                let increment = if stored.added == 0 {
+                    Increment::SkippingFileNoModification {
+                        source: file.clone(),
+                        destination: path.clone(),
+                        bytes: stored.size,
+                        reason: SkipReason::AlreadyStored,
+                    }
+                } else {
+                    Increment::FileCopied {
+                        source: file.clone(),
+                        destination: path.clone(),
+                        bytes: stored.size,
+                        reason: CopyReason::ContentChanged,
+                    }
+                };
+                message_sender.send(Message::Progress(Progress::IncrementSuccess(increment)));
+            }
+            (file, stored)
+        })
+        .buffered(crate::cpu_count())
+        .collect()
+        .await;
+    let mut file_errors = vec![];
+    let (mut added_bytes, mut stored_bytes) = (0, 0);
+    for (file, stored) in results {
+        match stored {
+            Ok(stored) => {
+                added_bytes += stored.added;
+                stored_bytes += stored.stored;
+                entries.push(Entry::File {
+                    path: relative(&file),
+                    size: stored.size,
+                    modified: stored.modified,
+                    chunks: stored.chunks,
+                });
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !file.exists() => {
+                message_sender.send(Message::Warning(Warning::SourceVanished(file)));
+            }
+            Err(e) => {
+                let error = ProcessPathError {
+                    not_processed: Some(file),
+                    kind: ProcessPathErrorKind::CannotCopyFile {
+                        to: path.clone(),
+                        io_error: e.to_string(),
+                    },
+                };
+                message_sender.send(Message::Progress(Progress::IncrementFail(error.clone())));
+                file_errors.push(error);
+            }
+        }
+    }
+    if file_errors.is_empty() {
+        message_sender.send(Message::Progress(Progress::EndSuccess(
+            ProgressType::CopingFiles,
+        )));
+    } else {
+        message_sender.send(Message::Progress(Progress::EndFail(
+            file_errors.len(),
+            ProgressType::CopingFiles,
+        )));
+    }
+
+    let owned_store = store.clone();
+    tokio::task::spawn_blocking(move || owned_store.flush())
+        .await
+        .map_err(std::io::Error::other)
+        .flatten()
+        .map_err(|e| Error::CannotWriteSnapshot(path.clone(), e.to_string()))?;
+    let snapshot = Snapshot {
+        id,
+        created: std::time::SystemTime::now(),
+        source_root: source_root.to_owned(),
+        entries,
+    };
+    let text = snapshot.to_string();
+    let data = match &key {
+        Some(key) => key.encrypt(text.as_bytes()),
+        None => text.into_bytes(),
+    };
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, data)
+        .and_then(|()| std::fs::rename(&temporary, &path))
+        .map_err(|e| Error::CannotWriteSnapshot(path.clone(), e.to_string()))?;
+    message_sender.send(Message::Info(Info::SnapshotCreated {
+        snapshot: snapshot.summary(),
+        added_bytes,
+        stored_bytes,
+    }));
+    Error::from_processing_results(directory_errors, file_errors)
+}
+
+/// Writes a file of a snapshot via a partial file, such that an interrupted restore does not
+/// leave a truncated file behind.
+fn restore_file(
+    store: &ChunkStore,
+    chunks: &[chunks::ChunkId],
+    modified: u128,
+    target: &std::path::Path,
+) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let partial = crate::staging_path(target, None);
+    let mut file = std::fs::File::create(&partial)?;
+    let written = chunks
+        .iter()
+        .try_for_each(|id| file.write_all(&store.get(id)?));
+    if let Err(e) = written {
+        drop(file);
+        std::fs::remove_file(&partial).ok();
+        return Err(e);
+    }
+    file.set_modified(to_system_time(modified))?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&partial, target)
+}
+
+/// Restores a snapshot into `target_root`, the most recent one if `id` is `None`. Files which
+/// have the size and modification time of the snapshot are not written again.
+#[allow(clippy::too_many_lines)]
+pub async fn restore(
+    repository_root: &std::path::Path,
+    id: Option<&str>,
+    target_root: &std::path::Path,
+    secret: Option<&Secret>,
+    message_sender: &impl MessageSender,
+) -> Result<(), Error> {
+    use futures::stream::StreamExt;
+
+    let key = open_key(repository_root, secret)?;
+    let snapshots = snapshots(repository_root, key.as_ref())?;
+    let snapshot = match id {
+        Some(id) => snapshots
+            .into_iter()
+            .find(|snapshot| snapshot.id == id)
+            .ok_or_else(|| Error::SnapshotNotFound {
+                repository_root: repository_root.to_owned(),
+                id: id.to_owned(),
+            })?,
+        None => snapshots
+            .into_iter()
+            .last()
+            .ok_or_else(|| Error::NoSnapshot(repository_root.to_owned()))?,
+    };
+    let path = snapshot_path(repository_root, &snapshot.id);
+    let store = ChunkStore::open(repository_root, version(repository_root)?, key).map_err(|e| {
+        Error::CannotReadDirectoryContent(repository_root.to_owned(), e.to_string())
+    })?;
+
+    let mut directory_errors = vec![];
+    let mut files = vec![];
+    for entry in &snapshot.entries {
+        match entry {
+            Entry::Directory {
+                path: directory, ..
+            } => {
+                let target = target_root.join(directory);
+                if let Err(e) = std::fs::create_dir_all(&target) {
+                    directory_errors.push(ProcessPathError {
+                        not_processed: None,
+                        kind: ProcessPathErrorKind::CannotCreateDestinationDir {
+                            destination: target,
+                            io_error: e.to_string(),
+                        },
+                    });
+                }
+            }
+            Entry::File {
+                path: file,
+                size,
+                modified,
+                chunks,
+            } => files.push((target_root.join(file), *size, *modified, chunks.clone())),
+        }
+    }
+
+    message_sender.send(Message::Progress(Progress::Start(
+        files.len(),
+        ProgressType::CopingFiles,
+    )));
+    let file_errors: Vec<_> = futures::stream::iter(files)
+        .map(async |(target, size, modified, chunks)| {
+            let reason = match std::fs::metadata(&target) {
+                Err(_) => CopyReason::NewFile,
+                Ok(metadata) if metadata.len() != size => CopyReason::SizeChanged,
+                Ok(metadata) if crate::manifest::modified(&metadata) != modified => {
+                    CopyReason::ModifiedTimeChanged
+                }
+                Ok(_) => {
+                    message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                        Increment::SkippingFileNoModification {
+                            source: path.clone(),
+                            destination: target,
+                            bytes: size,
+                            reason: SkipReason::SameMetadata,
+                        },
+                    )));
+                    return None;
+                }
+            };
+            let (owned_store, owned_target) = (store.clone(), target.clone());
+            let restored = tokio::task::spawn_blocking(move || {
+                restore_file(&owned_store, &chunks, modified, &owned_target)
+            })
+            .await
+            .map_err(std::io::Error::other)
+            .flatten();
+            match restored {
+                Ok(()) => {
+                    message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                        Increment::FileCopied {
+                            source: path.clone(),
+                            destination: target,
+                            bytes: size,
+                            reason,
+                        },
+                    )));
+                    None
+                }
+                Err(e) => {
+                    let error = ProcessPathError {
+                        not_processed: Some(path.clone()),
+                        kind: ProcessPathErrorKind::CannotCopyFile {
+                            to: target,
+                            io_error: e.to_string(),
+                        },
+                    };
+                    message_sender.send(Message::Progress(Progress::IncrementFail(error.clone())));
+                    Some(error)
+                }
+            }
+        })
+        .buffer_unordered(crate::cpu_count())
+        .filter_map(std::future::ready)
+        .collect()
+        .await;
+    if file_errors.is_empty() {
+        message_sender.send(Message::Progress(Progress::EndSuccess(
+            ProgressType::CopingFiles,
+        )));
+    } else {
+        message_sender.send(Message::Progress(Progress::EndFail(
+            file_errors.len(),
+            ProgressType::CopingFiles,
+        )));
+    }
+
+    // NOTE: Restoring the files has modified the directories, so their times come last and the
+    // deepest directories first
+    for entry in snapshot.entries.iter().rev() {
+        if let Entry::Directory {
+            path: directory,
+            modified,
+        } = entry
+        {
+            let target = target_root.join(directory);
+            let set = std::fs::File::open(&target)
+                .and_then(|directory| directory.set_modified(to_system_time(*modified)));
+            if set.is_err() {
+                message_sender.send(Message::Warning(Warning::CannotCopyModifiedTime {
+                    source: path.clone(),
+                    destination: target,
+                }));
+            }
+        }
+    }
+    Error::from_processing_results(directory_errors, file_errors)
+}
@@ -0,0 +1,300 @@
+//! Content addressed storage of the chunks of files. Each chunk is stored once, however many
+//! files and snapshots contain it. Large chunks are files of their own, small ones are
+//! collected in packs. Since version 2 of the repository, stored chunks start with a byte
+//! which says whether they are compressed. In encrypted repositories, this is encrypted as
+//! well, see [`super::crypto`].
+
+use std::io::{Read, Write};
+
+const CHUNKS_DIRECTORY: &str = "chunks";
+/// Chunks are cut where the content says so, such that they are this size on average. Only
+/// the last chunk of a file can be smaller than the minimum.
+const MIN_CHUNK_SIZE: u32 = 256 * 1024;
+const AVERAGE_CHUNK_SIZE: u32 = 1024 * 1024;
+const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+pub type ChunkId = blake3::Hash;
+
+const UNCOMPRESSED: u8 = 0;
+const ZSTD: u8 = 1;
+
+#[derive(Debug, Clone)]
+pub struct ChunkStore {
+    directory: std::path::PathBuf,
+    packs: std::sync::Arc<super::packs::Packs>,
+    /// Whether stored chunks start with the byte of their encoding, see the module docs.
+    encoded: bool,
+    /// Level of zstd for new chunks, uncompressed if `None`.
+    compression_level: Option<i32>,
+    /// The key of an encrypted repository.
+    key: Option<super::crypto::Key>,
+}
+
+fn chunk_path(directory: &std::path::Path, id: &ChunkId) -> std::path::PathBuf {
+    let hex = id.to_hex();
+    // NOTE: Many file systems get slow with hundreds of thousands of files in a directory
+    directory.join(&hex[..2]).join(hex.as_str())
+}
+
+/// Whether the chunk is stored in a file of its own, packed chunks are not looked at.
+pub fn has_file(repository_root: &std::path::Path, id: &ChunkId) -> bool {
+    chunk_path(&repository_root.join(CHUNKS_DIRECTORY), id).is_file()
+}
+
+impl ChunkStore {
+    /// Opens the store of a repository of the given version, reading the indexes of its
+    /// packs. `key` is the key of an encrypted repository.
+    pub fn open(
+        repository_root: &std::path::Path,
+        version: u32,
+        key: Option<super::crypto::Key>,
+    ) -> std::io::Result<Self> {
+        Ok(Self {
+            directory: repository_root.join(CHUNKS_DIRECTORY),
+            packs: std::sync::Arc::new(super::packs::Packs::load(repository_root)?),
+            encoded: version >= 2,
+            compression_level: None,
+            key,
+        })
+    }
+
+    /// Compresses new chunks with zstd of this level. Chunks which do not get smaller are
+    /// stored as they are. Repositories before version 2 cannot hold compressed chunks.
+    pub fn with_compression(mut self, level: Option<i32>) -> Self {
+        self.compression_level = level.filter(|_| self.encoded);
+        self
+    }
+
+    fn id(&self, data: &[u8]) -> ChunkId {
+        self.key
+            .as_ref()
+            .map_or_else(|| blake3::hash(data), |key| key.chunk_id(data))
+    }
+
+    fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let encoded = self.compress(data)?;
+        Ok(match &self.key {
+            Some(key) => key.encrypt(&encoded),
+            None => encoded,
+        })
+    }
+
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        if !self.encoded {
+            return Ok(data.to_vec());
+        }
+        if let Some(level) = self.compression_level {
+            let compressed = zstd::bulk::compress(data, level)?;
+            if compressed.len() < data.len() {
+                return Ok(std::iter::once(ZSTD).chain(compressed).collect());
+            }
+        }
+        Ok(std::iter::once(UNCOMPRESSED)
+            .chain(data.iter().copied())
+            .collect())
+    }
+
+    fn decode(&self, stored: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        let stored = match &self.key {
+            Some(key) => key.decrypt(&stored)?,
+            None => stored,
+        };
+        if !self.encoded {
+            return Ok(stored);
+        }
+        match stored.split_first() {
+            Some((&UNCOMPRESSED, data)) => Ok(data.to_vec()),
+            Some((&ZSTD, compressed)) => zstd::decode_all(compressed),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "The chunk has an unknown encoding",
+            )),
+        }
+    }
+
+    pub fn create(&self) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.directory)
+    }
+
+    fn path(&self, id: &ChunkId) -> std::path::PathBuf {
+        chunk_path(&self.directory, id)
+    }
+
+    /// Stores a chunk unless the store already has it. Returns its ID and the number of
+    /// bytes it takes in the store, 0 if it is not new. Small chunks are only stored once
+    /// their pack is full or the store is flushed.
+    pub fn put(&self, data: &[u8]) -> std::io::Result<(ChunkId, u64)> {
+        let id = self.id(data);
+        let path = self.path(&id);
+        if path.is_file() || self.packs.contains(&id) {
+            return Ok((id, 0));
+        }
+        let data = self.encode(data)?;
+        let stored = data.len() as u64;
+        if data.len() < super::packs::MAX_PACKED_CHUNK_SIZE {
+            let new = self.packs.add(id, &data)?;
+            return Ok((id, if new { stored } else { 0 }));
+        }
+        let directory = path.parent().expect("Chunks are stored in a subdirectory");
+        std::fs::create_dir_all(directory)?;
+        // NOTE: A chunk only gets its name once it is complete, concurrent writers of the same
+        // chunk each use their own temporary file
+        let temporary = directory.join(format!(
+            "{}.{}.tmp",
+            id.to_hex(),
+            uuid::Uuid::new_v4().simple()
+        ));
+        let mut file = std::fs::File::create(&temporary)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        std::fs::rename(&temporary, &path)?;
+        Ok((id, stored))
+    }
+
+    /// Writes the chunks which are waiting for their pack to be full.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.packs.flush()
+    }
+
+    /// Reads a chunk and checks that it still has its hash.
+    pub fn get(&self, id: &ChunkId) -> std::io::Result<Vec<u8>> {
+        let stored = match std::fs::read(self.path(id)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => self.packs.get(id)?.ok_or(e)?,
+            read => read?,
+        };
+        let data = self.decode(stored)?;
+        if self.id(&data) != *id {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("The chunk {} is corrupted", id.to_hex()),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Removes the chunks which are not in `referenced`, both the ones in files of their own
+    /// and the ones in packs, and the temporary files of interrupted writes.
+    pub fn collect_garbage(
+        &self,
+        referenced: &std::collections::HashSet<ChunkId>,
+    ) -> std::io::Result<super::GarbageCollected> {
+        let mut collected = super::GarbageCollected::default();
+        if self.directory.is_dir() {
+            for directory in std::fs::read_dir(&self.directory)? {
+                for file in std::fs::read_dir(directory?.path())? {
+                    let file = file?;
+                    let id = file
+                        .file_name()
+                        .to_str()
+                        .and_then(|name| blake3::Hash::from_hex(name).ok());
+                    if id.is_some_and(|id| referenced.contains(&id)) {
+                        continue;
+                    }
+                    if id.is_some() {
+                        collected.removed_chunks += 1;
+                    }
+                    collected.freed_bytes += file.metadata()?.len();
+                    std::fs::remove_file(file.path())?;
+                }
+            }
+        }
+        let (removed, repacked, freed) = self.packs.collect_garbage(referenced)?;
+        collected.removed_packs = removed;
+        collected.repacked_packs = repacked;
+        collected.freed_bytes += freed;
+        Ok(collected)
+    }
+}
+
+/// Splits what `reader` yields into chunks and passes them to `chunk` in order. An empty
+/// reader has no chunks.
+///
+/// The chunks are content defined (`FastCDC`): an edit or an insertion only changes the
+/// chunks around it, while the chunks after it are cut at the same places as before. Fixed
+/// size chunks would all shift, so a grown mailbox or VM image would be stored again.
+pub fn split(
+    reader: impl Read,
+    mut chunk: impl FnMut(&[u8]) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    for data in
+        fastcdc::v2020::StreamCDC::new(reader, MIN_CHUNK_SIZE, AVERAGE_CHUNK_SIZE, MAX_CHUNK_SIZE)
+    {
+        chunk(&data?.data)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(data: &[u8]) -> Vec<ChunkId> {
+        let mut chunks = vec![];
+        split(data, |chunk| {
+            chunks.push(blake3::hash(chunk));
+            Ok(())
+        })
+        .unwrap();
+        chunks
+    }
+
+    #[test]
+    fn test_split_content_defined() {
+        let mut data = vec![0; 16 * 1024 * 1024];
+        blake3::Hasher::new().finalize_xof().fill(&mut data);
+        let original = chunks(&data);
+        assert!(original.len() > 1);
+        assert!(chunks(&[]).is_empty());
+
+        // NOTE: With fixed size chunks, inserting a byte at the start changes every chunk
+        data.insert(0, 42);
+        let edited = chunks(&data);
+        let changed = edited.iter().filter(|id| !original.contains(id)).count();
+        assert!(changed <= 2, "{changed} of {} chunks changed", edited.len());
+    }
+
+    #[test]
+    fn test_chunk_encoding() {
+        let repository = tempfile::tempdir().unwrap();
+        let text = "compressible text ".repeat(20_000);
+        let mut random = vec![0; 200_000];
+        blake3::Hasher::new().finalize_xof().fill(&mut random);
+
+        let store = ChunkStore::open(repository.path(), 2, None)
+            .unwrap()
+            .with_compression(Some(3));
+        let (text_id, stored) = store.put(text.as_bytes()).unwrap();
+        assert!(stored < text.len() as u64 / 10);
+        let (random_id, stored) = store.put(&random).unwrap();
+        assert_eq!(stored, random.len() as u64 + 1);
+        assert_eq!(store.put(text.as_bytes()).unwrap().1, 0);
+        store.flush().unwrap();
+        assert_eq!(store.get(&text_id).unwrap(), text.as_bytes());
+        assert_eq!(store.get(&random_id).unwrap(), random);
+
+        let legacy = tempfile::tempdir().unwrap();
+        let store = ChunkStore::open(legacy.path(), 1, None)
+            .unwrap()
+            .with_compression(Some(3));
+        let (id, stored) = store.put(text.as_bytes()).unwrap();
+        assert_eq!(stored, text.len() as u64);
+        assert_eq!(store.get(&id).unwrap(), text.as_bytes());
+
+        let encrypted = tempfile::tempdir().unwrap();
+        let key = super::super::crypto::create(
+            encrypted.path(),
+            b"passphrase",
+            super::super::KeyKind::Passphrase,
+        )
+        .unwrap();
+        let store = ChunkStore::open(encrypted.path(), 3, Some(key))
+            .unwrap()
+            .with_compression(Some(3));
+        let (id, stored) = store.put(&random).unwrap();
+        assert_ne!(id, random_id);
+        assert_eq!(stored, random.len() as u64 + 1 + 40);
+        let file = std::fs::read(store.path(&id)).unwrap();
+        assert!(!file.windows(64).any(|window| window == &random[..64]));
+        assert_eq!(store.get(&id).unwrap(), random);
+    }
+}
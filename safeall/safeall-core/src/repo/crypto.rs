@@ -0,0 +1,420 @@
+//! Encryption of repositories. A random master key encrypts the chunks and the snapshots with
+//! XChaCha20-Poly1305, such that they can neither be read nor changed unnoticed without it.
+//! The key file holds copies of the master key, each encrypted with a key which Argon2id
+//! derives from a passphrase or the content of a key file. Adding or removing such a copy
+//! does not change the master key, so nothing has to be encrypted again.
+//!
+//! Chunks are identified by a hash keyed with the master key, such that the IDs in the pack
+//! indexes do not tell whether the repository contains a known file. The indexes themselves
+//! are not encrypted, they only reveal the sizes of the chunks.
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+
+use super::chunks::ChunkId;
+
+const KEY_FILE: &str = "key";
+const KEY_HEADER: &str = "# safeall keys 1";
+const NONCE_SIZE: usize = 24;
+const SALT_SIZE: usize = 16;
+/// Size of the key files which are generated when they do not exist.
+const KEY_FILE_SIZE: usize = 64;
+
+/// What unlocks an encrypted repository. A passphrase is not shown by `Debug`, such that it
+/// does not end up in logs.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Secret {
+    Passphrase(String),
+    /// A file whose content is the secret, e.g. random bytes on a USB stick which is only
+    /// plugged in for backups.
+    KeyFile(std::path::PathBuf),
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Secret::Passphrase(_) => write!(f, "Passphrase(..)"),
+            Secret::KeyFile(path) => write!(f, "KeyFile({path:?})"),
+        }
+    }
+}
+
+impl Secret {
+    #[must_use]
+    pub fn kind(&self) -> KeyKind {
+        match self {
+            Secret::Passphrase(_) => KeyKind::Passphrase,
+            Secret::KeyFile(_) => KeyKind::KeyFile,
+        }
+    }
+}
+
+/// The content of a key file. If `generate` is set, a key file which does not exist yet is
+/// filled with random bytes.
+pub fn read_key_file(path: &std::path::Path, generate: bool) -> std::io::Result<Vec<u8>> {
+    if generate && !path.exists() {
+        let mut content = vec![0; KEY_FILE_SIZE];
+        OsRng.fill_bytes(&mut content);
+        std::fs::write(path, &content)?;
+        return Ok(content);
+    }
+    std::fs::read(path)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyKind {
+    Passphrase,
+    KeyFile,
+}
+
+impl KeyKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            KeyKind::Passphrase => "passphrase",
+            KeyKind::KeyFile => "keyfile",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "passphrase" => Some(KeyKind::Passphrase),
+            "keyfile" => Some(KeyKind::KeyFile),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for KeyKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyKind::Passphrase => write!(f, "passphrase"),
+            KeyKind::KeyFile => write!(f, "key file"),
+        }
+    }
+}
+
+/// A passphrase or key file which unlocks an encrypted repository, without the secret.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisteredKey {
+    pub id: String,
+    pub kind: KeyKind,
+    pub added: std::time::SystemTime,
+}
+
+impl std::fmt::Display for RegisteredKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} added on {}",
+            self.id,
+            self.kind,
+            chrono::DateTime::<chrono::Local>::from(self.added).format("%Y-%m-%d %H:%M:%S")
+        )
+    }
+}
+
+/// A copy of the master key in the key file.
+struct Entry {
+    key: RegisteredKey,
+    salt: Vec<u8>,
+    encrypted: Vec<u8>,
+}
+
+/// The master key of an unlocked repository.
+#[derive(Clone)]
+pub struct Key {
+    master: [u8; 32],
+    cipher: chacha20poly1305::XChaCha20Poly1305,
+    /// Keys the hashes of the chunks, such that they do not tell what the chunks contain.
+    chunk_ids: [u8; 32],
+}
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Key(..)")
+    }
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn encrypt(cipher: &chacha20poly1305::XChaCha20Poly1305, data: &[u8]) -> Vec<u8> {
+    let nonce = chacha20poly1305::XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, data)
+        .expect("Encrypting data in memory cannot fail");
+    nonce.into_iter().chain(ciphertext).collect()
+}
+
+fn decrypt(cipher: &chacha20poly1305::XChaCha20Poly1305, data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < NONCE_SIZE {
+        return None;
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+    cipher
+        .decrypt(chacha20poly1305::XNonce::from_slice(nonce), ciphertext)
+        .ok()
+}
+
+impl Key {
+    fn from_master_key(master_key: [u8; 32]) -> Self {
+        let encryption_key = blake3::derive_key("safeall repository encryption", &master_key);
+        Self {
+            master: master_key,
+            cipher: chacha20poly1305::XChaCha20Poly1305::new(&encryption_key.into()),
+            chunk_ids: blake3::derive_key("safeall chunk id", &master_key),
+        }
+    }
+
+    pub fn chunk_id(&self, data: &[u8]) -> ChunkId {
+        blake3::keyed_hash(&self.chunk_ids, data)
+    }
+
+    /// Encrypts data with a random nonce, which is stored in front of it.
+    pub fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        encrypt(&self.cipher, data)
+    }
+
+    /// Decrypts what [`Key::encrypt`] returned. Fails if it has been changed.
+    pub fn decrypt(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        decrypt(&self.cipher, data)
+            .ok_or_else(|| invalid_data("The data cannot be decrypted, it is damaged"))
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The cipher with which a passphrase or key file encrypts its copy of the master key.
+fn secret_cipher(
+    secret: &[u8],
+    salt: &[u8],
+) -> std::io::Result<chacha20poly1305::XChaCha20Poly1305> {
+    let mut key = [0; 32];
+    argon2::Argon2::default()
+        .hash_password_into(secret, salt, &mut key)
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    Ok(chacha20poly1305::XChaCha20Poly1305::new(&key.into()))
+}
+
+impl Entry {
+    fn new(master_key: &[u8; 32], secret: &[u8], kind: KeyKind) -> std::io::Result<Self> {
+        let mut salt = vec![0; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        // NOTE: The key file only stores whole seconds
+        let seconds = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        Ok(Self {
+            key: RegisteredKey {
+                id: entry_id(&salt),
+                kind,
+                added: std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds),
+            },
+            encrypted: encrypt(&secret_cipher(secret, &salt)?, master_key),
+            salt,
+        })
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split(' ');
+        let id = fields.next()?.to_owned();
+        let kind = KeyKind::parse(fields.next()?)?;
+        let added =
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(fields.next()?.parse().ok()?);
+        Some(Self {
+            key: RegisteredKey { id, kind, added },
+            salt: from_hex(fields.next()?)?,
+            encrypted: from_hex(fields.next()?)?,
+        })
+    }
+}
+
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.key.id,
+            self.key.kind.as_str(),
+            self.key
+                .added
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            to_hex(&self.salt),
+            to_hex(&self.encrypted)
+        )
+    }
+}
+
+/// The salt is random, so it identifies an entry without storing another ID.
+fn entry_id(salt: &[u8]) -> String {
+    blake3::hash(salt).to_hex()[..8].to_owned()
+}
+
+fn read_entries(repository_root: &std::path::Path) -> std::io::Result<Vec<Entry>> {
+    let text = std::fs::read_to_string(repository_root.join(KEY_FILE))?;
+    let damaged = || invalid_data("The key file is damaged");
+    let mut lines = text.lines();
+    match lines.next() {
+        Some(KEY_HEADER) => lines
+            .map(Entry::parse)
+            .collect::<Option<_>>()
+            .ok_or_else(damaged),
+        _ => Err(damaged()),
+    }
+}
+
+fn write_entries(repository_root: &std::path::Path, entries: &[Entry]) -> std::io::Result<()> {
+    let path = repository_root.join(KEY_FILE);
+    let temporary = path.with_extension("tmp");
+    let content = std::iter::once(KEY_HEADER.to_owned())
+        .chain(entries.iter().map(ToString::to_string))
+        .collect::<Vec<_>>()
+        .join("\n");
+    std::fs::write(&temporary, content + "\n")?;
+    std::fs::rename(&temporary, &path)
+}
+
+/// Whether the repository has a key file and thereby encrypts its chunks and snapshots.
+pub fn is_encrypted(repository_root: &std::path::Path) -> bool {
+    repository_root.join(KEY_FILE).is_file()
+}
+
+/// Creates a random master key and writes it into the key file, encrypted with the secret.
+pub fn create(
+    repository_root: &std::path::Path,
+    secret: &[u8],
+    kind: KeyKind,
+) -> std::io::Result<Key> {
+    let mut master_key = [0; 32];
+    OsRng.fill_bytes(&mut master_key);
+    write_entries(repository_root, &[Entry::new(&master_key, secret, kind)?])?;
+    Ok(Key::from_master_key(master_key))
+}
+
+/// Reads the master key with the first entry of the key file which the secret decrypts, and
+/// returns the ID of that entry as well. `None` if the secret decrypts none of them.
+pub fn unlock(
+    repository_root: &std::path::Path,
+    secret: &[u8],
+) -> std::io::Result<Option<(Key, String)>> {
+    for entry in read_entries(repository_root)? {
+        let Some(master_key) = decrypt(&secret_cipher(secret, &entry.salt)?, &entry.encrypted)
+        else {
+            continue;
+        };
+        let master_key = master_key
+            .try_into()
+            .map_err(|_| invalid_data("The key file is damaged"))?;
+        return Ok(Some((Key::from_master_key(master_key), entry.key.id)));
+    }
+    Ok(None)
+}
+
+/// The entries of the key file.
+pub fn list(repository_root: &std::path::Path) -> std::io::Result<Vec<RegisteredKey>> {
+    Ok(read_entries(repository_root)?
+        .into_iter()
+        .map(|entry| entry.key)
+        .collect())
+}
+
+/// Adds a copy of the master key which the secret unlocks.
+pub fn add(
+    repository_root: &std::path::Path,
+    key: &Key,
+    secret: &[u8],
+    kind: KeyKind,
+) -> std::io::Result<RegisteredKey> {
+    let mut entries = read_entries(repository_root)?;
+    let entry = Entry::new(&key.master, secret, kind)?;
+    let added = entry.key.clone();
+    entries.push(entry);
+    write_entries(repository_root, &entries)?;
+    Ok(added)
+}
+
+/// Removes the entry with the ID from the key file. Returns whether there was such an entry.
+pub fn remove(repository_root: &std::path::Path, id: &str) -> std::io::Result<bool> {
+    let mut entries = read_entries(repository_root)?;
+    let count = entries.len();
+    entries.retain(|entry| entry.key.id != id);
+    if entries.len() == count {
+        return Ok(false);
+    }
+    write_entries(repository_root, &entries)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key() {
+        let repository = tempfile::tempdir().unwrap();
+        let passphrase = b"correct horse battery staple";
+        assert!(!is_encrypted(repository.path()));
+        let key = create(repository.path(), passphrase, KeyKind::Passphrase).unwrap();
+        assert!(is_encrypted(repository.path()));
+        let secret = Secret::Passphrase("correct horse".to_owned());
+        assert!(!format!("{secret:?}").contains("horse"));
+
+        let (unlocked, _) = unlock(repository.path(), passphrase).unwrap().unwrap();
+        let encrypted = key.encrypt(b"secret");
+        assert_eq!(unlocked.decrypt(&encrypted).unwrap(), b"secret");
+        assert_ne!(key.encrypt(b"secret"), encrypted);
+        assert_eq!(unlocked.chunk_id(b"secret"), key.chunk_id(b"secret"));
+        assert_ne!(key.chunk_id(b"secret"), blake3::hash(b"secret"));
+
+        let mut changed = encrypted.clone();
+        *changed.last_mut().unwrap() ^= 1;
+        assert!(key.decrypt(&changed).is_err());
+        assert!(key.decrypt(&encrypted[..10]).is_err());
+
+        assert!(unlock(repository.path(), b"wrong").unwrap().is_none());
+        std::fs::write(repository.path().join(KEY_FILE), "garbage").unwrap();
+        assert!(unlock(repository.path(), passphrase).is_err());
+    }
+
+    #[test]
+    fn test_several_keys() {
+        let repository = tempfile::tempdir().unwrap();
+        let key = create(repository.path(), b"first", KeyKind::Passphrase).unwrap();
+        let key_file = repository.path().join("usb.key");
+        assert!(read_key_file(&key_file, false).is_err());
+        let content = read_key_file(&key_file, true).unwrap();
+        assert_eq!(content.len(), KEY_FILE_SIZE);
+        assert_eq!(read_key_file(&key_file, true).unwrap(), content);
+        let second = add(repository.path(), &key, &content, KeyKind::KeyFile).unwrap();
+
+        let listed = list(repository.path()).unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[1], second);
+        let (unlocked, id) = unlock(repository.path(), &content).unwrap().unwrap();
+        assert_eq!(id, second.id);
+        assert_eq!(unlocked.decrypt(&key.encrypt(b"data")).unwrap(), b"data");
+
+        assert!(remove(repository.path(), &listed[0].id).unwrap());
+        assert!(!remove(repository.path(), &listed[0].id).unwrap());
+        assert!(unlock(repository.path(), b"first").unwrap().is_none());
+        assert!(unlock(repository.path(), &content).unwrap().is_some());
+    }
+}
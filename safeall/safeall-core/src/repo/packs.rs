@@ -0,0 +1,405 @@
+//! Pack files, which hold many small chunks in one file, such that a source with millions of
+//! tiny files does not become millions of files in the repository. Each pack has an index
+//! file which lists its chunks, the indexes of all packs are read when a repository is opened.
+
+use std::io::{Read, Seek, Write};
+
+use super::chunks::ChunkId;
+
+const PACKS_DIRECTORY: &str = "packs";
+const INDEX_DIRECTORY: &str = "index";
+const INDEX_HEADER: &str = "# safeall pack index 1";
+/// Chunks smaller than this are packed, larger ones are stored as files of their own.
+pub const MAX_PACKED_CHUNK_SIZE: usize = 128 * 1024;
+/// A pack is written once its chunks have this many bytes.
+const PACK_SIZE: usize = 8 * 1024 * 1024;
+
+type PackId = blake3::Hash;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    pack: PackId,
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    index: std::collections::HashMap<ChunkId, Location>,
+    /// Chunks of the pack which has not been written yet, by their offset in `data`.
+    pending: std::collections::HashMap<ChunkId, (u64, u64)>,
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Packs {
+    repository_root: std::path::PathBuf,
+    state: std::sync::Mutex<State>,
+}
+
+fn parse_index(pack: PackId, text: &str) -> Option<Vec<(ChunkId, Location)>> {
+    let mut lines = text.lines();
+    if lines.next()? != INDEX_HEADER {
+        return None;
+    }
+    lines
+        .map(|line| {
+            let mut parts = line.split(' ');
+            let id = blake3::Hash::from_hex(parts.next()?).ok()?;
+            let offset = parts.next()?.parse().ok()?;
+            let length = parts.next()?.parse().ok()?;
+            Some((
+                id,
+                Location {
+                    pack,
+                    offset,
+                    length,
+                },
+            ))
+        })
+        .collect()
+}
+
+fn pack_path(repository_root: &std::path::Path, pack: &PackId) -> std::path::PathBuf {
+    let hex = pack.to_hex();
+    repository_root
+        .join(PACKS_DIRECTORY)
+        .join(&hex[..2])
+        .join(hex.as_str())
+}
+
+/// Checks that the packs of a repository match their indexes without reading their chunks,
+/// and returns the chunks of the intact packs.
+pub fn check(
+    repository_root: &std::path::Path,
+    report: &mut super::RepositoryCheck,
+) -> std::io::Result<std::collections::HashSet<ChunkId>> {
+    let mut packed = std::collections::HashSet::new();
+    let mut indexed = std::collections::HashSet::new();
+    let index_directory = repository_root.join(INDEX_DIRECTORY);
+    if index_directory.is_dir() {
+        for entry in std::fs::read_dir(&index_directory)? {
+            let entry = entry?;
+            let Some(pack) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| blake3::Hash::from_hex(name).ok())
+            else {
+                continue;
+            };
+            indexed.insert(pack);
+            let chunks = std::fs::read_to_string(entry.path())
+                .ok()
+                .and_then(|text| parse_index(pack, &text));
+            let path = pack_path(repository_root, &pack);
+            let size = std::fs::metadata(&path).ok().map(|metadata| metadata.len());
+            match (chunks, size) {
+                (None, _) => report.damaged_packs.push(entry.path()),
+                (Some(chunks), Some(size))
+                    if chunks
+                        .iter()
+                        .all(|(_, location)| location.offset + location.length <= size) =>
+                {
+                    packed.extend(chunks.into_iter().map(|(id, _)| id));
+                }
+                (Some(_), _) => report.damaged_packs.push(path),
+            }
+        }
+    }
+    let packs_directory = repository_root.join(PACKS_DIRECTORY);
+    if packs_directory.is_dir() {
+        for directory in std::fs::read_dir(&packs_directory)? {
+            for file in std::fs::read_dir(directory?.path())? {
+                let file = file?;
+                let pack = file
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| blake3::Hash::from_hex(name).ok());
+                if pack.is_some_and(|pack| !indexed.contains(&pack)) {
+                    report.unindexed_packs.push(file.path());
+                }
+            }
+        }
+    }
+    Ok(packed)
+}
+
+/// Writes a file via a temporary file, such that it only gets its name once it is complete.
+fn write_atomically(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    let directory = path.parent().expect("Packs and indexes are in a directory");
+    std::fs::create_dir_all(directory)?;
+    let temporary = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&temporary)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, path)
+}
+
+impl Packs {
+    /// Reads the indexes of all packs of the repository.
+    pub fn load(repository_root: &std::path::Path) -> std::io::Result<Self> {
+        let mut state = State::default();
+        let directory = repository_root.join(INDEX_DIRECTORY);
+        let entries = match std::fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self {
+                    repository_root: repository_root.to_owned(),
+                    state: std::sync::Mutex::new(state),
+                });
+            }
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            // NOTE: Indexes which are being written have an extension
+            let Some(pack) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| blake3::Hash::from_hex(name).ok())
+            else {
+                continue;
+            };
+            let text = std::fs::read_to_string(entry.path())?;
+            let chunks = parse_index(pack, &text).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("The index of the pack {} is damaged", pack.to_hex()),
+                )
+            })?;
+            state.index.extend(chunks);
+        }
+        Ok(Self {
+            repository_root: repository_root.to_owned(),
+            state: std::sync::Mutex::new(state),
+        })
+    }
+
+    fn pack_path(&self, pack: &PackId) -> std::path::PathBuf {
+        pack_path(&self.repository_root, pack)
+    }
+
+    /// Adds a chunk to the pack which is being filled and writes the pack once it is full.
+    /// Returns whether the chunk is new.
+    pub fn add(&self, id: ChunkId, data: &[u8]) -> std::io::Result<bool> {
+        let mut state = self.state.lock().expect("Lock is never poisoned");
+        if state.index.contains_key(&id) || state.pending.contains_key(&id) {
+            return Ok(false);
+        }
+        self.push(&mut state, id, data)?;
+        Ok(true)
+    }
+
+    fn push(&self, state: &mut State, id: ChunkId, data: &[u8]) -> std::io::Result<()> {
+        let offset = state.data.len() as u64;
+        state.data.extend_from_slice(data);
+        state.pending.insert(id, (offset, data.len() as u64));
+        if state.data.len() >= PACK_SIZE {
+            self.write_pack(state)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the pack which is being filled, even if it is not full yet. Snapshots may only
+    /// be written afterwards, as they would refer to chunks which are not stored otherwise.
+    pub fn flush(&self) -> std::io::Result<()> {
+        let mut state = self.state.lock().expect("Lock is never poisoned");
+        self.write_pack(&mut state)
+    }
+
+    fn write_pack(&self, state: &mut State) -> std::io::Result<()> {
+        if state.pending.is_empty() {
+            return Ok(());
+        }
+        let pack = blake3::hash(&state.data);
+        write_atomically(&self.pack_path(&pack), &state.data)?;
+        // NOTE: A pack without an index is never read, but a crash cannot leave an index of a
+        // pack which is missing
+        let index: String = std::iter::once(format!("{INDEX_HEADER}\n"))
+            .chain(
+                state
+                    .pending
+                    .iter()
+                    .map(|(id, (offset, length))| format!("{} {offset} {length}\n", id.to_hex())),
+            )
+            .collect();
+        write_atomically(
+            &self
+                .repository_root
+                .join(INDEX_DIRECTORY)
+                .join(pack.to_hex().as_str()),
+            index.as_bytes(),
+        )?;
+        let pending = std::mem::take(&mut state.pending);
+        state
+            .index
+            .extend(pending.into_iter().map(|(id, (offset, length))| {
+                (
+                    id,
+                    Location {
+                        pack,
+                        offset,
+                        length,
+                    },
+                )
+            }));
+        state.data.clear();
+        Ok(())
+    }
+
+    /// Whether a pack has the chunk, including the pack which is being filled.
+    pub fn contains(&self, id: &ChunkId) -> bool {
+        let state = self.state.lock().expect("Lock is never poisoned");
+        state.index.contains_key(id) || state.pending.contains_key(id)
+    }
+
+    /// Reads a packed chunk, `None` if no pack has it.
+    pub fn get(&self, id: &ChunkId) -> std::io::Result<Option<Vec<u8>>> {
+        let location = self
+            .state
+            .lock()
+            .expect("Lock is never poisoned")
+            .index
+            .get(id)
+            .copied();
+        location.map(|location| self.read(&location)).transpose()
+    }
+
+    fn read(&self, location: &Location) -> std::io::Result<Vec<u8>> {
+        let mut file = std::fs::File::open(self.pack_path(&location.pack))?;
+        file.seek(std::io::SeekFrom::Start(location.offset))?;
+        let mut data = vec![0; usize::try_from(location.length).unwrap_or(usize::MAX)];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Removes the packs without a chunk in `referenced` and the packs which no index lists,
+    /// and writes the referenced chunks of partly used packs into new packs. Returns the
+    /// number of removed and repacked packs and the freed bytes.
+    pub fn collect_garbage(
+        &self,
+        referenced: &std::collections::HashSet<ChunkId>,
+    ) -> std::io::Result<(usize, usize, u64)> {
+        let mut state = self.state.lock().expect("Lock is never poisoned");
+        let mut packs: std::collections::HashMap<PackId, Vec<(ChunkId, Location)>> =
+            std::collections::HashMap::new();
+        for (id, location) in &state.index {
+            packs
+                .entry(location.pack)
+                .or_default()
+                .push((*id, *location));
+        }
+        let (mut removed, mut repacked, mut rewritten) = (0, 0, 0);
+        let mut obsolete = vec![];
+        for (pack, chunks) in packs {
+            let used: Vec<_> = chunks
+                .iter()
+                .filter(|(id, _)| referenced.contains(id))
+                .collect();
+            if used.len() == chunks.len() {
+                continue;
+            }
+            if used.is_empty() {
+                removed += 1;
+            } else {
+                repacked += 1;
+            }
+            for (id, location) in used {
+                let data = self.read(location)?;
+                rewritten += data.len() as u64;
+                self.push(&mut state, *id, &data)?;
+            }
+            for (id, location) in &chunks {
+                if location.pack == pack && state.index.get(id) == Some(location) {
+                    state.index.remove(id);
+                }
+            }
+            obsolete.push(pack);
+        }
+        self.write_pack(&mut state)?;
+
+        // NOTE: Old packs are only deleted once their chunks are in the new packs
+        let mut freed = 0;
+        for pack in &obsolete {
+            let path = self.pack_path(pack);
+            freed += std::fs::metadata(&path).map_or(0, |metadata| metadata.len());
+            std::fs::remove_file(path)?;
+            std::fs::remove_file(
+                self.repository_root
+                    .join(INDEX_DIRECTORY)
+                    .join(pack.to_hex().as_str()),
+            )?;
+        }
+        // NOTE: A crash between writing a pack and its index leaves a pack nobody reads
+        let known: std::collections::HashSet<_> =
+            state.index.values().map(|location| location.pack).collect();
+        let packs_directory = self.repository_root.join(PACKS_DIRECTORY);
+        if packs_directory.is_dir() {
+            for directory in std::fs::read_dir(&packs_directory)? {
+                for file in std::fs::read_dir(directory?.path())? {
+                    let file = file?;
+                    let pack = file
+                        .file_name()
+                        .to_str()
+                        .and_then(|name| blake3::Hash::from_hex(name).ok());
+                    if pack.is_some_and(|pack| known.contains(&pack)) {
+                        continue;
+                    }
+                    if pack.is_some() {
+                        removed += 1;
+                    }
+                    freed += file.metadata()?.len();
+                    std::fs::remove_file(file.path())?;
+                }
+            }
+        }
+        Ok((removed, repacked, freed.saturating_sub(rewritten)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packs() {
+        let repository = tempfile::tempdir().unwrap();
+        let chunks: Vec<_> = ["first", "second", "third"]
+            .into_iter()
+            .map(|data| (blake3::hash(data.as_bytes()), data.as_bytes()))
+            .collect();
+        let packs = Packs::load(repository.path()).unwrap();
+        for (id, data) in &chunks {
+            assert!(packs.add(*id, data).unwrap());
+        }
+        assert!(!packs.add(chunks[0].0, chunks[0].1).unwrap());
+        assert_eq!(packs.get(&chunks[0].0).unwrap(), None);
+        packs.flush().unwrap();
+
+        let packs = Packs::load(repository.path()).unwrap();
+        for (id, data) in &chunks {
+            assert_eq!(packs.get(id).unwrap().as_deref(), Some(*data));
+        }
+        assert!(!packs.add(chunks[1].0, chunks[1].1).unwrap());
+        let pack_directories = std::fs::read_dir(repository.path().join(PACKS_DIRECTORY))
+            .unwrap()
+            .count();
+        assert_eq!(pack_directories, 1);
+
+        let referenced = std::collections::HashSet::from([chunks[1].0]);
+        let (removed, repacked, freed) = packs.collect_garbage(&referenced).unwrap();
+        assert_eq!((removed, repacked), (0, 1));
+        assert_eq!(freed, ("first".len() + "third".len()) as u64);
+        let packs = Packs::load(repository.path()).unwrap();
+        assert_eq!(packs.get(&chunks[0].0).unwrap(), None);
+        assert_eq!(
+            packs.get(&chunks[1].0).unwrap().as_deref(),
+            Some(chunks[1].1)
+        );
+        let (removed, repacked, _) = packs
+            .collect_garbage(&std::collections::HashSet::new())
+            .unwrap();
+        assert_eq!((removed, repacked), (1, 0));
+        assert_eq!(packs.get(&chunks[1].0).unwrap(), None);
+    }
+}
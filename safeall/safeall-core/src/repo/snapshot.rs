@@ -0,0 +1,200 @@
+//! Snapshots list the directories and files of a source at one point in time, with the chunks
+//! of each file.
+
+use super::chunks::ChunkId;
+use crate::manifest::{escape, unescape};
+
+const HEADER: &str = "# safeall snapshot 1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    Directory {
+        path: std::path::PathBuf,
+        modified: u128,
+    },
+    File {
+        path: std::path::PathBuf,
+        size: u64,
+        /// Modification time in nanoseconds, as in the manifest.
+        modified: u128,
+        chunks: Vec<ChunkId>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub id: String,
+    /// Stored in nanoseconds, such that snapshots of the same second keep their order.
+    pub created: std::time::SystemTime,
+    pub source_root: std::path::PathBuf,
+    /// Paths are relative to the source root.
+    pub entries: Vec<Entry>,
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    match line.split_once(' ')? {
+        ("d", rest) => {
+            let (modified, path) = rest.split_once(' ')?;
+            Some(Entry::Directory {
+                path: unescape(path),
+                modified: modified.parse().ok()?,
+            })
+        }
+        ("f", rest) => {
+            let mut parts = rest.splitn(4, ' ');
+            let size = parts.next()?.parse().ok()?;
+            let modified = parts.next()?.parse().ok()?;
+            let chunks = match parts.next()? {
+                "-" => vec![],
+                chunks => chunks
+                    .split(',')
+                    .map(|chunk| blake3::Hash::from_hex(chunk).ok())
+                    .collect::<Option<_>>()?,
+            };
+            Some(Entry::File {
+                path: unescape(parts.next()?),
+                size,
+                modified,
+                chunks,
+            })
+        }
+        _ => None,
+    }
+}
+
+impl Snapshot {
+    /// Parses the content of a snapshot file. `None` if it is not a snapshot or damaged.
+    pub fn parse(id: &str, text: &str) -> Option<Self> {
+        let mut lines = text.lines();
+        if lines.next()? != HEADER {
+            return None;
+        }
+        let created = lines.next()?.strip_prefix("created ")?.parse().ok()?;
+        let source_root = unescape(lines.next()?.strip_prefix("source ")?);
+        Some(Self {
+            id: id.to_owned(),
+            created: super::to_system_time(created),
+            source_root,
+            entries: lines.map(parse_entry).collect::<Option<_>>()?,
+        })
+    }
+
+    pub fn summary(&self) -> SnapshotSummary {
+        let (files, bytes) =
+            self.entries
+                .iter()
+                .fold((0, 0), |(files, bytes), entry| match entry {
+                    Entry::File { size, .. } => (files + 1, bytes + size),
+                    Entry::Directory { .. } => (files, bytes),
+                });
+        SnapshotSummary {
+            id: self.id.clone(),
+            created: self.created,
+            source_root: self.source_root.clone(),
+            files,
+            bytes,
+        }
+    }
+}
+
+impl std::fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{HEADER}")?;
+        writeln!(
+            f,
+            "created {}",
+            self.created
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos())
+        )?;
+        writeln!(f, "source {}", escape(&self.source_root))?;
+        for entry in &self.entries {
+            match entry {
+                Entry::Directory { path, modified } => {
+                    writeln!(f, "d {modified} {}", escape(path))?;
+                }
+                Entry::File {
+                    path,
+                    size,
+                    modified,
+                    chunks,
+                } => {
+                    let chunks = if chunks.is_empty() {
+                        "-".to_owned()
+                    } else {
+                        chunks
+                            .iter()
+                            .map(|chunk| chunk.to_hex().to_string())
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    };
+                    writeln!(f, "f {size} {modified} {chunks} {}", escape(path))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot without its entries, to list the snapshots of a repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotSummary {
+    pub id: String,
+    pub created: std::time::SystemTime,
+    pub source_root: std::path::PathBuf,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+impl std::fmt::Display for SnapshotSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} from {} of \"{}\": {} files with ~{}",
+            self.id,
+            chrono::DateTime::<chrono::Local>::from(self.created).format("%Y-%m-%d %H:%M:%S"),
+            self.source_root.display(),
+            self.files,
+            crate::format_bytes(self.bytes)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let snapshot = Snapshot {
+            id: "abc".to_owned(),
+            created: std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000),
+            source_root: "/home/user".into(),
+            entries: vec![
+                Entry::Directory {
+                    path: "my documents".into(),
+                    modified: 1,
+                },
+                Entry::File {
+                    path: "my documents/line\nbreak.txt".into(),
+                    size: 3,
+                    modified: 2,
+                    chunks: vec![blake3::hash(b"abc"), blake3::hash(b"def")],
+                },
+                Entry::File {
+                    path: "empty".into(),
+                    size: 0,
+                    modified: 3,
+                    chunks: vec![],
+                },
+            ],
+        };
+        assert_eq!(
+            Snapshot::parse("abc", &snapshot.to_string()),
+            Some(snapshot.clone())
+        );
+        assert_eq!(snapshot.summary().files, 2);
+        assert_eq!(snapshot.summary().bytes, 3);
+        assert_eq!(Snapshot::parse("abc", "garbage"), None);
+    }
+}
@@ -0,0 +1,177 @@
+//! Checking the directory a restore writes into. Unlike the destination of a backup, the
+//! target of a restore usually already has files or does not exist at all.
+
+use crate::{Error, MessageSender};
+
+/// What a restore does when its target already has files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NonEmptyTarget {
+    /// Restore into it, overwriting the files which differ from the backup.
+    #[default]
+    Merge,
+    /// Refuse to restore, the target has to be empty.
+    RequireEmpty,
+}
+
+/// Whether the target of a restore may be on the filesystem of the backup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TargetFilesystem {
+    #[default]
+    Any,
+    /// E.g. to restore within a NAS without sending the files over the network.
+    SameAsBackup,
+    /// Restoring onto the drive of the backup usually means the paths are mixed up or the
+    /// target drive is not mounted.
+    OtherThanBackup,
+}
+
+/// What a restore does depending on the directory it restores into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestoreTarget {
+    /// Create the target if it does not exist, otherwise fail.
+    pub create: bool,
+    pub non_empty: NonEmptyTarget,
+    pub filesystem: TargetFilesystem,
+}
+
+impl Default for RestoreTarget {
+    fn default() -> Self {
+        Self {
+            create: true,
+            non_empty: NonEmptyTarget::default(),
+            filesystem: TargetFilesystem::default(),
+        }
+    }
+}
+
+/// The filesystem of `path`, or of the part of it which already exists.
+#[cfg(unix)]
+fn filesystem(path: &std::path::Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    path.ancestors()
+        .find_map(|p| std::fs::metadata(p).ok())
+        .map(|metadata| metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn filesystem(_path: &std::path::Path) -> Option<u64> {
+    // NOTE: There is no device number to compare, the filesystem policy is not checked
+    None
+}
+
+/// Checks the backup and the target of a restore against the policy without changing
+/// anything.
+pub fn check(
+    target_root: &std::path::Path,
+    backup_root: &std::path::Path,
+    policy: RestoreTarget,
+) -> Result<(), Error> {
+    if !backup_root.is_dir() {
+        return Err(Error::BackupDoesNotExist(backup_root.to_owned()));
+    }
+    if let (Some(target), Some(backup)) = (filesystem(target_root), filesystem(backup_root)) {
+        match policy.filesystem {
+            TargetFilesystem::SameAsBackup if target != backup => {
+                return Err(Error::RestoreTargetOnOtherFilesystem {
+                    target_root: target_root.to_owned(),
+                    backup_root: backup_root.to_owned(),
+                });
+            }
+            TargetFilesystem::OtherThanBackup if target == backup => {
+                return Err(Error::RestoreTargetOnBackupFilesystem {
+                    target_root: target_root.to_owned(),
+                    backup_root: backup_root.to_owned(),
+                });
+            }
+            _ => {}
+        }
+    }
+    if !target_root.exists() {
+        if !policy.create {
+            return Err(Error::SourceRootPathDoesNotExist(target_root.to_owned()));
+        }
+        return Ok(());
+    }
+    if !target_root.is_dir() {
+        return Err(Error::SourceRootIsNotADirectory(target_root.to_owned()));
+    }
+    if policy.non_empty == NonEmptyTarget::RequireEmpty {
+        let mut entries = std::fs::read_dir(target_root).map_err(|e| {
+            Error::CannotReadDirectoryContent(target_root.to_owned(), e.to_string())
+        })?;
+        if entries.next().is_some() {
+            return Err(Error::RestoreTargetNotEmpty(target_root.to_owned()));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the target of a restore and creates it if it does not exist yet.
+pub fn prepare(
+    target_root: &std::path::Path,
+    backup_root: &std::path::Path,
+    policy: RestoreTarget,
+    message_sender: &impl MessageSender,
+) -> Result<(), Error> {
+    check(target_root, backup_root, policy)?;
+    crate::validate_or_create_destination_root(target_root, message_sender)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restore_target() {
+        let directory = tempfile::tempdir().unwrap();
+        let backup = directory.path().join("backup");
+        let target = directory.path().join("target");
+        let (message_sender, _message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let policy = RestoreTarget::default();
+        assert!(matches!(
+            check(&target, &backup, policy),
+            Err(Error::BackupDoesNotExist(_))
+        ));
+        std::fs::create_dir(&backup).unwrap();
+
+        let must_exist = RestoreTarget {
+            create: false,
+            ..policy
+        };
+        assert!(matches!(
+            prepare(&target, &backup, must_exist, &message_sender),
+            Err(Error::SourceRootPathDoesNotExist(_))
+        ));
+        prepare(&target, &backup, policy, &message_sender).unwrap();
+        assert!(target.is_dir());
+
+        let require_empty = RestoreTarget {
+            non_empty: NonEmptyTarget::RequireEmpty,
+            ..policy
+        };
+        check(&target, &backup, require_empty).unwrap();
+        std::fs::write(target.join("file.txt"), b"content").unwrap();
+        assert!(matches!(
+            check(&target, &backup, require_empty),
+            Err(Error::RestoreTargetNotEmpty(_))
+        ));
+        check(&target, &backup, policy).unwrap();
+
+        let same = RestoreTarget {
+            filesystem: TargetFilesystem::SameAsBackup,
+            ..policy
+        };
+        check(&target, &backup, same).unwrap();
+        #[cfg(unix)]
+        {
+            let other = RestoreTarget {
+                filesystem: TargetFilesystem::OtherThanBackup,
+                ..policy
+            };
+            assert!(matches!(
+                check(&target, &backup, other),
+                Err(Error::RestoreTargetOnBackupFilesystem { .. })
+            ));
+        }
+    }
+}
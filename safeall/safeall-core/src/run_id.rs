@@ -0,0 +1,130 @@
+//! Every run gets an ID of its own, such that the messages, reports and logs of overlapping or
+//! resumed runs can be told apart.
+
+use crate::{Message, MessageSender};
+
+/// ID of a single call of [`crate::run`]. Messages which are sent outside of a run carry the
+/// nil ID, which is also the default.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(transparent)]
+pub struct RunId(uuid::Uuid);
+
+impl RunId {
+    #[must_use]
+    pub fn new() -> Self {
+        Self(uuid::Uuid::new_v4())
+    }
+}
+
+impl std::fmt::Display for RunId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.hyphenated())
+    }
+}
+
+impl std::str::FromStr for RunId {
+    type Err = uuid::Error;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        uuid::Uuid::parse_str(id).map(Self)
+    }
+}
+
+/// Hands every message of a run to the sender of the frontend together with the ID of the run.
+pub struct RunSender<'a, S> {
+    run_id: RunId,
+    message_sender: &'a S,
+}
+
+impl<'a, S: MessageSender> RunSender<'a, S> {
+    pub fn new(run_id: RunId, message_sender: &'a S) -> Self {
+        Self {
+            run_id,
+            message_sender,
+        }
+    }
+}
+
+impl<S: MessageSender> MessageSender for RunSender<'_, S> {
+    fn send(&self, message: Message) {
+        self.message_sender.send_from_run(self.run_id, message);
+    }
+
+    fn compared(&self, duration: std::time::Duration) {
+        self.message_sender.compared(duration);
+    }
+}
+
+/// Sends every message through a channel together with the ID of the run which sent it, e.g.
+/// to a frontend which shows several runs at once.
+#[derive(Debug, Clone)]
+pub struct TaggedSender(pub tokio::sync::mpsc::UnboundedSender<(RunId, Message)>);
+
+impl MessageSender for TaggedSender {
+    fn send(&self, message: Message) {
+        self.0.send((RunId::default(), message)).ok();
+    }
+
+    fn send_from_run(&self, run_id: RunId, message: Message) {
+        self.0.send((run_id, message)).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_run_id() {
+        let run_id = RunId::new();
+        assert_ne!(run_id, RunId::default());
+        assert_eq!(run_id.to_string().parse::<RunId>().unwrap(), run_id);
+        assert!("not an id".parse::<RunId>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_messages_carry_run_id() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::write(source.path().join("file.txt"), b"content").unwrap();
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let sync = || crate::Command::Sync {
+            source_root: source.path().to_owned(),
+            destination_root: destination.path().to_owned(),
+            options: crate::BackupOptions::default(),
+        };
+
+        crate::run(sync(), TaggedSender(message_sender.clone()))
+            .await
+            .unwrap();
+        crate::run(sync(), TaggedSender(message_sender))
+            .await
+            .unwrap();
+
+        let mut run_ids = vec![];
+        let mut reports = vec![];
+        while let Some((run_id, message)) = message_receiver.recv().await {
+            assert_ne!(run_id, RunId::default());
+            match message {
+                Message::Info(crate::Info::RunStarted(started)) => {
+                    assert_eq!(started, run_id);
+                    run_ids.push(run_id);
+                }
+                Message::Info(crate::Info::Report(report)) => {
+                    assert_eq!(report.run_id, run_id);
+                    reports.push(run_id);
+                }
+                _ => assert_eq!(Some(&run_id), run_ids.last()),
+            }
+        }
+        assert_eq!(run_ids.len(), 2);
+        assert_ne!(run_ids[0], run_ids[1]);
+        assert_eq!(reports, run_ids);
+        assert_eq!(
+            crate::Manifest::load(destination.path()).run_id(),
+            Some(run_ids[1])
+        );
+    }
+}
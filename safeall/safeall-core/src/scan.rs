@@ -0,0 +1,141 @@
+//! Number and size of the files a run would look at, computed up front for previews.
+
+use crate::{
+    Error, Increment, Message, MessageSender, ProcessPathError, Progress, ProgressType,
+    ReadDirType, RecursiveReadDir,
+};
+
+/// Number of entries between two messages while counting.
+const PROGRESS_INTERVAL: usize = 1000;
+
+/// Reports the progress of counting the entries of a source before they are processed,
+/// which takes minutes on large trees.
+pub struct ScanProgress<'a, S: MessageSender> {
+    message_sender: &'a S,
+    entries: usize,
+}
+
+impl<'a, S: MessageSender> ScanProgress<'a, S> {
+    pub fn start(message_sender: &'a S) -> Self {
+        // NOTE: The total is what is being counted, so it is not known yet
+        message_sender.send(Message::Progress(Progress::Start(
+            0,
+            ProgressType::Scanning,
+        )));
+        Self {
+            message_sender,
+            entries: 0,
+        }
+    }
+
+    pub fn entry(&mut self, entry: &Result<std::path::PathBuf, ProcessPathError>) {
+        self.entries += 1;
+        if !self.entries.is_multiple_of(PROGRESS_INTERVAL) {
+            return;
+        }
+        let path = match entry {
+            Ok(path) => Some(path),
+            Err(e) => e.not_processed.as_ref(),
+        };
+        if let Some(path) = path {
+            self.message_sender
+                .send(Message::Progress(Progress::IncrementSuccess(
+                    Increment::Scanned {
+                        path: path.clone(),
+                        entries: self.entries,
+                    },
+                )));
+        }
+    }
+
+    pub fn end(self) {
+        self.message_sender
+            .send(Message::Progress(Progress::EndSuccess(
+                ProgressType::Scanning,
+            )));
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScanSummary {
+    pub files: usize,
+    pub dirs: usize,
+    pub bytes: u64,
+}
+
+/// Walks `root` with the same filter as a run. Entries which cannot be read are not counted.
+pub fn summary(
+    root: &std::path::Path,
+    filter: &crate::filter::Filter,
+) -> Result<ScanSummary, Error> {
+    if let Ok(metadata) = std::fs::metadata(root)
+        && metadata.is_file()
+    {
+        return Ok(ScanSummary {
+            files: 1,
+            dirs: 0,
+            bytes: metadata.len(),
+        });
+    }
+    let read_dir = |readdir_type| {
+        RecursiveReadDir::try_new(root, readdir_type)
+            .map(|r| r.with_filter(filter.clone()))
+            .map_err(|e| Error::CannotReadDirectoryContent(root.to_owned(), e.to_string()))
+    };
+    let mut summary = ScanSummary {
+        dirs: read_dir(ReadDirType::DirectoriesOnly)?.flatten().count(),
+        ..ScanSummary::default()
+    };
+    for file in read_dir(ReadDirType::FilesOnly)?.flatten() {
+        summary.files += 1;
+        summary.bytes += std::fs::metadata(file).map_or(0, |metadata| metadata.len());
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_summary() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("nested/deeper")).unwrap();
+        std::fs::write(root.path().join("a.txt"), b"12345").unwrap();
+        std::fs::write(root.path().join("nested/b.txt"), b"123").unwrap();
+        std::fs::write(root.path().join("nested/c.log"), b"1234567").unwrap();
+
+        let everything = summary(root.path(), &crate::filter::Filter::default()).unwrap();
+        assert_eq!(
+            everything,
+            ScanSummary {
+                files: 3,
+                dirs: 2,
+                bytes: 15
+            }
+        );
+        let filter = crate::filter::Filter::try_new(&[], &["*.log".to_owned()], false).unwrap();
+        assert_eq!(summary(root.path(), &filter).unwrap().bytes, 8);
+    }
+
+    #[test]
+    fn test_scan_progress() {
+        let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut progress = ScanProgress::start(&message_sender);
+        for i in 0..2500 {
+            progress.entry(&Ok(i.to_string().into()));
+        }
+        progress.end();
+        let messages: Vec<_> = std::iter::from_fn(|| message_receiver.try_recv().ok()).collect();
+        assert_eq!(messages.len(), 4);
+        assert!(matches!(
+            &messages[2],
+            Message::Progress(Progress::IncrementSuccess(Increment::Scanned { path, entries: 2000 }))
+                if path == std::path::Path::new("1999")
+        ));
+        assert!(matches!(
+            messages[3],
+            Message::Progress(Progress::EndSuccess(ProgressType::Scanning))
+        ));
+    }
+}
@@ -0,0 +1,376 @@
+//! Listings of directories from previous runs, such that unchanged directories do not have to
+//! be read again.
+//!
+//! A listing is only used while its directory has the modification time it had when it was
+//! read, which changes whenever an entry is added, removed or renamed. Writing to a file does
+//! not change it, so only the names and types of the entries are cached.
+
+const SCAN_CACHE_FILE: &str = "listings";
+
+/// Directories modified this recently are not cached, as further changes within the
+/// resolution of the modification time would go unnoticed.
+const RACY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+tokio::task_local! {
+    static CACHE: std::sync::Arc<ScanCache>;
+}
+
+/// Type of a directory entry without following symbolic links.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Directory,
+    File,
+    Symlink,
+    /// FIFOs, sockets and device nodes.
+    Special,
+    /// The type could not be determined while listing the directory.
+    Unknown,
+}
+
+impl Kind {
+    fn from_file_type(file_type: Option<std::fs::FileType>) -> Self {
+        match file_type {
+            Some(file_type) if file_type.is_symlink() => Kind::Symlink,
+            Some(file_type) if file_type.is_dir() => Kind::Directory,
+            Some(file_type) if crate::special_files::is_special(file_type) => Kind::Special,
+            Some(_) => Kind::File,
+            None => Kind::Unknown,
+        }
+    }
+
+    fn to_char(self) -> char {
+        match self {
+            Kind::Directory => 'd',
+            Kind::File => 'f',
+            Kind::Symlink => 'l',
+            Kind::Special => 's',
+            Kind::Unknown => 'u',
+        }
+    }
+
+    fn from_char(c: char) -> Option<Self> {
+        match c {
+            'd' => Some(Kind::Directory),
+            'f' => Some(Kind::File),
+            'l' => Some(Kind::Symlink),
+            's' => Some(Kind::Special),
+            'u' => Some(Kind::Unknown),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Listing {
+    modified: u128,
+    entries: Vec<(String, Kind)>,
+}
+
+#[derive(Debug, Default)]
+struct Listings {
+    /// Listings of the previous runs which have not been used yet.
+    previous: std::collections::HashMap<std::path::PathBuf, Listing>,
+    /// Listings which have been used or read during this run, the only ones which are saved.
+    current: std::collections::HashMap<std::path::PathBuf, Listing>,
+}
+
+#[derive(Debug)]
+pub struct ScanCache {
+    root: std::path::PathBuf,
+    listings: std::sync::Mutex<Listings>,
+}
+
+fn cache_path(root: &std::path::Path) -> std::path::PathBuf {
+    root.join(crate::METADATA_DIRECTORY).join(SCAN_CACHE_FILE)
+}
+
+/// Modification time of `directory` in nanoseconds.
+fn modified(directory: &std::path::Path) -> Option<u128> {
+    Some(
+        std::fs::metadata(directory)
+            .ok()?
+            .modified()
+            .ok()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()?
+            .as_nanos(),
+    )
+}
+
+/// Parses lines of the form `# <modified> <directory>`, each followed by one line of the form
+/// `<kind> <name>` per entry.
+fn parse(content: &str) -> std::collections::HashMap<std::path::PathBuf, Listing> {
+    let mut listings = std::collections::HashMap::new();
+    let mut current: Option<(std::path::PathBuf, Listing)> = None;
+    for line in content.lines() {
+        if let Some(header) = line.strip_prefix("# ") {
+            listings.extend(current.take());
+            current = header.split_once(' ').and_then(|(modified, directory)| {
+                Some((
+                    crate::manifest::unescape(directory),
+                    Listing {
+                        modified: modified.parse().ok()?,
+                        entries: vec![],
+                    },
+                ))
+            });
+        } else if let Some((_, listing)) = &mut current {
+            let mut chars = line.chars();
+            match (chars.next().and_then(Kind::from_char), chars.next()) {
+                (Some(kind), Some(' ')) => {
+                    let name = crate::manifest::unescape(chars.as_str());
+                    listing
+                        .entries
+                        .push((name.to_string_lossy().into_owned(), kind));
+                }
+                // NOTE: A listing with an invalid entry would miss files
+                _ => current = None,
+            }
+        }
+    }
+    listings.extend(current);
+    listings
+}
+
+impl ScanCache {
+    /// Loads the listings saved in the destination at `root`. Invalid listings are skipped.
+    pub fn load(root: &std::path::Path) -> Self {
+        let previous = std::fs::read_to_string(cache_path(root))
+            .map(|content| parse(&content))
+            .unwrap_or_default();
+        Self {
+            root: root.to_owned(),
+            listings: std::sync::Mutex::new(Listings {
+                previous,
+                current: std::collections::HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn root(&self) -> &std::path::Path {
+        &self.root
+    }
+
+    fn listings(&self) -> std::sync::MutexGuard<'_, Listings> {
+        self.listings.lock().expect("Lock is never poisoned")
+    }
+
+    /// The entries of `directory` if it has not been modified since it was listed.
+    fn entries(&self, directory: &std::path::Path) -> Option<Vec<(String, Kind)>> {
+        let modified = modified(directory)?;
+        let mut listings = self.listings();
+        if let Some(listing) = listings.previous.remove(directory) {
+            listings.current.insert(directory.to_owned(), listing);
+        }
+        let listing = listings.current.get(directory)?;
+        (listing.modified == modified).then(|| listing.entries.clone())
+    }
+
+    fn store(&self, directory: &std::path::Path, listing: Listing) {
+        self.listings()
+            .current
+            .insert(directory.to_owned(), listing);
+    }
+
+    /// Writes the listings which have been used or read during this run.
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        let listings = self.listings();
+        let mut directories: Vec<_> = listings.current.iter().collect();
+        directories.sort_by_key(|(directory, _)| *directory);
+        let mut lines = vec![];
+        for (directory, listing) in directories {
+            lines.push(format!(
+                "# {} {}",
+                listing.modified,
+                crate::manifest::escape(directory)
+            ));
+            lines.extend(listing.entries.iter().map(|(name, kind)| {
+                format!(
+                    "{} {}",
+                    kind.to_char(),
+                    crate::manifest::escape(std::path::Path::new(name))
+                )
+            }));
+        }
+        drop(listings);
+        let mut content = lines.join("\n");
+        content.push('\n');
+        let path = cache_path(&self.root);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, content)
+    }
+}
+
+/// Runs `future` such that directory traversals use `cache`.
+pub async fn scope<F: Future>(cache: Option<std::sync::Arc<ScanCache>>, future: F) -> F::Output {
+    match cache {
+        Some(cache) => CACHE.scope(cache, future).await,
+        None => future.await,
+    }
+}
+
+/// The cache of the running command. Traversals started on blocking threads do not use it.
+pub fn current() -> Option<std::sync::Arc<ScanCache>> {
+    CACHE.try_with(std::sync::Arc::clone).ok()
+}
+
+/// A listing which is recorded while the directory is read, to be stored once it is complete.
+#[derive(Debug)]
+pub struct Recording {
+    cache: std::sync::Arc<ScanCache>,
+    listing: Listing,
+}
+
+/// Entries of a directory, either read from the filesystem or from the cache.
+#[derive(Debug)]
+pub enum Entries {
+    Read {
+        directory: std::path::PathBuf,
+        read_dir: std::fs::ReadDir,
+        recording: Option<Recording>,
+    },
+    Cached {
+        directory: std::path::PathBuf,
+        entries: std::vec::IntoIter<(String, Kind)>,
+    },
+}
+
+impl Entries {
+    /// A listing of `directory` without entries, for a traversal which does not read it.
+    pub fn empty(directory: &std::path::Path) -> Self {
+        Entries::Cached {
+            directory: directory.to_owned(),
+            entries: vec![].into_iter(),
+        }
+    }
+
+    /// Lists `directory` from `cache` if it is unchanged, otherwise with `read_dir`, in which
+    /// case the listing is stored in `cache` once all entries have been read.
+    pub fn open(
+        directory: &std::path::Path,
+        cache: Option<&std::sync::Arc<ScanCache>>,
+        read_dir: impl FnOnce(&std::path::Path) -> std::io::Result<std::fs::ReadDir>,
+    ) -> std::io::Result<Self> {
+        if let Some(entries) = cache.and_then(|cache| cache.entries(directory)) {
+            return Ok(Entries::Cached {
+                directory: directory.to_owned(),
+                entries: entries.into_iter(),
+            });
+        }
+        // NOTE: The modification time is taken before reading, such that changes while
+        // reading invalidate the listing
+        let recording = cache.and_then(|cache| {
+            let modified = modified(directory)?;
+            let racy = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .ok()?
+                .as_nanos()
+                .saturating_sub(modified)
+                < RACY_INTERVAL.as_nanos();
+            (!racy).then(|| Recording {
+                cache: std::sync::Arc::clone(cache),
+                listing: Listing {
+                    modified,
+                    entries: vec![],
+                },
+            })
+        });
+        Ok(Entries::Read {
+            directory: directory.to_owned(),
+            read_dir: read_dir(directory)?,
+            recording,
+        })
+    }
+}
+
+impl Iterator for Entries {
+    type Item = std::io::Result<(std::path::PathBuf, Kind)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Entries::Read {
+                directory,
+                read_dir,
+                recording,
+            } => match read_dir.next() {
+                Some(Ok(entry)) => {
+                    let kind = Kind::from_file_type(entry.file_type().ok());
+                    if let Some(current) = recording {
+                        match entry.file_name().into_string() {
+                            Ok(name) => current.listing.entries.push((name, kind)),
+                            // NOTE: Names which are not valid Unicode cannot be saved
+                            Err(_) => *recording = None,
+                        }
+                    }
+                    Some(Ok((entry.path(), kind)))
+                }
+                Some(Err(error)) => {
+                    *recording = None;
+                    Some(Err(error))
+                }
+                None => {
+                    if let Some(Recording { cache, listing }) = recording.take() {
+                        cache.store(directory, listing);
+                    }
+                    None
+                }
+            },
+            Entries::Cached { directory, entries } => entries
+                .next()
+                .map(|(name, kind)| Ok((directory.join(name), kind))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_cache() {
+        let root = tempfile::tempdir().unwrap();
+        let directory = root.path().join("directory");
+        std::fs::create_dir_all(directory.join("nested")).unwrap();
+        std::fs::write(directory.join("file"), b"content").unwrap();
+        let modified = std::fs::metadata(&directory).unwrap().modified().unwrap();
+        let set_modified = |modified| {
+            std::fs::File::open(&directory)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+        // NOTE: Recently modified directories are not cached
+        set_modified(modified - std::time::Duration::from_mins(1));
+        let list = |cache: &std::sync::Arc<ScanCache>| {
+            let mut entries: Vec<_> =
+                Entries::open(&directory, Some(cache), |d| std::fs::read_dir(d))
+                    .unwrap()
+                    .map(Result::unwrap)
+                    .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            entries
+        };
+        let expected = vec![
+            (directory.join("file"), Kind::File),
+            (directory.join("nested"), Kind::Directory),
+        ];
+
+        let cache = std::sync::Arc::new(ScanCache::load(root.path()));
+        assert_eq!(list(&cache), expected);
+        cache.save().unwrap();
+
+        let cache = std::sync::Arc::new(ScanCache::load(root.path()));
+        let cached = Entries::open(&directory, Some(&cache), |_| {
+            panic!("Directory must be listed from the cache")
+        })
+        .unwrap();
+        assert!(matches!(cached, Entries::Cached { .. }));
+        assert_eq!(list(&cache), expected);
+
+        std::fs::write(directory.join("new"), b"new").unwrap();
+        set_modified(modified + std::time::Duration::from_mins(1));
+        assert_eq!(list(&cache).len(), 3);
+    }
+}
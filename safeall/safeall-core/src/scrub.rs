@@ -0,0 +1,261 @@
+//! Integrity check of a destination against the hashes in its manifest, which does not need
+//! the source. Catches bit rot on drives which are only used for archiving.
+
+use crate::{
+    BackupOptions, Error, Increment, Message, MessageSender, Progress, ProgressType, Warning,
+};
+
+/// Files of a destination whose content does not match the manifest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// Number of files which have been read and hashed.
+    pub scrubbed: usize,
+    /// Files with the size and modification time of the manifest but a different content.
+    pub corrupted: Vec<std::path::PathBuf>,
+    /// Files of the manifest which are not in the destination anymore.
+    pub missing: Vec<std::path::PathBuf>,
+    /// Files whose size or modification time differs from the manifest, which means they have
+    /// been changed on purpose and cannot be checked.
+    pub modified: Vec<std::path::PathBuf>,
+    /// Files which could not be read, see the warnings for the reason.
+    pub unreadable: Vec<std::path::PathBuf>,
+    /// Corrupted files which have been copied from the source again.
+    pub repaired: Vec<std::path::PathBuf>,
+}
+
+impl ScrubReport {
+    /// Whether every file of the manifest is intact.
+    #[must_use]
+    pub fn is_intact(&self) -> bool {
+        self.corrupted.is_empty() && self.missing.is_empty() && self.unreadable.is_empty()
+    }
+}
+
+impl std::fmt::Display for ScrubReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Scrubbed {} files: {} are corrupted, {} are missing, {} have been modified and {} could not be read.",
+            self.scrubbed,
+            self.corrupted.len(),
+            self.missing.len(),
+            self.modified.len(),
+            self.unreadable.len()
+        )?;
+        if !self.repaired.is_empty() {
+            write!(f, " Repaired {} files.", self.repaired.len())?;
+        }
+        for path in &self.corrupted {
+            write!(f, "\n  Corrupted: \"{}\"", path.display())?;
+        }
+        for path in &self.missing {
+            write!(f, "\n  Missing: \"{}\"", path.display())?;
+        }
+        for path in &self.modified {
+            write!(f, "\n  Modified: \"{}\"", path.display())?;
+        }
+        for path in &self.unreadable {
+            write!(f, "\n  Unreadable: \"{}\"", path.display())?;
+        }
+        for path in &self.repaired {
+            write!(f, "\n  Repaired: \"{}\"", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Intact,
+    Corrupted,
+    Missing,
+    Modified,
+}
+
+fn check(path: &std::path::Path, record: &crate::FileRecord) -> std::io::Result<Outcome> {
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Outcome::Missing),
+        Err(e) => return Err(e),
+    };
+    if metadata.len() != record.size || crate::manifest::modified(&metadata) != record.modified {
+        return Ok(Outcome::Modified);
+    }
+    // NOTE: Best effort, a file which is still in memory would hide bit rot on the disk
+    crate::copier::drop_from_page_cache(path).ok();
+    if Some(crate::comparator::hash_file(path)?) == record.hash {
+        Ok(Outcome::Intact)
+    } else {
+        Ok(Outcome::Corrupted)
+    }
+}
+
+/// Hashes every file of the manifest of the destination and lists the files which differ.
+pub async fn scrub(
+    destination_root: &std::path::Path,
+    message_sender: &impl MessageSender,
+) -> Result<ScrubReport, Error> {
+    use futures::stream::StreamExt;
+
+    if !destination_root.is_dir() {
+        return Err(Error::RootDestinatinIsNotADirectory(
+            destination_root.to_owned(),
+        ));
+    }
+    let manifest = crate::Manifest::load(destination_root);
+    // NOTE: Manifests of older versions and files which could not be read during the backup
+    // have no hash
+    let mut files: Vec<_> = manifest
+        .records()
+        .filter_map(|(relative_path, record)| {
+            Some((destination_root.join(relative_path), record?.clone()))
+        })
+        .filter(|(_, record)| record.hash.is_some())
+        .collect();
+    if files.is_empty() {
+        return Err(Error::NoManifest(destination_root.to_owned()));
+    }
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    message_sender.send(Message::Progress(Progress::Start(
+        files.len(),
+        ProgressType::Scrubbing,
+    )));
+    message_sender.send(Message::Progress(Progress::TotalBytes(
+        files.iter().map(|(_, record)| record.size).sum(),
+        ProgressType::Scrubbing,
+    )));
+    let results: Vec<_> = futures::stream::iter(files)
+        .map(async |(path, record)| {
+            let owned_path = path.clone();
+            let bytes = record.size;
+            let outcome = tokio::task::spawn_blocking(move || check(&owned_path, &record))
+                .await
+                .map_err(std::io::Error::other)
+                .flatten();
+            message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                Increment::Hashed {
+                    source: path.clone(),
+                    bytes,
+                },
+            )));
+            (path, outcome)
+        })
+        .buffered(crate::cpu_count())
+        .collect()
+        .await;
+    let mut report = ScrubReport::default();
+    for (path, outcome) in results {
+        match outcome {
+            Ok(Outcome::Intact) => report.scrubbed += 1,
+            Ok(Outcome::Corrupted) => {
+                report.scrubbed += 1;
+                report.corrupted.push(path);
+            }
+            Ok(Outcome::Missing) => report.missing.push(path),
+            Ok(Outcome::Modified) => report.modified.push(path),
+            Err(e) => {
+                message_sender.send(Message::Warning(Warning::CannotReadBackup {
+                    path: path.clone(),
+                    io_error: e.to_string(),
+                }));
+                report.unreadable.push(path);
+            }
+        }
+    }
+    message_sender.send(Message::Progress(Progress::EndSuccess(
+        ProgressType::Scrubbing,
+    )));
+    Ok(report)
+}
+
+/// Copies corrupted files from the source again, but only sources which still have the hash
+/// of the manifest, as a changed source is not what has been backed up.
+pub async fn repair(
+    report: &mut ScrubReport,
+    source_root: &std::path::Path,
+    destination_root: &std::path::Path,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) {
+    let manifest = crate::Manifest::load(destination_root);
+    let mut files = vec![];
+    for destination in &report.corrupted {
+        let Ok(relative_path) = destination.strip_prefix(destination_root) else {
+            continue;
+        };
+        let Some(expected) = manifest
+            .record(relative_path)
+            .and_then(|record| record.hash)
+        else {
+            continue;
+        };
+        let source = source_root.join(relative_path);
+        let owned_source = source.clone();
+        let hash = tokio::task::spawn_blocking(move || crate::comparator::hash_file(&owned_source))
+            .await
+            .map_err(std::io::Error::other)
+            .flatten();
+        if hash.is_ok_and(|hash| hash == expected) {
+            files.push((source, destination.clone()));
+        } else {
+            message_sender.send(Message::Warning(Warning::SourceChangedSinceBackup {
+                source,
+                destination: destination.clone(),
+            }));
+        }
+    }
+    let repaired = crate::repair_files(files, options, message_sender).await;
+    report
+        .corrupted
+        .retain(|destination| !repaired.contains(destination));
+    report.repaired.extend(repaired);
+}
+
+/// Reconstructs corrupted files from their parity, see [`BackupOptions::parity`].
+pub async fn reconstruct(
+    report: &mut ScrubReport,
+    destination_root: &std::path::Path,
+    message_sender: &impl MessageSender,
+) {
+    let mut corrupted = vec![];
+    for destination in std::mem::take(&mut report.corrupted) {
+        let Ok(relative_path) = destination.strip_prefix(destination_root) else {
+            corrupted.push(destination);
+            continue;
+        };
+        let parity_file = crate::parity::parity_path(destination_root, relative_path);
+        if !parity_file.is_file() {
+            corrupted.push(destination);
+            continue;
+        }
+        let (owned_destination, owned_parity_file) = (destination.clone(), parity_file.clone());
+        let reconstruction = tokio::task::spawn_blocking(move || {
+            crate::parity::reconstruct(&owned_destination, &owned_parity_file)
+        })
+        .await
+        .map_err(std::io::Error::other)
+        .flatten();
+        match reconstruction {
+            Ok(crate::parity::Reconstruction::Repaired { .. }) => {
+                message_sender.send(Message::Info(crate::Info::Repaired {
+                    source: parity_file,
+                    destination: destination.clone(),
+                }));
+                report.repaired.push(destination);
+            }
+            Ok(
+                crate::parity::Reconstruction::Intact
+                | crate::parity::Reconstruction::Unrecoverable,
+            ) => corrupted.push(destination),
+            Err(e) => {
+                message_sender.send(Message::Warning(Warning::CannotReadBackup {
+                    path: parity_file,
+                    io_error: e.to_string(),
+                }));
+                corrupted.push(destination);
+            }
+        }
+    }
+    report.corrupted = corrupted;
+}
@@ -0,0 +1,89 @@
+//! The largest file which the filesystem of a destination can store. Most filesystems store
+//! files of any practical size, but FAT, which many USB sticks and SD cards still come with,
+//! stops at 4 GiB. Copying a larger file fails only once the limit is reached, with an error
+//! which does not say why.
+
+/// The largest file FAT12, FAT16 and FAT32 can store, one byte less than 4 GiB.
+pub const FAT_MAX_FILE_SIZE: u64 = u32::MAX as u64;
+
+/// The largest file the filesystem of `directory` can store, `None` if it has no limit which
+/// is known to matter.
+#[cfg(target_os = "linux")]
+pub fn max_file_size(directory: &std::path::Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(directory.as_os_str().as_bytes()).ok()?;
+    // SAFETY: `statfs` is plain data which is valid when zeroed
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: The path is a valid nul terminated string and `stat` is a valid pointer
+    if unsafe { libc::statfs(path.as_ptr(), &raw mut stat) } != 0 {
+        return None;
+    }
+    (stat.f_type == libc::MSDOS_SUPER_MAGIC).then_some(FAT_MAX_FILE_SIZE)
+}
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+pub fn max_file_size(directory: &std::path::Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(directory.as_os_str().as_bytes()).ok()?;
+    // SAFETY: `statfs` is plain data which is valid when zeroed
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: The path is a valid nul terminated string and `stat` is a valid pointer
+    if unsafe { libc::statfs(path.as_ptr(), &raw mut stat) } != 0 {
+        return None;
+    }
+    // SAFETY: The kernel terminates the name of the filesystem type with nul
+    let name = unsafe { std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    // NOTE: "msdos" on macOS and "msdosfs" on FreeBSD
+    name.to_bytes()
+        .starts_with(b"msdos")
+        .then_some(FAT_MAX_FILE_SIZE)
+}
+
+#[cfg(windows)]
+pub fn max_file_size(directory: &std::path::Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    const LENGTH: u32 = 261;
+
+    let path: Vec<u16> = directory
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+    let mut volume = [0u16; LENGTH as usize];
+    let mut filesystem = [0u16; LENGTH as usize];
+    // SAFETY: The path is a valid nul terminated wide string and the buffers are as long as
+    // passed
+    let found = unsafe {
+        windows_sys::Win32::Storage::FileSystem::GetVolumePathNameW(
+            path.as_ptr(),
+            volume.as_mut_ptr(),
+            LENGTH,
+        ) != 0
+            && windows_sys::Win32::Storage::FileSystem::GetVolumeInformationW(
+                volume.as_ptr(),
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                filesystem.as_mut_ptr(),
+                LENGTH,
+            ) != 0
+    };
+    let length = filesystem.iter().position(|c| *c == 0)?;
+    let filesystem = String::from_utf16_lossy(&filesystem[..length]);
+    // NOTE: "FAT" and "FAT32", exFAT stores files of any practical size
+    (found && filesystem.starts_with("FAT")).then_some(FAT_MAX_FILE_SIZE)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "freebsd",
+    windows
+)))]
+pub fn max_file_size(_directory: &std::path::Path) -> Option<u64> {
+    None
+}
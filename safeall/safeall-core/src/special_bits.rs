@@ -0,0 +1,99 @@
+//! Setuid, setgid and sticky bits and Linux file capabilities, which can only be kept if the
+//! process has the privileges to set them, e.g. when backing up system directories as root.
+
+#[cfg(unix)]
+const SPECIAL_BITS: u32 = 0o7000;
+
+#[cfg(all(target_os = "linux", feature = "xattr"))]
+const CAPABILITY_ATTRIBUTE: &str = "security.capability";
+
+/// Sets the special bits and the capabilities of `source` on `destination` and returns the
+/// names of those which had to be dropped.
+#[cfg(unix)]
+pub fn preserve(
+    source: &std::path::Path,
+    destination: &std::path::Path,
+) -> std::io::Result<Vec<&'static str>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut dropped = vec![];
+    let wanted = std::fs::metadata(source)?.permissions().mode() & SPECIAL_BITS;
+    let destination_mode = std::fs::metadata(destination)?.permissions().mode();
+    if wanted != 0 && destination_mode & SPECIAL_BITS != wanted {
+        let mode = destination_mode & !SPECIAL_BITS | wanted;
+        // NOTE: Without privileges the bits are either refused or silently cleared
+        std::fs::set_permissions(destination, std::fs::Permissions::from_mode(mode)).ok();
+        let kept = std::fs::metadata(destination)?.permissions().mode() & SPECIAL_BITS;
+        for (bit, name) in [
+            (0o4000, "setuid bit"),
+            (0o2000, "setgid bit"),
+            (0o1000, "sticky bit"),
+        ] {
+            if wanted & bit != 0 && kept & bit == 0 {
+                dropped.push(name);
+            }
+        }
+    }
+    dropped.extend(preserve_capabilities(source, destination)?);
+    Ok(dropped)
+}
+
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+pub fn preserve(
+    _source: &std::path::Path,
+    _destination: &std::path::Path,
+) -> std::io::Result<Vec<&'static str>> {
+    Ok(vec![])
+}
+
+#[cfg(all(target_os = "linux", feature = "xattr"))]
+fn preserve_capabilities(
+    source: &std::path::Path,
+    destination: &std::path::Path,
+) -> std::io::Result<Option<&'static str>> {
+    let Some(capabilities) = xattr::get(source, CAPABILITY_ATTRIBUTE)? else {
+        return Ok(None);
+    };
+    if xattr::get(destination, CAPABILITY_ATTRIBUTE)?.as_ref() == Some(&capabilities) {
+        return Ok(None);
+    }
+    Ok(xattr::set(destination, CAPABILITY_ATTRIBUTE, &capabilities)
+        .is_err()
+        .then_some("file capabilities"))
+}
+
+#[cfg(all(unix, not(all(target_os = "linux", feature = "xattr"))))]
+#[allow(clippy::unnecessary_wraps)]
+fn preserve_capabilities(
+    _source: &std::path::Path,
+    _destination: &std::path::Path,
+) -> std::io::Result<Option<&'static str>> {
+    Ok(None)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preserve_special_bits() {
+        use std::os::unix::fs::PermissionsExt;
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let destination = directory.path().join("destination");
+        std::fs::write(&source, b"program").unwrap();
+        std::fs::write(&destination, b"program").unwrap();
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o4755)).unwrap();
+        std::fs::set_permissions(&destination, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let dropped = preserve(&source, &destination).unwrap();
+
+        let mode = std::fs::metadata(&destination)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(dropped.is_empty(), mode & 0o4000 != 0);
+        assert_eq!(mode & 0o777, 0o755);
+    }
+}
@@ -0,0 +1,74 @@
+//! FIFOs, sockets and device nodes, which cannot be copied like regular files: reading a FIFO
+//! blocks until someone writes to it and reading a device copies the device instead.
+
+/// Whether `file_type` is neither a regular file, a directory nor a symbolic link.
+#[cfg(unix)]
+pub fn is_special(file_type: std::fs::FileType) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    file_type.is_fifo()
+        || file_type.is_socket()
+        || file_type.is_block_device()
+        || file_type.is_char_device()
+}
+
+#[cfg(not(unix))]
+pub fn is_special(_file_type: std::fs::FileType) -> bool {
+    false
+}
+
+/// Creates a special file of the same type, permissions and device number as `source`
+/// at `destination`, replacing what is there unless it is already the same.
+#[cfg(unix)]
+pub fn recreate(source: &std::path::Path, destination: &std::path::Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = std::fs::metadata(source)?;
+    if let Ok(existing) = std::fs::symlink_metadata(destination) {
+        if existing.mode() == metadata.mode() && existing.rdev() == metadata.rdev() {
+            return Ok(());
+        }
+        std::fs::remove_file(destination)?;
+    }
+    let path = std::ffi::CString::new(destination.as_os_str().as_bytes())
+        .map_err(std::io::Error::other)?;
+    #[allow(clippy::useless_conversion, clippy::cast_possible_truncation)]
+    let (mode, device) = (
+        libc::mode_t::try_from(metadata.mode()).map_err(std::io::Error::other)?,
+        metadata.rdev() as libc::dev_t,
+    );
+    // NOTE: Device nodes can only be created with `CAP_MKNOD`, FIFOs and sockets by everyone
+    // SAFETY: The path is a valid nul terminated string
+    if unsafe { libc::mknod(path.as_ptr(), mode, device) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn recreate(_source: &std::path::Path, _destination: &std::path::Path) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recreate_fifo() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let destination = directory.path().join("destination");
+        let path = std::ffi::CString::new(source.to_str().unwrap()).unwrap();
+        // SAFETY: The path is a valid nul terminated string
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o640) }, 0);
+        std::fs::write(&destination, b"regular file").unwrap();
+
+        recreate(&source, &destination).unwrap();
+        recreate(&source, &destination).unwrap();
+
+        let file_type = std::fs::symlink_metadata(&destination).unwrap().file_type();
+        assert!(is_special(file_type));
+        assert!(is_special(std::fs::metadata(&source).unwrap().file_type()));
+    }
+}
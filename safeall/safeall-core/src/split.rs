@@ -0,0 +1,337 @@
+//! Files which are larger than the destination can store, backed up in parts which it can.
+//! The backup of `<name>` consists of the parts `<name>.safeall-part-0001`, `-0002` and so on
+//! and the descriptor `<name>.safeall-split` with the length, modification time and hash of
+//! the whole file. Restores and verifies join the parts again.
+
+use std::io::{Read, Write};
+
+const DESCRIPTOR_SUFFIX: &str = ".safeall-split";
+const PART_INFIX: &str = ".safeall-part-";
+const HEADER: &str = "# safeall split file 1";
+
+/// What the descriptor records about the whole file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Descriptor {
+    pub length: u64,
+    /// Modification time in nanoseconds, see [`crate::manifest::modified`].
+    pub modified: u128,
+    pub part_size: u64,
+    pub parts: u64,
+    pub hash: blake3::Hash,
+}
+
+fn with_suffix(path: &std::path::Path, suffix: &str) -> std::path::PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Where the descriptor of the backup of `file` is stored.
+pub fn descriptor_path(file: &std::path::Path) -> std::path::PathBuf {
+    with_suffix(file, DESCRIPTOR_SUFFIX)
+}
+
+fn part_path(file: &std::path::Path, number: u64) -> std::path::PathBuf {
+    with_suffix(file, &format!("{PART_INFIX}{number:04}"))
+}
+
+/// Whether `path` is named like the descriptor of a split file.
+pub fn is_descriptor(path: &std::path::Path) -> bool {
+    path.file_name().is_some_and(|name| {
+        name.as_encoded_bytes()
+            .ends_with(DESCRIPTOR_SUFFIX.as_bytes())
+    })
+}
+
+/// The file whose backup `path` is the descriptor or a part of, judging by its name.
+pub fn original(path: &std::path::Path) -> Option<std::path::PathBuf> {
+    let name = path.file_name()?.as_encoded_bytes();
+    let original = if let Some(original) = name.strip_suffix(DESCRIPTOR_SUFFIX.as_bytes()) {
+        original
+    } else {
+        let position = name
+            .windows(PART_INFIX.len())
+            .rposition(|window| window == PART_INFIX.as_bytes())?;
+        let number = &name[position + PART_INFIX.len()..];
+        if number.len() < 4 || !number.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        &name[..position]
+    };
+    if original.is_empty() {
+        return None;
+    }
+    // SAFETY: The name is cut right before ASCII characters, which is a valid boundary
+    let original = unsafe { std::ffi::OsStr::from_encoded_bytes_unchecked(original) };
+    Some(path.with_file_name(original))
+}
+
+impl Descriptor {
+    /// The descriptor of the backup of `file`, `None` if it has not been split.
+    pub fn read(file: &std::path::Path) -> std::io::Result<Option<Self>> {
+        match std::fs::read_to_string(descriptor_path(file)) {
+            Ok(descriptor) => Self::parse(&descriptor).map(Some).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Invalid descriptor of a split file",
+                )
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn parse(descriptor: &str) -> Option<Self> {
+        let mut lines = descriptor.lines();
+        if lines.next()? != HEADER {
+            return None;
+        }
+        let fields: std::collections::HashMap<_, _> =
+            lines.filter_map(|line| line.split_once(' ')).collect();
+        Some(Self {
+            length: fields.get("length")?.parse().ok()?,
+            modified: fields.get("modified")?.parse().ok()?,
+            part_size: fields.get("part_size")?.parse().ok()?,
+            parts: fields.get("parts")?.parse().ok()?,
+            hash: blake3::Hash::from_hex(fields.get("hash")?).ok()?,
+        })
+    }
+
+    fn write(&self, file: &std::path::Path) -> std::io::Result<()> {
+        let path = descriptor_path(file);
+        let partial = with_suffix(&path, crate::STAGING_SUFFIX);
+        std::fs::write(
+            &partial,
+            format!(
+                "{HEADER}\nlength {}\nmodified {}\npart_size {}\nparts {}\nhash {}\n",
+                self.length,
+                self.modified,
+                self.part_size,
+                self.parts,
+                self.hash.to_hex()
+            ),
+        )?;
+        std::fs::rename(partial, path)
+    }
+
+    /// Whether the split file has the length and modification time of `metadata`.
+    pub fn matches(&self, metadata: &std::fs::Metadata) -> bool {
+        (self.length, self.modified) == (metadata.len(), crate::manifest::modified(metadata))
+    }
+
+    pub fn modified_time(&self) -> Option<std::time::SystemTime> {
+        let nanos = u64::try_from(self.modified).ok()?;
+        std::time::UNIX_EPOCH.checked_add(std::time::Duration::from_nanos(nanos))
+    }
+}
+
+/// Copies all of `reader` into `writer` and the hasher. Returns the copied bytes.
+fn copy_hashing(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    hasher: &mut blake3::Hasher,
+    buffer: &mut [u8],
+) -> std::io::Result<u64> {
+    let mut copied = 0;
+    loop {
+        let read = match reader.read(buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+        copied += read as u64;
+    }
+}
+
+fn remove_if_exists(path: &std::path::Path) -> std::io::Result<bool> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn remove_parts(file: &std::path::Path, first: u64) -> std::io::Result<()> {
+    let mut number = first;
+    while remove_if_exists(&part_path(file, number))? {
+        number += 1;
+    }
+    Ok(())
+}
+
+/// Removes the descriptor and the parts of the backup of `file`, if it has been split.
+pub fn remove(file: &std::path::Path) -> std::io::Result<()> {
+    if remove_if_exists(&descriptor_path(file))? {
+        remove_parts(file, 1)?;
+    }
+    Ok(())
+}
+
+/// Copies `source` into parts of at most `part_size` bytes next to `destination` and writes
+/// their descriptor. Parts of an earlier backup which are no longer needed are removed.
+pub fn split(
+    source: &std::path::Path,
+    destination: &std::path::Path,
+    part_size: u64,
+) -> std::io::Result<Descriptor> {
+    // NOTE: Parts without a descriptor are an incomplete backup which is split again
+    remove_if_exists(&descriptor_path(destination))?;
+    let mut file = std::fs::File::open(source)?;
+    let metadata = file.metadata()?;
+    let part_size = part_size.max(1);
+    let parts = metadata.len().div_ceil(part_size).max(1);
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0; crate::copier::DEFAULT_BUFFER_SIZE];
+    let mut length = 0;
+    for number in 1..=parts {
+        let mut part = std::fs::File::create(part_path(destination, number))?;
+        length += copy_hashing(
+            &mut (&mut file).take(part_size),
+            &mut part,
+            &mut hasher,
+            &mut buffer,
+        )?;
+    }
+    remove_parts(destination, parts + 1)?;
+    let descriptor = Descriptor {
+        length,
+        modified: crate::manifest::modified(&metadata),
+        part_size,
+        parts,
+        hash: hasher.finalize(),
+    };
+    descriptor.write(destination)?;
+    Ok(descriptor)
+}
+
+/// Reads the parts of the backup of `file` in order into `writer`. Returns the length and
+/// hash of what has been read.
+fn read_parts(
+    file: &std::path::Path,
+    descriptor: &Descriptor,
+    writer: &mut impl Write,
+) -> std::io::Result<(u64, blake3::Hash)> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0; crate::copier::DEFAULT_BUFFER_SIZE];
+    let mut length = 0;
+    for number in 1..=descriptor.parts {
+        let mut part = std::fs::File::open(part_path(file, number))?;
+        length += copy_hashing(&mut part, writer, &mut hasher, &mut buffer)?;
+    }
+    Ok((length, hasher.finalize()))
+}
+
+fn read_descriptor(file: &std::path::Path) -> std::io::Result<Descriptor> {
+    Descriptor::read(file)?.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))
+}
+
+/// Joins the parts of the backup of `file` into `target`. It is left as it is if the parts
+/// do not match the descriptor.
+pub fn join(file: &std::path::Path, target: &std::path::Path) -> std::io::Result<Descriptor> {
+    let descriptor = read_descriptor(file)?;
+    let partial = with_suffix(target, crate::STAGING_SUFFIX);
+    let joined = std::fs::File::create(&partial).and_then(|mut writer| {
+        if read_parts(file, &descriptor, &mut writer)? != (descriptor.length, descriptor.hash) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "The parts of the split file do not match its descriptor",
+            ));
+        }
+        writer.sync_all()
+    });
+    if let Err(e) = joined {
+        remove_if_exists(&partial)?;
+        return Err(e);
+    }
+    std::fs::rename(partial, target)?;
+    Ok(descriptor)
+}
+
+/// Whether the content of `source` differs from the split backup of `file`. All parts are
+/// read, so damage to any of them is found as well.
+pub fn differs(source: &std::path::Path, file: &std::path::Path) -> std::io::Result<bool> {
+    let descriptor = read_descriptor(file)?;
+    let (length, hash) = read_parts(file, &descriptor, &mut std::io::sink())?;
+    Ok((length, hash) != (descriptor.length, descriptor.hash)
+        || std::fs::metadata(source)?.len() != length
+        || crate::comparator::hash_file(source)? != hash)
+}
+
+/// The descriptor and the parts of the backup of `file`, none if it has not been split.
+pub fn artifacts(file: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let Ok(Some(descriptor)) = Descriptor::read(file) else {
+        return vec![];
+    };
+    std::iter::once(descriptor_path(file))
+        .chain((1..=descriptor.parts).map(|number| part_path(file, number)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_original() {
+        let original = |path: &str| original(std::path::Path::new(path));
+        assert_eq!(
+            original("a/image.iso.safeall-split"),
+            Some("a/image.iso".into())
+        );
+        assert_eq!(
+            original("a/image.iso.safeall-part-0012"),
+            Some("a/image.iso".into())
+        );
+        assert_eq!(
+            original("a/image.iso.safeall-part-12345"),
+            Some("a/image.iso".into())
+        );
+        assert_eq!(original("a/image.iso.safeall-part-12"), None);
+        assert_eq!(original("a/image.iso.safeall-part-00x1"), None);
+        assert_eq!(original("a/.safeall-split"), None);
+        assert_eq!(original("a/image.iso"), None);
+    }
+
+    #[test]
+    fn test_split_and_join() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("image.iso");
+        let destination = directory.path().join("backup.iso");
+        let content: Vec<u8> = (0..=255).cycle().take(2500).collect();
+        std::fs::write(&source, &content).unwrap();
+        // NOTE: Left over from an earlier backup with smaller parts
+        std::fs::write(part_path(&destination, 4), b"stale").unwrap();
+
+        let descriptor = split(&source, &destination, 1000).unwrap();
+
+        assert_eq!(descriptor.length, 2500);
+        assert_eq!(descriptor.parts, 3);
+        assert_eq!(Descriptor::read(&destination).unwrap(), Some(descriptor));
+        assert_eq!(
+            std::fs::read(part_path(&destination, 3)).unwrap().len(),
+            500
+        );
+        assert!(!part_path(&destination, 4).exists());
+        assert_eq!(artifacts(&destination).len(), 4);
+        assert!(!differs(&source, &destination).unwrap());
+
+        let target = directory.path().join("restored.iso");
+        join(&destination, &target).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), content);
+
+        std::fs::write(part_path(&destination, 2), vec![0; 1000]).unwrap();
+        assert!(differs(&source, &destination).unwrap());
+        assert_eq!(
+            join(&destination, &target).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        assert_eq!(std::fs::read(&target).unwrap(), content);
+
+        remove(&destination).unwrap();
+        assert!(artifacts(&destination).is_empty());
+        assert!(!part_path(&destination, 1).exists());
+    }
+}
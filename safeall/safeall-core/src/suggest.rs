@@ -0,0 +1,187 @@
+//! Exclusions suggested after a run, based on what has been copied.
+
+/// Names of directories which only hold data that can be downloaded or generated again.
+const CACHE_DIRECTORIES: [&str; 6] = [
+    ".cache",
+    "__pycache__",
+    ".pytest_cache",
+    ".mypy_cache",
+    ".npm",
+    ".gradle",
+];
+const BUILD_DIRECTORIES: [&str; 3] = ["node_modules", ".tox", ".venv"];
+const TRASH_DIRECTORIES: [&str; 3] = [".Trash", "$RECYCLE.BIN", "Trash"];
+/// Copied files of at least this size which have not been modified for
+/// [`LARGE_FILE_UNMODIFIED`] are suggested on their own.
+const LARGE_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+const LARGE_FILE_UNMODIFIED: std::time::Duration = std::time::Duration::from_hours(365 * 24);
+/// Number of suggestions with the most bytes which are reported.
+const MAX_SUGGESTIONS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExcludeReason {
+    Cache,
+    BuildOutput,
+    Trash,
+    /// A large file which has not been modified for a long time, e.g. an old disk image.
+    LargeUnmodifiedFile,
+}
+
+impl std::fmt::Display for ExcludeReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExcludeReason::Cache => write!(f, "cache"),
+            ExcludeReason::BuildOutput => write!(f, "build output"),
+            ExcludeReason::Trash => write!(f, "trash"),
+            ExcludeReason::LargeUnmodifiedFile => write!(f, "large file not modified for a year"),
+        }
+    }
+}
+
+/// An exclude pattern which would have saved copying `files` with `bytes` in the last run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExcludeSuggestion {
+    /// Pattern in the syntax of [`crate::BackupOptions::exclude`].
+    pub pattern: String,
+    pub reason: ExcludeReason,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Collects the copied files which match a suggestion.
+#[derive(Debug)]
+pub struct Collector {
+    source_root: std::path::PathBuf,
+    suggestions: std::collections::HashMap<(String, ExcludeReason), (u64, u64)>,
+}
+
+fn directory_reason(directory: &std::path::Path, name: &str) -> Option<ExcludeReason> {
+    if CACHE_DIRECTORIES.contains(&name) {
+        Some(ExcludeReason::Cache)
+    } else if BUILD_DIRECTORIES.contains(&name)
+        // NOTE: Only the build directory of Cargo, other `target` directories may be data
+        || name == "target" && directory.with_file_name("Cargo.toml").is_file()
+    {
+        Some(ExcludeReason::BuildOutput)
+    } else if TRASH_DIRECTORIES.contains(&name) || name.starts_with(".Trash-") {
+        Some(ExcludeReason::Trash)
+    } else {
+        None
+    }
+}
+
+fn is_unmodified(path: &std::path::Path) -> bool {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|elapsed| elapsed >= LARGE_FILE_UNMODIFIED)
+}
+
+impl Collector {
+    pub fn new(source_root: &std::path::Path) -> Self {
+        Self {
+            source_root: source_root.to_owned(),
+            suggestions: std::collections::HashMap::new(),
+        }
+    }
+
+    /// Records a copied file of the source.
+    pub fn record(&mut self, source: &std::path::Path, bytes: u64) {
+        let Ok(relative_path) = source.strip_prefix(&self.source_root) else {
+            return;
+        };
+        let mut directory = self.source_root.clone();
+        let mut suggestion = None;
+        if let Some(parent) = relative_path.parent() {
+            for component in parent {
+                directory.push(component);
+                let name = component.to_string_lossy();
+                if let Some(reason) = directory_reason(&directory, &name) {
+                    let pattern = if name == "target" {
+                        // NOTE: A leading slash anchors the pattern at the root of the source
+                        let relative_directory = directory
+                            .strip_prefix(&self.source_root)
+                            .unwrap_or(&directory);
+                        format!("/{}/", relative_directory.to_string_lossy())
+                    } else {
+                        format!("{name}/")
+                    };
+                    suggestion = Some((pattern, reason));
+                    break;
+                }
+            }
+        }
+        if suggestion.is_none() && bytes >= LARGE_FILE_SIZE && is_unmodified(source) {
+            suggestion = Some((
+                format!("/{}", relative_path.to_string_lossy()),
+                ExcludeReason::LargeUnmodifiedFile,
+            ));
+        }
+        if let Some(key) = suggestion {
+            let (files, total) = self.suggestions.entry(key).or_default();
+            *files += 1;
+            *total += bytes;
+        }
+    }
+
+    /// The suggestions with the most bytes first.
+    pub fn finish(self) -> Vec<ExcludeSuggestion> {
+        let mut suggestions: Vec<_> = self
+            .suggestions
+            .into_iter()
+            .map(|((pattern, reason), (files, bytes))| ExcludeSuggestion {
+                pattern,
+                reason,
+                files,
+                bytes,
+            })
+            .collect();
+        suggestions.sort_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then_with(|| a.pattern.cmp(&b.pattern))
+        });
+        suggestions.truncate(MAX_SUGGESTIONS);
+        suggestions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exclude_suggestions() {
+        let source = tempfile::tempdir().unwrap();
+        let project = source.path().join("project");
+        std::fs::create_dir_all(project.join("target")).unwrap();
+        std::fs::write(project.join("Cargo.toml"), b"").unwrap();
+        std::fs::create_dir_all(source.path().join("data/target")).unwrap();
+
+        let mut collector = Collector::new(source.path());
+        collector.record(&project.join("target/debug/app"), 100);
+        collector.record(&project.join("target/release/app"), 50);
+        collector.record(&source.path().join("data/target/values.csv"), 10);
+        collector.record(&source.path().join("home/.cache/pip/wheel"), 20);
+        collector.record(&source.path().join("small.iso"), 1);
+        collector.record(std::path::Path::new("/elsewhere/.cache/file"), 1);
+        assert_eq!(
+            collector.finish(),
+            vec![
+                ExcludeSuggestion {
+                    pattern: "/project/target/".to_owned(),
+                    reason: ExcludeReason::BuildOutput,
+                    files: 2,
+                    bytes: 150,
+                },
+                ExcludeSuggestion {
+                    pattern: ".cache/".to_owned(),
+                    reason: ExcludeReason::Cache,
+                    files: 1,
+                    bytes: 20,
+                },
+            ]
+        );
+    }
+}
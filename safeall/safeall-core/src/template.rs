@@ -0,0 +1,73 @@
+//! Expansion of `${VARIABLE}` in the root paths, such that the same paths can be used on
+//! several machines and produce dated backups.
+//!
+//! `${HOME}`, `${HOSTNAME}` and `${DATE}` (`YYYY-MM-DD`) are always available, all other
+//! variables are taken from the environment.
+
+fn variable(name: &str) -> Option<String> {
+    match name {
+        "HOME" => dirs::home_dir().map(|home| home.to_string_lossy().into_owned()),
+        "HOSTNAME" => hostname::get()
+            .ok()
+            .map(|hostname| hostname.to_string_lossy().into_owned()),
+        "DATE" => Some(chrono::Local::now().format("%Y-%m-%d").to_string()),
+        _ => std::env::var(name).ok(),
+    }
+}
+
+fn expand_with(
+    template: &str,
+    variable: impl Fn(&str) -> Option<String>,
+) -> Result<String, crate::Error> {
+    let invalid = |name: &str| crate::Error::InvalidPathTemplate {
+        template: template.to_owned(),
+        variable: name.to_owned(),
+    };
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find('}').ok_or_else(|| invalid(after))?;
+        let name = &after[..end];
+        expanded.push_str(&variable(name).ok_or_else(|| invalid(name))?);
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Expands all variables in `path`. Paths which are not valid UTF-8 are returned unchanged.
+pub fn expand(path: &std::path::Path) -> Result<std::path::PathBuf, crate::Error> {
+    match path.to_str() {
+        Some(template) => expand_with(template, variable).map(Into::into),
+        None => Ok(path.to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_variables() {
+        let variable = |name: &str| match name {
+            "HOME" => Some("/home/me".to_owned()),
+            "HOSTNAME" => Some("laptop".to_owned()),
+            _ => None,
+        };
+        assert_eq!(
+            expand_with("${HOME}/backup/${HOSTNAME}", variable).unwrap(),
+            "/home/me/backup/laptop"
+        );
+        assert_eq!(
+            expand_with("/plain/$path", variable).unwrap(),
+            "/plain/$path"
+        );
+        assert!(matches!(
+            expand_with("/${UNKNOWN}/x", variable),
+            Err(crate::Error::InvalidPathTemplate { variable, .. }) if variable == "UNKNOWN"
+        ));
+        assert!(expand_with("/${HOME", variable).is_err());
+    }
+}
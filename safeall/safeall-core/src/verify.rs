@@ -0,0 +1,239 @@
+//! Integrity check of a destination against its source which does not modify either.
+
+use crate::{
+    BackupOptions, Comparator, CompareStrategy, Decision, Error, Increment, Message, MessageSender,
+    Progress, ProgressType, ReadDirType, RecursiveReadDir, SymlinkPolicy, Warning,
+};
+
+/// Differences between a source and its backup.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of files which have been compared.
+    pub verified: usize,
+    /// Files in the destination whose content differs from the source.
+    pub mismatched: Vec<std::path::PathBuf>,
+    /// Files of the source which are not in the destination.
+    pub missing: Vec<std::path::PathBuf>,
+    /// Files in the destination which are not in the source.
+    pub extra: Vec<std::path::PathBuf>,
+    /// Files which could not be read, see the warnings for the reason.
+    pub unreadable: Vec<std::path::PathBuf>,
+    /// Files which differed and have been copied from the source again.
+    pub repaired: Vec<std::path::PathBuf>,
+}
+
+impl VerifyReport {
+    /// Whether the destination is a complete and intact backup of the source.
+    #[must_use]
+    pub fn is_consistent(&self) -> bool {
+        self.mismatched.is_empty()
+            && self.missing.is_empty()
+            && self.extra.is_empty()
+            && self.unreadable.is_empty()
+    }
+}
+
+impl std::fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Verified {} files: {} differ, {} are missing, {} are not in the source and {} could not be read.",
+            self.verified,
+            self.mismatched.len(),
+            self.missing.len(),
+            self.extra.len(),
+            self.unreadable.len()
+        )?;
+        if !self.repaired.is_empty() {
+            write!(f, " Repaired {} files.", self.repaired.len())?;
+        }
+        for path in &self.mismatched {
+            write!(f, "\n  Differs: \"{}\"", path.display())?;
+        }
+        for path in &self.missing {
+            write!(f, "\n  Missing: \"{}\"", path.display())?;
+        }
+        for path in &self.extra {
+            write!(f, "\n  Not in the source: \"{}\"", path.display())?;
+        }
+        for path in &self.unreadable {
+            write!(f, "\n  Unreadable: \"{}\"", path.display())?;
+        }
+        for path in &self.repaired {
+            write!(f, "\n  Repaired: \"{}\"", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+fn read_dir(
+    root: &std::path::Path,
+    filter: &crate::filter::Filter,
+) -> Result<RecursiveReadDir, Error> {
+    RecursiveReadDir::try_new(root, ReadDirType::FilesOnly)
+        .map(|r| r.with_filter(filter.clone()))
+        .map_err(|e| Error::CannotReadDirectoryContent(root.to_owned(), e.to_string()))
+}
+
+/// Compares the type, length and content of both files, or the targets of links which are
+/// backed up as links. A destination which has been split is compared with its parts.
+fn differs(
+    source: &std::path::Path,
+    destination: &std::path::Path,
+    symlinks: SymlinkPolicy,
+) -> std::io::Result<bool> {
+    if std::fs::symlink_metadata(destination).is_err()
+        && crate::split::descriptor_path(destination).is_file()
+    {
+        return crate::split::differs(source, destination);
+    }
+    let metadata = |path| {
+        if symlinks == SymlinkPolicy::Follow {
+            std::fs::metadata(path)
+        } else {
+            std::fs::symlink_metadata(path)
+        }
+    };
+    let (source_metadata, destination_metadata) = (metadata(source)?, metadata(destination)?);
+    if source_metadata.is_symlink() || destination_metadata.is_symlink() {
+        return Ok(
+            source_metadata.file_type() != destination_metadata.file_type()
+                || std::fs::read_link(source)? != std::fs::read_link(destination)?,
+        );
+    }
+    if !source_metadata.is_file() || !destination_metadata.is_file() {
+        return Ok(source_metadata.file_type() != destination_metadata.file_type());
+    }
+    // NOTE: Bit rot does not change the modification time, so the hash cache is not used
+    let decision = CompareStrategy::AlwaysHash.compare(
+        source,
+        &source_metadata,
+        destination,
+        &destination_metadata,
+    )?;
+    Ok(matches!(decision, Decision::Copy(_)))
+}
+
+/// Hashes every file of the source and its backup and lists the differences.
+pub async fn verify(
+    source_root: &std::path::Path,
+    destination_root: &std::path::Path,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) -> Result<VerifyReport, Error> {
+    use futures::stream::StreamExt;
+
+    if !source_root.is_dir() {
+        return Err(Error::SourceRootIsNotADirectory(source_root.to_owned()));
+    }
+    if !destination_root.is_dir() {
+        return Err(Error::RootDestinatinIsNotADirectory(
+            destination_root.to_owned(),
+        ));
+    }
+    let filter = options.filter(&[source_root, destination_root])?;
+    let into_error = |e| Error::ProcessPathErrors {
+        directories: vec![],
+        files: vec![e],
+    };
+    let mut report = VerifyReport {
+        extra: crate::get_paths_in_destinatination_but_not_in_source(
+            read_dir(source_root, &filter)?,
+            read_dir(destination_root, &filter)?,
+        )
+        .await
+        .map_err(into_error)?,
+        ..VerifyReport::default()
+    };
+    let mut existing = vec![];
+    for source in read_dir(source_root, &filter)?
+        .skipping_special_files(options.special_files == crate::SpecialFilePolicy::Skip)
+        .flatten()
+    {
+        let destination = crate::get_destination_file_path(destination_root, source_root, &source)
+            .map_err(into_error)?;
+        if tokio::fs::symlink_metadata(&destination).await.is_ok()
+            || crate::split::descriptor_path(&destination).is_file()
+        {
+            existing.push((source, destination));
+        } else {
+            report.missing.push(destination);
+        }
+    }
+    report.missing.sort();
+
+    message_sender.send(Message::Progress(Progress::Start(
+        existing.len(),
+        ProgressType::Verifying,
+    )));
+    let symlinks = options.symlinks;
+    let mut results: Vec<_> = futures::stream::iter(existing)
+        .map(async |(source, destination)| {
+            let (owned_source, owned_destination) = (source.clone(), destination.clone());
+            let differs = tokio::task::spawn_blocking(move || {
+                differs(&owned_source, &owned_destination, symlinks)
+            })
+            .await
+            .map_err(std::io::Error::other)
+            .flatten();
+            let bytes = tokio::fs::metadata(&source)
+                .await
+                .map_or(0, |metadata| metadata.len());
+            message_sender.send(Message::Progress(Progress::IncrementSuccess(
+                Increment::Hashed {
+                    source: source.clone(),
+                    bytes,
+                },
+            )));
+            (source, destination, differs)
+        })
+        .buffer_unordered(crate::cpu_count())
+        .collect()
+        .await;
+    results.sort_by(|a, b| a.1.cmp(&b.1));
+    for (source, destination, differs) in results {
+        report.verified += 1;
+        match differs {
+            Ok(false) => {}
+            Ok(true) => report.mismatched.push(destination),
+            Err(e) => {
+                message_sender.send(Message::Warning(Warning::CannotCompare {
+                    source,
+                    destination: destination.clone(),
+                    io_error: e.to_string(),
+                }));
+                report.unreadable.push(destination);
+            }
+        }
+    }
+    message_sender.send(Message::Progress(Progress::EndSuccess(
+        ProgressType::Verifying,
+    )));
+    Ok(report)
+}
+
+/// Copies the files which differ from the source again. Links and special files are left as
+/// they are.
+pub async fn repair(
+    report: &mut VerifyReport,
+    source_root: &std::path::Path,
+    destination_root: &std::path::Path,
+    options: &BackupOptions,
+    message_sender: &impl MessageSender,
+) {
+    let files = report
+        .mismatched
+        .iter()
+        .filter_map(|destination| {
+            let source = source_root.join(destination.strip_prefix(destination_root).ok()?);
+            std::fs::symlink_metadata(&source)
+                .is_ok_and(|metadata| metadata.is_file())
+                .then(|| (source, destination.clone()))
+        })
+        .collect();
+    let repaired = crate::repair_files(files, options, message_sender).await;
+    report
+        .mismatched
+        .retain(|destination| !repaired.contains(destination));
+    report.repaired = repaired;
+}
@@ -0,0 +1,67 @@
+//! Extended attributes such as tags, `com.apple.*` metadata and `security.selinux` labels, which
+//! are copied after the content of a file.
+//!
+//! Only available on unix with the `xattr` feature, otherwise nothing is copied.
+
+/// Replaces all extended attributes of `destination` with the ones of `source`. Attributes
+/// which cannot be set, e.g. because of missing privileges, do not stop the others from being
+/// copied but the first error is returned.
+#[cfg(all(unix, feature = "xattr"))]
+pub fn copy(source: &std::path::Path, destination: &std::path::Path) -> std::io::Result<()> {
+    let mut first_error = None;
+    for name in xattr::list(destination)? {
+        if xattr::get(source, &name)?.is_none()
+            && let Err(e) = xattr::remove(destination, &name)
+        {
+            first_error.get_or_insert(e);
+        }
+    }
+    for name in xattr::list(source)? {
+        let Some(value) = xattr::get(source, &name)? else {
+            continue;
+        };
+        if xattr::get(destination, &name)?.as_ref() == Some(&value) {
+            continue;
+        }
+        if let Err(e) = xattr::set(destination, &name, &value) {
+            first_error.get_or_insert(e);
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+#[cfg(not(all(unix, feature = "xattr")))]
+#[allow(clippy::unnecessary_wraps)]
+pub fn copy(_source: &std::path::Path, _destination: &std::path::Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix, feature = "xattr"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_extended_attributes() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let destination = directory.path().join("destination");
+        std::fs::write(&source, b"content").unwrap();
+        std::fs::write(&destination, b"content").unwrap();
+        if xattr::set(&source, "user.safeall.tag", b"red").is_err() {
+            // NOTE: The file system of the temporary directory does not support user attributes
+            return;
+        }
+        xattr::set(&destination, "user.safeall.stale", b"old").unwrap();
+
+        copy(&source, &destination).unwrap();
+
+        assert_eq!(
+            xattr::get(&destination, "user.safeall.tag").unwrap(),
+            Some(b"red".to_vec())
+        );
+        assert_eq!(
+            xattr::get(&destination, "user.safeall.stale").unwrap(),
+            None
+        );
+    }
+}
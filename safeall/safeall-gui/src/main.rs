@@ -23,6 +23,12 @@ enum Message {
     StartSyncRestore,
     BackupUpdate(safeall::Message),
     BackupFinished(Result<(), Error>),
+    RunChecks,
+    ChecksFinished {
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+        result: Result<Vec<safeall::Finding>, Error>,
+    },
     WindowEvent {
         id: iced::window::Id,
         event: iced::window::Event,
@@ -36,6 +42,10 @@ enum Message {
     DestinationInputChanged(String),
     SourceInputChanged(String),
     OpenPath(std::path::PathBuf),
+    RevealPath(std::path::PathBuf),
+    CopyPath(std::path::PathBuf),
+    ExcludePath(std::path::PathBuf),
+    RetryPath(std::path::PathBuf),
     OpenApp,
     TrayMessage(TrayMessage),
     TrayIconFailure(Result<(), Error>),
@@ -53,9 +63,217 @@ enum BackupState {
     Error,
 }
 
+/// A file or folder which a run could not back up.
+#[derive(Clone, Debug)]
+struct Problem {
+    path: std::path::PathBuf,
+    description: String,
+}
+
+/// The checks of the doctor before the first sync of a source and a destination.
+#[derive(Default, Debug)]
+enum Preflight {
+    #[default]
+    Idle,
+    Running {
+        _task: iced::task::Handle,
+    },
+    Failed(String),
+}
+
+/// What the doctor looks at, shown as a checklist with the findings of each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Check {
+    Location,
+    Writable,
+    Names,
+    ModificationTimes,
+    PathLengths,
+    FileSizes,
+    Space,
+    Readable,
+}
+
+impl Check {
+    const ALL: [Check; 8] = [
+        Check::Location,
+        Check::Writable,
+        Check::Names,
+        Check::ModificationTimes,
+        Check::PathLengths,
+        Check::FileSizes,
+        Check::Space,
+        Check::Readable,
+    ];
+
+    fn of(finding: &safeall::Finding) -> Self {
+        use safeall::Finding as F;
+        match finding {
+            F::DestinationInsideSource | F::SourceInsideDestination => Check::Location,
+            F::DestinationNotWritable(_) => Check::Writable,
+            F::CaseCollisions(_) => Check::Names,
+            F::CoarseModificationTimes { .. } => Check::ModificationTimes,
+            F::PathsTooLong { .. } => Check::PathLengths,
+            F::FilesTooLarge { .. } => Check::FileSizes,
+            F::NotEnoughSpace { .. } => Check::Space,
+            F::Unreadable(_) => Check::Readable,
+        }
+    }
+}
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Check::Location => write!(f, "Source and destination are apart"),
+            Check::Writable => write!(f, "Destination is writable"),
+            Check::Names => write!(f, "Destination keeps all names apart"),
+            Check::ModificationTimes => write!(f, "Destination keeps modification times"),
+            Check::PathLengths => write!(f, "Paths fit into the destination"),
+            Check::FileSizes => write!(f, "Files fit into the destination"),
+            Check::Space => write!(f, "Destination has enough space"),
+            Check::Readable => write!(f, "Source is readable"),
+        }
+    }
+}
+
+/// The phases of a run in the order in which they happen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Scan,
+    CreateDirectories,
+    Copy,
+    Purge,
+}
+
+impl Phase {
+    const ALL: [Phase; 4] = [
+        Phase::Scan,
+        Phase::CreateDirectories,
+        Phase::Copy,
+        Phase::Purge,
+    ];
+
+    fn of(progress_type: &safeall::ProgressType) -> Option<Self> {
+        use safeall::ProgressType as T;
+        match progress_type {
+            T::Scanning => Some(Phase::Scan),
+            T::CreatingDirectories => Some(Phase::CreateDirectories),
+            T::Hashing | T::CopingFiles | T::RetryingFiles => Some(Phase::Copy),
+            T::DeletingDirs | T::DeletingFiles => Some(Phase::Purge),
+            T::Verifying | T::Scrubbing => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Phase::Scan => write!(f, "Scan"),
+            Phase::CreateDirectories => write!(f, "Create folders"),
+            Phase::Copy => write!(f, "Copy"),
+            Phase::Purge => write!(f, "Delete"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum PhaseState {
+    #[default]
+    Pending,
+    /// The scan does not know its total, it counts the entries in `done`.
+    Running {
+        done: usize,
+        total: usize,
+    },
+    Done,
+    Failed,
+}
+
+/// Progress of the current run per phase, built from the progress messages of core.
+#[derive(Debug, Default)]
+struct RunProgress {
+    phases: [PhaseState; Phase::ALL.len()],
+    message: String,
+}
+
+impl RunProgress {
+    fn update(&mut self, message: &safeall::Message) {
+        use safeall::Progress as P;
+        let safeall::Message::Progress(progress) = message else {
+            return;
+        };
+        match progress {
+            P::Start(total, progress_type) => {
+                if let Some(phase) = Phase::of(progress_type) {
+                    // NOTE: Phases with nothing to do are skipped by core
+                    for earlier in &mut self.phases[..phase as usize] {
+                        if *earlier == PhaseState::Pending {
+                            *earlier = PhaseState::Done;
+                        }
+                    }
+                    self.phases[phase as usize] = PhaseState::Running {
+                        done: 0,
+                        total: *total,
+                    };
+                }
+            }
+            P::IncrementSuccess(safeall::Increment::Scanned { entries, .. }) => {
+                if let PhaseState::Running { done, .. } = &mut self.phases[Phase::Scan as usize] {
+                    *done = *entries;
+                }
+            }
+            P::IncrementSuccess(_) | P::IncrementFail(_) => {
+                if let Some(PhaseState::Running { done, .. }) = self
+                    .phases
+                    .iter_mut()
+                    .rev()
+                    .find(|state| matches!(state, PhaseState::Running { .. }))
+                {
+                    *done += 1;
+                }
+            }
+            P::EndSuccess(progress_type) => {
+                if let Some(phase) = Phase::of(progress_type) {
+                    self.phases[phase as usize] = PhaseState::Done;
+                }
+            }
+            P::EndFail(_, progress_type) => {
+                if let Some(phase) = Phase::of(progress_type) {
+                    self.phases[phase as usize] = PhaseState::Failed;
+                }
+            }
+            P::TotalBytes(_, _) => return,
+        }
+        self.message = progress.to_string();
+    }
+
+    /// The phase which is running with its progress.
+    fn current(&self) -> Option<(Phase, usize, usize)> {
+        Phase::ALL
+            .into_iter()
+            .zip(self.phases)
+            .rev()
+            .find_map(|(phase, state)| match state {
+                PhaseState::Running { done, total } => Some((phase, done, total)),
+                _ => None,
+            })
+    }
+}
+
 #[derive(Default, Debug)]
 struct Gui {
     backup_state: BackupState,
+    progress: RunProgress,
+    preflight: Preflight,
+    /// Findings of the doctor per source and destination.
+    diagnoses:
+        std::collections::HashMap<(std::path::PathBuf, std::path::PathBuf), Vec<safeall::Finding>>,
+    /// Files and folders which the runs could not back up, the newest last.
+    problems: Vec<Problem>,
+    /// Source and destination of the run which found the problems.
+    run_roots: Option<(std::path::PathBuf, std::path::PathBuf)>,
+    /// Patterns of the problems which have been excluded, used by all further runs.
+    exclude: Vec<String>,
     source: Option<std::path::PathBuf>,
     destination: Option<std::path::PathBuf>,
     menu_ids: Option<std::collections::HashMap<tray_icon::menu::MenuId, MenuItem>>,
@@ -71,6 +289,7 @@ impl Gui {
         let content = column![
             self.view_title(),
             self.view_user_input(),
+            self.view_preflight(),
             self.view_progress(),
             self.view_errors_and_warnings(),
         ]
@@ -136,7 +355,7 @@ impl Gui {
         let backup_button = move |button_txt, on_press, hover_txt| {
             tooltip(
                 button(button_txt)
-                    .on_press(on_press)
+                    .on_press_maybe(on_press)
                     .height(30)
                     .style(|theme, status| {
                         let mut style = button::primary(theme, status);
@@ -152,28 +371,29 @@ impl Gui {
             row![
                 backup_button(
                     "Backup",
-                    Message::StartBackup,
+                    Some(Message::StartBackup),
                     "Will backup your source folder into your destination folder. \
                         This NEVER deletes a file which is in the destination but not in \
                         the source folder."
                 ),
                 backup_button(
                     "Sync",
-                    Message::StartSync,
+                    self.may_sync().then_some(Message::StartSync),
                     "Will make your destination identical with your source. \
                         This will DELETE files that are in the destination but not in \
-                        the source folder."
+                        the source folder. Only possible once the checks found no \
+                        problems."
                 ),
                 backup_button(
                     "Restore",
-                    Message::StartRestore,
+                    Some(Message::StartRestore),
                     "Will restore your source folder from your destination folder. \
                         This will NEVER delete files that are in the source but not in \
                         the destination folder."
                 ),
                 backup_button(
                     "Sync Restore",
-                    Message::StartSyncRestore,
+                    Some(Message::StartSyncRestore),
                     "Will make your source folder identical with your destination folder.\
                         This will DELETE files that are in the source but not in \
                         the destination folder."
@@ -186,41 +406,146 @@ impl Gui {
         user_input.into()
     }
 
+    fn view_preflight(&self) -> iced::Element<'_, Message> {
+        use iced::border;
+        use iced::widget::{button, column, row, text};
+
+        let run_checks = button("Run checks")
+            .on_press_maybe(
+                (self.pair().is_some() && !matches!(self.preflight, Preflight::Running { .. }))
+                    .then_some(Message::RunChecks),
+            )
+            .height(30)
+            .style(|theme, status| {
+                let mut style = button::primary(theme, status);
+                style.border.radius = border::Radius::new(15);
+                style
+            });
+        let header = row![text("Checks before the first sync:"), run_checks].spacing(10);
+
+        let checklist: iced::Element<'_, Message> = match (&self.preflight, self.diagnosis()) {
+            (Preflight::Running { .. }, _) => text("Checking...").size(12).into(),
+            (Preflight::Failed(error), _) => text(error).size(12).style(text::danger).into(),
+            (Preflight::Idle, None) => {
+                text("Choose a source and a destination and run the checks to enable Sync.")
+                    .size(12)
+                    .into()
+            }
+            (Preflight::Idle, Some(findings)) => {
+                column(Check::ALL.into_iter().map(|check| {
+                    let found = findings
+                        .iter()
+                        .filter(|finding| Check::of(finding) == check)
+                        .collect::<Vec<_>>();
+                    let (result, style): (_, fn(&iced::Theme) -> text::Style) =
+                        match found.iter().map(|finding| finding.severity()).max() {
+                            None => ("OK", text::success),
+                            Some(safeall::Severity::Error) => ("Problem", text::danger),
+                            Some(_) => ("Warning", text::warning),
+                        };
+                    let mut item =
+                        column![text(format!("{check}: {result}")).size(14).style(style)]
+                            .spacing(2);
+                    // NOTE: The findings say how to fix them
+                    for finding in found {
+                        item = item.push(text(finding.to_string()).size(12));
+                    }
+                    iced::Element::<Message>::from(item)
+                }))
+                .spacing(5)
+                .into()
+            }
+        };
+
+        column![header, checklist].spacing(5).into()
+    }
+
     fn view_progress(&self) -> iced::Element<'_, Message> {
-        use iced::widget::{center_x, column, progress_bar, text};
+        use iced::widget::{center_x, column, progress_bar, row, text};
+
+        let steps = row(Phase::ALL
+            .into_iter()
+            .zip(self.progress.phases)
+            .enumerate()
+            .map(|(i, (phase, state))| {
+                let step = text(format!("{}. {phase}", i + 1)).size(14);
+                iced::Element::<Message>::from(match state {
+                    PhaseState::Pending => step.style(text::secondary),
+                    PhaseState::Running { .. } => step.font(FONT_BOLD).style(text::primary),
+                    PhaseState::Done => step.style(text::success),
+                    PhaseState::Failed => step.style(text::danger),
+                })
+            }))
+        .spacing(30);
 
-        let progress_bar = progress_bar(0.0..=100.0, 32.0);
-        let progress_info = "Nothing to do...";
-        let progress = column![text(progress_info).size(12), center_x(progress_bar)];
+        #[allow(clippy::cast_precision_loss)]
+        let (percent, phase_info) = match self.progress.current() {
+            // NOTE: The scan counts what the other phases process, so it has no total
+            Some((Phase::Scan, done, _)) => (0.0, format!("Scan: counted {done} entries")),
+            Some((phase, done, total)) => (
+                if total == 0 {
+                    100.0
+                } else {
+                    done as f32 / total as f32 * 100.0
+                },
+                format!("{phase}: {done} of {total}"),
+            ),
+            None if self.progress.message.is_empty() => (0.0, "Nothing to do...".to_owned()),
+            None => (100.0, "Finished".to_owned()),
+        };
+        let progress = column![
+            center_x(steps),
+            text(phase_info).size(12),
+            center_x(progress_bar(0.0..=100.0, percent)),
+            text(&self.progress.message).size(12),
+        ]
+        .spacing(5);
         progress.into()
     }
 
     fn view_errors_and_warnings(&self) -> iced::Element<'_, Message> {
-        const ROWS: [&str; 20] = ["/home/chrigi"; 20];
-
         use iced::Length::Fill;
         use iced::alignment::Alignment::Center;
-        use iced::alignment::Horizontal::Right;
-        use iced::widget::{button, column, container, scrollable, table, text};
+        use iced::widget::{button, column, container, row, scrollable, table, text};
 
+        // NOTE: A retry would abort the run which is in progress
+        let may_retry =
+            self.run_roots.is_some() && !matches!(self.backup_state, BackupState::Running { .. });
+        let action = |label, on_press: Option<Message>| {
+            button(text(label).size(12))
+                .style(button::text)
+                .on_press_maybe(on_press)
+        };
         let columns = {
             let bold = |header| text(header).font(FONT_BOLD);
             [
-                table::column(bold("File"), |path: &std::path::Path| {
-                    button(text(path.display().to_string()))
+                table::column(bold("File"), |problem: &Problem| {
+                    button(text(problem.path.display().to_string()))
                         .style(button::text)
-                        .on_press(Message::OpenPath(path.to_owned()))
+                        .on_press(Message::OpenPath(problem.path.clone()))
+                })
+                .align_y(Center),
+                table::column(bold("Error"), |problem: &Problem| {
+                    text(problem.description.clone()).size(12)
+                })
+                .align_y(Center)
+                .width(Fill),
+                table::column(bold("Actions"), move |problem: &Problem| {
+                    let path = || problem.path.clone();
+                    row![
+                        action("Show in folder", Some(Message::RevealPath(path()))),
+                        action("Copy path", Some(Message::CopyPath(path()))),
+                        action("Exclude", Some(Message::ExcludePath(path()))),
+                        action("Retry", may_retry.then(|| Message::RetryPath(path()))),
+                    ]
                 })
                 .align_y(Center),
-                table::column(bold("Error"), |path: &std::path::Path| text("Some Error"))
-                    .align_y(Center)
-                    .width(Fill),
             ]
         };
 
         let errors_and_warnings = column![
             text("Errors and Warnings:"),
-            container(scrollable(table(columns, ROWS.map(std::path::Path::new))))
+            container(scrollable(table(columns, &self.problems)))
                 .width(Fill)
                 .height(Fill)
                 .padding(10)
@@ -242,13 +567,52 @@ impl Gui {
                 let Some(destination_root) = self.destination.clone() else {
                     return iced::Task::done(Message::NoDestinationSet);
                 };
+                self.problems.clear();
+                self.run_roots = Some((source_root.clone(), destination_root.clone()));
                 self.start_backup(safeall::Command::Backup {
                     source_root,
-                    destination_root,
+                    destination: safeall::Destination::Directory(destination_root),
+                    options: self.options(),
                 })
             }
             Message::StartSync => {
-                println!("start sync");
+                let Some((source_root, destination_root)) = self.pair() else {
+                    return iced::Task::done(Message::NoSourceSet);
+                };
+                if !self.may_sync() {
+                    return iced::Task::done(Message::RunChecks);
+                }
+                self.problems.clear();
+                self.run_roots = Some((source_root.clone(), destination_root.clone()));
+                self.start_backup(safeall::Command::Sync {
+                    source_root,
+                    destination_root,
+                    options: self.options(),
+                })
+            }
+            Message::RunChecks => {
+                let Some((source, destination)) = self.pair() else {
+                    return iced::Task::none();
+                };
+                self.run_checks(source, destination)
+            }
+            Message::ChecksFinished {
+                source,
+                destination,
+                result,
+            } => {
+                // NOTE: The folders may have been changed while checking
+                if self.pair().as_ref() != Some(&(source.clone(), destination.clone())) {
+                    return iced::Task::none();
+                }
+                self.preflight = match result {
+                    Ok(findings) => {
+                        self.diagnoses.insert((source, destination), findings);
+                        Preflight::Idle
+                    }
+                    Err(Error::SafeAll(error)) => Preflight::Failed(error.to_string()),
+                    Err(error) => Preflight::Failed(format!("{error:?}")),
+                };
                 iced::Task::none()
             }
             Message::StartRestore => {
@@ -260,11 +624,24 @@ impl Gui {
                 iced::Task::none()
             }
             Message::BackupUpdate(message) => {
-                println!("{message:?}");
+                self.progress.update(&message);
+                if let safeall::Message::Progress(safeall::Progress::IncrementFail(error)) =
+                    &message
+                    && let Some(path) = &error.not_processed
+                {
+                    self.problems.push(Problem {
+                        path: path.clone(),
+                        description: error.to_string(),
+                    });
+                }
                 iced::Task::none()
             }
             Message::BackupFinished(result) => {
-                println!("{result:?}");
+                self.backup_state = if result.is_ok() {
+                    BackupState::Success
+                } else {
+                    BackupState::Error
+                };
                 iced::Task::none()
             }
             Message::ChooseSource => {
@@ -272,6 +649,7 @@ impl Gui {
             }
             Message::SourceFileChosen(path) => {
                 self.source = path;
+                self.preflight = Preflight::Idle;
                 iced::Task::none()
             }
             Message::ChooseDestination => {
@@ -279,6 +657,7 @@ impl Gui {
             }
             Message::DestinationFileChosen(path) => {
                 self.destination = path;
+                self.preflight = Preflight::Idle;
                 iced::Task::none()
             }
             Message::NoSourceSet => {
@@ -299,6 +678,7 @@ impl Gui {
                         Err(error) => println!("{error}"),
                     }
                 }
+                self.preflight = Preflight::Idle;
 
                 iced::Task::none()
             }
@@ -312,6 +692,7 @@ impl Gui {
                         Err(error) => println!("{error}"),
                     }
                 }
+                self.preflight = Preflight::Idle;
 
                 iced::Task::none()
             }
@@ -323,6 +704,35 @@ impl Gui {
                     .unwrap();
                 iced::Task::none()
             }
+            Message::RevealPath(path) => {
+                if let Err(error) = reveal(&path) {
+                    println!("{error}");
+                }
+                iced::Task::none()
+            }
+            Message::CopyPath(path) => iced::clipboard::write(path.display().to_string()),
+            Message::ExcludePath(path) => {
+                let Some(relative_path) = self.relative_to_run(&path) else {
+                    return iced::Task::none();
+                };
+                self.exclude.push(safeall::exclude_pattern(relative_path));
+                self.problems
+                    .retain(|problem| !problem.path.starts_with(&path));
+                iced::Task::none()
+            }
+            Message::RetryPath(path) => {
+                let Some((source_root, destination_root)) = self.run_roots.clone() else {
+                    return iced::Task::none();
+                };
+                let options = self.options();
+                self.problems.retain(|problem| problem.path != path);
+                self.start_backup(safeall::Command::BackupPaths {
+                    paths: vec![path],
+                    source_root,
+                    destination_root,
+                    options,
+                })
+            }
             Message::OpenApp => {
                 if self.window_ids.is_empty() {
                     let settings = iced::window::Settings::default();
@@ -385,6 +795,83 @@ impl Gui {
         iced::Theme::CatppuccinLatte
     }
 
+    /// The options of all runs, without the problems which have been excluded.
+    fn options(&self) -> safeall::BackupOptions {
+        safeall::BackupOptions {
+            exclude: self.exclude.clone(),
+            known_destinations: safeall::known_destinations_path(),
+            ..Default::default()
+        }
+    }
+
+    /// `path` relative to the source of the run which found the problems.
+    fn relative_to_run<'a>(&self, path: &'a std::path::Path) -> Option<&'a std::path::Path> {
+        path.strip_prefix(&self.run_roots.as_ref()?.0).ok()
+    }
+
+    fn pair(&self) -> Option<(std::path::PathBuf, std::path::PathBuf)> {
+        Some((self.source.clone()?, self.destination.clone()?))
+    }
+
+    fn diagnosis(&self) -> Option<&Vec<safeall::Finding>> {
+        self.diagnoses.get(&self.pair()?)
+    }
+
+    /// Sync deletes files, so it needs checks without problems for the chosen folders first.
+    fn may_sync(&self) -> bool {
+        self.diagnosis().is_some_and(|findings| {
+            findings
+                .iter()
+                .all(|finding| finding.severity() != safeall::Severity::Error)
+        })
+    }
+
+    fn run_checks(
+        &mut self,
+        source: std::path::PathBuf,
+        destination: std::path::PathBuf,
+    ) -> iced::Task<Message> {
+        let command = safeall::Command::Doctor {
+            source_root: source.clone(),
+            destination_root: destination.clone(),
+            options: self.options(),
+        };
+        let (task, handle) = iced::Task::perform(
+            async move {
+                let (message_sender, mut message_receiver) = tokio::sync::mpsc::unbounded_channel();
+                let run = tokio::spawn(async move { safeall::run(command, message_sender).await });
+
+                let mut findings = vec![];
+                while let Some(message) = message_receiver.recv().await {
+                    if let safeall::Message::Info(safeall::Info::Diagnosis(diagnosis)) = message {
+                        findings = diagnosis;
+                    }
+                }
+
+                match run.await {
+                    // NOTE: The findings are shown, the error only counts them
+                    Ok(Ok(()) | Err(safeall::Error::ProblemsFound(_))) => Ok(findings),
+                    Ok(Err(error)) => Err(Error::SafeAll(error)),
+                    Err(error) => Err(Error::Tokio {
+                        join_error: error.to_string(),
+                    }),
+                }
+            },
+            move |result| Message::ChecksFinished {
+                source: source.clone(),
+                destination: destination.clone(),
+                result,
+            },
+        )
+        .abortable();
+
+        self.preflight = Preflight::Running {
+            _task: handle.abort_on_drop(),
+        };
+
+        task
+    }
+
     fn start_backup(&mut self, command: safeall::Command) -> iced::Task<Message> {
         let (task, handle) = iced::Task::sip(
             iced::task::sipper(async move |mut iced_sender| {
@@ -407,6 +894,7 @@ impl Gui {
         )
         .abortable();
 
+        self.progress = RunProgress::default();
         self.backup_state = BackupState::Running {
             _task: handle.abort_on_drop(),
         };
@@ -414,6 +902,25 @@ impl Gui {
         task
     }
 }
+/// Shows `path` selected in the file manager of the platform.
+fn reveal(path: &std::path::Path) -> std::io::Result<std::process::Child> {
+    if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+            .arg("-R")
+            .arg(path)
+            .spawn()
+    } else if cfg!(target_os = "windows") {
+        let mut select = std::ffi::OsString::from("/select,");
+        select.push(path);
+        std::process::Command::new("explorer").arg(select).spawn()
+    } else {
+        // NOTE: There is no common way to select a file on Linux, so its folder is opened
+        std::process::Command::new("xdg-open")
+            .arg(path.parent().unwrap_or(path))
+            .spawn()
+    }
+}
+
 const FONT_BYTES: &[u8] = include_bytes!("../fonts/Roboto.ttf");
 const FONT_REGULAR: iced::Font = iced::Font::with_name("Roboto");
 const FONT_BOLD: iced::Font = iced::Font {